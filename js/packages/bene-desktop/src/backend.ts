@@ -0,0 +1,227 @@
+import { invoke, isTauri } from "@tauri-apps/api/core";
+import { getCurrentWebview } from "@tauri-apps/api/webview";
+import { getCurrentWebviewWindow } from "@tauri-apps/api/webviewWindow";
+import { open as openDialog } from "@tauri-apps/plugin-dialog";
+import { open as openShell } from "@tauri-apps/plugin-shell";
+import type {
+  Annotation,
+  Epub,
+  LoadProgress,
+  ReadingPosition,
+  Target
+} from "bene-types";
+
+export type SharedState =
+  | { type: "Waiting" }
+  | { type: "Loading"; value: LoadProgress }
+  | { type: "Error"; value: string }
+  | { type: "Ready"; value: { epub: Epub; target: Target | null } };
+
+/** The commands of the reader's backend, which runs either in the app or in `bene serve`. */
+export interface Backend {
+  /** The URL of the reader's page. */
+  readerUrl: string;
+  state(): Promise<SharedState>;
+  onState(callback: (state: SharedState) => void): void;
+  /** Called with the paths of files in the EPUB which changed without changing its package. */
+  onResourcesChanged(callback: (paths: string[]) => void): void;
+  /** Asks the user for an EPUB to open. */
+  requestUpload(): Promise<void>;
+  /** Opens an EPUB dropped onto the window. */
+  uploadFile(file: File): Promise<void>;
+  /** Stops opening an EPUB, going back to the EPUB opened before it. */
+  cancelLoad(): Promise<void>;
+  openUrl(url: string): void;
+  /** Returns the stored annotations on the loaded EPUB. */
+  listAnnotations(): Promise<Annotation[]>;
+  createAnnotation(annotation: Annotation): Promise<void>;
+  updateAnnotation(annotation: Annotation): Promise<void>;
+  deleteAnnotation(id: string): Promise<void>;
+  /** Returns where to open the loaded EPUB, if not at its beginning. */
+  readingPosition(): Promise<ReadingPosition | undefined>;
+  saveReadingPosition(position: ReadingPosition): Promise<void>;
+}
+
+class TauriBackend implements Backend {
+  // Custom schemes generate different URLs on Windows vs. non-Windows
+  // platforms. See: https://docs.rs/tauri/2.9.5/tauri/struct.Builder.html#warning
+  readerUrl = window.navigator.platform.startsWith("Win")
+    ? "http://bene.localhost/index.html"
+    : "bene://localhost/index.html";
+
+  constructor() {
+    getCurrentWebview().onDragDropEvent(event => {
+      if (event.payload.type === "drop") {
+        let paths = event.payload.paths;
+        if (paths.length !== 1) return;
+
+        let path = paths[0];
+        if (!path.endsWith("epub")) return;
+
+        this.upload(path);
+      }
+    });
+  }
+
+  async upload(path: string) {
+    await invoke("upload", { path });
+  }
+
+  state() {
+    return invoke<SharedState>("state");
+  }
+
+  // Each window has its own book, so only listen to events sent to this window.
+  onState(callback: (state: SharedState) => void) {
+    getCurrentWebviewWindow().listen<SharedState>("state", event =>
+      callback(event.payload)
+    );
+  }
+
+  onResourcesChanged(callback: (paths: string[]) => void) {
+    getCurrentWebviewWindow().listen<string[]>("resources-changed", event =>
+      callback(event.payload)
+    );
+  }
+
+  async requestUpload() {
+    let path = await openDialog({
+      multiple: false,
+      directory: false,
+      filters: [
+        {
+          name: "EPUB",
+          extensions: ["epub"]
+        }
+      ]
+    });
+    if (!path) return;
+    await this.upload(path);
+  }
+
+  async uploadFile(_file: File) {
+    // Dragged files should be handled by Tauri-level drag events,
+    // so this should never be called.
+    throw Error("Unreachable");
+  }
+
+  async cancelLoad() {
+    await invoke("cancel_load");
+  }
+
+  openUrl(url: string) {
+    openShell(url);
+  }
+
+  listAnnotations() {
+    return invoke<Annotation[]>("list_annotations");
+  }
+
+  async createAnnotation(annotation: Annotation) {
+    await invoke("create_annotation", { annotation });
+  }
+
+  async updateAnnotation(annotation: Annotation) {
+    await invoke("update_annotation", { annotation });
+  }
+
+  async deleteAnnotation(id: string) {
+    await invoke("delete_annotation", { id });
+  }
+
+  async readingPosition() {
+    let position = await invoke<ReadingPosition | null>("reading_position");
+    return position ?? undefined;
+  }
+
+  async saveReadingPosition(position: ReadingPosition) {
+    await invoke("save_reading_position", { position });
+  }
+}
+
+/** Talks to the HTTP API of `bene serve`. */
+class HttpBackend implements Backend {
+  readerUrl = "bene-reader/index.html";
+  events = new EventSource("api/events");
+
+  async request(method: string, path: string, body?: BodyInit) {
+    let response = await fetch(`api/${path}`, { method, body });
+    if (!response.ok) throw Error(await response.text());
+    return response;
+  }
+
+  async state() {
+    let response = await this.request("GET", "state");
+    return (await response.json()) as SharedState;
+  }
+
+  onState(callback: (state: SharedState) => void) {
+    this.events.addEventListener("state", event =>
+      callback(JSON.parse(event.data) as SharedState)
+    );
+  }
+
+  onResourcesChanged(callback: (paths: string[]) => void) {
+    this.events.addEventListener("resources-changed", event =>
+      callback(JSON.parse(event.data) as string[])
+    );
+  }
+
+  requestUpload() {
+    return new Promise<void>((resolve, reject) => {
+      let input = document.createElement("input");
+      input.type = "file";
+      input.accept = ".epub,application/epub+zip";
+      input.addEventListener("change", () => {
+        let file = input.files?.[0];
+        if (file) this.uploadFile(file).then(resolve, reject);
+        else resolve();
+      });
+      input.click();
+    });
+  }
+
+  async uploadFile(file: File) {
+    await this.request("POST", "upload", file);
+  }
+
+  async cancelLoad() {
+    await this.request("POST", "cancel");
+  }
+
+  openUrl(url: string) {
+    window.open(url, "_blank", "noopener");
+  }
+
+  async listAnnotations() {
+    let response = await this.request("GET", "annotations");
+    return (await response.json()) as Annotation[];
+  }
+
+  async createAnnotation(annotation: Annotation) {
+    await this.request("POST", "annotations", JSON.stringify(annotation));
+  }
+
+  async updateAnnotation(annotation: Annotation) {
+    await this.request(
+      "PUT",
+      `annotations/${encodeURIComponent(annotation.id)}`,
+      JSON.stringify(annotation)
+    );
+  }
+
+  async deleteAnnotation(id: string) {
+    await this.request("DELETE", `annotations/${encodeURIComponent(id)}`);
+  }
+
+  // `bene serve` does not keep reading positions, as it may be shared by several readers.
+  async readingPosition() {
+    return undefined;
+  }
+
+  async saveReadingPosition(_position: ReadingPosition) {}
+}
+
+export function createBackend(): Backend {
+  return isTauri() ? new TauriBackend() : new HttpBackend();
+}
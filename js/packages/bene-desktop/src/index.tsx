@@ -1,15 +1,12 @@
-import { invoke } from "@tauri-apps/api/core";
-import { listen } from "@tauri-apps/api/event";
-import { getCurrentWebview } from "@tauri-apps/api/webview";
-import { open as openDialog } from "@tauri-apps/plugin-dialog";
-import { open as openShell } from "@tauri-apps/plugin-shell";
 import type {
   ChildMessage,
-  Epub,
   LoadedEpub,
   ParentMessage,
   Result
 } from "bene-types";
+import { createBackend, type SharedState } from "./backend";
+
+const backend = createBackend();
 
 let child_ready = false;
 
@@ -25,91 +22,80 @@ function sendMessageToChild(message: ParentMessage) {
   }
 }
 
-async function upload(path: string) {
-  await invoke("upload", { path });
-}
-
 window.addEventListener("message", async event => {
   const message = event.data as ChildMessage;
   console.info("Parent received message:", message);
 
   if (message.type === "ready") {
     child_ready = true;
-    let state = await invoke<SharedState>("state");
-    handleSharedState(state);
+    let state = await backend.state();
+    await handleSharedState(state);
   } else if (message.type === "request-upload") {
-    let path = await openDialog({
-      multiple: false,
-      directory: false,
-      filters: [
-        {
-          name: "EPUB",
-          extensions: ["epub"]
-        }
-      ]
-    });
-    if (!path) return;
-    upload(path);
+    await backend.requestUpload();
+  } else if (message.type === "cancel-load") {
+    await backend.cancelLoad();
   } else if (message.type === "open-url") {
     const urlStr = message.data;
-    openShell(urlStr.toString());
+    backend.openUrl(urlStr.toString());
   } else if (message.type === "navigate") {
     // Ignore, this is just for web target.
+  } else if (message.type === "save-annotation") {
+    await backend.createAnnotation(message.data);
+  } else if (message.type === "update-annotation") {
+    await backend.updateAnnotation(message.data);
+  } else if (message.type === "delete-annotation") {
+    await backend.deleteAnnotation(message.data);
+  } else if (message.type === "save-position") {
+    await backend.saveReadingPosition(message.data);
   } else if (message.type === "finished-upload") {
-    // Dragged files should be handled by Tauri-level drag events,
-    // so this should never be called.
-    throw Error("Unreachable");
+    await backend.uploadFile(message.data);
   } else {
     console.warn("Unhandled message", message);
   }
 });
 
-type SharedState =
-  | { type: "Waiting" }
-  | { type: "Loading" }
-  | { type: "Error"; value: string }
-  | { type: "Ready"; value: Epub };
-
-function handleSharedState(state: SharedState) {
+async function handleSharedState(state: SharedState) {
   let epubResult: Result<LoadedEpub, string> | undefined;
   if (state.type === "Ready") {
-    const epub = state.value as Epub;
+    const { epub, target } = state.value;
+    const position = await backend.readingPosition();
+    const annotations = await backend.listAnnotations().catch(err => {
+      console.warn("Failed to load annotations", err);
+      return [];
+    });
     epubResult = {
       status: "ok",
-      data: { metadata: epub, url: undefined, path: "" }
+      data: {
+        metadata: epub,
+        url: undefined,
+        path: "",
+        position,
+        target: target ?? undefined,
+        annotations
+      }
     };
   } else if (state.type === "Error") {
     epubResult = {
       status: "error",
       error: state.value
     };
-  } else if (state.type === "Waiting" || state.type === "Loading") {
+  } else if (state.type === "Loading") {
+    sendMessageToChild({ type: "loading", data: state.value });
+    return;
+  } else if (state.type === "Waiting") {
+    sendMessageToChild({ type: "waiting" });
     return;
   }
 
   sendMessageToChild({ type: "loaded-epub", data: epubResult! });
 }
 
-listen<SharedState>("state", event => handleSharedState(event.payload));
-
-getCurrentWebview().onDragDropEvent(event => {
-  if (event.payload.type === "drop") {
-    let paths = event.payload.paths;
-    if (paths.length !== 1) return;
-
-    let path = paths[0];
-    if (!path.endsWith("epub")) return;
-
-    upload(path);
-  }
-});
+backend.onState(handleSharedState);
+backend.onResourcesChanged(paths =>
+  sendMessageToChild({ type: "resources-changed", data: paths })
+);
 
-// Custom schemes generate different URLs on Windows vs. non-Windows
-// platforms. See: https://docs.rs/tauri/2.9.5/tauri/struct.Builder.html#warning
-const isWindows = window.navigator.platform.startsWith("Win");
 const iframe = document.createElement("iframe");
 iframe.id = "reader";
-iframe.src = isWindows
-  ? "http://bene.localhost/index.html"
-  : "bene://localhost/index.html";
+iframe.src = backend.readerUrl;
 document.body.appendChild(iframe);
@@ -1,8 +1,17 @@
 import type { Rendition } from "bene-types";
 import type { Annotation } from "bene-types/bindings/Annotation";
+import type { Body } from "bene-types/bindings/Body";
+import type { Fragment } from "bene-types/bindings/Fragment";
 import type { Path } from "bene-types/bindings/Path";
-import { type DocState, useDocState } from "./index";
+import { unwrap } from "solid-js/store";
+import { type DocState, sendMessageToParent, useDocState } from "./index";
 import { type Plugin, SolidPlugin } from "./plugin";
+import { INJECTED_ATTR, spineStep } from "./position";
+
+/** Set on the highlights of the user's annotations to the annotation's id. */
+const ANNOTATION_ATTR = "data-bene-annotation";
+
+type TextBody = Extract<Body, { type: "Text" }>;
 
 function getTextRanges(fullRange: Range): Range[] {
   // TODO: this might cause perf issues in large docs because some selections
@@ -156,7 +165,7 @@ function annotateSelection(
   state: DocState,
   chapterHref: string,
   selection: Selection
-) {
+): Annotation {
   let rendition = state.rendition();
   const pkg = new DOMParser().parseFromString(
     rendition.package_string,
@@ -172,7 +181,7 @@ function annotateSelection(
     components: [
       {
         type: "Step",
-        value: 6
+        value: spineStep(rendition)
       },
       {
         type: "Step",
@@ -248,10 +257,26 @@ function annotateSelection(
     range: { from, to }
   };
   let annotation: Annotation = {
-    selector,
-    body: null
+    id: crypto.randomUUID(),
+    motivation: ["highlighting"],
+    created: new Date().toISOString(),
+    creator: [],
+    modified: null,
+    audience: [],
+    rights: [],
+    targets: [{ selector }],
+    bodies: []
   };
-  addAnnotation(contentDoc, contentWindow, state, chapterHref, annotation);
+  addAnnotation(
+    contentDoc,
+    contentWindow,
+    state,
+    chapterHref,
+    annotation,
+    true
+  );
+  sendMessageToParent({ type: "save-annotation", data: annotation });
+  return annotation;
 }
 
 function addAnnotation(
@@ -259,7 +284,86 @@ function addAnnotation(
   contentWindow: Window & typeof globalThis,
   state: DocState,
   chapterHref: string,
-  { selector }: Annotation
+  annotation: Annotation,
+  editable: boolean
+) {
+  let title = describeAnnotation(annotation);
+  let id = editable ? annotation.id : undefined;
+  for (let { selector } of annotation.targets) {
+    addTarget(
+      contentDoc,
+      contentWindow,
+      state,
+      chapterHref,
+      selector,
+      title,
+      id
+    );
+  }
+}
+
+/** Returns the highlights of the user's annotation with the given `id`. */
+function findMarks(contentDoc: Document, id: string): HTMLElement[] {
+  return Array.from(
+    contentDoc.querySelectorAll<HTMLElement>(
+      `mark[${ANNOTATION_ATTR}="${CSS.escape(id)}"]`
+    )
+  );
+}
+
+const isComment = (body: Body): body is TextBody =>
+  body.type === "Text" && !body.purpose.includes("tagging");
+
+/** Returns `annotation` with its first comment replaced by `comment`, or removed if empty. */
+function setComment(annotation: Annotation, comment: string): Annotation {
+  let bodies = [...annotation.bodies];
+  let index = bodies.findIndex(isComment);
+  if (index === -1) {
+    if (comment)
+      bodies.push({
+        type: "Text",
+        value: comment,
+        format: null,
+        language: null,
+        purpose: ["commenting"]
+      });
+  } else if (comment) {
+    bodies[index] = { ...(bodies[index] as TextBody), value: comment };
+  } else {
+    bodies.splice(index, 1);
+  }
+  return { ...annotation, bodies, modified: new Date().toISOString() };
+}
+
+/** Summarizes the authorship and tags of an annotation for display on hover. */
+function describeAnnotation(annotation: Annotation): string {
+  let lines = [];
+  let authors = annotation.creator
+    .map(agent => agent.name ?? agent.nickname ?? agent.id)
+    .filter(name => name !== null);
+  if (authors.length > 0) lines.push(`By ${authors.join(", ")}`);
+
+  let tags = annotation.bodies.flatMap(body =>
+    body.type === "Text" && body.purpose.includes("tagging") ? [body.value] : []
+  );
+  if (tags.length > 0) lines.push(`Tags: ${tags.join(", ")}`);
+
+  let comments = annotation.bodies.flatMap(body =>
+    isComment(body) ? [body.value] : []
+  );
+  lines.push(...comments);
+
+  return lines.join("\n");
+}
+
+function addTarget(
+  contentDoc: Document,
+  contentWindow: Window & typeof globalThis,
+  state: DocState,
+  chapterHref: string,
+  selector: Fragment,
+  title: string,
+  id?: string
 ) {
   let resolver = new CFIResolver(
     state.rendition(),
@@ -306,24 +410,45 @@ function addAnnotation(
       // todo
     } else {
       let mark = contentDoc.createElement("mark");
+      mark.setAttribute(INJECTED_ATTR, "");
+      if (title) mark.title = title;
+      if (id) mark.setAttribute(ANNOTATION_ATTR, id);
       textRange.surroundContents(mark);
     }
   }
 }
 
+/** Highlights the rendition's annotations and the user's on the current chapter. */
 function addAnnotations(
-  _contentDoc: Document,
-  _contentWindow: Window & typeof globalThis,
-  _state: DocState,
-  _chapterHref: string
+  contentDoc: Document,
+  contentWindow: Window & typeof globalThis,
+  state: DocState
 ) {
-  // for (let annot of state.rendition().annotations) {
-  //   addAnnotation(contentDoc, contentWindow, state, chapterHref, annot);
-  // }
+  let chapterHref = state.chapterHref();
+  let annotations = [
+    ...state.rendition().annotations.map(a => [a, false] as const),
+    ...state.annotations.map(a => [a, true] as const)
+  ];
+  for (let [annotation, editable] of annotations) {
+    // Annotations on other chapters are skipped by the resolver, so only errors end up here.
+    try {
+      addAnnotation(
+        contentDoc,
+        contentWindow,
+        state,
+        chapterHref,
+        annotation,
+        editable
+      );
+    } catch (err) {
+      console.warn(`Failed to show annotation ${annotation.id}`, err);
+    }
+  }
 }
 
 interface AnnotationState {
-  annotations: Annotation[];
+  /** The id of the user's annotation whose highlight was last clicked. */
+  selected?: string;
 }
 
 export class AnnotationPlugin
@@ -331,36 +456,109 @@ export class AnnotationPlugin
   implements Plugin
 {
   initialState() {
-    return { annotations: [] };
+    return {};
+  }
+
+  /** Selects the annotation with the given `id`, or none. */
+  select(contentDoc: Document, id: string | undefined) {
+    for (let mark of contentDoc.querySelectorAll("mark.selected"))
+      mark.classList.remove("selected");
+    if (id) {
+      for (let mark of findMarks(contentDoc, id))
+        mark.classList.add("selected");
+    }
+    this.setState({ selected: id });
   }
 
   Toolbar = () => {
-    let [docState] = useDocState();
+    let [docState, setDocState] = useDocState();
+    let contentDoc = () => docState.iframe!.contentDocument!;
+    let selected = () =>
+      docState.annotations.find(a => a.id === this.state.selected);
 
     function highlightSelection() {
       let selection = docState.iframe!.contentWindow!.getSelection();
       if (!selection) return;
 
-      annotateSelection(
-        docState.iframe!.contentDocument!,
+      let annotation = annotateSelection(
+        contentDoc(),
         docState.iframe!.contentWindow! as Window & typeof globalThis,
         docState,
         docState.chapterHref(),
         selection
       );
+      setDocState({ annotations: [...docState.annotations, annotation] });
+    }
+
+    function updateComment(annotation: Annotation, comment: string) {
+      let updated = setComment(unwrap(annotation), comment.trim());
+      let title = describeAnnotation(updated);
+      for (let mark of findMarks(contentDoc(), updated.id)) mark.title = title;
+      setDocState({
+        annotations: docState.annotations.map(a =>
+          a.id === updated.id ? updated : a
+        )
+      });
+      sendMessageToParent({ type: "update-annotation", data: updated });
     }
 
+    let removeAnnotation = (annotation: Annotation) => {
+      for (let mark of findMarks(contentDoc(), annotation.id)) {
+        let parent = mark.parentNode!;
+        mark.replaceWith(...mark.childNodes);
+        parent.normalize();
+      }
+      this.setState({ selected: undefined });
+      setDocState({
+        annotations: docState.annotations.filter(a => a.id !== annotation.id)
+      });
+      sendMessageToParent({ type: "delete-annotation", data: annotation.id });
+    };
+
     return (
-      <button
-        type="button"
-        class="icon-button highlight"
-        aria-label="Highlight text"
-        onClick={highlightSelection}
-      />
+      <>
+        {selected() && (
+          <>
+            <input
+              type="text"
+              class="annotation-comment"
+              aria-label="Comment on highlight"
+              placeholder="Add a comment"
+              value={selected()!.bodies.find(isComment)?.value ?? ""}
+              onChange={e => updateComment(selected()!, e.target.value)}
+            />
+            <button
+              type="button"
+              class="icon-button delete"
+              aria-label="Remove highlight"
+              onClick={() => removeAnnotation(selected()!)}
+            />
+            <div class="split-icon-button-separator" />
+          </>
+        )}
+        <button
+          type="button"
+          class="icon-button highlight"
+          aria-label="Highlight text"
+          onClick={highlightSelection}
+        />
+      </>
     );
   };
 
-  mount(document: Document, window: Window & typeof globalThis): void {
-    addAnnotations(document, window, {} as any, {} as any);
+  mount(
+    document: Document,
+    window: Window & typeof globalThis,
+    state: DocState
+  ): void {
+    this.setState({ selected: undefined });
+    addAnnotations(document, window, state);
+    document.addEventListener("click", event => {
+      let mark =
+        event.target instanceof window.Element
+          ? event.target.closest(`mark[${ANNOTATION_ATTR}]`)
+          : null;
+      this.select(document, mark?.getAttribute(ANNOTATION_ATTR) ?? undefined);
+    });
   }
 }
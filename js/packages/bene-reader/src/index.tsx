@@ -2,12 +2,16 @@ import componentScriptUrl from "bene-components?url";
 import { throttle } from "@solid-primitives/scheduled";
 import componentStyleUrl from "bene-components/dist/bene-components.css?url";
 import type {
+  Annotation,
   ChildMessage,
   Epub,
   Item,
   LoadedEpub,
+  LoadProgress,
   ParentMessage,
-  Rendition
+  ReadingPosition,
+  Rendition,
+  Target
 } from "bene-types";
 import _ from "lodash";
 import {
@@ -15,6 +19,7 @@ import {
   createEffect,
   createSignal,
   on,
+  onCleanup,
   onMount,
   useContext
 } from "solid-js";
@@ -24,16 +29,28 @@ import contentStyleUrl from "../styles/content.scss?url";
 import { AnnotationPlugin } from "./annotation";
 import { findNavItem, Nav } from "./nav";
 import type { Plugin } from "./plugin";
+import {
+  currentPosition,
+  goToTarget,
+  INJECTED_ATTR,
+  restorePosition,
+  spineIndex,
+  targetSpineIndex
+} from "./position";
 import { ZoomPlugin } from "./zoom";
 
 const ZOOM_PLUGIN = new ZoomPlugin();
 const ANNOT_PLUGIN = new AnnotationPlugin();
 const PLUGINS: Plugin[] = [ZOOM_PLUGIN, ANNOT_PLUGIN];
 
+/** Receives `change` events with the paths of EPUB files which changed on disk. */
+const resourcesChanged = new EventTarget();
+
 function insertJs(doc: Document, url: string) {
   const script = doc.createElement("script");
   script.setAttribute("type", "text/javascript");
   script.setAttribute("src", url);
+  script.setAttribute(INJECTED_ATTR, "");
   doc.body.appendChild(script);
 }
 
@@ -42,6 +59,7 @@ export function insertCss(doc: Document, url: string) {
   link.setAttribute("rel", "stylesheet");
   link.setAttribute("type", "text/css");
   link.setAttribute("href", url);
+  link.setAttribute(INJECTED_ATTR, "");
   doc.head.appendChild(link);
 }
 
@@ -61,6 +79,12 @@ export interface DocState {
   epub: Epub;
   url?: URL;
   initialPath?: string;
+  /** Where to open the EPUB, if not at its beginning. */
+  position?: ReadingPosition;
+  /** Where to open the EPUB instead of `position`, if it was opened with a link to a location. */
+  target?: Target;
+  /** The user's annotations on the EPUB, as opposed to those shipped with its renditions. */
+  annotations: Annotation[];
   iframe?: HTMLIFrameElement;
 
   rendition(): Rendition;
@@ -71,8 +95,14 @@ export interface DocState {
 }
 
 type State =
-  | { type: "ready"; state: DocState }
+  | {
+      type: "ready";
+      state: DocState;
+      /** The progress of opening another EPUB in place of this one. */
+      loading?: LoadProgress;
+    }
   | { type: "error"; error: string }
+  | { type: "loading"; progress: LoadProgress }
   | { type: "waiting" };
 
 export const epubUrl = (rendition: Rendition, href: string) =>
@@ -213,7 +243,11 @@ function Content(props: { navigateEvent: EventTarget }) {
       docHeight: number;
     }
 
-    let initializedScroll = state.chapterUrl().includes("#");
+    // A target or saved reading position takes precedence over the last scroll position.
+    const opensAtLocation =
+      state.target !== undefined || state.position !== undefined;
+    let initializedScroll = state.chapterUrl().includes("#") || opensAtLocation;
+    let restoredPosition = !opensAtLocation;
     const savedScrollStr = localStorage.getItem(SCROLL_KEY);
     const savedScroll =
       savedScrollStr !== null
@@ -254,13 +288,21 @@ function Content(props: { navigateEvent: EventTarget }) {
         docHeight
       };
       localStorage.setItem(SCROLL_KEY, JSON.stringify(scrollInfo));
+      if (restoredPosition) savePosition();
     }
 
+    const POSITION_SAVE_DELAY = 1000;
+    const savePosition = throttle(() => {
+      const position = currentPosition(state, iframe.contentWindow!);
+      if (position) sendMessageToParent({ type: "save-position", data: position });
+    }, POSITION_SAVE_DELAY);
+
     function injectReaderStylesAndScripts(contentDoc: Document) {
       insertCss(contentDoc, contentStyleUrl);
       insertCss(contentDoc, componentStyleUrl);
 
       const styleEl = contentDoc.createElement("style");
+      styleEl.setAttribute(INJECTED_ATTR, "");
       updateStyleEl(styleEl);
       contentDoc.head.appendChild(styleEl);
       setStyleEl(styleEl);
@@ -341,12 +383,14 @@ function Content(props: { navigateEvent: EventTarget }) {
 
     function makePortable(contentDoc: Document) {
       let article = contentDoc.createElement("article");
+      article.setAttribute(INJECTED_ATTR, "");
       article.append(...contentDoc.body.children);
       contentDoc.body.appendChild(article);
 
       contentDoc.querySelectorAll("figcaption").forEach(el => {
         if (el.children.length > 1) {
           let container = contentDoc.createElement("div");
+          container.setAttribute(INJECTED_ATTR, "");
           container.append(...el.children);
           el.appendChild(container);
         }
@@ -358,9 +402,38 @@ function Content(props: { navigateEvent: EventTarget }) {
       if (!article) throw Error("Missing <article> element!");
 
       const handleRoot = contentDoc.createElement("resize-handle");
+      handleRoot.setAttribute(INJECTED_ATTR, "");
       article.appendChild(handleRoot);
     }
 
+    // Re-renders the document if it or a file it uses changed, keeping the scroll position.
+    const onResourcesChanged = (e: Event) => {
+      const paths = (e as CustomEvent<string[]>).detail;
+      const contentWindow = iframe.contentWindow!;
+      const urls = [
+        contentWindow.location.href,
+        ...contentWindow.performance
+          .getEntriesByType("resource")
+          .map(entry => entry.name)
+      ].map(url => decodeURIComponent(new URL(url).pathname));
+      const affected = paths.some(path =>
+        urls.some(url => url.endsWith(`/epub-content/${path}`))
+      );
+      if (!affected) return;
+
+      const scrollY = contentWindow.scrollY;
+      iframe.addEventListener(
+        "load",
+        () => iframe.contentWindow!.scrollTo({ top: scrollY }),
+        { once: true }
+      );
+      contentWindow.location.reload();
+    };
+    resourcesChanged.addEventListener("change", onResourcesChanged);
+    onCleanup(() =>
+      resourcesChanged.removeEventListener("change", onResourcesChanged)
+    );
+
     function handleSelection(contentDoc: Document) {
       contentDoc.addEventListener("mouseup", () => {
         let selection = contentDoc.getSelection();
@@ -392,8 +465,14 @@ function Content(props: { navigateEvent: EventTarget }) {
       handleSelection(contentDoc);
 
       PLUGINS.forEach(plugin => {
-        if (plugin.mount) plugin.mount(contentDoc, contentWindow);
+        if (plugin.mount) plugin.mount(contentDoc, contentWindow, state);
       });
+
+      if (!restoredPosition) {
+        if (state.target) goToTarget(contentWindow, state.target);
+        else restorePosition(state, contentWindow, state.position!);
+        restoredPosition = true;
+      }
     });
   });
 
@@ -439,6 +518,30 @@ function ViewerInner() {
   );
 }
 
+const loadStageLabels: Record<LoadProgress["stage"], string> = {
+  "reading-container": "Reading container",
+  "parsing-packages": "Parsing packages",
+  "building-indexes": "Building indexes"
+};
+
+function Progress(props: { progress: LoadProgress }) {
+  return (
+    <>
+      <div class="loader" />
+      <p>
+        Step {props.progress.step} of {props.progress.steps}:{" "}
+        {loadStageLabels[props.progress.stage]}…
+      </p>
+      <button
+        type="button"
+        onClick={() => sendMessageToParent({ type: "cancel-load" })}
+      >
+        Cancel
+      </button>
+    </>
+  );
+}
+
 function Loader() {
   const [state] = useContext(StateContext)!;
 
@@ -457,6 +560,8 @@ function Loader() {
             style={{ opacity: 0.4, top: "2px", left: "3px" }}
           />
         </>
+      ) : state.type === "loading" ? (
+        <Progress progress={state.progress} />
       ) : state.type === "error" ? (
         <pre>Error: {state.error}</pre>
       ) : null}
@@ -468,7 +573,20 @@ function Viewer() {
   const [state] = useContext(StateContext)!;
   return (
     <div class="viewer">
-      {state.type === "ready" ? <ViewerInner /> : <Loader />}
+      {state.type === "ready" ? (
+        <>
+          <ViewerInner />
+          {state.loading && (
+            <div class="load-overlay">
+              <div class="loader-container">
+                <Progress progress={state.loading} />
+              </div>
+            </div>
+          )}
+        </>
+      ) : (
+        <Loader />
+      )}
     </div>
   );
 }
@@ -505,6 +623,9 @@ function createInitialState(data: LoadedEpub): DocState {
     epub: data.metadata,
     url: data.url ? new URL(data.url) : undefined,
     initialPath: data.path,
+    position: data.position,
+    target: data.target,
+    annotations: data.annotations ?? [],
 
     rendition() {
       return data.metadata.renditions[this.renditionIndex];
@@ -543,6 +664,19 @@ function createInitialState(data: LoadedEpub): DocState {
     }
   };
 
+  // Open the chapter of the target, or else of the reading position if it is still in the spine.
+  const targetChapter =
+    data.target && targetSpineIndex(state.rendition(), data.target);
+  if (targetChapter === undefined) state.target = undefined;
+  const chapter =
+    targetChapter ?? (data.position && spineIndex(data.position.cfi));
+  if (
+    chapter !== undefined &&
+    chapter < state.rendition().package.spine.itemref.length
+  ) {
+    state.chapterIndex = chapter;
+  }
+
   // Show nav by default if there's at least one item in the spine.
   state.showNav = state.rendition().package.spine.itemref.length > 1;
 
@@ -570,11 +704,27 @@ function listenForParentMessages(setState: SetStoreFunction<State>) {
           })
         );
       }
+    } else if (message.type === "loading") {
+      // Keep showing the current EPUB while it reloads, or while another one opens on top of it.
+      let progress = message.data;
+      setState(state =>
+        state.type !== "ready"
+          ? { type: "loading", progress }
+          : progress.reload
+            ? state
+            : { ...state, loading: progress }
+      );
+    } else if (message.type === "waiting") {
+      setState(reconcile({ type: "waiting" }));
+    } else if (message.type === "resources-changed") {
+      resourcesChanged.dispatchEvent(
+        new CustomEvent("change", { detail: message.data })
+      );
     }
   });
 }
 
-function sendMessageToParent(message: ChildMessage) {
+export function sendMessageToParent(message: ChildMessage) {
   window.parent.postMessage(message, "*");
 }
 
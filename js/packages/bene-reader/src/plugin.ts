@@ -1,9 +1,15 @@
 import type { JSX } from "solid-js/jsx-runtime";
 import { createStore, type SetStoreFunction } from "solid-js/store";
+import type { DocState } from "./index";
 
 export interface Plugin {
   Toolbar?(): JSX.Element;
-  mount?(document: Document, window: Window & typeof globalThis): void;
+  /** Called each time a chapter of the EPUB in `state` is loaded. */
+  mount?(
+    document: Document,
+    window: Window & typeof globalThis,
+    state: DocState
+  ): void;
   onKeydown?(event: KeyboardEvent): void;
 }
 
@@ -0,0 +1,202 @@
+import type { ReadingPosition, Rendition, Target } from "bene-types";
+import type { DocState } from "./index";
+
+/**
+ * Marks elements which the reader adds to a document, like the `<article>` around the body of a
+ * non-portable EPUB or the `<mark>`s of annotations. CFIs look through them, so that they point
+ * into the document as it is in the EPUB.
+ */
+export const INJECTED_ATTR = "data-bene-injected";
+
+const isInjected = (el: Element) => el.hasAttribute(INJECTED_ATTR);
+
+/** Returns the children of `el` in the EPUB's document. */
+function sourceChildren(el: Element): Element[] {
+  return Array.from(el.children).flatMap(child =>
+    isInjected(child) ? sourceChildren(child) : [child]
+  );
+}
+
+// Characters which must be escaped with `^` in CFI assertions.
+const escapeAssertion = (id: string) => id.replace(/[\^[\](),;=]/g, "^$&");
+const unescapeAssertion = (id: string) => id.replace(/\^(.)/g, "$1");
+
+/** Returns the CFI steps from the document's root to `el`. */
+function elementSteps(el: Element): string {
+  let steps = "";
+  let node: Element = el;
+  while (node.parentElement) {
+    let parent = node.parentElement;
+    while (parent.parentElement && isInjected(parent)) {
+      parent = parent.parentElement;
+    }
+    const index = sourceChildren(parent).indexOf(node);
+    const assertion = node.id ? `[${escapeAssertion(node.id)}]` : "";
+    steps = `/${2 * (index + 1)}${assertion}${steps}`;
+    node = parent;
+  }
+  return steps;
+}
+
+/** Returns the element nearest the top of the window. */
+function topElement(doc: Document, win: Window): Element | undefined {
+  const article = doc.querySelector("article") ?? doc.body;
+  const rect = article.getBoundingClientRect();
+  const x = rect.left + rect.width / 2;
+  // Look further down if the top of the window falls between paragraphs.
+  for (let y = 1; y < win.innerHeight / 2; y += 16) {
+    let el = doc.elementFromPoint(x, y);
+    while (el && isInjected(el) && el.parentElement) el = el.parentElement;
+    if (el && el !== doc.body && el !== doc.documentElement && !isInjected(el))
+      return el;
+  }
+  return undefined;
+}
+
+/** Returns how far the window is scrolled through its document, from 0 to 1. */
+function scrollFraction(win: Window): number {
+  const maxScroll =
+    win.document.documentElement.scrollHeight - win.innerHeight;
+  return maxScroll > 0 ? Math.min(win.scrollY / maxScroll, 1) : 0;
+}
+
+const spineSteps = new WeakMap<Rendition, number>();
+
+/**
+ * Returns the CFI step of the spine in the package document of `rendition`, which is usually
+ * `/6` but comes later if e.g. a `<guide>` precedes the spine.
+ */
+export function spineStep(rendition: Rendition): number {
+  let step = spineSteps.get(rendition);
+  if (step === undefined) {
+    const pkg = new DOMParser().parseFromString(
+      rendition.package_string,
+      "application/xml"
+    );
+    const index = Array.from(pkg.documentElement.children).findIndex(
+      el => el.localName === "spine"
+    );
+    step = index === -1 ? 6 : 2 * (index + 1);
+    spineSteps.set(rendition, step);
+  }
+  return step;
+}
+
+/** Returns the reading position of the chapter shown in `win`. */
+export function currentPosition(
+  state: DocState,
+  win: Window
+): ReadingPosition | undefined {
+  const el = topElement(win.document, win);
+  if (!el) return undefined;
+
+  const rendition = state.rendition();
+  const itemrefs = rendition.package.spine.itemref;
+  const itemref = itemrefs[state.chapterIndex];
+  const itemrefStep = 2 * (state.chapterIndex + 1);
+  const itemrefAssertion = itemref["@id"]
+    ? `[${escapeAssertion(itemref["@id"])}]`
+    : "";
+  const spine = `/${spineStep(rendition)}/${itemrefStep}${itemrefAssertion}`;
+  return {
+    cfi: `epubcfi(${spine}!${elementSteps(el)})`,
+    percentage: (state.chapterIndex + scrollFraction(win)) / itemrefs.length
+  };
+}
+
+const STEP = /\/(\d+)(?:\[((?:\^.|[^\]^])*)\])?/g;
+
+/** Returns the index in the spine of the chapter which `cfi` points into. */
+export function spineIndex(cfi: string): number | undefined {
+  const match = cfi.match(/^epubcfi\(\/(\d+)\/(\d+)/);
+  if (!match) return undefined;
+  const [spine, itemref] = [parseInt(match[1]), parseInt(match[2])];
+  return spine % 2 === 0 && itemref % 2 === 0 && itemref > 0
+    ? itemref / 2 - 1
+    : undefined;
+}
+
+/**
+ * Returns the element in `doc` which `cfi` points to, or its nearest ancestor which can be found
+ * if the document has changed since.
+ */
+function resolveElement(cfi: string, doc: Document): Element | undefined {
+  const indirection = cfi.indexOf("!");
+  if (indirection === -1) return undefined;
+
+  let el = doc.documentElement;
+  for (const [, stepStr, assertion] of cfi
+    .slice(indirection + 1)
+    .matchAll(STEP)) {
+    const byId = assertion && doc.getElementById(unescapeAssertion(assertion));
+    if (byId) {
+      el = byId;
+      continue;
+    }
+
+    const step = parseInt(stepStr);
+    const child =
+      step % 2 === 0 ? sourceChildren(el)[step / 2 - 1] : undefined;
+    if (!child) break;
+    el = child as HTMLElement;
+  }
+  return el === doc.documentElement ? undefined : el;
+}
+
+/** Returns the index in the spine of the chapter which `target` points into. */
+export function targetSpineIndex(
+  rendition: Rendition,
+  target: Target
+): number | undefined {
+  if ("cfi" in target) {
+    const index = spineIndex(target.cfi);
+    return index !== undefined && index < rendition.package.spine.itemref.length
+      ? index
+      : undefined;
+  }
+
+  const href = decodeURI(target.href.split("#")[0]);
+  const item = rendition.package.manifest.item?.find(
+    candidate => decodeURI(candidate["@href"]) === href
+  );
+  if (!item) return undefined;
+  const index = rendition.package.spine.itemref.findIndex(
+    itemref => itemref["@idref"] === item["@id"]
+  );
+  return index === -1 ? undefined : index;
+}
+
+/** Scrolls the chapter shown in `win` to `target`, which points into it. */
+export function goToTarget(win: Window, target: Target) {
+  let el: Element | null | undefined;
+  if ("cfi" in target) {
+    el = resolveElement(target.cfi, win.document);
+  } else {
+    const fragment = target.href.split("#")[1];
+    el = fragment
+      ? win.document.getElementById(decodeURIComponent(fragment))
+      : undefined;
+  }
+  el?.scrollIntoView({ block: "start" });
+}
+
+/** Scrolls the chapter shown in `win` to `position`. */
+export function restorePosition(
+  state: DocState,
+  win: Window,
+  position: ReadingPosition
+) {
+  const el =
+    spineIndex(position.cfi) === state.chapterIndex
+      ? resolveElement(position.cfi, win.document)
+      : undefined;
+  if (el) {
+    el.scrollIntoView({ block: "start" });
+  } else {
+    const chapters = state.rendition().package.spine.itemref.length;
+    const fraction = position.percentage * chapters - state.chapterIndex;
+    const maxScroll =
+      win.document.documentElement.scrollHeight - win.innerHeight;
+    win.scrollTo({ top: Math.min(Math.max(fraction, 0), 1) * maxScroll });
+  }
+}
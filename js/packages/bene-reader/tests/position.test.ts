@@ -0,0 +1,48 @@
+import type { Rendition } from "bene-types";
+import { expect, test } from "vitest";
+import { spineIndex, spineStep, targetSpineIndex } from "../src/position";
+
+// A `<guide>` before the spine moves it from the third child of the package to the fourth.
+const PACKAGE = `<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+  <metadata/>
+  <manifest/>
+  <guide/>
+  <spine/>
+</package>`;
+
+function rendition(packageString: string): Rendition {
+  return {
+    package_string: packageString,
+    package: {
+      manifest: {
+        item: [
+          { "@id": "one", "@href": "one.xhtml" },
+          { "@id": "two", "@href": "two.xhtml" }
+        ]
+      },
+      spine: { itemref: [{ "@idref": "one" }, { "@idref": "two" }] }
+    }
+  } as unknown as Rendition;
+}
+
+test("spineStep", () => {
+  expect(spineStep(rendition(PACKAGE))).toBe(8);
+  expect(spineStep(rendition(PACKAGE.replace("<guide/>", "")))).toBe(6);
+});
+
+test("spineIndex", () => {
+  expect(spineIndex("epubcfi(/6/4!/4/2)")).toBe(1);
+  expect(spineIndex("epubcfi(/8/4[two]!/4/2/1:0)")).toBe(1);
+  expect(spineIndex("epubcfi(/8/3!/4)")).toBeUndefined();
+  expect(spineIndex("/8/4!/4")).toBeUndefined();
+});
+
+test("targetSpineIndex", () => {
+  let guided = rendition(PACKAGE);
+  expect(targetSpineIndex(guided, { cfi: "epubcfi(/8/4!/4/2)" })).toBe(1);
+  expect(targetSpineIndex(guided, { cfi: "epubcfi(/8/6!/4/2)" })).toBe(
+    undefined
+  );
+  expect(targetSpineIndex(guided, { href: "two.xhtml#sec" })).toBe(1);
+});
@@ -1,5 +1,7 @@
+import type { Annotation } from "./bindings/Annotation";
 import type { Epub } from "./bindings/Epub";
 
+export type { Annotation } from "./bindings/Annotation";
 export type { Epub } from "./bindings/Epub";
 export type { Item } from "./bindings/Item";
 export type { Path } from "./bindings/Path";
@@ -15,12 +17,50 @@ export interface LoadedEpub {
   metadata: Epub;
   path?: string;
   url?: string;
+  /** Where to open the EPUB, if not at its beginning. */
+  position?: ReadingPosition;
+  /** Where to open the EPUB instead of `position`, if it was opened with a link to a location. */
+  target?: Target;
+  /** The user's annotations on the EPUB, from the annotation store. */
+  annotations?: Annotation[];
 }
 
-export type ParentMessage = {
-  type: "loaded-epub";
-  data: Result<LoadedEpub, string>;
-};
+/** A location in an EPUB, as an EPUB CFI or the href of a content document with a fragment. */
+export type Target = { cfi: string } | { href: string };
+
+/** Where the reader last was in an EPUB. */
+export interface ReadingPosition {
+  /** An EPUB CFI of the element at the top of the screen. */
+  cfi: string;
+  /** How far through the EPUB the position is, from 0 to 1. */
+  percentage: number;
+}
+
+/** How far the desktop app has got in opening an EPUB. */
+export interface LoadProgress {
+  stage: "reading-container" | "parsing-packages" | "building-indexes";
+  /** The stage's number, counting from 1. */
+  step: number;
+  steps: number;
+  /** Whether the EPUB is the one already open, which stays open while it reloads. */
+  reload: boolean;
+}
+
+export type ParentMessage =
+  | {
+      type: "loaded-epub";
+      data: Result<LoadedEpub, string>;
+    }
+  | {
+      type: "loading";
+      data: LoadProgress;
+    }
+  | { type: "waiting" }
+  | {
+      /** Paths in the EPUB of files which changed on disk. */
+      type: "resources-changed";
+      data: string[];
+    };
 
 export type ChildMessage =
   | { type: "ready" }
@@ -33,7 +73,24 @@ export type ChildMessage =
       data: string;
     }
   | { type: "request-upload" }
+  | { type: "cancel-load" }
   | {
       type: "finished-upload";
       data: File;
+    }
+  | {
+      type: "save-annotation";
+      data: Annotation;
+    }
+  | {
+      type: "update-annotation";
+      data: Annotation;
+    }
+  | {
+      type: "delete-annotation";
+      data: string;
+    }
+  | {
+      type: "save-position";
+      data: ReadingPosition;
     };
@@ -170,6 +170,13 @@ function App() {
       } else if (message.type === "open-url") {
         const url = message.data;
         window.open(url, "_blank");
+      } else if (
+        message.type === "save-annotation" ||
+        message.type === "update-annotation" ||
+        message.type === "delete-annotation" ||
+        message.type === "save-position"
+      ) {
+        // Ignore, the web target has no annotation store or reading positions.
       } else if (message.type === "ready") {
         // Ignore, only used on desktop target.
         // TODO: *should* this be ignored?
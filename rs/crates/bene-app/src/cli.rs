@@ -0,0 +1,454 @@
+//! Subcommands which work on EPUB files from the command line without opening a window.
+
+use std::{
+  fs,
+  io::{self, Write},
+  path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, ensure};
+use bene_epub::{
+  Archive, Epub, FileZip, Item, MetaField, Rendition, ZipFormat,
+  annotation::{
+    self, Diagnostic,
+    export::{self, Format},
+    import::{self, Source},
+  },
+  nav::TocEntry,
+  validate::{self, Severity},
+};
+use clap::Subcommand;
+use serde::Serialize;
+
+use crate::store::{AnnotationStore, PublicationKey};
+
+#[derive(Subcommand)]
+pub enum Command {
+  /// Print the metadata of an EPUB as JSON
+  Info {
+    /// Path to .epub file or unzipped EPUB directory
+    path: PathBuf,
+  },
+
+  /// Print the table of contents of an EPUB
+  Toc {
+    /// Path to .epub file or unzipped EPUB directory
+    path: PathBuf,
+
+    /// Print the entries as JSON
+    #[arg(long)]
+    json: bool,
+  },
+
+  /// List the files in an EPUB, along with their manifest items
+  Ls {
+    /// Path to .epub file or unzipped EPUB directory
+    path: PathBuf,
+
+    /// Print the files as JSON
+    #[arg(long)]
+    json: bool,
+  },
+
+  /// Write the contents of a file in an EPUB to standard output
+  Cat {
+    /// Path to .epub file or unzipped EPUB directory
+    path: PathBuf,
+
+    /// Path of the file in the archive, or relative to the package document
+    file: String,
+  },
+
+  /// Check an EPUB for problems, exiting with an error if any are found
+  Validate {
+    /// Path to .epub file or unzipped EPUB directory
+    path: PathBuf,
+
+    /// Print the problems as JSON
+    #[arg(long)]
+    json: bool,
+  },
+
+  /// Print the annotations shipped with an EPUB and the user's own annotations on it as JSON
+  Annotations {
+    /// Path to .epub file or unzipped EPUB directory
+    path: PathBuf,
+  },
+
+  /// Export the annotations on an EPUB to Markdown, HTML or CSV
+  Export {
+    /// Path to .epub file or unzipped EPUB directory
+    path: PathBuf,
+
+    /// Output format: markdown, html or csv
+    #[arg(short, long, default_value = "markdown")]
+    format: Format,
+
+    /// File to write the export to, instead of standard output
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+  },
+
+  /// Import highlights exported from another reading system into the store
+  Import {
+    /// Path to .epub file or unzipped EPUB directory
+    path: PathBuf,
+
+    /// Where the export came from: kindle, koreader, apple-books or calibre
+    #[arg(long)]
+    from: Source,
+
+    /// The exported file, e.g. `My Clippings.txt`
+    file: PathBuf,
+  },
+
+  /// Merge annotations with other devices through a shared folder, e.g. one synced by Syncthing
+  Sync {
+    /// The shared folder, which every device syncs with
+    folder: PathBuf,
+  },
+
+  /// Serve the reader to web browsers, and the annotation store over the W3C Web Annotation
+  /// Protocol
+  #[cfg(feature = "server")]
+  Serve {
+    /// Path to an .epub file or unzipped EPUB directory to open
+    path: Option<PathBuf>,
+
+    /// Directory of the built frontend packages, laid out like `js/packages` in the repository
+    #[arg(long, default_value = concat!(env!("CARGO_MANIFEST_DIR"), "/../../../js/packages"))]
+    frontend_dir: PathBuf,
+
+    /// Address to listen on, e.g. `0.0.0.0:8181` to share annotations on the local network
+    #[arg(long, default_value = "127.0.0.1:8181")]
+    addr: String,
+
+    /// Number of annotations in each page of a container
+    #[arg(long, default_value_t = crate::server::PAGE_SIZE)]
+    page_size: usize,
+  },
+}
+
+impl Command {
+  /// Runs the subcommand, reading the user's annotations from the store in `store_dir`.
+  ///
+  /// # Errors
+  /// If the subcommand fails.
+  pub fn run(self, store_dir: PathBuf) -> Result<()> {
+    let store = AnnotationStore::new(store_dir.clone());
+    match self {
+      Command::Info { path } => {
+        let (_, epub) = load(&path)?;
+        print_json(&Info::new(rendition(&epub)?))
+      }
+      Command::Toc { path, json } => {
+        let (mut archive, epub) = load(&path)?;
+        let toc = rendition(&epub)?.toc(&mut archive)?;
+        if json {
+          return print_json(&toc);
+        }
+        print_toc(&toc, 0);
+        Ok(())
+      }
+      Command::Ls { path, json } => {
+        let (archive, epub) = load(&path)?;
+        let files = list_files(&archive, rendition(&epub)?);
+        if json {
+          return print_json(&files);
+        }
+        for file in files {
+          let item = file.item.map_or_else(
+            || "-\t-".to_string(),
+            |item| format!("{}\t{}", item.id, item.media_type),
+          );
+          println!("{}\t{item}", file.path);
+        }
+        Ok(())
+      }
+      Command::Cat { path, file } => {
+        let (mut archive, epub) = load(&path)?;
+        let file = if archive.contains(&file) {
+          file
+        } else {
+          rendition(&epub)?.file_path(&file)
+        };
+        let contents = archive.read_file(&file)?;
+        Ok(io::stdout().write_all(&contents)?)
+      }
+      Command::Validate { path, json } => validate(&path, json),
+      Command::Annotations { path } => print_annotations(&store, &path),
+      Command::Export {
+        path,
+        format,
+        output,
+      } => {
+        let (mut archive, epub) = load(&path)?;
+        let publication = PublicationKey::for_epub(&epub, &path)?;
+        let contents = export_annotations(&store, &publication, &mut archive, &epub, format)?;
+        match output {
+          Some(output) => fs::write(&output, contents)
+            .with_context(|| format!("Failed to write: {}", output.display())),
+          None => Ok(io::stdout().write_all(contents.as_bytes())?),
+        }
+      }
+      Command::Import { path, from, file } => {
+        let (mut archive, epub) = load(&path)?;
+        let publication = PublicationKey::for_epub(&epub, &path)?;
+        let summary = import_annotations(&store, &publication, &mut archive, &epub, from, &file)?;
+        println!("Imported {} new annotations from {from}", summary.imported);
+        if summary.other_publications > 0 {
+          println!(
+            "Skipped {} entries from other publications",
+            summary.other_publications
+          );
+        }
+        for diagnostic in &summary.unanchored {
+          eprintln!("Could not anchor {}", diagnostic.message);
+        }
+        Ok(())
+      }
+      Command::Sync { folder } => {
+        let changed = store.sync(&folder)?;
+        println!("Updated annotations on {changed} publications");
+        Ok(())
+      }
+      #[cfg(feature = "server")]
+      Command::Serve {
+        path,
+        frontend_dir,
+        addr,
+        page_size,
+      } => {
+        let frontend = crate::server::web::Frontend::new(&frontend_dir);
+        let mut server = crate::server::Server::bind(&addr, store)?
+          .with_page_size(page_size)
+          .with_reader(frontend, path)?;
+        // The app keeps its library next to the annotation store.
+        match crate::library::Library::open(&store_dir.with_file_name("library.sqlite3")) {
+          Ok(library) => server = server.with_library(library),
+          Err(err) => log::warn!("Failed to open library: {err:?}"),
+        }
+        let addr = server.local_addr().map_or(addr, |addr| addr.to_string());
+        println!("Serving the reader at http://{addr}/");
+        println!("Serving annotations at http://{addr}/annotations/");
+        server.run();
+        Ok(())
+      }
+    }
+  }
+}
+
+fn print_toc(entries: &[TocEntry], depth: usize) {
+  for entry in entries {
+    let indent = "  ".repeat(depth);
+    match &entry.href {
+      Some(href) => println!("{indent}{}\t{href}", entry.title),
+      None => println!("{indent}{}", entry.title),
+    }
+    print_toc(&entry.children, depth + 1);
+  }
+}
+
+fn validate(path: &Path, json: bool) -> Result<()> {
+  let mut archive =
+    Archive::load(FileZip(path.to_path_buf())).context("Failed to parse epub as zip")?;
+  let problems = validate::validate(&mut archive);
+  if json {
+    print_json(&problems)?;
+  } else {
+    for problem in &problems {
+      println!("{problem}");
+    }
+  }
+  let errors = problems
+    .iter()
+    .filter(|problem| problem.severity == Severity::Error)
+    .count();
+  ensure!(errors == 0, "Found {errors} errors in {}", path.display());
+  Ok(())
+}
+
+fn print_annotations(store: &AnnotationStore, path: &Path) -> Result<()> {
+  let (mut archive, epub) = load(path)?;
+  let rendition = rendition(&epub)?;
+  let publication = PublicationKey::for_epub(&epub, path)?;
+  let (stored, diagnostics) =
+    annotation::process(store.list(&publication)?, &mut archive, rendition);
+  for diagnostic in diagnostics {
+    eprintln!("Could not anchor stored {diagnostic}");
+  }
+  let source = publication.source();
+  let annotations = rendition
+    .annotations()
+    .iter()
+    .chain(&stored)
+    .map(|annotation| annotation.to_raw(&source))
+    .collect::<Vec<_>>();
+  print_json(&annotations)
+}
+
+fn rendition(epub: &Epub) -> Result<&Rendition> {
+  epub.renditions.first().context("EPUB has no renditions")
+}
+
+fn print_json<T: Serialize + ?Sized>(value: &T) -> Result<()> {
+  let mut stdout = io::stdout().lock();
+  serde_json::to_writer_pretty(&mut stdout, value)?;
+  Ok(writeln!(stdout)?)
+}
+
+/// The metadata printed by [`Command::Info`].
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Info<'a> {
+  identifier: Option<String>,
+  titles: Vec<&'a str>,
+  creators: Vec<&'a str>,
+  languages: Vec<&'a str>,
+  date: Option<&'a str>,
+  modified: Option<&'a str>,
+  version: &'a str,
+  package_path: &'a str,
+  spine: Vec<&'a str>,
+}
+
+impl<'a> Info<'a> {
+  fn new(rendition: &'a Rendition) -> Self {
+    let fields = &rendition.package.metadata.fields;
+    let values =
+      |f: fn(&MetaField) -> Option<&str>| fields.iter().filter_map(f).collect::<Vec<_>>();
+    Info {
+      identifier: rendition.unique_identifier(),
+      titles: values(|field| match field {
+        MetaField::Title(title) => Some(title),
+        _ => None,
+      }),
+      creators: values(|field| match field {
+        MetaField::Creator(creator) => Some(creator),
+        _ => None,
+      }),
+      languages: values(|field| match field {
+        MetaField::Language(language) => Some(language),
+        _ => None,
+      }),
+      date: values(|field| match field {
+        MetaField::Date(date) => Some(date),
+        _ => None,
+      })
+      .first()
+      .copied(),
+      modified: values(|field| match field {
+        MetaField::Meta {
+          property: Some(property),
+          contents,
+        } if property == "dcterms:modified" => contents.as_deref(),
+        _ => None,
+      })
+      .first()
+      .copied(),
+      version: &rendition.package.version,
+      package_path: rendition.package_path(),
+      spine: rendition
+        .package
+        .spine
+        .itemref
+        .iter()
+        .map(|itemref| itemref.idref.as_str())
+        .collect(),
+    }
+  }
+}
+
+/// A file in the archive listed by [`Command::Ls`].
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct File<'a> {
+  path: &'a str,
+  /// The manifest item for the file, if any.
+  item: Option<&'a Item>,
+}
+
+fn list_files<'a, F: ZipFormat>(
+  archive: &'a Archive<F>,
+  rendition: &'a Rendition,
+) -> Vec<File<'a>> {
+  archive
+    .file_names()
+    .filter(|path| !path.ends_with('/'))
+    .map(|path| File {
+      path,
+      item: rendition
+        .package
+        .manifest
+        .items
+        .iter()
+        .find(|item| rendition.file_path(&item.href) == path),
+    })
+    .collect()
+}
+
+fn load(path: &Path) -> Result<(Archive, Epub)> {
+  let mut archive =
+    Archive::load(FileZip(path.to_path_buf())).context("Failed to parse epub as zip")?;
+  let epub = Epub::load(&mut archive)?;
+  Ok((archive, epub))
+}
+
+/// Exports both the annotations shipped with `epub` and the user's own annotations on it.
+///
+/// # Errors
+/// If `epub` has no renditions or the store cannot be read.
+pub fn export_annotations<F: ZipFormat>(
+  store: &AnnotationStore,
+  publication: &PublicationKey,
+  archive: &mut Archive<F>,
+  epub: &Epub,
+  format: Format,
+) -> Result<String> {
+  let rendition = epub.renditions.first().context("EPUB has no renditions")?;
+  let mut annotations = rendition.annotations().to_vec();
+  annotations.extend(store.processed(publication, archive, rendition)?);
+  export::export(archive, epub, &annotations, format)
+}
+
+/// The outcome of importing an export into the store.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportSummary {
+  /// The number of annotations added to the store, excluding ones imported before.
+  pub imported: usize,
+  pub unanchored: Vec<Diagnostic>,
+  pub other_publications: usize,
+}
+
+/// Imports the entries in the export `file` which belong to `epub` into the user's store.
+///
+/// # Errors
+/// If `epub` has no renditions, `file` cannot be read or parsed, or the store cannot be written.
+pub fn import_annotations<F: ZipFormat>(
+  store: &AnnotationStore,
+  publication: &PublicationKey,
+  archive: &mut Archive<F>,
+  epub: &Epub,
+  source: Source,
+  file: &Path,
+) -> Result<ImportSummary> {
+  let rendition = epub.renditions.first().context("EPUB has no renditions")?;
+  let input =
+    fs::read_to_string(file).with_context(|| format!("Failed to read: {}", file.display()))?;
+  let entries = source
+    .parse(input.trim_start_matches('\u{feff}'))
+    .with_context(|| format!("Failed to parse {source} export: {}", file.display()))?;
+  let import = import::import(archive, rendition, source, entries);
+  let iri = publication.source();
+  let annotations = import
+    .annotations
+    .iter()
+    .map(|annotation| annotation.to_raw(&iri))
+    .collect();
+  Ok(ImportSummary {
+    imported: store.import(publication, annotations)?,
+    unanchored: import.unanchored,
+    other_publications: import.other_publications,
+  })
+}
@@ -0,0 +1,912 @@
+//! A catalog of the EPUBs in the user's library folders, kept in a database with a
+//! full-text index over their titles and authors, and the reading position in each publication.
+
+use std::{
+  collections::HashSet,
+  fs,
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use bene_epub::{Archive, Creator, Epub, FileZip, MetaField, Series};
+use log::{debug, warn};
+use rusqlite::{Connection, OptionalExtension, Transaction, params, params_from_iter};
+use serde::{Deserialize, Serialize};
+
+use crate::{reader::ReadingPosition, store::PublicationKey};
+
+/// The changes to the database schema in each version, where the schema's version is the number of
+/// migrations applied to it.
+const MIGRATIONS: [&str; 2] = [CATALOG, POSITIONS];
+
+const CATALOG: &str = "
+  CREATE TABLE folders (path TEXT PRIMARY KEY);
+  CREATE TABLE books (
+    id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    folder TEXT NOT NULL REFERENCES folders(path) ON DELETE CASCADE,
+    size INTEGER NOT NULL,
+    modified INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    author_sort TEXT NOT NULL,
+    series TEXT,
+    series_position TEXT,
+    cover_type TEXT,
+    cover BLOB,
+    last_opened INTEGER
+  );
+  CREATE TABLE creators (
+    book INTEGER NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    file_as TEXT,
+    PRIMARY KEY (book, position)
+  );
+  CREATE VIRTUAL TABLE books_fts USING fts5(
+    title, authors, tokenize = 'unicode61 remove_diacritics 2'
+  );
+  CREATE TRIGGER books_fts_delete AFTER DELETE ON books BEGIN
+    DELETE FROM books_fts WHERE rowid = old.id;
+  END;
+";
+
+const POSITIONS: &str = "
+  CREATE TABLE positions (
+    identifier TEXT NOT NULL,
+    content_hash TEXT NOT NULL,
+    cfi TEXT NOT NULL,
+    percentage REAL NOT NULL,
+    updated INTEGER NOT NULL,
+    PRIMARY KEY (identifier, content_hash)
+  );
+";
+
+/// A book in the library.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryBook {
+  pub id: i64,
+  pub path: PathBuf,
+  pub title: String,
+  pub creators: Vec<Creator>,
+  pub series: Option<Series>,
+  pub has_cover: bool,
+  /// When the book was last opened, in seconds since the Unix epoch.
+  pub last_opened: Option<i64>,
+}
+
+/// Which books to list from the library, and in what order.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LibraryQuery {
+  /// Words which must each begin a word of the book's title or authors.
+  pub search: Option<String>,
+  /// The name of one of the book's creators.
+  pub author: Option<String>,
+  pub series: Option<String>,
+  pub sort: LibrarySort,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LibrarySort {
+  #[default]
+  Title,
+  /// By the `file-as` name of the first creator.
+  Author,
+  /// By series and then position in the series, with books in no series last.
+  Series,
+  /// Most recently opened first, with books never opened last.
+  LastOpened,
+}
+
+impl LibrarySort {
+  fn order_by(self) -> &'static str {
+    match self {
+      LibrarySort::Title => "title COLLATE NOCASE",
+      LibrarySort::Author => "author_sort COLLATE NOCASE, title COLLATE NOCASE",
+      LibrarySort::Series => {
+        "series IS NULL, series COLLATE NOCASE, CAST(series_position AS REAL), \
+         title COLLATE NOCASE"
+      }
+      LibrarySort::LastOpened => "last_opened IS NULL, last_opened DESC, title COLLATE NOCASE",
+    }
+  }
+}
+
+/// The outcome of [`Library::scan`].
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScanSummary {
+  pub added: usize,
+  pub updated: usize,
+  pub removed: usize,
+  /// Files which could not be read as EPUBs, with the reason why.
+  pub failed: Vec<(PathBuf, String)>,
+}
+
+/// The metadata of an EPUB file to catalog.
+struct Entry {
+  title: String,
+  creators: Vec<Creator>,
+  series: Option<Series>,
+  cover: Option<(String, Vec<u8>)>,
+}
+
+impl Entry {
+  fn read(path: &Path) -> Result<Self> {
+    let mut archive = Archive::load(FileZip(path.to_path_buf()))?;
+    let epub = Epub::load(&mut archive)?;
+    let rendition = epub.renditions.first().context("EPUB has no renditions")?;
+    let title = rendition
+      .package
+      .metadata
+      .fields
+      .iter()
+      .find_map(|field| match field {
+        MetaField::Title(title) => Some(title.trim().to_string()),
+        _ => None,
+      })
+      .unwrap_or_else(|| file_stem(path));
+    let cover = rendition.cover_item().and_then(|item| {
+      let contents = archive
+        .read_file(&rendition.file_path(&item.href))
+        .inspect_err(|err| warn!("Failed to read cover of {}: {err:?}", path.display()))
+        .ok()?;
+      Some((item.media_type.clone(), contents))
+    });
+    Ok(Entry {
+      title,
+      creators: rendition.creators(),
+      series: rendition.series(),
+      cover,
+    })
+  }
+
+  /// Returns the name to sort the book by its authors.
+  fn author_sort(&self) -> String {
+    let creator = self.creators.first();
+    creator
+      .map(|creator| creator.file_as.as_ref().unwrap_or(&creator.name).clone())
+      .unwrap_or_default()
+  }
+}
+
+/// The user's library of EPUBs.
+pub struct Library {
+  path: PathBuf,
+  conn: Connection,
+}
+
+impl Library {
+  /// Opens the library database at `path`, creating it if it does not exist.
+  ///
+  /// # Errors
+  /// If the database cannot be opened, or was written by a newer version of Bene.
+  pub fn open(path: &Path) -> Result<Self> {
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    let conn = Connection::open(path)
+      .with_context(|| format!("Failed to open library: {}", path.display()))?;
+    // Scans write on their own connection, so that the library can be listed during one.
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "foreign_keys", true)?;
+    conn.busy_timeout(Duration::from_secs(5))?;
+
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+      bail!(
+        "Library was written by a newer version of Bene: {}",
+        path.display()
+      );
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+      let tx = conn.unchecked_transaction()?;
+      tx.execute_batch(migration)?;
+      tx.pragma_update(None, "user_version", i + 1)?;
+      tx.commit()?;
+    }
+
+    Ok(Library {
+      path: path.to_path_buf(),
+      conn,
+    })
+  }
+
+  /// Opens another connection to the same library.
+  ///
+  /// # Errors
+  /// If the database cannot be opened.
+  pub fn reopen(&self) -> Result<Self> {
+    Library::open(&self.path)
+  }
+
+  /// Returns the folders which are scanned for EPUBs.
+  ///
+  /// # Errors
+  /// If the database cannot be read.
+  pub fn folders(&self) -> Result<Vec<PathBuf>> {
+    let mut stmt = self
+      .conn
+      .prepare("SELECT path FROM folders ORDER BY path")?;
+    let folders = stmt.query_map([], |row| row.get::<_, String>(0))?;
+    Ok(
+      folders
+        .map(|path| path.map(PathBuf::from))
+        .collect::<Result<_, _>>()?,
+    )
+  }
+
+  /// Adds `folder` to the folders which are scanned for EPUBs.
+  ///
+  /// # Errors
+  /// If `folder` is not a directory, or the database cannot be written.
+  pub fn add_folder(&self, folder: &Path) -> Result<()> {
+    let folder = folder
+      .canonicalize()
+      .with_context(|| format!("Failed to find folder: {}", folder.display()))?;
+    ensure!(folder.is_dir(), "Not a folder: {}", folder.display());
+    self.conn.execute(
+      "INSERT OR IGNORE INTO folders (path) VALUES (?)",
+      [path_str(&folder)?],
+    )?;
+    Ok(())
+  }
+
+  /// Removes `folder` and the books found in it from the library.
+  ///
+  /// # Errors
+  /// If the database cannot be written.
+  pub fn remove_folder(&self, folder: &Path) -> Result<()> {
+    let folder = folder
+      .canonicalize()
+      .unwrap_or_else(|_| folder.to_path_buf());
+    self
+      .conn
+      .execute("DELETE FROM folders WHERE path = ?", [path_str(&folder)?])?;
+    Ok(())
+  }
+
+  /// Catalogs the EPUB files in the library's folders.
+  ///
+  /// Files which have not changed since the last scan are skipped, and books whose files were
+  /// deleted are removed.
+  ///
+  /// # Errors
+  /// If the database cannot be read or written. Files which cannot be read as EPUBs are instead
+  /// reported in [`ScanSummary::failed`].
+  pub fn scan(&mut self) -> Result<ScanSummary> {
+    let mut summary = ScanSummary::default();
+    for folder in self.folders()? {
+      let mut files = Vec::new();
+      find_epubs(&folder, &mut files);
+      let folder = path_str(&folder)?;
+
+      let mut found = HashSet::new();
+      for (path, metadata) in files {
+        let Ok(path_key) = path_str(&path) else {
+          continue;
+        };
+        found.insert(path_key.to_string());
+        let size = i64::try_from(metadata.len())?;
+        let modified = metadata
+          .modified()
+          .map_or(0, |modified| timestamp(modified).as_millis());
+        let modified = i64::try_from(modified)?;
+
+        let existing = self
+          .conn
+          .query_row(
+            "SELECT id, size, modified FROM books WHERE path = ?",
+            [path_key],
+            |row| {
+              Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+              ))
+            },
+          )
+          .optional()?;
+        if existing.is_some_and(|(_, s, m)| (s, m) == (size, modified)) {
+          continue;
+        }
+
+        debug!("Cataloging EPUB: {}", path.display());
+        let entry = match Entry::read(&path) {
+          Ok(entry) => entry,
+          Err(err) => {
+            summary.failed.push((path, format!("{err:?}")));
+            continue;
+          }
+        };
+        let tx = self.conn.transaction()?;
+        write_book(&tx, path_key, folder, size, modified, &entry)?;
+        tx.commit()?;
+        if existing.is_some() {
+          summary.updated += 1;
+        } else {
+          summary.added += 1;
+        }
+      }
+
+      let tx = self.conn.transaction()?;
+      let removed = {
+        let mut stmt = tx.prepare("SELECT id, path FROM books WHERE folder = ?")?;
+        let rows = stmt.query_map([folder], |row| {
+          Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?;
+        rows
+          .filter_map(|row| row.map_or(None, |(id, path)| (!found.contains(&path)).then_some(id)))
+          .collect::<Vec<_>>()
+      };
+      for id in &removed {
+        tx.execute("DELETE FROM books WHERE id = ?", [id])?;
+      }
+      tx.commit()?;
+      summary.removed += removed.len();
+    }
+    Ok(summary)
+  }
+
+  /// Lists the books in the library which match `query`.
+  ///
+  /// # Errors
+  /// If the database cannot be read.
+  pub fn list(&self, query: &LibraryQuery) -> Result<Vec<LibraryBook>> {
+    let mut conditions = Vec::new();
+    let mut values = Vec::new();
+    if let Some(search) = query.search.as_deref().and_then(fts_query) {
+      conditions.push("id IN (SELECT rowid FROM books_fts WHERE books_fts MATCH ?)");
+      values.push(search);
+    }
+    if let Some(author) = &query.author {
+      conditions.push("EXISTS (SELECT 1 FROM creators WHERE book = books.id AND name = ?)");
+      values.push(author.clone());
+    }
+    if let Some(series) = &query.series {
+      conditions.push("series = ?");
+      values.push(series.clone());
+    }
+    let filter = if conditions.is_empty() {
+      String::new()
+    } else {
+      format!("WHERE {}", conditions.join(" AND "))
+    };
+
+    let sql = format!(
+      "SELECT id, path, title, series, series_position, cover IS NOT NULL, last_opened
+       FROM books {filter} ORDER BY {}",
+      query.sort.order_by()
+    );
+    let mut stmt = self.conn.prepare(&sql)?;
+    let rows = stmt.query_map(params_from_iter(&values), |row| {
+      let series = match row.get::<_, Option<String>>(3)? {
+        Some(name) => Some(Series {
+          name,
+          position: row.get(4)?,
+        }),
+        None => None,
+      };
+      Ok(LibraryBook {
+        id: row.get(0)?,
+        path: PathBuf::from(row.get::<_, String>(1)?),
+        title: row.get(2)?,
+        creators: Vec::new(),
+        series,
+        has_cover: row.get(5)?,
+        last_opened: row.get(6)?,
+      })
+    })?;
+
+    let mut creators = self
+      .conn
+      .prepare("SELECT name, file_as FROM creators WHERE book = ? ORDER BY position")?;
+    rows
+      .map(|book| {
+        let mut book = book?;
+        book.creators = creators
+          .query_map([book.id], |row| {
+            Ok(Creator {
+              name: row.get(0)?,
+              file_as: row.get(1)?,
+            })
+          })?
+          .collect::<Result<_, _>>()?;
+        Ok(book)
+      })
+      .collect()
+  }
+
+  /// Returns the path of the book with `id`.
+  ///
+  /// # Errors
+  /// If there is no such book, or the database cannot be read.
+  pub fn path(&self, id: i64) -> Result<PathBuf> {
+    let path = self
+      .conn
+      .query_row("SELECT path FROM books WHERE id = ?", [id], |row| {
+        row.get::<_, String>(0)
+      })
+      .optional()?
+      .with_context(|| format!("No book in the library with id: {id}"))?;
+    Ok(PathBuf::from(path))
+  }
+
+  /// Returns the media type and contents of the cover image of the book with `id`.
+  ///
+  /// # Errors
+  /// If the book has no cover, or the database cannot be read.
+  pub fn cover(&self, id: i64) -> Result<(String, Vec<u8>)> {
+    self
+      .conn
+      .query_row(
+        "SELECT cover_type, cover FROM books WHERE id = ? AND cover IS NOT NULL",
+        [id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+      )
+      .optional()?
+      .with_context(|| format!("No cover in the library for book: {id}"))
+  }
+
+  /// Returns the last reading position saved for `key`.
+  ///
+  /// If none was saved for this edition of the publication, the latest position in any other
+  /// edition is returned, as the same CFI often still points to the same place.
+  ///
+  /// # Errors
+  /// If the database cannot be read.
+  pub fn position(&self, key: &PublicationKey) -> Result<Option<ReadingPosition>> {
+    let position = self
+      .conn
+      .query_row(
+        "SELECT cfi, percentage FROM positions WHERE identifier = ?
+         ORDER BY content_hash = ? DESC, updated DESC LIMIT 1",
+        [&key.identifier, &key.content_hash],
+        |row| {
+          Ok(ReadingPosition {
+            cfi: row.get(0)?,
+            percentage: row.get(1)?,
+          })
+        },
+      )
+      .optional()?;
+    Ok(position)
+  }
+
+  /// Returns the keys of the publications with a reading position.
+  ///
+  /// # Errors
+  /// If the database cannot be read.
+  #[cfg_attr(not(feature = "server"), allow(dead_code))]
+  pub fn publications(&self) -> Result<Vec<PublicationKey>> {
+    let mut stmt = self
+      .conn
+      .prepare("SELECT DISTINCT identifier, content_hash FROM positions")?;
+    let keys = stmt
+      .query_map([], |row| {
+        Ok(PublicationKey {
+          identifier: row.get(0)?,
+          content_hash: row.get(1)?,
+        })
+      })?
+      .collect::<rusqlite::Result<_>>()?;
+    Ok(keys)
+  }
+
+  /// Saves `position` as the reading position for `key`.
+  ///
+  /// # Errors
+  /// If the database cannot be written.
+  pub fn save_position(&self, key: &PublicationKey, position: &ReadingPosition) -> Result<()> {
+    let now = i64::try_from(timestamp(SystemTime::now()).as_millis())?;
+    self.conn.execute(
+      "INSERT OR REPLACE INTO positions (identifier, content_hash, cfi, percentage, updated)
+       VALUES (?, ?, ?, ?, ?)",
+      params![
+        key.identifier,
+        key.content_hash,
+        position.cfi,
+        position.percentage.clamp(0., 1.),
+        now
+      ],
+    )?;
+    Ok(())
+  }
+
+  /// Records that the book at `path` was just opened, if it is in the library.
+  ///
+  /// # Errors
+  /// If the database cannot be written.
+  pub fn mark_opened(&self, path: &Path) -> Result<()> {
+    let now = i64::try_from(timestamp(SystemTime::now()).as_secs())?;
+    self.conn.execute(
+      "UPDATE books SET last_opened = ? WHERE path = ?",
+      params![now, path_str(path)?],
+    )?;
+    Ok(())
+  }
+}
+
+/// Inserts or replaces the catalog entry of the book at `path`.
+fn write_book(
+  tx: &Transaction,
+  path: &str,
+  folder: &str,
+  size: i64,
+  modified: i64,
+  entry: &Entry,
+) -> Result<()> {
+  let (cover_type, cover) = entry.cover.clone().unzip();
+  let series = entry.series.as_ref();
+  let id: i64 = tx.query_row(
+    "INSERT INTO books
+       (path, folder, size, modified, title, author_sort, series, series_position, cover_type, cover)
+     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+     ON CONFLICT (path) DO UPDATE SET
+       folder = excluded.folder, size = excluded.size, modified = excluded.modified,
+       title = excluded.title, author_sort = excluded.author_sort, series = excluded.series,
+       series_position = excluded.series_position, cover_type = excluded.cover_type,
+       cover = excluded.cover
+     RETURNING id",
+    params![
+      path,
+      folder,
+      size,
+      modified,
+      entry.title,
+      entry.author_sort(),
+      series.map(|series| &series.name),
+      series.and_then(|series| series.position.as_ref()),
+      cover_type,
+      cover
+    ],
+    |row| row.get(0),
+  )?;
+
+  tx.execute("DELETE FROM creators WHERE book = ?", [id])?;
+  for (position, creator) in entry.creators.iter().enumerate() {
+    tx.execute(
+      "INSERT INTO creators (book, position, name, file_as) VALUES (?, ?, ?, ?)",
+      params![id, position, creator.name, creator.file_as],
+    )?;
+  }
+
+  let authors = entry
+    .creators
+    .iter()
+    .flat_map(|creator| [Some(&creator.name), creator.file_as.as_ref()])
+    .flatten()
+    .map(String::as_str)
+    .collect::<Vec<_>>()
+    .join(" ");
+  tx.execute("DELETE FROM books_fts WHERE rowid = ?", [id])?;
+  tx.execute(
+    "INSERT INTO books_fts (rowid, title, authors) VALUES (?, ?, ?)",
+    params![id, entry.title, authors],
+  )?;
+  Ok(())
+}
+
+/// Finds the `.epub` files under `dir`, skipping hidden files and folders.
+fn find_epubs(dir: &Path, files: &mut Vec<(PathBuf, fs::Metadata)>) {
+  let entries = match fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(err) => {
+      warn!("Failed to read library folder {}: {err}", dir.display());
+      return;
+    }
+  };
+  for entry in entries.flatten() {
+    if entry.file_name().to_string_lossy().starts_with('.') {
+      continue;
+    }
+    let path = entry.path();
+    let Ok(metadata) = fs::metadata(&path) else {
+      continue;
+    };
+    if metadata.is_dir() {
+      find_epubs(&path, files);
+    } else if path
+      .extension()
+      .is_some_and(|ext| ext.eq_ignore_ascii_case("epub"))
+    {
+      files.push((path, metadata));
+    }
+  }
+}
+
+/// Converts words typed by the user into an FTS5 query which matches rows containing each of them
+/// as the prefix of a word.
+fn fts_query(search: &str) -> Option<String> {
+  let terms = search
+    .split_whitespace()
+    .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+    .collect::<Vec<_>>();
+  (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+fn path_str(path: &Path) -> Result<&str> {
+  path
+    .to_str()
+    .with_context(|| format!("Path is not valid UTF-8: {}", path.display()))
+}
+
+fn file_stem(path: &Path) -> String {
+  path
+    .file_stem()
+    .map(|stem| stem.to_string_lossy().into_owned())
+    .unwrap_or_default()
+}
+
+fn timestamp(time: SystemTime) -> Duration {
+  time.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+  use std::io::Cursor;
+
+  use super::*;
+
+  const CONTAINER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container xmlns="urn:oasis:names:tc:opendocument:xmlns:container" version="1.0">
+  <rootfiles>
+    <rootfile full-path="EPUB/package.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#;
+
+  const CHAPTER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml"><head><title>Chapter</title></head><body/></html>"#;
+
+  /// Writes an EPUB to `path` whose package has the elements `metadata`, and a cover image if
+  /// `cover` is true.
+  fn write_epub(path: &Path, metadata: &str, cover: bool) {
+    let dir = tempfile::tempdir().unwrap();
+    let cover_item = if cover {
+      r#"<item id="cover" href="cover.png" properties="cover-image" media-type="image/png"/>"#
+    } else {
+      ""
+    };
+    let package = format!(
+      r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:test:{}</dc:identifier>
+    <dc:language>en</dc:language>
+    <meta property="dcterms:modified">2024-01-01T00:00:00Z</meta>
+    {metadata}
+  </metadata>
+  <manifest>
+    <item id="chapter" href="chapter.xhtml" media-type="application/xhtml+xml"/>
+    {cover_item}
+  </manifest>
+  <spine><itemref idref="chapter"/></spine>
+</package>"#,
+      file_stem(path)
+    );
+    fs::create_dir_all(dir.path().join("META-INF")).unwrap();
+    fs::create_dir_all(dir.path().join("EPUB")).unwrap();
+    fs::write(dir.path().join("META-INF/container.xml"), CONTAINER).unwrap();
+    fs::write(dir.path().join("EPUB/package.opf"), package).unwrap();
+    fs::write(dir.path().join("EPUB/chapter.xhtml"), CHAPTER).unwrap();
+    if cover {
+      fs::write(dir.path().join("EPUB/cover.png"), b"cover").unwrap();
+    }
+
+    let mut archive = Archive::load(FileZip(dir.path().to_path_buf())).unwrap();
+    let contents = archive.rewrite(Cursor::new(Vec::new()), &[]).unwrap();
+    fs::write(path, contents.into_inner()).unwrap();
+  }
+
+  fn titles(books: &[LibraryBook]) -> Vec<&str> {
+    books.iter().map(|book| book.title.as_str()).collect()
+  }
+
+  #[test]
+  fn test_library_scan() {
+    let dir = tempfile::tempdir().unwrap();
+    let folder = dir.path().join("books");
+    fs::create_dir_all(folder.join("nested/.hidden")).unwrap();
+    write_epub(
+      &folder.join("waves.epub"),
+      r##"<dc:title>The Waves</dc:title>
+      <dc:creator id="woolf">Virginia Woolf</dc:creator>
+      <meta refines="#woolf" property="file-as">Woolf, Virginia</meta>"##,
+      true,
+    );
+    write_epub(
+      &folder.join("nested/emma.epub"),
+      "<dc:title>Emma</dc:title><dc:creator>Jane Austen</dc:creator>",
+      false,
+    );
+    write_epub(
+      &folder.join("nested/.hidden/secret.epub"),
+      "<dc:title>Secret</dc:title>",
+      false,
+    );
+    fs::write(folder.join("broken.epub"), b"not a zip").unwrap();
+
+    let mut library = Library::open(&dir.path().join("library.sqlite3")).unwrap();
+    library.add_folder(&folder).unwrap();
+    let summary = library.scan().unwrap();
+    assert_eq!((summary.added, summary.updated, summary.removed), (2, 0, 0));
+    assert_eq!(summary.failed.len(), 1);
+
+    let books = library.list(&LibraryQuery::default()).unwrap();
+    assert_eq!(titles(&books), ["Emma", "The Waves"]);
+    let waves = &books[1];
+    assert_eq!(
+      waves.creators,
+      [Creator {
+        name: "Virginia Woolf".into(),
+        file_as: Some("Woolf, Virginia".into())
+      }]
+    );
+    assert!(waves.has_cover);
+    assert_eq!(
+      library.cover(waves.id).unwrap(),
+      ("image/png".into(), b"cover".to_vec())
+    );
+    assert!(library.cover(books[0].id).is_err());
+
+    // Unchanged files are skipped, and deleted files are removed.
+    assert_eq!(library.scan().unwrap().added, 0);
+    fs::remove_file(folder.join("nested/emma.epub")).unwrap();
+    let summary = library.scan().unwrap();
+    assert_eq!(summary.removed, 1);
+    assert_eq!(
+      titles(&library.list(&LibraryQuery::default()).unwrap()),
+      ["The Waves"]
+    );
+
+    // Books are kept across connections, and removed with their folder.
+    let library = library.reopen().unwrap();
+    assert_eq!(library.folders().unwrap(), [folder.canonicalize().unwrap()]);
+    library.remove_folder(&folder).unwrap();
+    assert!(library.folders().unwrap().is_empty());
+    assert!(library.list(&LibraryQuery::default()).unwrap().is_empty());
+    let search = LibraryQuery {
+      search: Some("waves".into()),
+      ..LibraryQuery::default()
+    };
+    assert!(library.list(&search).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_library_positions() {
+    let dir = tempfile::tempdir().unwrap();
+    let library = Library::open(&dir.path().join("library.sqlite3")).unwrap();
+    let key = |content_hash: &str| PublicationKey {
+      identifier: "urn:test:book".into(),
+      content_hash: content_hash.into(),
+    };
+    let position = |cfi: &str, percentage| ReadingPosition {
+      cfi: cfi.into(),
+      percentage,
+    };
+    assert_eq!(library.position(&key("a")).unwrap(), None);
+
+    library
+      .save_position(&key("a"), &position("epubcfi(/6/2!/4/2)", 0.1))
+      .unwrap();
+    library
+      .save_position(&key("a"), &position("epubcfi(/6/4!/4/8)", 0.5))
+      .unwrap();
+    std::thread::sleep(Duration::from_millis(5));
+    library
+      .save_position(&key("b"), &position("epubcfi(/6/2!/4/6)", 1.5))
+      .unwrap();
+
+    // Each edition keeps its own position, and other editions fall back to the latest one.
+    let library = library.reopen().unwrap();
+    assert_eq!(
+      library.position(&key("a")).unwrap(),
+      Some(position("epubcfi(/6/4!/4/8)", 0.5))
+    );
+    assert_eq!(
+      library.position(&key("c")).unwrap(),
+      Some(position("epubcfi(/6/2!/4/6)", 1.))
+    );
+    let other = PublicationKey {
+      identifier: "urn:test:other".into(),
+      content_hash: "a".into(),
+    };
+    assert_eq!(library.position(&other).unwrap(), None);
+  }
+
+  #[test]
+  fn test_library_query() {
+    let dir = tempfile::tempdir().unwrap();
+    let folder = dir.path().join("books");
+    fs::create_dir_all(&folder).unwrap();
+    let books = [
+      ("emma", "Emma", "Jane Austen", "Austen, Jane", None),
+      (
+        "persuasion",
+        "Persuasion",
+        "Jane Austen",
+        "Austen, Jane",
+        None,
+      ),
+      (
+        "dune",
+        "Dune",
+        "Frank Herbert",
+        "Herbert, Frank",
+        Some(("Dune", "1")),
+      ),
+      (
+        "messiah",
+        "Dune Messiah",
+        "Frank Herbert",
+        "Herbert, Frank",
+        Some(("Dune", "2")),
+      ),
+      ("zola", "Thérèse Raquin", "Émile Zola", "Zola, Émile", None),
+    ];
+    for (file, title, creator, file_as, series) in books {
+      let series = series.map_or(String::new(), |(name, position)| {
+        format!(
+          r##"<meta property="belongs-to-collection" id="c">{name}</meta>
+          <meta refines="#c" property="group-position">{position}</meta>"##
+        )
+      });
+      write_epub(
+        &folder.join(format!("{file}.epub")),
+        &format!(
+          r##"<dc:title>{title}</dc:title>
+          <dc:creator id="a">{creator}</dc:creator>
+          <meta refines="#a" property="file-as">{file_as}</meta>
+          {series}"##
+        ),
+        false,
+      );
+    }
+
+    let mut library = Library::open(&dir.path().join("library.sqlite3")).unwrap();
+    library.add_folder(&folder).unwrap();
+    library.scan().unwrap();
+    let list = |query: LibraryQuery| titles(&library.list(&query).unwrap()).join(", ");
+
+    let search = |search: &str| LibraryQuery {
+      search: Some(search.into()),
+      ..LibraryQuery::default()
+    };
+    assert_eq!(list(search("aust")), "Emma, Persuasion");
+    assert_eq!(list(search("dune herb")), "Dune, Dune Messiah");
+    assert_eq!(list(search("therese emile")), "Thérèse Raquin");
+    assert_eq!(list(search("\"")), "");
+    assert_eq!(list(search("  ")), list(LibraryQuery::default()));
+
+    let sort = |sort| LibraryQuery {
+      sort,
+      ..LibraryQuery::default()
+    };
+    assert_eq!(
+      list(sort(LibrarySort::Author)),
+      "Emma, Persuasion, Dune, Dune Messiah, Thérèse Raquin"
+    );
+    assert_eq!(
+      list(sort(LibrarySort::Series)),
+      "Dune, Dune Messiah, Emma, Persuasion, Thérèse Raquin"
+    );
+
+    let emma = library.list(&search("emma")).unwrap()[0].id;
+    library.mark_opened(&library.path(emma).unwrap()).unwrap();
+    assert_eq!(
+      list(sort(LibrarySort::LastOpened)),
+      "Emma, Dune, Dune Messiah, Persuasion, Thérèse Raquin"
+    );
+
+    let filtered = LibraryQuery {
+      author: Some("Frank Herbert".into()),
+      series: Some("Dune".into()),
+      sort: LibrarySort::Series,
+      ..LibraryQuery::default()
+    };
+    assert_eq!(list(filtered), "Dune, Dune Messiah");
+  }
+}
@@ -0,0 +1,159 @@
+//! Links which open a book at a location, like
+//! `bene-reader://open?path=/books/x.epub&cfi=epubcfi(...)`.
+
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use bene_epub::cfi;
+use tauri::Url;
+
+/// The URL scheme of links to books, which the app is registered to open. It differs from the
+/// `bene` scheme of the reader's assets.
+const SCHEME: &str = "bene-reader";
+
+/// A location in a book to open it at.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Target {
+  /// An EPUB CFI, like `epubcfi(/6/4!/4/2/1:0)`.
+  Cfi(String),
+  /// The href of a content document relative to the package document, with an optional
+  /// fragment, like `chapter3.xhtml#sec2`.
+  Href(String),
+}
+
+impl Target {
+  /// Returns the target at `cfi`.
+  ///
+  /// # Errors
+  /// If `cfi` is not a valid EPUB CFI.
+  pub fn cfi(cfi: &str) -> Result<Self> {
+    cfi::Fragment::parse(cfi).with_context(|| format!("Invalid CFI: {cfi}"))?;
+    Ok(Target::Cfi(cfi.to_string()))
+  }
+
+  /// Returns the target at `href`.
+  ///
+  /// # Errors
+  /// If `href` is empty.
+  pub fn href(href: &str) -> Result<Self> {
+    ensure!(!href.is_empty(), "Empty href");
+    Ok(Target::Href(href.to_string()))
+  }
+}
+
+/// A book to open, and where to open it if not where it was last read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BookLink {
+  pub path: PathBuf,
+  pub target: Option<Target>,
+}
+
+impl BookLink {
+  /// Reads a URL which the app was asked to open, either a `file` URL or a link like
+  /// `bene-reader://open?path=/books/x.epub&href=chapter3.xhtml%23sec2`.
+  ///
+  /// # Errors
+  /// If the URL is not a file or a valid link.
+  pub fn from_url(url: &Url) -> Result<Self> {
+    match url.scheme() {
+      "file" => {
+        let path = url
+          .to_file_path()
+          .map_err(|()| anyhow!("Invalid file URL: {url}"))?;
+        Ok(BookLink { path, target: None })
+      }
+      SCHEME => {
+        ensure!(
+          url.host_str() == Some("open"),
+          "Unknown link, expected {SCHEME}://open: {url}"
+        );
+        let (mut path, mut target) = (None, None);
+        for (key, value) in url.query_pairs() {
+          let parsed = match &*key {
+            "path" => {
+              path = Some(PathBuf::from(&*value));
+              continue;
+            }
+            "cfi" => Target::cfi(&value)?,
+            "href" => Target::href(&value)?,
+            _ => bail!("Unknown parameter `{key}` in link: {url}"),
+          };
+          ensure!(target.is_none(), "Link has more than one target: {url}");
+          target = Some(parsed);
+        }
+        let path = path.with_context(|| format!("Link has no path: {url}"))?;
+        Ok(BookLink { path, target })
+      }
+      scheme => bail!("Cannot open {scheme} URL: {url}"),
+    }
+  }
+}
+
+/// Parses a command-line argument, which is either a path or a link to a book. Links are passed
+/// as arguments when the app is opened with one on Linux and Windows.
+impl FromStr for BookLink {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    if s.starts_with(&format!("{SCHEME}://")) {
+      let url = Url::parse(s).with_context(|| format!("Invalid link: {s}"))?;
+      BookLink::from_url(&url)
+    } else {
+      Ok(BookLink {
+        path: PathBuf::from(s),
+        target: None,
+      })
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_book_link() {
+    let link: BookLink = "bene-reader://open?path=/books/x.epub&cfi=epubcfi(/6/4!/4/2/1:0)"
+      .parse()
+      .unwrap();
+    assert_eq!(link.path, PathBuf::from("/books/x.epub"));
+    assert_eq!(
+      link.target,
+      Some(Target::Cfi("epubcfi(/6/4!/4/2/1:0)".into()))
+    );
+
+    let link: BookLink = "bene-reader://open?path=%2Fmy%20books%2Fx.epub&href=ch3.xhtml%23sec2"
+      .parse()
+      .unwrap();
+    assert_eq!(link.path, PathBuf::from("/my books/x.epub"));
+    assert_eq!(link.target, Some(Target::Href("ch3.xhtml#sec2".into())));
+
+    let link: BookLink = "books/x.epub".parse().unwrap();
+    assert_eq!(link.path, PathBuf::from("books/x.epub"));
+    assert_eq!(link.target, None);
+
+    let url = Url::from_file_path("/books/x.epub").unwrap();
+    let link = BookLink::from_url(&url).unwrap();
+    assert_eq!(link.path, PathBuf::from("/books/x.epub"));
+  }
+
+  #[test]
+  fn test_book_link_errors() {
+    for link in [
+      "bene-reader://open?cfi=epubcfi(/6/4!/4/2/1:0)",
+      "bene-reader://open?path=x.epub&cfi=/6/4",
+      "bene-reader://open?path=x.epub&cfi=epubcfi(/6/4!/4/2/1:0)&href=ch3.xhtml",
+      "bene-reader://open?path=x.epub&page=3",
+      "bene-reader://close?path=x.epub",
+    ] {
+      assert!(link.parse::<BookLink>().is_err(), "{link}");
+    }
+    for url in ["https://example.com/x.epub", "bene://open?path=x.epub"] {
+      assert!(
+        BookLink::from_url(&Url::parse(url).unwrap()).is_err(),
+        "{url}"
+      );
+    }
+  }
+}
@@ -9,108 +9,473 @@
 
 use std::{
   borrow::Cow,
+  collections::HashMap,
   fs,
-  path::PathBuf,
-  sync::{Mutex, MutexGuard},
+  path::{Path, PathBuf},
+  sync::{
+    Arc, Mutex, MutexGuard,
+    atomic::{AtomicUsize, Ordering},
+  },
 };
 
-use anyhow::{Context, Result, anyhow};
-use bene_epub::{Archive, Epub, FileZip};
-use cfg_if::cfg_if;
+use anyhow::{Context, Result, anyhow, bail};
+use bene_epub::annotation::{self, Annotation, export::Format, import::Source};
 use clap::Parser;
 use log::{debug, warn};
-use notify::Watcher as _;
 use tauri::{
-  App, AppHandle, Emitter, Manager, State, async_runtime, http, utils::config::FrontendDist,
+  App, AppHandle, Emitter, Manager, WebviewWindow, WebviewWindowBuilder, WindowEvent,
+  async_runtime, http, utils::config::FrontendDist,
 };
+use tauri_plugin_deep_link::DeepLinkExt;
 
-struct LocalState {
-  archive: ArchivePool,
-  _watcher: Watcher,
+use self::{
+  library::{Library, LibraryBook, LibraryQuery},
+  link::{BookLink, Target},
+  reader::{Book, LocalState, ReadingPosition, SharedState, Sink},
+  store::AnnotationStore,
+};
+
+mod cli;
+mod library;
+mod link;
+mod reader;
+#[cfg(feature = "server")]
+mod server;
+mod store;
+mod sync;
+
+/// Sends the events of a reader to its window, and keeps its reading positions in the library.
+struct Window {
+  app: AppHandle,
+  /// The label of the reader's window.
+  label: String,
+}
+
+impl Sink for Window {
+  fn emit(&self, event: &str, payload: impl serde::Serialize + Clone) {
+    if let Err(err) = self.app.emit_to(&self.label, event, payload) {
+      warn!("Failed to emit `{event}` event: {err:?}");
+    }
+  }
+
+  fn store(&self) -> MutexGuard<'_, AnnotationStore> {
+    self
+      .app
+      .state::<AnnotationStoreLock>()
+      .inner()
+      .lock()
+      .unwrap()
+  }
+
+  fn position(&self, book: &Book) -> Option<ReadingPosition> {
+    with_library(&self.app, |library| library.position(&book.publication))
+      .inspect_err(|err| warn!("Failed to read reading position: {err}"))
+      .ok()
+      .flatten()
+  }
+
+  fn opened(&self, path: &Path) {
+    if with_library(&self.app, |library| library.mark_opened(path)).is_ok() {
+      emit_library_changed(&self.app);
+    }
+  }
+}
+
+/// The reader of one window.
+type Reader = reader::Reader<Window>;
+
+/// The readers of the open windows, by window label.
+type Readers = Mutex<HashMap<String, Arc<Reader>>>;
+type AnnotationStoreLock = Mutex<AnnotationStore>;
+type LibraryLock = Mutex<Library>;
+
+const MAIN_WINDOW: &str = "main";
+
+/// Adds a reader for the window with `label`, which has no book yet.
+fn add_reader(app: &AppHandle, label: &str) -> Arc<Reader> {
+  let reader = Reader::new(Window {
+    app: app.clone(),
+    label: label.to_string(),
+  });
+  let readers = app.state::<Readers>();
+  readers
+    .lock()
+    .unwrap()
+    .insert(label.to_string(), Arc::clone(&reader));
+  reader
+}
+
+/// Returns the reader of the window with `label`, if it is open.
+fn get_reader(app: &AppHandle, label: &str) -> Option<Arc<Reader>> {
+  app.state::<Readers>().lock().unwrap().get(label).cloned()
+}
+
+/// Returns the reader of the window which called a command.
+fn window_reader(window: &WebviewWindow) -> Result<Arc<Reader>, String> {
+  get_reader(window.app_handle(), window.label())
+    .ok_or_else(|| format!("No reader for window {}", window.label()))
+}
+
+/// Removes the reader of a closed window, stopping its load and watcher.
+fn remove_reader(app: &AppHandle, label: &str) {
+  let reader = app.state::<Readers>().lock().unwrap().remove(label);
+  if let Some(reader) = reader {
+    reader.close();
+  }
+}
+
+/// Opens a window with its own reader, configured like the main window.
+fn open_window(app: &AppHandle) -> Result<Arc<Reader>> {
+  static WINDOWS: AtomicUsize = AtomicUsize::new(1);
+  let mut config = app
+    .config()
+    .app
+    .windows
+    .first()
+    .context("No window is configured")?
+    .clone();
+  config.label = format!("reader-{}", WINDOWS.fetch_add(1, Ordering::Relaxed));
+
+  // The reader is added first, so that the window can ask for its state as soon as it loads.
+  let reader = add_reader(app, &config.label);
+  let window =
+    WebviewWindowBuilder::from_config(app, &config).and_then(WebviewWindowBuilder::build);
+  match window {
+    #[allow(unused_variables)]
+    Ok(window) => {
+      #[cfg(debug_assertions)]
+      window.open_devtools();
+      Ok(reader)
+    }
+    Err(err) => {
+      remove_reader(app, &config.label);
+      Err(err).context("Failed to open window")
+    }
+  }
+}
+
+/// Opens each of `books` in its own window. The first one is opened in the main window if it has
+/// no book yet.
+fn open_books(app: &AppHandle, books: Vec<BookLink>, restore_position: bool) {
+  let mut idle = get_reader(app, MAIN_WINDOW)
+    .filter(|reader| matches!(*reader.shared_state.lock().unwrap(), SharedState::Waiting));
+  for book in books {
+    let reader = match idle.take().map_or_else(|| open_window(app), Ok) {
+      Ok(reader) => reader,
+      Err(err) => {
+        warn!("Failed to open {}: {err:?}", book.path.display());
+        continue;
+      }
+    };
+    reader.load(book.path, restore_position, book.target);
+  }
+}
+
+#[tauri::command]
+fn state(window: WebviewWindow) -> Result<SharedState, String> {
+  let reader = window_reader(&window)?;
+  Ok(reader.shared_state.lock().unwrap().clone())
+}
+
+#[tauri::command]
+fn cancel_load(window: WebviewWindow) -> Result<(), String> {
+  window_reader(&window)?.cancel_load();
+  Ok(())
+}
+
+#[tauri::command]
+fn upload(window: WebviewWindow, path: PathBuf) -> Result<(), String> {
+  window_reader(&window)?.load(path, true, None);
+  Ok(())
+}
+
+/// Runs `f` on the user's annotation store for the publication loaded in `reader`.
+fn with_store<T>(
+  reader: &Reader,
+  f: impl FnOnce(&AnnotationStore, &LocalState) -> Result<T>,
+) -> Result<T, String> {
+  let local_state = reader.local_state.lock().unwrap();
+  let result = match &*local_state {
+    Some(local_state) => f(&reader.sink.store(), local_state),
+    None => Err(anyhow!("Epub not loaded yet")),
+  };
+  result.map_err(|err| format!("{err:?}"))
+}
+
+#[tauri::command]
+fn list_annotations(window: WebviewWindow) -> Result<Vec<Annotation>, String> {
+  let reader = window_reader(&window)?;
+  with_store(&reader, |store, local_state| {
+    let SharedState::Ready { epub, .. } = &*reader.shared_state.lock().unwrap() else {
+      bail!("Epub not loaded yet");
+    };
+    let mut archive = local_state.book.archive.lock_one();
+    store.processed(
+      &local_state.book.publication,
+      &mut archive,
+      &epub.renditions[0],
+    )
+  })
+}
+
+#[tauri::command]
+fn export_annotations(window: WebviewWindow, format: Format, path: PathBuf) -> Result<(), String> {
+  let reader = window_reader(&window)?;
+  with_store(&reader, |store, local_state| {
+    let SharedState::Ready { epub, .. } = &*reader.shared_state.lock().unwrap() else {
+      bail!("Epub not loaded yet");
+    };
+    let mut archive = local_state.book.archive.lock_one();
+    let contents = cli::export_annotations(
+      store,
+      &local_state.book.publication,
+      &mut archive,
+      epub,
+      format,
+    )?;
+    fs::write(&path, contents).with_context(|| format!("Failed to write: {}", path.display()))
+  })
 }
 
-#[derive(serde::Serialize, Clone)]
-#[serde(tag = "type", content = "value")]
-enum SharedState {
-  Waiting,
-  Loading,
-  Error(String),
-  Ready(Epub),
+#[tauri::command]
+fn import_annotations(
+  window: WebviewWindow,
+  source: Source,
+  path: PathBuf,
+) -> Result<cli::ImportSummary, String> {
+  let reader = window_reader(&window)?;
+  with_store(&reader, |store, local_state| {
+    let SharedState::Ready { epub, .. } = &*reader.shared_state.lock().unwrap() else {
+      bail!("Epub not loaded yet");
+    };
+    let mut archive = local_state.book.archive.lock_one();
+    cli::import_annotations(
+      store,
+      &local_state.book.publication,
+      &mut archive,
+      epub,
+      source,
+      &path,
+    )
+  })
 }
 
-type SharedStateLock = Mutex<SharedState>;
-type LocalStateLock = Mutex<Option<LocalState>>;
+#[tauri::command]
+fn create_annotation(window: WebviewWindow, mut annotation: Annotation) -> Result<(), String> {
+  let reader = window_reader(&window)?;
+  with_store(&reader, |store, local_state| {
+    let SharedState::Ready { epub, .. } = &*reader.shared_state.lock().unwrap() else {
+      bail!("Epub not loaded yet");
+    };
+    let mut archive = local_state.book.archive.lock_one();
+    annotation::fingerprint(&mut annotation, &mut archive, &epub.renditions[0]);
+    let publication = &local_state.book.publication;
+    store.create(publication, annotation.to_raw(&publication.source()))
+  })
+}
 
-fn set_shared_state(app: &AppHandle, state: SharedState) {
-  *app.state::<SharedStateLock>().lock().unwrap() = state.clone();
-  app
-    .emit("state", state)
-    .expect("Failed to emit `state` event");
-  debug!("Emitting event");
+#[tauri::command]
+fn update_annotation(window: WebviewWindow, mut annotation: Annotation) -> Result<(), String> {
+  let reader = window_reader(&window)?;
+  with_store(&reader, |store, local_state| {
+    let SharedState::Ready { epub, .. } = &*reader.shared_state.lock().unwrap() else {
+      bail!("Epub not loaded yet");
+    };
+    let mut archive = local_state.book.archive.lock_one();
+    annotation::fingerprint(&mut annotation, &mut archive, &epub.renditions[0]);
+    let publication = &local_state.book.publication;
+    store.update(publication, annotation.to_raw(&publication.source()))
+  })
 }
 
 #[tauri::command]
-fn state(state: State<'_, SharedStateLock>) -> SharedState {
-  state.lock().unwrap().clone()
+fn reading_position(window: WebviewWindow) -> Result<Option<ReadingPosition>, String> {
+  let reader = window_reader(&window)?;
+  let local_state = reader.local_state.lock().unwrap();
+  let local_state = local_state.as_ref().ok_or("Epub not loaded yet")?;
+  Ok(local_state.position.clone())
+}
+
+#[tauri::command]
+fn save_reading_position(window: WebviewWindow, position: ReadingPosition) -> Result<(), String> {
+  let reader = window_reader(&window)?;
+  let mut local_state = reader.local_state.lock().unwrap();
+  let local_state = local_state.as_mut().ok_or("Epub not loaded yet")?;
+  with_library(window.app_handle(), |library| {
+    library.save_position(&local_state.book.publication, &position)
+  })?;
+  local_state.position = Some(position);
+  Ok(())
 }
 
 #[tauri::command]
-fn upload(handle: AppHandle, path: PathBuf) {
-  load_epub(handle, path);
+fn delete_annotation(window: WebviewWindow, id: String) -> Result<(), String> {
+  let reader = window_reader(&window)?;
+  with_store(&reader, |store, local_state| {
+    store.delete(&local_state.book.publication, &id)
+  })
+}
+
+/// Runs `f` on the user's library.
+fn with_library<T>(app: &AppHandle, f: impl FnOnce(&Library) -> Result<T>) -> Result<T, String> {
+  let library = app.state::<LibraryLock>();
+  let result = f(&library.lock().unwrap());
+  result.map_err(|err| format!("{err:?}"))
+}
+
+fn emit_library_changed(app: &AppHandle) {
+  if let Err(err) = app.emit("library-changed", ()) {
+    warn!("Failed to emit `library-changed` event: {err:?}");
+  }
+}
+
+#[tauri::command]
+fn library(app: AppHandle, query: LibraryQuery) -> Result<Vec<LibraryBook>, String> {
+  with_library(&app, |library| library.list(&query))
+}
+
+#[tauri::command]
+fn library_folders(app: AppHandle) -> Result<Vec<PathBuf>, String> {
+  with_library(&app, Library::folders)
+}
+
+#[tauri::command]
+fn add_library_folder(app: AppHandle, path: PathBuf) -> Result<(), String> {
+  with_library(&app, |library| library.add_folder(&path))?;
+  scan_library(app);
+  Ok(())
+}
+
+#[tauri::command]
+fn remove_library_folder(app: AppHandle, path: PathBuf) -> Result<(), String> {
+  with_library(&app, |library| library.remove_folder(&path))?;
+  emit_library_changed(&app);
+  Ok(())
+}
+
+/// Scans the library's folders in the background, and emits `library-changed` when done.
+#[tauri::command]
+fn scan_library(app: AppHandle) {
+  async_runtime::spawn_blocking(move || {
+    // The scan writes on its own connection, so that the library can be listed in the meantime.
+    let scanned = with_library(&app, Library::reopen)
+      .and_then(|mut library| library.scan().map_err(|err| format!("{err:?}")));
+    match scanned {
+      Ok(summary) => {
+        for (path, err) in &summary.failed {
+          warn!("Failed to catalog {}: {err}", path.display());
+        }
+        debug!(
+          "Scanned library: {} added, {} updated, {} removed",
+          summary.added, summary.updated, summary.removed
+        );
+        emit_library_changed(&app);
+      }
+      Err(err) => warn!("Failed to scan library: {err}"),
+    }
+  });
+}
+
+#[tauri::command]
+fn open_library_book(window: WebviewWindow, id: i64) -> Result<(), String> {
+  let reader = window_reader(&window)?;
+  let path = with_library(window.app_handle(), |library| library.path(id))?;
+  reader.load(path, true, None);
+  Ok(())
 }
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct CliArgs {
-  /// Path to .epub file
-  path: Option<PathBuf>,
+  /// Paths to .epub files or unzipped EPUB directories, or `bene-reader://open?path=...` links to
+  /// them, each opened in its own window
+  paths: Vec<BookLink>,
+
+  /// Open the books at their beginning rather than where they were last read
+  #[arg(long, requires = "paths")]
+  from_start: bool,
+
+  /// Open the books at an EPUB CFI, like `epubcfi(/6/4!/4/2/1:0)`
+  #[arg(
+    long,
+    value_name = "CFI",
+    value_parser = Target::cfi,
+    requires = "paths",
+    conflicts_with_all = ["href", "from_start"]
+  )]
+  at: Option<Target>,
+
+  /// Open the books at a content document, like `chapter3.xhtml#sec2`
+  #[arg(
+    long,
+    value_parser = Target::href,
+    requires = "paths",
+    conflicts_with = "from_start"
+  )]
+  href: Option<Target>,
+
+  #[command(subcommand)]
+  command: Option<cli::Command>,
 }
 
-fn load_reader_asset(app: &AppHandle, local_path: &str) -> Result<Vec<u8>> {
-  let mut full_path = if cfg!(dev) {
+impl CliArgs {
+  /// Returns the books to open, with relative paths resolved against `cwd`.
+  fn books(self, cwd: &Path) -> Vec<BookLink> {
+    // A link's own target takes precedence over `--at` and `--href`.
+    let target = self.at.or(self.href);
+    self
+      .paths
+      .into_iter()
+      .map(|book| BookLink {
+        path: cwd.join(&book.path),
+        target: book.target.or_else(|| target.clone()),
+      })
+      .collect()
+  }
+}
+
+/// Returns the directory of the reader's bundle.
+fn reader_dir(app: &AppHandle) -> PathBuf {
+  if cfg!(dev) {
     let FrontendDist::Directory(dir) = app.config().build.frontend_dist.as_ref().unwrap() else {
       unreachable!()
     };
     dir.join("../../bene-reader/dist")
   } else {
     app.path().resource_dir().unwrap().join("bene-reader")
-  };
-  full_path = full_path.join(local_path.strip_prefix('/').unwrap());
-
-  fs::read(&full_path).with_context(|| format!("Failed to read: {}", full_path.display()))
+  }
 }
 
+/// Serves an asset to the window with `label`, reading the book from its reader.
 fn serve_asset(
   app: &AppHandle,
+  label: &str,
   request: http::Request<Vec<u8>>,
 ) -> http::Response<Cow<'static, [u8]>> {
   let path = request.uri().path();
-  let state = app.state::<LocalStateLock>();
-  let (result, path) = match path.strip_prefix("/epub-content/") {
-    Some(epub_path) => match &*state.lock().unwrap() {
-      Some(state) => {
-        let mut archive = state.archive.lock_one();
-        (archive.read_file(epub_path), epub_path)
-      }
-      None => (Err(anyhow!("Epub not loaded yet")), epub_path),
-    },
-    None => (load_reader_asset(app, path), path),
+  if let Some(id) = path.strip_prefix("/library-covers/") {
+    return serve_cover(app, id);
+  }
+
+  let Some(reader) = get_reader(app, label) else {
+    warn!("Failed to read asset {path} for closed window {label}");
+    return http::Response::builder()
+      .status(http::StatusCode::NOT_FOUND)
+      .body(Cow::Owned(vec![]))
+      .unwrap();
   };
-  match result {
-    Ok(contents) => {
+  let book = reader.local_state.lock().unwrap();
+  match reader::read_asset(
+    path,
+    &reader_dir(app),
+    book.as_ref().map(|state| &state.book),
+  ) {
+    Ok(asset) => {
       let mut response = http::Response::builder().status(http::StatusCode::OK);
-      match bene_epub::guess_mime_type(path) {
-        Some(content_type) => response = response.header("Content-Type", content_type),
-        None => warn!("Unknown content type for path: {path}"),
+      if let Some(content_type) = asset.content_type {
+        response = response.header("Content-Type", content_type);
       }
-
-      #[allow(clippy::case_sensitive_file_extension_comparisons)]
-      let contents = if path.ends_with(".xhtml") {
-        bene_epub::htmlify_xhtml(contents).unwrap()
-      } else {
-        contents
-      };
-
-      response.body(Cow::Owned(contents)).unwrap()
+      response.body(Cow::Owned(asset.contents)).unwrap()
     }
     Err(e) => {
       warn!("Failed to read asset {path} with error {e}");
@@ -122,125 +487,107 @@ fn serve_asset(
   }
 }
 
-const ARCHIVE_POOL_SIZE: usize = 8;
-
-struct ArchivePool {
-  pool: Vec<Mutex<Archive>>,
-}
-
-impl ArchivePool {
-  fn new(archive: Archive) -> Result<Self> {
-    let pool = (0..ARCHIVE_POOL_SIZE)
-      .map(|_| Ok(Mutex::new(archive.try_clone()?)))
-      .collect::<Result<Vec<_>>>()?;
-    Ok(ArchivePool { pool })
-  }
-
-  fn lock_one(&self) -> MutexGuard<'_, Archive> {
-    for archive in &self.pool {
-      if let Ok(archive) = archive.try_lock() {
-        return archive;
-      }
+/// Serves the cover image of the library book with `id`.
+fn serve_cover(app: &AppHandle, id: &str) -> http::Response<Cow<'static, [u8]>> {
+  let cover = id
+    .parse()
+    .map_err(|err| format!("{err:?}"))
+    .and_then(|id| with_library(app, |library| library.cover(id)));
+  match cover {
+    Ok((content_type, contents)) => http::Response::builder()
+      .status(http::StatusCode::OK)
+      .header("Content-Type", content_type)
+      .body(Cow::Owned(contents))
+      .unwrap(),
+    Err(err) => {
+      warn!("Failed to read cover of library book {id} with error {err}");
+      http::Response::builder()
+        .status(http::StatusCode::NOT_FOUND)
+        .body(Cow::Owned(vec![]))
+        .unwrap()
     }
-
-    self.pool[0].lock().unwrap()
   }
 }
 
-struct Watcher {
-  _watcher: notify::RecommendedWatcher,
-  _watch_task: async_runtime::JoinHandle<()>,
-}
-
-impl Watcher {
-  fn new(app: &AppHandle, path: PathBuf) -> Result<Self> {
-    let (tx, mut rx) = async_runtime::channel(64);
-    let mut watcher = notify::recommended_watcher(move |e| {
-      if let Err(err) = tx.blocking_send(e) {
-        warn!("Failed to send event to watch task: {err:?}");
-      }
-    })?;
-    watcher.watch(&path, notify::RecursiveMode::NonRecursive)?;
-
-    let app2 = app.clone();
-    let watch_task = async_runtime::spawn(async move {
-      while let Some(res) = rx.recv().await {
-        match res {
-          Ok(event) => match &event.kind {
-            notify::EventKind::Modify(notify::event::ModifyKind::Data(_)) => {
-              let shared_state_lock = app2.state::<SharedStateLock>();
-              if matches!(*shared_state_lock.lock().unwrap(), SharedState::Ready(_)) {
-                load_epub(app2, path);
-                return;
-              } else {
-                debug!("Received file update while loading EPUB, ignoring");
-              }
-            }
-
-            _ => debug!("Received unhandled file watcher event: {event:?}"),
-          },
-          Err(err) => {
-            warn!("File watcher received error: {err:?}");
-            break;
-          }
-        }
-      }
-    });
-
-    Ok(Watcher {
-      _watcher: watcher,
-      _watch_task: watch_task,
-    })
-  }
-}
-
-fn load_epub(app: AppHandle, path: PathBuf) {
-  debug!("Loading EPUB from path: {}", path.display());
-  set_shared_state(&app, SharedState::Loading);
-
-  async_runtime::spawn_blocking(move || {
-    let (local_state, shared_state) = (|| {
-      let path = path.canonicalize()?;
-      let mut archive =
-        Archive::load(FileZip(path.clone())).context("Failed to parse epub as zip")?;
-      let epub = Epub::load(&mut archive)?;
-      let archive = ArchivePool::new(archive)?;
-      let watcher = Watcher::new(&app, path)?;
-
-      let local_state = Some(LocalState {
-        archive,
-        _watcher: watcher,
-      });
-      let shared_state = SharedState::Ready(epub);
-      Ok((local_state, shared_state))
-    })()
-    .unwrap_or_else(|err: anyhow::Error| (None, SharedState::Error(format!("{err:?}"))));
-
-    *app.state::<LocalStateLock>().lock().unwrap() = local_state;
-    set_shared_state(&app, shared_state);
-  });
+/// Attaches to the console of the terminal the app was run from, if any, so that the CLI's output
+/// is shown there. Release builds on Windows have no console of their own.
+#[cfg(all(windows, not(debug_assertions)))]
+fn attach_console() {
+  use windows_sys::Win32::System::Console::{ATTACH_PARENT_PROCESS, AttachConsole};
+  // This fails when the app was not run from a terminal, in which case there is nowhere to print.
+  unsafe { AttachConsole(ATTACH_PARENT_PROCESS) };
 }
 
 fn main() -> Result<()> {
+  #[cfg(all(windows, not(debug_assertions)))]
+  attach_console();
   let args = CliArgs::parse();
+  let context = tauri::generate_context!();
+
+  if let Some(command) = args.command {
+    // Matches the directory used by `PathResolver::app_data_dir` in the app.
+    let data_dir = dirs::data_dir().context("Failed to find the user's data directory")?;
+    let store_dir = data_dir
+      .join(&context.config().identifier)
+      .join("annotations");
+    return command.run(store_dir);
+  }
 
   #[allow(unused_variables)]
-  let setup = |app: &mut App| {
+  let setup = move |app: &mut App| {
     #[cfg(debug_assertions)]
     {
-      let window = app.get_webview_window("main").unwrap();
+      let window = app.get_webview_window(MAIN_WINDOW).unwrap();
       window.open_devtools();
     }
 
-    if let Some(path) = args.path {
-      let handle = app.handle().clone();
-      load_epub(handle, path);
+    let store_dir = app.path().app_data_dir()?.join("annotations");
+    app.manage::<AnnotationStoreLock>(Mutex::new(AnnotationStore::new(store_dir)));
+    let library = Library::open(&app.path().app_data_dir()?.join("library.sqlite3"))?;
+    app.manage::<LibraryLock>(Mutex::new(library));
+    scan_library(app.handle().clone());
+
+    add_reader(app.handle(), MAIN_WINDOW);
+    let restore_position = !args.from_start;
+    let cwd = std::env::current_dir().unwrap_or_default();
+    open_books(app.handle(), args.books(&cwd), restore_position);
+
+    // On macOS, files and links opened with the app arrive as URLs, whether or not it is running.
+    let handle = app.handle().clone();
+    app.deep_link().on_open_url(move |event| {
+      let books = event
+        .urls()
+        .iter()
+        .filter_map(|url| {
+          BookLink::from_url(url)
+            .inspect_err(|err| warn!("Failed to open {url}: {err:?}"))
+            .ok()
+        })
+        .collect();
+      open_books(&handle, books, true);
+    });
+    // Installers register the scheme, but portable and development builds must do it themselves.
+    #[cfg(any(windows, target_os = "linux"))]
+    if let Err(err) = app.deep_link().register_all() {
+      warn!("Failed to register links: {err:?}");
     }
 
     Ok(())
   };
 
   let builder = tauri::Builder::default()
+    // On Linux and Windows, files and links opened while the app is running start a second
+    // instance, which passes its arguments here and exits.
+    .plugin(tauri_plugin_single_instance::init(
+      |app, argv, cwd| match CliArgs::try_parse_from(argv) {
+        Ok(args) => {
+          let restore_position = !args.from_start;
+          open_books(app, args.books(Path::new(&cwd)), restore_position);
+        }
+        Err(err) => warn!("Failed to open books from another instance: {err}"),
+      },
+    ))
+    .plugin(tauri_plugin_deep_link::init())
     .plugin(tauri_plugin_dialog::init())
     .plugin(tauri_plugin_shell::init())
     .plugin(
@@ -249,34 +596,40 @@ fn main() -> Result<()> {
         .build(),
     )
     .setup(setup)
-    .manage::<SharedStateLock>(Mutex::new(SharedState::Waiting))
-    .manage::<LocalStateLock>(Mutex::new(None))
-    .invoke_handler(tauri::generate_handler![state, upload])
+    .manage::<Readers>(Mutex::default())
+    .on_window_event(|window, event| {
+      if let WindowEvent::Destroyed = event {
+        remove_reader(window.app_handle(), window.label());
+      }
+    })
+    .invoke_handler(tauri::generate_handler![
+      state,
+      upload,
+      cancel_load,
+      list_annotations,
+      create_annotation,
+      update_annotation,
+      delete_annotation,
+      export_annotations,
+      import_annotations,
+      reading_position,
+      save_reading_position,
+      library,
+      library_folders,
+      add_library_folder,
+      remove_library_folder,
+      scan_library,
+      open_library_book
+    ])
     .register_asynchronous_uri_scheme_protocol("bene", move |ctx, request, responder| {
       let app = ctx.app_handle().clone();
+      let label = ctx.webview_label().to_string();
       async_runtime::spawn_blocking(move || {
-        let response = serve_asset(&app, request);
+        let response = serve_asset(&app, &label, request);
         responder.respond(response);
       });
     });
 
-  let app = builder.build(tauri::generate_context!())?;
-
-  // On MacOS only, the "open file with" interaction is translated into
-  // a `RunEvent::Opened` event. On all other platforms, it is a CLI argument.
-  cfg_if! {
-    if #[cfg(any(target_os = "macos", target_os = "ios"))] {
-      app.run(|app, event| {
-        if let tauri::RunEvent::Opened { urls } = event {
-          let path = PathBuf::from(urls[0].path());
-          let handle = app.clone();
-          load_epub(handle, path);
-        }
-      });
-    } else {
-      app.run(|_, _| {});
-    }
-  }
-
+  builder.run(context)?;
   Ok(())
 }
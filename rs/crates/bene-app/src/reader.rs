@@ -0,0 +1,698 @@
+//! The parts of the reader's backend which do not depend on how the reader is displayed, shared by
+//! the app's window and the browser-based `bene serve`.
+
+use std::{
+  fmt, fs,
+  path::{Path, PathBuf},
+  sync::{
+    Arc, Mutex, MutexGuard,
+    atomic::{AtomicU64, Ordering},
+    mpsc::{self, RecvTimeoutError},
+  },
+  thread,
+  time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use bene_epub::{Archive, Checksums, Epub, FileZip, annotation::AnchorReport};
+use log::{debug, info, trace, warn};
+use notify::{EventKind, RecursiveMode, Watcher as _, event::ModifyKind};
+
+use crate::{
+  link::Target,
+  store::{AnnotationStore, PublicationKey},
+};
+
+/// Where the reader last was in a publication.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadingPosition {
+  /// An EPUB CFI of the element at the top of the screen.
+  pub cfi: String,
+  /// How far through the publication the position is, from 0 to 1.
+  pub percentage: f64,
+}
+
+/// The state of the reader, as seen by the frontend.
+#[derive(serde::Serialize, Clone)]
+#[serde(tag = "type", content = "value")]
+pub enum SharedState {
+  Waiting,
+  Loading(LoadProgress),
+  Error(String),
+  Ready {
+    epub: Epub,
+    /// Where to open the book, if it was opened with a link to a location in it.
+    target: Option<Target>,
+  },
+}
+
+/// The stages of opening a book, in order.
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LoadStage {
+  /// Opening the archive and listing its files.
+  ReadingContainer,
+  /// Parsing the container and the package documents it lists.
+  ParsingPackages,
+  /// Checksumming the book's files and identifying the publication.
+  BuildingIndexes,
+}
+
+/// How far a book has been loaded, so that the reader can show progress on big books.
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoadProgress {
+  pub stage: LoadStage,
+  /// The number of the stage, counting from 1.
+  pub step: usize,
+  pub steps: usize,
+  /// Whether the book is the one already open, which the reader keeps showing while it reloads.
+  pub reload: bool,
+}
+
+impl LoadProgress {
+  const STAGES: [LoadStage; 3] = [
+    LoadStage::ReadingContainer,
+    LoadStage::ParsingPackages,
+    LoadStage::BuildingIndexes,
+  ];
+
+  pub fn new(stage: LoadStage, reload: bool) -> Self {
+    LoadProgress {
+      stage,
+      step: Self::STAGES.iter().position(|s| *s == stage).unwrap() + 1,
+      steps: Self::STAGES.len(),
+      reload,
+    }
+  }
+}
+
+/// Counts the loads of books, so that results of a load are discarded once another load starts
+/// or the load is cancelled.
+#[derive(Default)]
+pub struct Loads(Arc<AtomicU64>);
+
+impl Loads {
+  /// Starts a new load, which makes every earlier load stale.
+  pub fn start(&self) -> LoadTicket {
+    LoadTicket {
+      generation: self.0.fetch_add(1, Ordering::SeqCst) + 1,
+      current: Arc::clone(&self.0),
+    }
+  }
+
+  /// Cancels the current load, if any.
+  pub fn cancel(&self) {
+    self.0.fetch_add(1, Ordering::SeqCst);
+  }
+}
+
+/// Identifies one load started by [`Loads::start`].
+pub struct LoadTicket {
+  generation: u64,
+  current: Arc<AtomicU64>,
+}
+
+impl LoadTicket {
+  /// Returns true if no load has started since this one, and it was not cancelled.
+  pub fn is_current(&self) -> bool {
+    self.current.load(Ordering::SeqCst) == self.generation
+  }
+}
+
+/// The error of a load which was cancelled before it finished.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Loading was cancelled")
+  }
+}
+
+impl std::error::Error for Cancelled {}
+
+const ARCHIVE_POOL_SIZE: usize = 8;
+
+/// Clones of an [`Archive`], so that concurrent requests for its files do not wait on each other.
+pub struct ArchivePool {
+  pool: Vec<Mutex<Archive>>,
+}
+
+impl ArchivePool {
+  fn new(archive: Archive) -> Result<Self> {
+    let pool = (0..ARCHIVE_POOL_SIZE)
+      .map(|_| Ok(Mutex::new(archive.try_clone()?)))
+      .collect::<Result<Vec<_>>>()?;
+    Ok(ArchivePool { pool })
+  }
+
+  pub fn lock_one(&self) -> MutexGuard<'_, Archive> {
+    for archive in &self.pool {
+      if let Ok(archive) = archive.try_lock() {
+        return archive;
+      }
+    }
+
+    self.pool[0].lock().unwrap()
+  }
+}
+
+/// An EPUB file opened for reading.
+pub struct Book {
+  /// The canonical path of the EPUB file.
+  pub path: PathBuf,
+  pub archive: ArchivePool,
+  pub epub: Epub,
+  pub publication: PublicationKey,
+  /// The checksums of the book's files when it was opened. Unzipped books are read from disk as
+  /// they are now, so these are what [`Book::reload`] compares against.
+  checksums: Checksums,
+}
+
+impl Book {
+  /// Opens the EPUB file at `path`, calling `on_stage` as each [`LoadStage`] starts.
+  ///
+  /// # Errors
+  /// If the file cannot be read or is not a valid EPUB, or if `on_stage` returns an error, which
+  /// stops the load.
+  pub fn open_with_progress(
+    path: &Path,
+    mut on_stage: impl FnMut(LoadStage) -> Result<()>,
+  ) -> Result<Self> {
+    on_stage(LoadStage::ReadingContainer)?;
+    let path = path.canonicalize()?;
+    let archive = Archive::load(FileZip(path.clone())).context("Failed to parse epub as zip")?;
+    let mut archive = ArchivePool::new(archive)?;
+
+    on_stage(LoadStage::ParsingPackages)?;
+    let epub = Epub::load(archive.pool[0].get_mut().unwrap())?;
+
+    on_stage(LoadStage::BuildingIndexes)?;
+    let checksums = archive.pool[0].get_mut().unwrap().checksums()?;
+    let publication = PublicationKey::for_epub(&epub, &path)?;
+    Ok(Book {
+      path,
+      archive,
+      epub,
+      publication,
+      checksums,
+    })
+  }
+
+  /// Reopens the book after it changed on disk.
+  ///
+  /// The book's files are compared by checksum to those it was opened with. If a file parsed into
+  /// the [`Epub`] changed, then the book must be opened again. Otherwise the [`Epub`] is reused, so
+  /// the reader only has to re-render the documents which use the changed files.
+  ///
+  /// # Errors
+  /// If the book cannot be read.
+  pub fn reload(&self) -> Result<Reload> {
+    let mut archive = Archive::load(FileZip(self.path.clone()))?;
+    let checksums = archive.checksums()?;
+    let changed = bene_epub::changed_files(&self.checksums, &checksums);
+    let package_files = self.epub.package_files();
+    if changed.iter().any(|file| package_files.contains(file)) {
+      return Ok(Reload::Full);
+    }
+
+    let publication = if changed.is_empty() {
+      self.publication.clone()
+    } else {
+      PublicationKey::for_epub(&self.epub, &self.path)?
+    };
+    let book = Book {
+      path: self.path.clone(),
+      archive: ArchivePool::new(archive)?,
+      epub: self.epub.clone(),
+      publication,
+      checksums,
+    };
+    Ok(Reload::Resources(book, changed))
+  }
+
+  /// Carries the annotations of the `previous` edition of the book over to this one, if the book
+  /// is a new edition of the same publication, so that edits to the EPUB do not orphan them.
+  pub fn reanchor(
+    &self,
+    store: &AnnotationStore,
+    previous: &PublicationKey,
+  ) -> Option<AnchorReport> {
+    let publication = &self.publication;
+    if publication.identifier.is_empty()
+      || previous.identifier != publication.identifier
+      || previous == publication
+    {
+      return None;
+    }
+
+    let mut archive = self.archive.lock_one();
+    match store.reanchor(
+      previous,
+      publication,
+      &mut archive,
+      &self.epub.renditions[0],
+    ) {
+      Ok(report) => {
+        info!(
+          "Re-anchored annotations: {} intact, {} moved, {} orphaned",
+          report.intact.len(),
+          report.moved.len(),
+          report.orphaned.len()
+        );
+        for diagnostic in &report.orphaned {
+          warn!("Orphaned {diagnostic}");
+        }
+        Some(report)
+      }
+      Err(err) => {
+        warn!("Failed to re-anchor annotations: {err:?}");
+        None
+      }
+    }
+  }
+}
+
+/// How a book changed on disk, as found by [`Book::reload`].
+pub enum Reload {
+  /// The book's package changed, so it must be opened again.
+  Full,
+  /// Only the files at these paths changed, and may be read from the new book.
+  Resources(Book, Vec<String>),
+}
+
+/// How long the book must go without edits before it is reloaded, so that a burst of events
+/// from one save causes one reload.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// How many times to wait for an edited EPUB file to become a whole ZIP file again.
+const PARSE_RETRIES: u32 = 40;
+const PARSE_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Watches a book on disk for edits, which outlives reloads of the book.
+///
+/// EPUB files are watched through their parent directory, so that the watch follows the path when
+/// an editor or build tool saves by renaming a new file over the old one. Unzipped EPUB
+/// directories are watched recursively.
+pub struct BookWatcher {
+  path: PathBuf,
+  _watcher: notify::RecommendedWatcher,
+}
+
+impl BookWatcher {
+  /// Watches the book at `path`, calling `on_edit` on a background thread once each burst of
+  /// edits has settled and the book can be parsed.
+  ///
+  /// # Errors
+  /// If the watch cannot be started.
+  pub fn new(path: &Path, on_edit: impl Fn() + Send + 'static) -> Result<Self> {
+    let (sender, receiver) = mpsc::channel();
+    let book = path.to_path_buf();
+    let mut watcher =
+      notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+        Ok(event) if is_edit(&book, &event) => {
+          let _ = sender.send(());
+        }
+        Ok(event) => trace!("Received unhandled file watcher event: {event:?}"),
+        Err(err) => warn!("File watcher received error: {err:?}"),
+      })?;
+    if path.is_dir() {
+      watcher.watch(path, RecursiveMode::Recursive)?;
+    } else {
+      let parent = path.parent().context("Book has no parent directory")?;
+      watcher.watch(parent, RecursiveMode::NonRecursive)?;
+    }
+
+    let book = path.to_path_buf();
+    thread::spawn(move || {
+      // The channel is closed when the watcher is dropped, which ends this thread.
+      while receiver.recv().is_ok() {
+        loop {
+          match receiver.recv_timeout(DEBOUNCE) {
+            Ok(()) => {}
+            Err(RecvTimeoutError::Timeout) => break,
+            Err(RecvTimeoutError::Disconnected) => return,
+          }
+        }
+        wait_until_parseable(&book);
+        on_edit();
+      }
+    });
+
+    Ok(BookWatcher {
+      path: path.to_path_buf(),
+      _watcher: watcher,
+    })
+  }
+
+  /// The path of the watched book.
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+}
+
+/// Returns true if `event` from watching the book at `path` edits the book.
+///
+/// Changes to hidden files and editor backups in unzipped EPUB directories are ignored, as they
+/// are not part of the book.
+fn is_edit(path: &Path, event: &notify::Event) -> bool {
+  let kind_is_edit = matches!(
+    event.kind,
+    EventKind::Create(_)
+      | EventKind::Remove(_)
+      | EventKind::Modify(ModifyKind::Any | ModifyKind::Data(_) | ModifyKind::Name(_))
+  );
+  kind_is_edit
+    && event.paths.iter().any(|changed| {
+      let Ok(relative) = changed.strip_prefix(path) else {
+        return false;
+      };
+      !relative.components().any(|component| {
+        let name = component.as_os_str().to_string_lossy();
+        name.starts_with('.') || name.ends_with('~')
+      })
+    })
+}
+
+/// Waits for the EPUB file at `path` to be parseable as a ZIP file, as it may be partially written.
+fn wait_until_parseable(path: &Path) {
+  for _ in 0..PARSE_RETRIES {
+    if path.is_dir() || Archive::load(FileZip(path.to_path_buf())).is_ok() {
+      return;
+    }
+    debug!("Waiting for {} to be written", path.display());
+    thread::sleep(PARSE_RETRY_DELAY);
+  }
+  warn!("Gave up waiting for {} to be written", path.display());
+}
+
+/// Where a [`Reader`] sends its events, which differs between the app's windows and browsers.
+pub trait Sink: Send + Sync + 'static {
+  /// Sends `event` with `payload` to the reader's frontend.
+  fn emit(&self, event: &str, payload: impl serde::Serialize + Clone);
+
+  /// Locks the user's annotation store.
+  fn store(&self) -> MutexGuard<'_, AnnotationStore>;
+
+  /// Returns where `book` was last read, if the frontend keeps track of it.
+  fn position(&self, _book: &Book) -> Option<ReadingPosition> {
+    None
+  }
+
+  /// Called once the book at `path` is loaded and shown.
+  fn opened(&self, _path: &Path) {}
+}
+
+/// The book opened in a reader.
+pub struct LocalState {
+  pub book: Book,
+  /// Where to open the book, which is where it was last read unless it was opened from the start.
+  pub position: Option<ReadingPosition>,
+}
+
+/// A reader of one book at a time, and the loads and watcher of it.
+pub struct Reader<S> {
+  pub sink: S,
+  /// Locked after `local_state` when both are needed, so that they cannot deadlock.
+  pub shared_state: Mutex<SharedState>,
+  pub local_state: Mutex<Option<LocalState>>,
+  watcher: Mutex<Option<BookWatcher>>,
+  loads: Loads,
+}
+
+impl<S: Sink> Reader<S> {
+  /// Creates a reader with no book, which sends its events to `sink`.
+  pub fn new(sink: S) -> Arc<Self> {
+    Arc::new(Reader {
+      sink,
+      shared_state: Mutex::new(SharedState::Waiting),
+      local_state: Mutex::new(None),
+      watcher: Mutex::new(None),
+      loads: Loads::default(),
+    })
+  }
+
+  /// Opens the EPUB at `path` in the background, reporting progress as it goes, and reloads it
+  /// whenever it changes on disk. Loads which are started before it finishes replace it.
+  ///
+  /// If `restore_position` is true, the book is opened where it was last read, unless it is
+  /// opened at a `target`.
+  pub fn load(self: &Arc<Self>, path: PathBuf, restore_position: bool, target: Option<Target>) {
+    debug!("Loading EPUB from path: {}", path.display());
+    self.watch(&path);
+    let ticket = self.loads.start();
+    // The reader keeps showing the open book while it is opened again, like after an edit.
+    let canonical = path.canonicalize().ok();
+    let reload = canonical.is_some()
+      && self
+        .local_state
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|local_state| &local_state.book.path)
+        == canonical.as_ref();
+
+    let reader = Arc::clone(self);
+    thread::spawn(move || {
+      let loaded = (|| -> Result<LocalState> {
+        let book = Book::open_with_progress(&path, |stage| {
+          let state = SharedState::Loading(LoadProgress::new(stage, reload));
+          if reader.update_load(&ticket, state, |_| {}) {
+            Ok(())
+          } else {
+            Err(Cancelled.into())
+          }
+        })?;
+        let previous = reader
+          .local_state
+          .lock()
+          .unwrap()
+          .as_ref()
+          .map(|local_state| local_state.book.publication.clone());
+        if let Some(previous) = previous {
+          reader.reanchor(&book, &previous);
+        }
+
+        let position = if restore_position {
+          reader.sink.position(&book)
+        } else {
+          None
+        };
+        Ok(LocalState { book, position })
+      })();
+      let opened = loaded
+        .as_ref()
+        .ok()
+        .map(|local_state| local_state.book.path.clone());
+      let (local_state, shared_state) = match loaded {
+        Ok(local_state) => {
+          let shared_state = SharedState::Ready {
+            epub: local_state.book.epub.clone(),
+            target,
+          };
+          (Some(local_state), shared_state)
+        }
+        Err(err) if err.is::<Cancelled>() => {
+          debug!("Stopped stale load of {}", path.display());
+          return;
+        }
+        Err(err) => (None, SharedState::Error(format!("{err:?}"))),
+      };
+
+      let finish = |current: &mut Option<LocalState>| *current = local_state;
+      if !reader.update_load(&ticket, shared_state, finish) {
+        debug!("Discarded stale load of {}", path.display());
+      } else if let Some(opened) = opened {
+        reader.sink.opened(&opened);
+      }
+    });
+  }
+
+  /// Sets the shared state to the progress or result of the load with `ticket`, after calling
+  /// `finish`, unless the load is stale. Returns false if it is stale.
+  fn update_load(
+    &self,
+    ticket: &LoadTicket,
+    state: SharedState,
+    finish: impl FnOnce(&mut Option<LocalState>),
+  ) -> bool {
+    // The states are locked while checking the ticket, so that a stale load cannot slip in.
+    let mut local_state = self.local_state.lock().unwrap();
+    let mut shared_state = self.shared_state.lock().unwrap();
+    if !ticket.is_current() {
+      return false;
+    }
+    finish(&mut local_state);
+    self.set_shared_state(&mut shared_state, state);
+    true
+  }
+
+  fn set_shared_state(&self, current: &mut SharedState, state: SharedState) {
+    *current = state.clone();
+    self.sink.emit("state", state);
+  }
+
+  /// Cancels the load in progress, if any, going back to the book loaded before it.
+  pub fn cancel_load(self: &Arc<Self>) {
+    let local_state = self.local_state.lock().unwrap();
+    let mut shared_state = self.shared_state.lock().unwrap();
+    if !matches!(*shared_state, SharedState::Loading(_)) {
+      return;
+    }
+
+    debug!("Cancelling load");
+    self.loads.cancel();
+    let state = match &*local_state {
+      Some(local_state) => {
+        self.watch(&local_state.book.path);
+        SharedState::Ready {
+          epub: local_state.book.epub.clone(),
+          target: None,
+        }
+      }
+      None => SharedState::Waiting,
+    };
+    self.set_shared_state(&mut shared_state, state);
+  }
+
+  /// Stops the reader's load and watcher, once its frontend is gone.
+  pub fn close(&self) {
+    self.loads.cancel();
+    self.watcher.lock().unwrap().take();
+  }
+
+  /// Updates the loaded book in place if only its resources changed on disk, and emits a
+  /// `resources-changed` event with their paths. Returns false if the book must be opened again.
+  fn reload_resources(&self) -> bool {
+    let mut local_state = self.local_state.lock().unwrap();
+    let Some(local_state) = local_state.as_mut() else {
+      return false;
+    };
+    let (book, changed) = match local_state.book.reload() {
+      Ok(Reload::Resources(book, changed)) => (book, changed),
+      Ok(Reload::Full) => return false,
+      Err(err) => {
+        warn!("Failed to reload EPUB: {err:?}");
+        return false;
+      }
+    };
+    if changed.is_empty() {
+      return true;
+    }
+
+    debug!("Reloading changed resources: {changed:?}");
+    self.reanchor(&book, &local_state.book.publication);
+    local_state.book = book;
+    self.sink.emit("resources-changed", changed);
+    true
+  }
+
+  /// Carries the annotations of the `previous` edition of `book` over to it, and emits an
+  /// `annotations-reanchored` event with the [`AnchorReport`].
+  fn reanchor(&self, book: &Book, previous: &PublicationKey) {
+    let report = book.reanchor(&self.sink.store(), previous);
+    if let Some(report) = report {
+      self.sink.emit("annotations-reanchored", report);
+    }
+  }
+
+  /// Watches `path` for edits, unless it is already being watched.
+  ///
+  /// The watcher is kept across reloads, and replaced when a different book is opened.
+  fn watch(self: &Arc<Self>, path: &Path) {
+    let Ok(path) = path.canonicalize() else {
+      return;
+    };
+    let mut watcher = self.watcher.lock().unwrap();
+    if watcher
+      .as_ref()
+      .is_some_and(|watcher| watcher.path() == path)
+    {
+      return;
+    }
+
+    // The watcher is owned by the reader, so it must not keep the reader alive.
+    let reader = Arc::downgrade(self);
+    let book = path.clone();
+    let on_edit = move || {
+      if let Some(reader) = reader.upgrade() {
+        reader.on_edit(&book);
+      }
+    };
+    *watcher = BookWatcher::new(&path, on_edit)
+      .inspect_err(|err| warn!("Failed to watch {}: {err:?}", path.display()))
+      .ok();
+  }
+
+  /// Reloads the book at `path` after it was edited. A load in progress is restarted, as it may
+  /// have read the book before the edit.
+  fn on_edit(self: &Arc<Self>, path: &Path) {
+    let watched = self
+      .watcher
+      .lock()
+      .unwrap()
+      .as_ref()
+      .is_some_and(|watcher| watcher.path() == path);
+    if !watched {
+      return;
+    }
+
+    let ready = matches!(
+      *self.shared_state.lock().unwrap(),
+      SharedState::Ready { .. }
+    );
+    if !(ready && self.reload_resources()) {
+      self.load(path.to_path_buf(), true, None);
+    }
+  }
+}
+
+/// A file requested by the reader.
+pub struct Asset {
+  pub contents: Vec<u8>,
+  pub content_type: Option<String>,
+}
+
+/// Reads the file at the URL `path` requested by the reader.
+///
+/// Paths under `/epub-content/` are read from the loaded `book`, and others from the reader's
+/// bundle in `reader_dir`. XHTML files are converted to HTML so that browsers render them as such.
+///
+/// # Errors
+/// If the file does not exist, `path` leaves `reader_dir`, or no book is loaded.
+pub fn read_asset(path: &str, reader_dir: &Path, book: Option<&Book>) -> Result<Asset> {
+  let (contents, path) = match path.strip_prefix("/epub-content/") {
+    Some(epub_path) => {
+      let book = book.ok_or_else(|| anyhow!("Epub not loaded yet"))?;
+      (book.archive.lock_one().read_file(epub_path)?, epub_path)
+    }
+    None => {
+      // The reader's bundle may be served over the network, so it must not be escaped.
+      if path.split('/').any(|segment| segment == "..") {
+        bail!("Invalid path: {path}");
+      }
+      let full_path = reader_dir.join(path.trim_start_matches('/'));
+      let contents =
+        fs::read(&full_path).with_context(|| format!("Failed to read: {}", full_path.display()))?;
+      (contents, path)
+    }
+  };
+
+  let content_type = bene_epub::guess_mime_type(path);
+  if content_type.is_none() {
+    warn!("Unknown content type for path: {path}");
+  }
+
+  #[allow(clippy::case_sensitive_file_extension_comparisons)]
+  let contents = if path.ends_with(".xhtml") {
+    bene_epub::htmlify_xhtml(contents)?
+  } else {
+    contents
+  };
+
+  Ok(Asset {
+    contents,
+    content_type,
+  })
+}
@@ -0,0 +1,987 @@
+//! An HTTP server for the user's annotations which implements the
+//! [W3C Web Annotation Protocol](https://www.w3.org/TR/annotation-protocol/), so that annotations
+//! can be shared with other Bene instances and annotation tools.
+//!
+//! `/annotations/` lists one LDP basic container per publication, at `/annotations/{slug}/`, which
+//! contains one resource per annotation. There are containers for the publications in the store,
+//! the book open in the reader, and the books read in the library, so that the first annotation on
+//! a publication can be posted to its container.
+//!
+//! The server can also serve the reader itself to web browsers, as described in [`web`].
+
+use std::{
+  collections::HashMap,
+  fmt::Write as _,
+  io::Read,
+  net::{SocketAddr, ToSocketAddrs},
+  path::PathBuf,
+  sync::Mutex,
+};
+
+use anyhow::{Context, Result, anyhow};
+use bene_epub::annotation::RawAnnotation;
+use log::warn;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+use self::web::{Frontend, Session};
+use crate::{
+  library::Library,
+  reader::Asset,
+  store::{AnnotationStore, PublicationKey},
+};
+
+pub mod web;
+
+const ANNO_CONTEXT: &str = "http://www.w3.org/ns/anno.jsonld";
+const LDP_CONTEXT: &str = "http://www.w3.org/ns/ldp.jsonld";
+const MEDIA_TYPE: &str = r#"application/ld+json; profile="http://www.w3.org/ns/anno.jsonld""#;
+
+const PREFER_MINIMAL: &str = "http://www.w3.org/ns/ldp#PreferMinimalContainer";
+const PREFER_IRIS: &str = "http://www.w3.org/ns/oa#PreferContainedIRIs";
+const PREFER_DESCRIPTIONS: &str = "http://www.w3.org/ns/oa#PreferContainedDescriptions";
+
+const CONTAINER_LINK: &str = concat!(
+  r#"<http://www.w3.org/ns/ldp#BasicContainer>; rel="type", "#,
+  r#"<http://www.w3.org/TR/annotation-protocol/>; rel="http://www.w3.org/ns/ldp#constrainedBy""#
+);
+const RESOURCE_LINK: &str = r#"<http://www.w3.org/ns/ldp#Resource>; rel="type""#;
+const ANNOTATION_ALLOW: &str = "GET, HEAD, PUT, DELETE, OPTIONS";
+
+/// The number of annotations in each [`AnnotationPage`](https://www.w3.org/TR/annotation-model/#annotation-page).
+pub const PAGE_SIZE: usize = 100;
+
+/// The largest request body the server accepts.
+const MAX_BODY: u64 = 16 * 1024 * 1024;
+
+/// Serves an [`AnnotationStore`] over HTTP.
+pub struct Server {
+  http: tiny_http::Server,
+  store: AnnotationStore,
+  page_size: usize,
+  web: Option<Session>,
+  library: Option<Library>,
+  /// The publications with containers, by slug, which are listed again when a slug is not found.
+  publications: Mutex<HashMap<String, PublicationKey>>,
+}
+
+impl Server {
+  /// Listens on `addr` for requests about the annotations in `store`.
+  ///
+  /// # Errors
+  /// If the server cannot listen on `addr`.
+  pub fn bind(addr: impl ToSocketAddrs, store: AnnotationStore) -> Result<Self> {
+    let http = tiny_http::Server::http(addr).map_err(|err| anyhow!("Failed to listen: {err}"))?;
+    Ok(Server {
+      http,
+      store,
+      page_size: PAGE_SIZE,
+      web: None,
+      library: None,
+      publications: Mutex::new(HashMap::new()),
+    })
+  }
+
+  /// Also serves the reader from the bundles in `frontend`, opening the EPUB at `path` if given.
+  ///
+  /// # Errors
+  /// If the reader's session cannot be created.
+  pub fn with_reader(mut self, frontend: Frontend, path: Option<PathBuf>) -> Result<Self> {
+    let session = Session::new(frontend, self.store.clone())?;
+    if let Some(path) = path {
+      session.load(path);
+    }
+    self.web = Some(session);
+    Ok(self)
+  }
+
+  /// Also serves containers for the publications read in `library`.
+  #[must_use]
+  pub fn with_library(mut self, library: Library) -> Self {
+    self.library = Some(library);
+    self
+  }
+
+  /// Sets the number of annotations in each page of a container.
+  #[must_use]
+  pub fn with_page_size(mut self, page_size: usize) -> Self {
+    self.page_size = page_size.max(1);
+    self
+  }
+
+  /// Returns the address the server is listening on.
+  pub fn local_addr(&self) -> Option<SocketAddr> {
+    self.http.server_addr().to_ip()
+  }
+
+  /// Handles requests until the process exits.
+  pub fn run(&self) {
+    for mut request in self.http.incoming_requests() {
+      let mut max_body = MAX_BODY;
+      if let Some(web) = &self.web {
+        match request.url() {
+          "/api/events" => {
+            web.subscribe(request);
+            continue;
+          }
+          "/api/upload" => max_body = web::MAX_UPLOAD,
+          _ => {}
+        }
+      }
+      let reply = Request::read(&mut request, self.local_addr(), max_body)
+        .and_then(|req| self.handle(&req))
+        .unwrap_or_else(|err| Reply::error(500, &format!("{err:?}")));
+      if let Err(err) = request.respond(reply.into_response()) {
+        warn!("Failed to send response: {err}");
+      }
+    }
+  }
+
+  fn handle(&self, req: &Request) -> Result<Reply> {
+    let Some(rest) = req.path.strip_prefix("/annotations") else {
+      return match &self.web {
+        Some(web) => web.handle(req),
+        None => Ok(Reply::error(404, "Not found")),
+      };
+    };
+    let segments = rest
+      .trim_start_matches('/')
+      .split('/')
+      .map(percent_decode)
+      .collect::<Option<Vec<_>>>();
+    let (slug, rest) = match segments.as_deref() {
+      Some([root]) if root.is_empty() => return self.root(req),
+      Some([slug, rest @ ..]) => (slug, rest),
+      _ => return Ok(Reply::error(404, "Not found")),
+    };
+    let Some(key) = self.publication(slug)? else {
+      return Ok(Reply::error(404, "No such publication"));
+    };
+    match rest {
+      [] => Ok(Reply::redirect(&format!("{}/", req.path))),
+      [id] if id.is_empty() => self.container(req, &key),
+      [id] => self.annotation(req, &key, id),
+      _ => Ok(Reply::error(404, "Not found")),
+    }
+  }
+
+  /// Lists the publications with containers, sorted by slug.
+  fn publications(&self) -> Result<Vec<PublicationKey>> {
+    let mut keys = self.store.publications()?;
+    if let Some(key) = self.web.as_ref().and_then(Session::publication) {
+      keys.push(key);
+    }
+    if let Some(library) = &self.library {
+      keys.extend(library.publications()?);
+    }
+    keys.sort_by_key(PublicationKey::slug);
+    keys.dedup();
+
+    let mut publications = self.publications.lock().unwrap();
+    *publications = keys.iter().map(|key| (key.slug(), key.clone())).collect();
+    Ok(keys)
+  }
+
+  /// Returns the publication whose container is at `slug`, if any.
+  fn publication(&self, slug: &str) -> Result<Option<PublicationKey>> {
+    if let Some(key) = self.publications.lock().unwrap().get(slug) {
+      return Ok(Some(key.clone()));
+    }
+    Ok(
+      self
+        .publications()?
+        .into_iter()
+        .find(|key| key.slug() == slug),
+    )
+  }
+
+  /// Lists the containers of all publications.
+  fn root(&self, req: &Request) -> Result<Reply> {
+    const ALLOW: &str = "GET, HEAD, OPTIONS";
+    if !matches!(req.method.as_str(), "GET" | "HEAD") {
+      return Ok(Reply::options_or_not_allowed(req).header("Allow", ALLOW));
+    }
+    let containers = self
+      .publications()?
+      .iter()
+      .map(|key| json!({ "id": req.container_iri(key), "label": key.identifier }))
+      .collect::<Vec<_>>();
+    let root = json!({
+      "@context": LDP_CONTEXT,
+      "id": format!("{}/annotations/", req.base),
+      "type": "BasicContainer",
+      "contains": containers,
+    });
+    Ok(Reply::json(req, &root).header("Allow", ALLOW))
+  }
+
+  fn container(&self, req: &Request, key: &PublicationKey) -> Result<Reply> {
+    const ALLOW: &str = "GET, HEAD, POST, OPTIONS";
+    let reply = match req.method.as_str() {
+      "GET" | "HEAD" => {
+        let annotations = self.store.list(key)?;
+        let iris = req.query("iris").is_some_and(|iris| iris == "1");
+        match req.query("page") {
+          Some(page) => {
+            let Some(page) = page
+              .parse()
+              .ok()
+              .filter(|page| page * self.page_size < annotations.len().max(1))
+            else {
+              return Ok(Reply::error(404, "No such page"));
+            };
+            let mut page = self.page(req, key, &annotations, page, iris);
+            page["@context"] = json!(ANNO_CONTEXT);
+            Reply::json(req, &page)
+          }
+          None => {
+            let prefer = Prefer::parse(req.header("Prefer"));
+            let container = self.collection(req, key, &annotations, &prefer);
+            let reply = Reply::json(req, &container).header("Vary", "Accept, Prefer");
+            if prefer.representation {
+              reply.header("Preference-Applied", "return=representation")
+            } else {
+              reply
+            }
+          }
+        }
+      }
+      "POST" => {
+        let mut annotation = match parse_annotation(&req.body) {
+          Ok(annotation) => annotation,
+          Err(err) => return Ok(Reply::error(400, &format!("{err:?}"))),
+        };
+        let existing = self.store.list(key)?;
+        let taken = |id: &str| existing.iter().any(|a| a.id == id);
+        annotation.id = req
+          .header("Slug")
+          .map(str::trim)
+          .filter(|slug| {
+            !slug.is_empty()
+              && slug
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c))
+          })
+          .filter(|slug| !taken(slug))
+          .map_or_else(|| uuid::Uuid::new_v4().to_string(), String::from);
+        self.store.create(key, annotation.clone())?;
+        let iri = req.annotation_iri(key, &annotation.id);
+        return Ok(
+          Reply::json(req, &describe(annotation, &iri)?)
+            .status(201)
+            .header("Location", &iri)
+            .header("Link", RESOURCE_LINK)
+            .header("Allow", ANNOTATION_ALLOW),
+        );
+      }
+      _ => Reply::options_or_not_allowed(req),
+    };
+    Ok(
+      reply
+        .header("Link", CONTAINER_LINK)
+        .header("Accept-Post", MEDIA_TYPE)
+        .header("Allow", ALLOW),
+    )
+  }
+
+  /// Describes the container of `key` according to the client's preferences.
+  fn collection(
+    &self,
+    req: &Request,
+    key: &PublicationKey,
+    annotations: &[RawAnnotation],
+    prefer: &Prefer,
+  ) -> Value {
+    let mut container = json!({
+      "@context": [ANNO_CONTEXT, LDP_CONTEXT],
+      "id": req.container_iri(key),
+      "type": ["BasicContainer", "AnnotationCollection"],
+      "label": key.identifier,
+      "total": annotations.len(),
+    });
+    if !annotations.is_empty() {
+      let last = (annotations.len() - 1) / self.page_size;
+      container["first"] = if prefer.minimal {
+        json!(req.page_iri(key, 0, prefer.iris))
+      } else {
+        self.page(req, key, annotations, 0, prefer.iris)
+      };
+      container["last"] = json!(req.page_iri(key, last, prefer.iris));
+    }
+    container
+  }
+
+  /// Describes the `page`th page of `annotations`, either in full or by their IRIs.
+  fn page(
+    &self,
+    req: &Request,
+    key: &PublicationKey,
+    annotations: &[RawAnnotation],
+    page: usize,
+    iris: bool,
+  ) -> Value {
+    let start = page * self.page_size;
+    let end = (start + self.page_size).min(annotations.len());
+    let items = annotations[start..end]
+      .iter()
+      .map(|annotation| {
+        let iri = req.annotation_iri(key, &annotation.id);
+        if iris {
+          Value::String(iri)
+        } else {
+          describe(annotation.clone(), &iri).unwrap_or(Value::Null)
+        }
+      })
+      .collect::<Vec<_>>();
+    let mut value = json!({
+      "id": req.page_iri(key, page, iris),
+      "type": "AnnotationPage",
+      "partOf": { "id": req.container_iri(key), "total": annotations.len() },
+      "startIndex": start,
+      "items": items,
+    });
+    if page > 0 {
+      value["prev"] = json!(req.page_iri(key, page - 1, iris));
+    }
+    if end < annotations.len() {
+      value["next"] = json!(req.page_iri(key, page + 1, iris));
+    }
+    value
+  }
+
+  fn annotation(&self, req: &Request, key: &PublicationKey, id: &str) -> Result<Reply> {
+    let Some(existing) = self.store.list(key)?.into_iter().find(|a| a.id == id) else {
+      return Ok(Reply::error(404, "No such annotation"));
+    };
+    let iri = req.annotation_iri(key, id);
+    let current = describe(existing, &iri)?;
+
+    let reply = match req.method.as_str() {
+      "GET" | "HEAD" => Reply::json(req, &current),
+      "PUT" | "DELETE" if !req.if_match(&etag(&current)) => {
+        return Ok(Reply::error(412, "The annotation has been modified"));
+      }
+      "PUT" => {
+        let mut annotation = match parse_annotation(&req.body) {
+          Ok(annotation) => annotation,
+          Err(err) => return Ok(Reply::error(400, &format!("{err:?}"))),
+        };
+        if !annotation.id.is_empty() && annotation.id != iri && annotation.id != id {
+          return Ok(Reply::error(
+            400,
+            "The annotation's id does not match its IRI",
+          ));
+        }
+        annotation.id = id.to_string();
+        self.store.update(key, annotation.clone())?;
+        Reply::json(req, &describe(annotation, &iri)?)
+      }
+      "DELETE" => {
+        self.store.delete(key, id)?;
+        Reply::empty(204)
+      }
+      _ => Reply::options_or_not_allowed(req),
+    };
+    Ok(
+      reply
+        .header("Link", RESOURCE_LINK)
+        .header("Allow", ANNOTATION_ALLOW),
+    )
+  }
+}
+
+/// Parses an annotation sent by a client, which may leave out its `id`.
+fn parse_annotation(body: &[u8]) -> Result<RawAnnotation> {
+  let mut value: Value = serde_json::from_slice(body).context("Request body is not JSON")?;
+  let object = value
+    .as_object_mut()
+    .context("Request body is not an annotation")?;
+  object.entry("id").or_insert_with(|| json!(""));
+  serde_json::from_value(value).context("Request body is not an annotation")
+}
+
+/// Serializes `annotation` with its IRI on this server as its `id`.
+fn describe(mut annotation: RawAnnotation, iri: &str) -> Result<Value> {
+  annotation.id = iri.to_string();
+  Ok(serde_json::to_value(annotation)?)
+}
+
+fn etag(value: &Value) -> String {
+  let hash = format!("{:x}", Sha256::digest(value.to_string().as_bytes()));
+  format!("\"{}\"", &hash[..16])
+}
+
+/// Checks whether the list of entity tags in an `If-Match` or `If-None-Match` header matches `etag`.
+fn etag_matches(tags: &str, etag: &str) -> bool {
+  tags
+    .split(',')
+    .map(str::trim)
+    .any(|tag| tag == "*" || tag == etag)
+}
+
+/// Percent-encodes everything except unreserved characters, so the result is one path segment.
+fn percent_encode(s: &str) -> String {
+  let mut encoded = String::with_capacity(s.len());
+  for byte in s.bytes() {
+    if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+      encoded.push(byte as char);
+    } else {
+      write!(encoded, "%{byte:02X}").unwrap();
+    }
+  }
+  encoded
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+  let mut bytes = Vec::with_capacity(s.len());
+  let mut iter = s.bytes();
+  while let Some(byte) = iter.next() {
+    if byte == b'%' {
+      let hex = [iter.next()?, iter.next()?];
+      bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    } else {
+      bytes.push(byte);
+    }
+  }
+  String::from_utf8(bytes).ok()
+}
+
+/// The preferences a client gives for the representation of a container in a `Prefer` header.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Prefer {
+  /// Whether the client asked for `return=representation`.
+  representation: bool,
+  /// Only link to the pages of the container, instead of embedding the first one.
+  minimal: bool,
+  /// List annotations by their IRIs, instead of embedding their descriptions.
+  iris: bool,
+}
+
+impl Prefer {
+  fn parse(header: Option<&str>) -> Self {
+    let mut prefer = Prefer::default();
+    let Some(header) = header else {
+      return prefer;
+    };
+    let mut params = header.split(';').map(str::trim);
+    if params.next() != Some("return=representation") {
+      return prefer;
+    }
+    prefer.representation = true;
+    let (mut include, mut omit) = (Vec::new(), Vec::new());
+    for param in params {
+      let Some((name, value)) = param.split_once('=') else {
+        continue;
+      };
+      let iris = value.trim().trim_matches('"').split_whitespace();
+      match name.trim() {
+        "include" => include.extend(iris),
+        "omit" => omit.extend(iris),
+        _ => {}
+      }
+    }
+    prefer.minimal = include.contains(&PREFER_MINIMAL);
+    prefer.iris = include.contains(&PREFER_IRIS)
+      || (omit.contains(&PREFER_DESCRIPTIONS) && !include.contains(&PREFER_DESCRIPTIONS));
+    prefer
+  }
+}
+
+/// The parts of an HTTP request used by the server.
+struct Request {
+  method: String,
+  path: String,
+  query: Vec<(String, String)>,
+  headers: Vec<(String, String)>,
+  body: Vec<u8>,
+  /// The scheme and authority which the client used to reach the server.
+  base: String,
+}
+
+impl Request {
+  fn read(
+    request: &mut tiny_http::Request,
+    local_addr: Option<SocketAddr>,
+    max_body: u64,
+  ) -> Result<Self> {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let query = query
+      .split('&')
+      .filter_map(|param| param.split_once('='))
+      .map(|(name, value)| (name.to_string(), value.to_string()))
+      .collect();
+    let headers: Vec<(String, String)> = request
+      .headers()
+      .iter()
+      .map(|header| (header.field.to_string(), header.value.to_string()))
+      .collect();
+    let host = headers
+      .iter()
+      .find(|(name, _)| name.eq_ignore_ascii_case("Host"))
+      .map(|(_, value)| value.clone())
+      .or_else(|| local_addr.map(|addr| addr.to_string()))
+      .unwrap_or_else(|| "localhost".into());
+    let mut body = Vec::new();
+    request
+      .as_reader()
+      .take(max_body)
+      .read_to_end(&mut body)
+      .context("Failed to read request body")?;
+    Ok(Request {
+      method: request.method().to_string(),
+      path: path.to_string(),
+      query,
+      headers,
+      body,
+      base: format!("http://{host}"),
+    })
+  }
+
+  fn header(&self, name: &str) -> Option<&str> {
+    self
+      .headers
+      .iter()
+      .find(|(field, _)| field.eq_ignore_ascii_case(name))
+      .map(|(_, value)| value.as_str())
+  }
+
+  fn query(&self, name: &str) -> Option<&str> {
+    self
+      .query
+      .iter()
+      .find(|(field, _)| field == name)
+      .map(|(_, value)| value.as_str())
+  }
+
+  /// Checks the `If-Match` header, which is satisfied when absent.
+  fn if_match(&self, etag: &str) -> bool {
+    self
+      .header("If-Match")
+      .is_none_or(|tags| etag_matches(tags, etag))
+  }
+
+  fn container_iri(&self, key: &PublicationKey) -> String {
+    format!("{}/annotations/{}/", self.base, key.slug())
+  }
+
+  fn annotation_iri(&self, key: &PublicationKey, id: &str) -> String {
+    format!("{}{}", self.container_iri(key), percent_encode(id))
+  }
+
+  fn page_iri(&self, key: &PublicationKey, page: usize, iris: bool) -> String {
+    let iris = if iris { "iris=1&" } else { "" };
+    format!("{}?{iris}page={page}", self.container_iri(key))
+  }
+}
+
+/// A response to a [`Request`].
+struct Reply {
+  status: u16,
+  headers: Vec<(String, String)>,
+  body: Vec<u8>,
+}
+
+impl Reply {
+  fn empty(status: u16) -> Self {
+    Reply {
+      status,
+      headers: Vec::new(),
+      body: Vec::new(),
+    }
+  }
+
+  fn error(status: u16, message: &str) -> Self {
+    Reply {
+      body: message.as_bytes().to_vec(),
+      ..Reply::empty(status)
+    }
+    .header("Content-Type", "text/plain; charset=utf-8")
+  }
+
+  fn bytes(content_type: &str, body: Vec<u8>) -> Self {
+    Reply {
+      body,
+      ..Reply::empty(200)
+    }
+    .header("Content-Type", content_type)
+  }
+
+  fn asset(asset: Asset) -> Self {
+    match asset.content_type {
+      Some(content_type) => Reply::bytes(&content_type, asset.contents),
+      None => Reply {
+        body: asset.contents,
+        ..Reply::empty(200)
+      },
+    }
+  }
+
+  fn redirect(location: &str) -> Self {
+    Reply::empty(301).header("Location", location)
+  }
+
+  /// Responds with `value`, or with 304 Not Modified if the client already has it.
+  fn json(req: &Request, value: &Value) -> Self {
+    let etag = etag(value);
+    let cached = req
+      .header("If-None-Match")
+      .is_some_and(|tags| etag_matches(tags, &etag));
+    let reply = if cached {
+      Reply::empty(304)
+    } else {
+      Reply {
+        body: serde_json::to_vec_pretty(value).expect("JSON values serialize"),
+        ..Reply::empty(200)
+      }
+      .header("Content-Type", MEDIA_TYPE)
+    };
+    reply.header("ETag", &etag)
+  }
+
+  /// Responds to an `OPTIONS` request, or to a method which the resource does not allow.
+  fn options_or_not_allowed(req: &Request) -> Self {
+    Reply::empty(if req.method == "OPTIONS" { 200 } else { 405 })
+  }
+
+  #[must_use]
+  fn status(mut self, status: u16) -> Self {
+    self.status = status;
+    self
+  }
+
+  #[must_use]
+  fn header(mut self, name: &str, value: &str) -> Self {
+    self.headers.push((name.to_string(), value.to_string()));
+    self
+  }
+
+  fn into_response(self) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    let mut response = tiny_http::Response::from_data(self.body).with_status_code(self.status);
+    for (name, value) in self.headers {
+      if let Ok(header) = tiny_http::Header::from_bytes(name.as_bytes(), value.as_bytes()) {
+        response.add_header(header);
+      }
+    }
+    response
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::{io::Write as _, net::TcpStream, thread};
+
+  use super::*;
+
+  fn annotation(id: &str, value: &str) -> String {
+    format!(
+      r#"{{
+        "@context": "http://www.w3.org/ns/anno.jsonld",
+        "id": "{id}",
+        "type": "Annotation",
+        "bodyValue": "{value}",
+        "target": "urn:test:book#epubcfi(/6/2!/4/2,/1:0,/1:5)"
+      }}"#
+    )
+  }
+
+  pub(super) struct Response {
+    pub(super) status: u16,
+    headers: Vec<(String, String)>,
+    pub(super) body: String,
+  }
+
+  impl Response {
+    pub(super) fn header(&self, name: &str) -> Option<&str> {
+      self
+        .headers
+        .iter()
+        .find(|(field, _)| field.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+    }
+
+    pub(super) fn json(&self) -> Value {
+      serde_json::from_str(&self.body).unwrap()
+    }
+  }
+
+  pub(super) fn send(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+  ) -> Response {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut request = format!(
+      "{method} {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\nContent-Length: {}\r\n",
+      body.len()
+    );
+    for (name, value) in headers {
+      write!(request, "{name}: {value}\r\n").unwrap();
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let mut lines = head.lines();
+    let status = lines.next().unwrap().split(' ').nth(1).unwrap();
+    let headers = lines
+      .filter_map(|line| line.split_once(": "))
+      .map(|(name, value)| (name.to_string(), value.to_string()))
+      .collect();
+    Response {
+      status: status.parse().unwrap(),
+      headers,
+      body: body.to_string(),
+    }
+  }
+
+  /// Starts a server on a store with `count` annotations for one publication.
+  fn start(count: usize, page_size: usize) -> (tempfile::TempDir, SocketAddr, String) {
+    let dir = tempfile::tempdir().unwrap();
+    let store = AnnotationStore::new(dir.path().to_path_buf());
+    let key = PublicationKey {
+      identifier: "urn:test:book".into(),
+      content_hash: "abc123".into(),
+    };
+    let annotations = (0..count)
+      .map(|i| RawAnnotation::parse(&annotation(&format!("a{i}"), "note")).unwrap())
+      .collect();
+    store.import(&key, annotations).unwrap();
+
+    let server = Server::bind("127.0.0.1:0", store)
+      .unwrap()
+      .with_page_size(page_size);
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    (dir, addr, format!("/annotations/{}/", key.slug()))
+  }
+
+  #[test]
+  fn test_container() {
+    let (_dir, addr, container) = start(3, 2);
+    let base = format!("http://{addr}");
+
+    let root = send(addr, "GET", "/annotations/", &[], "").json();
+    assert_eq!(root["contains"][0]["id"], format!("{base}{container}"));
+    assert_eq!(root["contains"][0]["label"], "urn:test:book");
+
+    let response = send(addr, "GET", &container, &[], "");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Type"), Some(MEDIA_TYPE));
+    assert!(
+      response
+        .header("Link")
+        .unwrap()
+        .contains("ldp#BasicContainer")
+    );
+    assert!(response.header("Allow").unwrap().contains("POST"));
+    let collection = response.json();
+    assert_eq!(collection["total"], 3);
+    assert_eq!(collection["last"], format!("{base}{container}?page=1"));
+    let first = &collection["first"];
+    assert_eq!(first["type"], "AnnotationPage");
+    assert_eq!(first["items"].as_array().unwrap().len(), 2);
+    assert_eq!(first["items"][0]["id"], format!("{base}{container}a0"));
+    assert_eq!(first["items"][0]["bodyValue"], "note");
+    assert_eq!(first["next"], format!("{base}{container}?page=1"));
+
+    let page = send(addr, "GET", &format!("{container}?page=1"), &[], "").json();
+    assert_eq!(page["startIndex"], 2);
+    assert_eq!(page["items"].as_array().unwrap().len(), 1);
+    assert_eq!(page["prev"], format!("{base}{container}?page=0"));
+    assert!(page.get("next").is_none());
+    assert_eq!(
+      send(addr, "GET", &format!("{container}?page=2"), &[], "").status,
+      404
+    );
+
+    // Clients can ask for links to the pages, or for the IRIs of the annotations.
+    let prefer = format!(r#"return=representation;include="{PREFER_MINIMAL}""#);
+    let response = send(addr, "GET", &container, &[("Prefer", &prefer)], "");
+    assert_eq!(
+      response.header("Preference-Applied"),
+      Some("return=representation")
+    );
+    assert_eq!(
+      response.json()["first"],
+      format!("{base}{container}?page=0")
+    );
+    let prefer = format!(r#"return=representation;include="{PREFER_IRIS}""#);
+    let collection = send(addr, "GET", &container, &[("Prefer", &prefer)], "").json();
+    assert_eq!(
+      collection["first"]["items"][1],
+      format!("{base}{container}a1")
+    );
+    assert_eq!(
+      collection["last"],
+      format!("{base}{container}?iris=1&page=1")
+    );
+
+    // Unchanged representations are not sent again.
+    let etag = response.header("ETag").unwrap().to_string();
+    let response = send(addr, "GET", &container, &[("If-None-Match", &etag)], "");
+    assert_eq!(response.status, 200);
+    let response = send(
+      addr,
+      "GET",
+      &container,
+      &[("Prefer", &prefer), ("If-None-Match", &etag)],
+      "",
+    );
+    assert_eq!(response.status, 200);
+    let etag = response.header("ETag").unwrap().to_string();
+    let response = send(
+      addr,
+      "GET",
+      &container,
+      &[("Prefer", &prefer), ("If-None-Match", &etag)],
+      "",
+    );
+    assert_eq!(response.status, 304);
+
+    assert_eq!(send(addr, "DELETE", &container, &[], "").status, 405);
+    assert_eq!(
+      send(addr, "GET", "/annotations/missing/", &[], "").status,
+      404
+    );
+  }
+
+  #[test]
+  fn test_annotation_lifecycle() {
+    let (dir, addr, container) = start(1, PAGE_SIZE);
+    let base = format!("http://{addr}");
+
+    let response = send(
+      addr,
+      "POST",
+      &container,
+      &[("Slug", "my-note")],
+      &annotation("ignored", "one"),
+    );
+    assert_eq!(response.status, 201);
+    let location = response.header("Location").unwrap().to_string();
+    assert_eq!(location, format!("{base}{container}my-note"));
+    assert_eq!(response.json()["id"], location);
+    let path = location.strip_prefix(&base).unwrap();
+
+    // Without a usable slug, the server picks an id.
+    let body = r#"{"@context": "http://www.w3.org/ns/anno.jsonld", "type": "Annotation", "target": "urn:test:book"}"#;
+    let response = send(addr, "POST", &container, &[("Slug", "my-note")], body);
+    assert_eq!(response.status, 201);
+    assert_ne!(response.header("Location").unwrap(), location);
+    assert_eq!(send(addr, "POST", &container, &[], "{}").status, 400);
+
+    let response = send(addr, "GET", path, &[], "");
+    assert_eq!(response.status, 200);
+    assert!(response.header("Link").unwrap().contains("ldp#Resource"));
+    let etag = response.header("ETag").unwrap().to_string();
+
+    let update = annotation(&location, "two");
+    let response = send(addr, "PUT", path, &[("If-Match", "\"stale\"")], &update);
+    assert_eq!(response.status, 412);
+    let response = send(addr, "PUT", path, &[("If-Match", &etag)], &update);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.json()["bodyValue"], "two");
+    assert_ne!(response.header("ETag").unwrap(), etag);
+    let response = send(addr, "PUT", path, &[], &annotation("other", "three"));
+    assert_eq!(response.status, 400);
+
+    // Changes are made to the same store as the app's.
+    let store = AnnotationStore::new(dir.path().to_path_buf());
+    let key = store.publications().unwrap().remove(0);
+    let stored = store.list(&key).unwrap();
+    assert_eq!(stored[1].id, "my-note");
+    assert_eq!(stored[1].body_value.as_deref(), Some("two"));
+
+    assert_eq!(
+      send(addr, "DELETE", path, &[("If-Match", &etag)], "").status,
+      412
+    );
+    assert_eq!(send(addr, "DELETE", path, &[], "").status, 204);
+    assert_eq!(send(addr, "GET", path, &[], "").status, 404);
+    assert_eq!(store.list(&key).unwrap().len(), 2);
+  }
+
+  #[test]
+  fn test_containers_without_annotations() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = AnnotationStore::new(dir.path().join("annotations"));
+    let library = Library::open(&dir.path().join("library.sqlite3")).unwrap();
+    let read = PublicationKey {
+      identifier: "urn:test:read".into(),
+      content_hash: "abc123".into(),
+    };
+    let position = crate::reader::ReadingPosition {
+      cfi: "epubcfi(/6/2!/4)".into(),
+      percentage: 0.0,
+    };
+    library.save_position(&read, &position).unwrap();
+    let book = PathBuf::from(concat!(
+      env!("CARGO_MANIFEST_DIR"),
+      "/../../../epubs/portable-epubs"
+    ));
+    let server = Server::bind("127.0.0.1:0", store.clone())
+      .unwrap()
+      .with_reader(web::Frontend::new(dir.path()), Some(book))
+      .unwrap()
+      .with_library(library);
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    while send(addr, "GET", "/api/state", &[], "").json()["type"] != "Ready" {
+      thread::sleep(std::time::Duration::from_millis(50));
+    }
+
+    // The open book and the books read in the library have containers before they have annotations.
+    let root = send(addr, "GET", "/annotations/", &[], "").json();
+    let containers = root["contains"].as_array().unwrap();
+    assert_eq!(containers.len(), 2);
+    for container in containers {
+      let path = container["id"]
+        .as_str()
+        .unwrap()
+        .strip_prefix(&format!("http://{addr}"))
+        .unwrap();
+      assert_eq!(send(addr, "GET", path, &[], "").json()["total"], 0);
+      let response = send(addr, "POST", path, &[], &annotation("", "note"));
+      assert_eq!(response.status, 201);
+    }
+    assert_eq!(store.publications().unwrap().len(), 2);
+    let container = format!("/annotations/{}/", read.slug());
+    assert_eq!(send(addr, "GET", &container, &[], "").json()["total"], 1);
+  }
+
+  #[test]
+  fn test_prefer() {
+    assert_eq!(Prefer::parse(None), Prefer::default());
+    assert_eq!(
+      Prefer::parse(Some(&format!(
+        r#"return=representation; include="{PREFER_MINIMAL} {PREFER_IRIS}""#
+      ))),
+      Prefer {
+        representation: true,
+        minimal: true,
+        iris: true
+      }
+    );
+    assert_eq!(
+      Prefer::parse(Some(&format!(
+        r#"return=representation; omit="{PREFER_DESCRIPTIONS}""#
+      ))),
+      Prefer {
+        representation: true,
+        minimal: false,
+        iris: true
+      }
+    );
+    assert_eq!(percent_encode("https://a/b c"), "https%3A%2F%2Fa%2Fb%20c");
+    assert_eq!(
+      percent_decode("https%3A%2F%2Fa%2Fb%20c").as_deref(),
+      Some("https://a/b c")
+    );
+  }
+}
@@ -0,0 +1,576 @@
+//! Serves the reader to web browsers, so that Bene can be used on a machine without a display.
+//!
+//! The frontend is the same as the app's: the page at `/` is the desktop frontend, which embeds the
+//! reader from `/bene-reader/`. The app's commands are replaced by endpoints under `/api/`:
+//! - `GET /api/state` returns the [`SharedState`](crate::reader::SharedState).
+//! - `GET /api/events` streams `state`, `annotations-reanchored` and `resources-changed` events as
+//!   server-sent events.
+//! - `POST /api/upload` opens an EPUB uploaded as the request's body, and `POST /api/cancel`
+//!   cancels opening it. Other files on the server cannot be opened, as the server may be shared.
+//! - `GET /api/annotations` lists the annotations on the loaded book, `POST /api/annotations`
+//!   creates one, and `PUT` or `DELETE` on `/api/annotations/{id}` updates or deletes it.
+
+use std::{
+  fs,
+  io::Write,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex, MutexGuard, mpsc},
+  thread,
+  time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, ensure};
+use bene_epub::annotation::{self, Annotation, RawAnnotation};
+use serde::Serialize;
+
+use super::{Reply, Request, percent_decode};
+use crate::{
+  reader::{self, Book, Reader, Sink},
+  store::{AnnotationStore, PublicationKey},
+};
+
+/// The largest EPUB which can be uploaded.
+pub(super) const MAX_UPLOAD: u64 = 512 * 1024 * 1024;
+
+/// How often a comment is sent to idle event streams, so that closed connections are noticed.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// The directories of the frontend's built bundles.
+pub struct Frontend {
+  /// The desktop frontend, which hosts the reader.
+  pub host_dir: PathBuf,
+  /// The reader.
+  pub reader_dir: PathBuf,
+}
+
+impl Frontend {
+  /// Finds the bundles in `dir`, which is laid out like the `js/packages` directory of the
+  /// repository.
+  pub fn new(dir: &Path) -> Self {
+    Frontend {
+      host_dir: dir.join("bene-desktop/dist"),
+      reader_dir: dir.join("bene-reader/dist"),
+    }
+  }
+}
+
+/// Sends the events of the reader to every browser listening for them.
+struct Browsers {
+  store: Mutex<AnnotationStore>,
+  /// The streams of the browsers listening for events.
+  listeners: Mutex<Vec<mpsc::Sender<String>>>,
+}
+
+impl Sink for Browsers {
+  fn emit(&self, event: &str, payload: impl Serialize + Clone) {
+    let message = server_sent_event(event, &payload);
+    let mut listeners = self.listeners.lock().unwrap();
+    listeners.retain(|listener| listener.send(message.clone()).is_ok());
+  }
+
+  fn store(&self) -> MutexGuard<'_, AnnotationStore> {
+    self.store.lock().unwrap()
+  }
+}
+
+/// The reader's state, shared by every browser connected to the server.
+pub(super) struct Session {
+  frontend: Frontend,
+  /// A directory for the EPUBs uploaded by browsers.
+  uploads: tempfile::TempDir,
+  reader: Arc<Reader<Browsers>>,
+}
+
+impl Session {
+  /// Creates a session which serves `frontend` and stores annotations in `store`.
+  ///
+  /// # Errors
+  /// If the directory for uploads cannot be created.
+  pub(super) fn new(frontend: Frontend, store: AnnotationStore) -> Result<Self> {
+    Ok(Session {
+      frontend,
+      uploads: tempfile::tempdir()?,
+      reader: Reader::new(Browsers {
+        store: Mutex::new(store),
+        listeners: Mutex::new(Vec::new()),
+      }),
+    })
+  }
+
+  /// Opens the EPUB at `path` in the background, reloading it whenever the file changes. Loads
+  /// which are started before it finishes replace it.
+  pub(super) fn load(&self, path: PathBuf) {
+    self.reader.load(path, false, None);
+  }
+
+  /// Returns the key of the book open in the reader, if any.
+  pub(super) fn publication(&self) -> Option<PublicationKey> {
+    let local_state = self.reader.local_state.lock().unwrap();
+    local_state
+      .as_ref()
+      .map(|local_state| local_state.book.publication.clone())
+  }
+
+  /// Streams events to the browser which sent `request`, starting with the current state.
+  pub(super) fn subscribe(&self, request: tiny_http::Request) {
+    let (sender, receiver) = mpsc::channel();
+    // The state is locked until the stream is registered, so that no change is missed.
+    let state = self.reader.shared_state.lock().unwrap();
+    sender
+      .send(server_sent_event("state", &*state))
+      .expect("The receiver is alive");
+    self.reader.sink.listeners.lock().unwrap().push(sender);
+    drop(state);
+
+    thread::spawn(move || {
+      let mut writer = request.into_writer();
+      let mut message = concat!(
+        "HTTP/1.1 200 OK\r\n",
+        "Content-Type: text/event-stream\r\n",
+        "Cache-Control: no-cache\r\n",
+        "Connection: close\r\n\r\n"
+      )
+      .to_string();
+      loop {
+        if writer
+          .write_all(message.as_bytes())
+          .and_then(|()| writer.flush())
+          .is_err()
+        {
+          break;
+        }
+        message = match receiver.recv_timeout(KEEP_ALIVE) {
+          Ok(message) => message,
+          Err(mpsc::RecvTimeoutError::Timeout) => ": keep-alive\n\n".into(),
+          Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+      }
+    });
+  }
+
+  /// Handles a request for the frontend or the API.
+  pub(super) fn handle(&self, req: &Request) -> Result<Reply> {
+    if let Some(endpoint) = req.path.strip_prefix("/api/") {
+      return self.api(req, endpoint);
+    }
+    let asset = match req.path.strip_prefix("/bene-reader") {
+      Some(path) => {
+        let local_state = self.reader.local_state.lock().unwrap();
+        let book = local_state.as_ref().map(|local_state| &local_state.book);
+        reader::read_asset(path, &self.frontend.reader_dir, book)
+      }
+      None => {
+        let path = if req.path == "/" {
+          "/index.html"
+        } else {
+          &req.path
+        };
+        reader::read_asset(path, &self.frontend.host_dir, None)
+      }
+    };
+    Ok(match asset {
+      Ok(asset) => Reply::asset(asset),
+      Err(err) => Reply::error(404, &format!("{err:?}")),
+    })
+  }
+
+  fn api(&self, req: &Request, endpoint: &str) -> Result<Reply> {
+    Ok(match (req.method.as_str(), endpoint) {
+      ("GET", "state") => {
+        let state = serde_json::to_vec(&*self.reader.shared_state.lock().unwrap())?;
+        Reply::bytes("application/json", state)
+      }
+      ("POST", "upload") => match self.upload(req) {
+        Ok(path) => {
+          self.load(path);
+          Reply::empty(202)
+        }
+        Err(err) => Reply::error(400, &format!("{err:?}")),
+      },
+      ("POST", "cancel") => {
+        self.reader.cancel_load();
+        Reply::empty(204)
+      }
+      ("GET", "annotations") => match self.annotations() {
+        Ok(annotations) => Reply::bytes("application/json", serde_json::to_vec(&annotations)?),
+        Err(err) => Reply::error(400, &format!("{err:?}")),
+      },
+      ("POST", "annotations") => self.edit(|store, book| {
+        let annotation = book.fingerprint(&req.body)?;
+        store.create(&book.publication, annotation)
+      }),
+      (method @ ("PUT" | "DELETE"), endpoint) if endpoint.starts_with("annotations/") => {
+        self.edit(|store, book| {
+          let id = endpoint
+            .strip_prefix("annotations/")
+            .and_then(percent_decode)
+            .context("Invalid annotation id")?;
+          if method == "DELETE" {
+            return store.delete(&book.publication, &id);
+          }
+          let annotation = book.fingerprint(&req.body)?;
+          ensure!(
+            annotation.id == id,
+            "The annotation's id does not match its URL"
+          );
+          store.update(&book.publication, annotation)
+        })
+      }
+      _ => Reply::error(404, "Not found"),
+    })
+  }
+
+  /// Saves the EPUB uploaded by `req`, and returns its path.
+  fn upload(&self, req: &Request) -> Result<PathBuf> {
+    let path = self
+      .uploads
+      .path()
+      .join(format!("{}.epub", uuid::Uuid::new_v4().simple()));
+    fs::write(&path, &req.body).with_context(|| format!("Failed to write: {}", path.display()))?;
+    Ok(path)
+  }
+
+  /// Returns the stored annotations on the loaded book, anchored against its first rendition.
+  fn annotations(&self) -> Result<Vec<Annotation>> {
+    let local_state = self.reader.local_state.lock().unwrap();
+    let book = &local_state.as_ref().context("Epub not loaded yet")?.book;
+    let mut archive = book.archive.lock_one();
+    self
+      .reader
+      .sink
+      .store()
+      .processed(&book.publication, &mut archive, &book.epub.renditions[0])
+  }
+
+  /// Edits the annotations on the loaded book with `f`.
+  fn edit(&self, f: impl FnOnce(&AnnotationStore, &Book) -> Result<()>) -> Reply {
+    let local_state = self.reader.local_state.lock().unwrap();
+    let result = match local_state.as_ref() {
+      Some(local_state) => f(&self.reader.sink.store(), &local_state.book),
+      None => Err(anyhow!("Epub not loaded yet")),
+    };
+    match result {
+      Ok(()) => Reply::empty(204),
+      Err(err) => Reply::error(400, &format!("{err:?}")),
+    }
+  }
+}
+
+impl Book {
+  /// Parses an [`Annotation`] sent by the reader, recording the text it quotes from the book.
+  fn fingerprint(&self, body: &[u8]) -> Result<RawAnnotation> {
+    let mut annotation: Annotation = serde_json::from_slice(body).context("Invalid annotation")?;
+    let mut archive = self.archive.lock_one();
+    annotation::fingerprint(&mut annotation, &mut archive, &self.epub.renditions[0]);
+    Ok(annotation.to_raw(&self.publication.source()))
+  }
+}
+
+fn server_sent_event(event: &str, value: &impl Serialize) -> String {
+  let data = serde_json::to_string(value).expect("Events serialize");
+  format!("event: {event}\ndata: {data}\n\n")
+}
+
+#[cfg(test)]
+mod test {
+  use std::{
+    io::{Cursor, Read, Write as _},
+    net::TcpStream,
+  };
+
+  use bene_epub::{Archive, FileZip, cfi};
+
+  use super::*;
+  use crate::server::{Server, test::send};
+
+  /// Reads from an event stream until `pattern` is received.
+  fn read_until(stream: &mut TcpStream, pattern: &str) -> String {
+    let mut received = String::new();
+    let mut buffer = [0; 1024];
+    while !received.contains(pattern) {
+      let n = stream
+        .read(&mut buffer)
+        .unwrap_or_else(|err| panic!("{err} before receiving {pattern}: {received}"));
+      assert!(
+        n > 0,
+        "Stream closed before receiving {pattern}: {received}"
+      );
+      received.push_str(std::str::from_utf8(&buffer[..n]).unwrap());
+    }
+    received
+  }
+
+  #[test]
+  fn test_reader() {
+    let dir = tempfile::tempdir().unwrap();
+    for (path, contents) in [
+      ("bene-desktop/dist/index.html", "host"),
+      ("bene-reader/dist/index.html", "reader"),
+      ("secret.txt", "secret"),
+    ] {
+      let path = dir.path().join(path);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(path, contents).unwrap();
+    }
+    let store = AnnotationStore::new(dir.path().join("annotations"));
+    let server = Server::bind("127.0.0.1:0", store)
+      .unwrap()
+      .with_reader(Frontend::new(dir.path()), None)
+      .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let response = send(addr, "GET", "/", &[], "");
+    assert_eq!(response.body, "host");
+    assert_eq!(response.header("Content-Type"), Some("text/html"));
+    assert_eq!(
+      send(addr, "GET", "/bene-reader/index.html", &[], "").body,
+      "reader"
+    );
+    assert_eq!(
+      send(addr, "GET", "/bene-reader/../../secret.txt", &[], "").status,
+      404
+    );
+    assert_eq!(
+      send(
+        addr,
+        "GET",
+        "/bene-reader/epub-content/EPUB/package.opf",
+        &[],
+        ""
+      )
+      .status,
+      404
+    );
+    assert_eq!(
+      send(addr, "GET", "/api/state", &[], "").json(),
+      serde_json::json!({ "type": "Waiting" })
+    );
+    assert_eq!(send(addr, "GET", "/api/annotations", &[], "").status, 400);
+    assert_eq!(
+      send(addr, "POST", "/api/annotations", &[], "{}").status,
+      400
+    );
+
+    // Browsers are told about each change to the state as it happens.
+    let mut events = TcpStream::connect(addr).unwrap();
+    write!(events, "GET /api/events HTTP/1.1\r\nHost: {addr}\r\n\r\n").unwrap();
+    let received = read_until(&mut events, "Waiting");
+    assert!(received.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(received.contains("Content-Type: text/event-stream"));
+    assert!(received.ends_with("event: state\ndata: {\"type\":\"Waiting\"}\n\n"));
+
+    let response = send(addr, "POST", "/api/upload", &[], "not an epub");
+    assert_eq!(response.status, 202);
+    let received = read_until(&mut events, "Error");
+    assert!(received.starts_with(
+      "event: state\ndata: {\"type\":\"Loading\",\"value\":{\"stage\":\"reading-container\",\"step\":1,\"steps\":3,\"reload\":false}}\n\n"
+    ));
+    assert!(received.contains("Failed to parse epub as zip"));
+    assert_eq!(
+      send(addr, "GET", "/api/state", &[], "").json()["type"],
+      "Error"
+    );
+
+    // Browsers cannot open other files on the server by their path.
+    let book = concat!(env!("CARGO_MANIFEST_DIR"), "/../../../epubs/portable-epubs");
+    let body = serde_json::json!({ "path": book }).to_string();
+    let json = [("Content-Type", "application/json")];
+    assert_eq!(send(addr, "POST", "/api/upload", &json, &body).status, 202);
+    let received = read_until(&mut events, "Error");
+    assert!(!received.contains("Ready"), "{received}");
+  }
+
+  fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+      let entry = entry.unwrap();
+      if entry.file_type().unwrap().is_dir() {
+        copy_dir(&entry.path(), &to.join(entry.file_name()));
+      } else {
+        fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+      }
+    }
+  }
+
+  #[test]
+  fn test_reload() {
+    let dir = tempfile::tempdir().unwrap();
+    let book = dir.path().join("book");
+    copy_dir(
+      Path::new(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../../../epubs/portable-epubs"
+      )),
+      &book,
+    );
+    let store = AnnotationStore::new(dir.path().join("annotations"));
+    let server = Server::bind("127.0.0.1:0", store)
+      .unwrap()
+      .with_reader(Frontend::new(dir.path()), Some(book.clone()))
+      .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let mut events = TcpStream::connect(addr).unwrap();
+    events
+      .set_read_timeout(Some(Duration::from_secs(10)))
+      .unwrap();
+    write!(events, "GET /api/events HTTP/1.1\r\nHost: {addr}\r\n\r\n").unwrap();
+    read_until(&mut events, "Ready");
+
+    // Editing a chapter only reloads the chapter.
+    let chapter = book.join("EPUB/index.xhtml");
+    let contents = fs::read_to_string(&chapter).unwrap();
+    fs::write(
+      &chapter,
+      contents.replace("</body>", "<p>Edited</p></body>"),
+    )
+    .unwrap();
+    let received = read_until(
+      &mut events,
+      "event: resources-changed\ndata: [\"EPUB/index.xhtml\"]\n\n",
+    );
+    assert!(!received.contains("Loading"), "{received}");
+    let response = send(
+      addr,
+      "GET",
+      "/bene-reader/epub-content/EPUB/index.xhtml",
+      &[],
+      "",
+    );
+    assert!(response.body.contains("Edited"));
+
+    // Editing the package reloads the book.
+    let package = book.join("EPUB/package.opf");
+    let contents = fs::read_to_string(&package).unwrap();
+    fs::write(&package, format!("{contents}\n")).unwrap();
+    let received = read_until(&mut events, "Ready");
+    assert!(received.contains("\"reload\":true"), "{received}");
+  }
+
+  #[test]
+  fn test_annotations() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = AnnotationStore::new(dir.path().join("annotations"));
+    let book = Path::new(concat!(
+      env!("CARGO_MANIFEST_DIR"),
+      "/../../../epubs/portable-epubs"
+    ));
+    let server = Server::bind("127.0.0.1:0", store)
+      .unwrap()
+      .with_reader(Frontend::new(dir.path()), Some(book.to_path_buf()))
+      .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let mut events = TcpStream::connect(addr).unwrap();
+    events
+      .set_read_timeout(Some(Duration::from_secs(10)))
+      .unwrap();
+    write!(events, "GET /api/events HTTP/1.1\r\nHost: {addr}\r\n\r\n").unwrap();
+    read_until(&mut events, "Ready");
+    assert_eq!(
+      send(addr, "GET", "/api/annotations", &[], "").json(),
+      serde_json::json!([])
+    );
+
+    let annotation = Annotation {
+      id: "urn:uuid:a".into(),
+      motivation: vec!["highlighting".into()],
+      created: None,
+      creator: Vec::new(),
+      modified: None,
+      audience: Vec::new(),
+      rights: Vec::new(),
+      targets: vec![annotation::Target {
+        selector: cfi::Fragment::parse("epubcfi(/6/2!/4/2/2/2,/1:0,/1:14)").unwrap(),
+        quote: None,
+      }],
+      bodies: Vec::new(),
+    };
+    let body = serde_json::to_string(&annotation).unwrap();
+    assert_eq!(
+      send(addr, "POST", "/api/annotations", &[], &body).status,
+      204
+    );
+
+    // Stored annotations are listed with the text they quote, so the reader can show them again.
+    let listed = send(addr, "GET", "/api/annotations", &[], "").json();
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    assert_eq!(listed[0]["id"], "urn:uuid:a");
+    assert_eq!(listed[0]["targets"][0]["quote"]["exact"], "Portable EPUBs");
+
+    let path = "/api/annotations/urn%3Auuid%3Aa";
+    assert_eq!(send(addr, "DELETE", path, &[], "").status, 204);
+    assert_eq!(
+      send(addr, "GET", "/api/annotations", &[], "").json(),
+      serde_json::json!([])
+    );
+  }
+
+  #[test]
+  fn test_watch() {
+    let dir = tempfile::tempdir().unwrap();
+    let unzipped = dir.path().join("unzipped");
+    copy_dir(
+      Path::new(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../../../epubs/portable-epubs"
+      )),
+      &unzipped,
+    );
+    // The images are left out, as compressing them is slow.
+    fs::remove_dir_all(unzipped.join("EPUB/img")).unwrap();
+    let mut archive = Archive::load(FileZip(unzipped.clone())).unwrap();
+    let chapter = fs::read_to_string(unzipped.join("EPUB/index.xhtml")).unwrap();
+    let mut edit = |text: &str| {
+      let contents = chapter.replace("</body>", &format!("<p>{text}</p></body>"));
+      let replacements = [("EPUB/index.xhtml".to_string(), contents.into_bytes())];
+      archive
+        .rewrite(Cursor::new(Vec::new()), &replacements)
+        .unwrap()
+        .into_inner()
+    };
+
+    let book = dir.path().join("book.epub");
+    fs::write(&book, edit("Original")).unwrap();
+    let store = AnnotationStore::new(dir.path().join("annotations"));
+    let server = Server::bind("127.0.0.1:0", store)
+      .unwrap()
+      .with_reader(Frontend::new(dir.path()), Some(book.clone()))
+      .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let mut events = TcpStream::connect(addr).unwrap();
+    events
+      .set_read_timeout(Some(Duration::from_secs(10)))
+      .unwrap();
+    write!(events, "GET /api/events HTTP/1.1\r\nHost: {addr}\r\n\r\n").unwrap();
+    read_until(&mut events, "Ready");
+    let changed = "event: resources-changed\ndata: [\"EPUB/index.xhtml\"]\n\n";
+
+    // Saving by renaming a new file over the book is followed.
+    let temp = dir.path().join("book.epub.tmp");
+    fs::write(&temp, edit("Renamed")).unwrap();
+    fs::rename(&temp, &book).unwrap();
+    read_until(&mut events, changed);
+
+    // A partially written book is not loaded until it is whole.
+    let contents = edit("Partial");
+    fs::write(&book, &contents[..contents.len() / 2]).unwrap();
+    thread::sleep(Duration::from_secs(1));
+    fs::write(&book, &contents).unwrap();
+    let received = read_until(&mut events, changed);
+    assert!(!received.contains("Error"), "{received}");
+    let response = send(
+      addr,
+      "GET",
+      "/bene-reader/epub-content/EPUB/index.xhtml",
+      &[],
+      "",
+    );
+    assert!(response.body.contains("Partial"));
+  }
+}
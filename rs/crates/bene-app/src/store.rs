@@ -0,0 +1,662 @@
+//! A per-user store of annotations, kept on disk outside of EPUB files so that
+//! publications are never modified.
+
+use std::{
+  fs,
+  io::{self, Write},
+  path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use bene_epub::{
+  Archive, Epub, FileZip, Rendition, ZipFormat,
+  annotation::{self, AnchorReport, Annotation, RawAnnotation},
+};
+use iref::IriRefBuf;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use sha2::{Digest, Sha256};
+
+use crate::sync::{Document, Stamp};
+
+/// The version of the store's file format written by this version of Bene.
+const SCHEMA_VERSION: u64 = 2;
+
+/// The file in the store directory which holds the id of this device's replica.
+const REPLICA_FILE: &str = "replica";
+
+/// Identifies one edition of a publication in the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicationKey {
+  /// The value of the package's `unique-identifier`.
+  pub identifier: String,
+  /// The SHA-256 hash of the EPUB file in hex, which distinguishes editions with the same identifier.
+  pub content_hash: String,
+}
+
+impl PublicationKey {
+  /// Computes the key of the EPUB file at `path` whose package has the unique identifier `identifier`.
+  ///
+  /// An unzipped EPUB directory is hashed by the paths and contents of its files.
+  ///
+  /// # Errors
+  /// If the file at `path` cannot be read.
+  pub fn new(identifier: String, path: &Path) -> Result<Self> {
+    let mut hasher = Sha256::new();
+    if path.is_dir() {
+      let mut archive = Archive::load(FileZip(path.to_path_buf()))?;
+      let files = archive.file_names().map(String::from).collect::<Vec<_>>();
+      for file in files {
+        let contents = archive.read_file(&file)?;
+        hasher.update(file.as_bytes());
+        hasher.update((contents.len() as u64).to_le_bytes());
+        hasher.update(&contents);
+      }
+    } else {
+      let mut file =
+        fs::File::open(path).with_context(|| format!("Failed to open: {}", path.display()))?;
+      io::copy(&mut file, &mut hasher)?;
+    }
+    Ok(PublicationKey {
+      identifier,
+      content_hash: format!("{:x}", hasher.finalize()),
+    })
+  }
+
+  /// Computes the key of the EPUB file at `path`, identified by its first rendition.
+  ///
+  /// # Errors
+  /// If the file at `path` cannot be read.
+  pub fn for_epub(epub: &Epub, path: &Path) -> Result<Self> {
+    let identifier = epub
+      .renditions
+      .first()
+      .and_then(Rendition::unique_identifier)
+      .unwrap_or_default();
+    PublicationKey::new(identifier, path)
+  }
+
+  /// Returns an IRI for the publication, used as the `source` of stored annotation targets.
+  pub fn source(&self) -> IriRefBuf {
+    IriRefBuf::new(self.identifier.clone())
+      .ok()
+      .filter(|_| !self.identifier.is_empty())
+      .unwrap_or_else(|| {
+        IriRefBuf::new(format!("urn:sha256:{}", self.content_hash))
+          .expect("Hex digests form valid IRIs")
+      })
+  }
+
+  /// Returns a name for the publication which is safe to use in file names and URL paths.
+  pub fn slug(&self) -> String {
+    // Identifiers can contain characters which are invalid in file names, so we hash them.
+    let identifier = Sha256::digest(self.identifier.as_bytes());
+    format!("{identifier:x}-{}", self.content_hash)
+  }
+
+  fn file_name(&self) -> String {
+    format!("{}.json", self.slug())
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StoreFile {
+  version: u64,
+  identifier: String,
+  content_hash: String,
+  #[serde(flatten)]
+  document: Document,
+}
+
+impl StoreFile {
+  fn key(&self) -> PublicationKey {
+    PublicationKey {
+      identifier: self.identifier.clone(),
+      content_hash: self.content_hash.clone(),
+    }
+  }
+}
+
+/// A directory of annotation files, one per [`PublicationKey`].
+///
+/// Annotations are stored as W3C Web Annotations, along with the [`Document`] history which lets
+/// each device's copy of the store be merged with others by [`AnnotationStore::sync`].
+#[derive(Clone)]
+pub struct AnnotationStore {
+  dir: PathBuf,
+}
+
+impl AnnotationStore {
+  pub fn new(dir: PathBuf) -> Self {
+    AnnotationStore { dir }
+  }
+
+  /// Returns the keys of all publications with a store file.
+  ///
+  /// Files from before the store recorded publication keys are skipped.
+  ///
+  /// # Errors
+  /// If the store directory exists but cannot be read.
+  #[cfg_attr(not(feature = "server"), allow(dead_code))]
+  pub fn publications(&self) -> Result<Vec<PublicationKey>> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Header {
+      identifier: String,
+      content_hash: String,
+    }
+
+    let entries = match fs::read_dir(&self.dir) {
+      Ok(entries) => entries,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(err) => {
+        return Err(err).with_context(|| format!("Failed to read: {}", self.dir.display()));
+      }
+    };
+
+    let mut keys = Vec::new();
+    for entry in entries {
+      let path = entry?.path();
+      if path.extension().is_none_or(|ext| ext != "json") {
+        continue;
+      }
+      let header = fs::read_to_string(&path)
+        .ok()
+        .and_then(|contents| serde_json::from_str::<Header>(&contents).ok());
+      if let Some(Header {
+        identifier,
+        content_hash,
+      }) = header
+      {
+        keys.push(PublicationKey {
+          identifier,
+          content_hash,
+        });
+      }
+    }
+    keys.sort_by_key(PublicationKey::slug);
+    Ok(keys)
+  }
+
+  /// Returns all annotations stored for `key`.
+  ///
+  /// # Errors
+  /// If the store file exists but cannot be read, parsed or migrated.
+  pub fn list(&self, key: &PublicationKey) -> Result<Vec<RawAnnotation>> {
+    Ok(self.read(key)?.document.annotations)
+  }
+
+  /// Returns the annotations stored for `key`, anchored against `rendition`.
+  ///
+  /// Annotations which cannot be anchored are skipped with a warning.
+  ///
+  /// # Errors
+  /// If the store file exists but cannot be read, parsed or migrated.
+  pub fn processed<F: ZipFormat>(
+    &self,
+    key: &PublicationKey,
+    archive: &mut Archive<F>,
+    rendition: &Rendition,
+  ) -> Result<Vec<Annotation>> {
+    let (annotations, diagnostics) = annotation::process(self.list(key)?, archive, rendition);
+    for diagnostic in diagnostics {
+      warn!("Stored {diagnostic}");
+    }
+    Ok(annotations)
+  }
+
+  /// Adds a new annotation for `key`.
+  ///
+  /// # Errors
+  /// If an annotation with the same id already exists, or the store cannot be read or written.
+  pub fn create(&self, key: &PublicationKey, annotation: RawAnnotation) -> Result<()> {
+    let mut file = self.read(key)?;
+    file.document.insert(annotation, &self.replica()?)?;
+    self.write(key, &file)
+  }
+
+  /// Adds the `annotations` whose ids have never been stored for `key`, in a single write.
+  ///
+  /// Returns the number of annotations added.
+  ///
+  /// # Errors
+  /// If the store cannot be read or written.
+  pub fn import(&self, key: &PublicationKey, annotations: Vec<RawAnnotation>) -> Result<usize> {
+    let mut file = self.read(key)?;
+    let replica = self.replica()?;
+    let len = file.document.annotations.len();
+    for annotation in annotations {
+      if !file.document.contains(&annotation.id) {
+        file.document.insert(annotation, &replica)?;
+      }
+    }
+    let added = file.document.annotations.len() - len;
+    if added > 0 {
+      self.write(key, &file)?;
+    }
+    Ok(added)
+  }
+
+  /// Carries the annotations of the edition `from` over to the edition `to` of the same
+  /// publication, re-anchoring them against `to`'s `rendition`.
+  ///
+  /// Annotations already stored for `to`, or deleted from it, are left alone. Orphaned annotations
+  /// are carried over unchanged, so that they anchor again if their text is restored. The
+  /// annotations are then deleted from `from`, so that each is only stored once.
+  ///
+  /// # Errors
+  /// If the store cannot be read or written.
+  pub fn reanchor<F: ZipFormat>(
+    &self,
+    from: &PublicationKey,
+    to: &PublicationKey,
+    archive: &mut Archive<F>,
+    rendition: &Rendition,
+  ) -> Result<AnchorReport> {
+    let mut file = self.read(from)?;
+    let (annotations, report) = annotation::reanchor(
+      file.document.annotations.clone(),
+      archive,
+      rendition,
+      &to.source(),
+    );
+    self.import(to, annotations)?;
+
+    // Deletions leave tombstones, so that syncing does not bring the old copies back.
+    if !file.document.annotations.is_empty() {
+      let replica = self.replica()?;
+      let ids: Vec<String> = file
+        .document
+        .annotations
+        .iter()
+        .map(|a| a.id.clone())
+        .collect();
+      for id in ids {
+        file.document.remove(&id, &replica)?;
+      }
+      self.write(from, &file)?;
+    }
+    Ok(report)
+  }
+
+  /// Replaces the stored annotation with the same id as `annotation`.
+  ///
+  /// # Errors
+  /// If no annotation has the same id, or the store cannot be read or written.
+  pub fn update(&self, key: &PublicationKey, annotation: RawAnnotation) -> Result<()> {
+    let mut file = self.read(key)?;
+    file.document.update(annotation, &self.replica()?)?;
+    self.write(key, &file)
+  }
+
+  /// Removes the annotation with the given `id`.
+  ///
+  /// # Errors
+  /// If no annotation has the id, or the store cannot be read or written.
+  pub fn delete(&self, key: &PublicationKey, id: &str) -> Result<()> {
+    let mut file = self.read(key)?;
+    file.document.remove(id, &self.replica()?)?;
+    self.write(key, &file)
+  }
+
+  /// Merges the store with the copies of other devices in the shared `folder`, then writes this
+  /// device's copy there.
+  ///
+  /// Every device writes only its own files, named after its replica, so that tools which
+  /// synchronize folders never see conflicting writes. Conflicted copies made by such tools are
+  /// merged like any other file.
+  ///
+  /// Returns the number of publications whose annotations changed.
+  ///
+  /// # Errors
+  /// If the store or `folder` cannot be read or written.
+  pub fn sync(&self, folder: &Path) -> Result<usize> {
+    let replica = self.replica()?;
+    fs::create_dir_all(folder)
+      .with_context(|| format!("Failed to create: {}", folder.display()))?;
+
+    let mut remote: Vec<StoreFile> = Vec::new();
+    for entry in fs::read_dir(folder)? {
+      let path = entry?.path();
+      if path.extension().is_none_or(|ext| ext != "json") {
+        continue;
+      }
+      match read_file(&path, None) {
+        Ok(file) => remote.push(file),
+        Err(err) => warn!("Skipping {err:?}"),
+      }
+    }
+
+    let mut keys = self.publications()?;
+    for file in &remote {
+      let key = file.key();
+      if !keys.contains(&key) {
+        keys.push(key);
+      }
+    }
+
+    let mut changed = 0;
+    for key in keys {
+      let local = self.read(&key)?;
+      let document = remote
+        .iter()
+        .filter(|file| file.key() == key)
+        .fold(local.document.clone(), |document, file| {
+          document.merge(&file.document)
+        });
+      let before = serde_json::to_value(&local.document)?;
+      let merged = StoreFile { document, ..local };
+      if serde_json::to_value(&merged.document)? != before {
+        self.write(&key, &merged)?;
+        changed += 1;
+      }
+      let shared = folder.join(format!("{}.{replica}.json", key.slug()));
+      write_file(&shared, &merged)?;
+    }
+    Ok(changed)
+  }
+
+  /// Returns the id of this device's replica, creating one if needed.
+  fn replica(&self) -> Result<String> {
+    let path = self.dir.join(REPLICA_FILE);
+    match fs::read_to_string(&path) {
+      Ok(replica) if !replica.trim().is_empty() => return Ok(replica.trim().to_string()),
+      Ok(_) => {}
+      Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+      Err(err) => return Err(err).with_context(|| format!("Failed to read: {}", path.display())),
+    }
+    fs::create_dir_all(&self.dir)
+      .with_context(|| format!("Failed to create: {}", self.dir.display()))?;
+    let replica = uuid::Uuid::new_v4().simple().to_string();
+    fs::write(&path, &replica).with_context(|| format!("Failed to write: {}", path.display()))?;
+    Ok(replica)
+  }
+
+  fn path(&self, key: &PublicationKey) -> PathBuf {
+    self.dir.join(key.file_name())
+  }
+
+  fn read(&self, key: &PublicationKey) -> Result<StoreFile> {
+    let path = self.path(key);
+    let contents = match fs::read_to_string(&path) {
+      Ok(contents) => contents,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => {
+        return Ok(StoreFile {
+          version: SCHEMA_VERSION,
+          identifier: key.identifier.clone(),
+          content_hash: key.content_hash.clone(),
+          document: Document::default(),
+        });
+      }
+      Err(err) => return Err(err).with_context(|| format!("Failed to read: {}", path.display())),
+    };
+    parse_file(&contents, &path, Some(key))
+  }
+
+  fn write(&self, key: &PublicationKey, file: &StoreFile) -> Result<()> {
+    fs::create_dir_all(&self.dir)
+      .with_context(|| format!("Failed to create: {}", self.dir.display()))?;
+    write_file(&self.path(key), file)
+  }
+}
+
+/// Reads the store file at `path`, whose publication is `key` if it predates store files
+/// recording their publication.
+fn read_file(path: &Path, key: Option<&PublicationKey>) -> Result<StoreFile> {
+  let contents =
+    fs::read_to_string(path).with_context(|| format!("Failed to read: {}", path.display()))?;
+  parse_file(&contents, path, key)
+}
+
+fn parse_file(contents: &str, path: &Path, key: Option<&PublicationKey>) -> Result<StoreFile> {
+  let value = serde_json::from_str(contents)
+    .with_context(|| format!("Annotation store is not valid JSON: {}", path.display()))?;
+  let value = migrate(value, key)?;
+  serde_json::from_value(value)
+    .with_context(|| format!("Annotation store is malformed: {}", path.display()))
+}
+
+/// Writes `file` to a temporary file and then renames it over `path`,
+/// so that a crash never leaves a partially written store.
+///
+/// Files are left untouched when their contents would not change.
+fn write_file(path: &Path, file: &StoreFile) -> Result<()> {
+  let contents = serde_json::to_vec_pretty(file)?;
+  if fs::read(path).is_ok_and(|existing| existing == contents) {
+    return Ok(());
+  }
+  let dir = path
+    .parent()
+    .context("Store file has no parent directory")?;
+  let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+  temp.write_all(&contents)?;
+  temp.as_file().sync_all()?;
+  temp
+    .persist(path)
+    .with_context(|| format!("Failed to write: {}", path.display()))?;
+  Ok(())
+}
+
+/// Upgrades a store file from any earlier schema version to [`SCHEMA_VERSION`].
+fn migrate(mut value: Value, key: Option<&PublicationKey>) -> Result<Value> {
+  loop {
+    let version = match &value {
+      // Version 0 is a bare list of annotations, the same as a `ppub:annotations` file.
+      Value::Array(_) => 0,
+      Value::Object(object) => object
+        .get("version")
+        .and_then(Value::as_u64)
+        .context("Annotation store has no version")?,
+      _ => bail!("Annotation store is neither an object nor an array"),
+    };
+
+    value = match version {
+      0 => {
+        let key = key.context("Annotation store does not identify its publication")?;
+        json!({
+          "version": 1,
+          "identifier": key.identifier,
+          "contentHash": key.content_hash,
+          "annotations": value,
+        })
+      }
+      // Version 2 adds the history used for syncing. Existing annotations are stamped in order
+      // by no replica, so every device which migrates the same file agrees on the stamps.
+      1 => {
+        let mut clocks = Map::new();
+        if let Some(annotations) = value["annotations"].as_array() {
+          for (i, annotation) in annotations.iter().enumerate() {
+            let (Some(id), Some(fields)) = (annotation["id"].as_str(), annotation.as_object())
+            else {
+              continue;
+            };
+            let stamp = Stamp(i as u64 + 1, String::new());
+            let clock: Map<String, Value> = fields
+              .keys()
+              .map(|field| (field.clone(), json!(stamp)))
+              .collect();
+            clocks.insert(id.to_string(), Value::Object(clock));
+          }
+        }
+        value["version"] = json!(2);
+        value["clocks"] = Value::Object(clocks);
+        value["tombstones"] = json!({});
+        value
+      }
+      SCHEMA_VERSION => return Ok(value),
+      _ => bail!(
+        "Annotation store has version {version}, but this version of Bene only supports up to {SCHEMA_VERSION}"
+      ),
+    };
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn key() -> PublicationKey {
+    PublicationKey {
+      identifier: "urn:test:book".into(),
+      content_hash: "abc123".into(),
+    }
+  }
+
+  fn annotation(id: &str, value: &str) -> RawAnnotation {
+    RawAnnotation::parse(&format!(
+      r#"{{
+        "@context": "http://www.w3.org/ns/anno.jsonld",
+        "id": "{id}",
+        "type": "Annotation",
+        "bodyValue": "{value}",
+        "target": "urn:test:book#epubcfi(/6/2!/4/2,/1:0,/1:5)"
+      }}"#
+    ))
+    .unwrap()
+  }
+
+  fn ids(annotations: &[RawAnnotation]) -> Vec<&str> {
+    annotations.iter().map(|a| a.id.as_str()).collect()
+  }
+
+  #[test]
+  fn test_store_crud() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = AnnotationStore::new(dir.path().join("annotations"));
+    let key = key();
+    assert!(store.list(&key).unwrap().is_empty());
+
+    store.create(&key, annotation("a", "one")).unwrap();
+    store.create(&key, annotation("b", "two")).unwrap();
+    assert!(store.create(&key, annotation("a", "again")).is_err());
+    assert_eq!(ids(&store.list(&key).unwrap()), ["a", "b"]);
+
+    store.update(&key, annotation("a", "updated")).unwrap();
+    assert!(store.update(&key, annotation("c", "missing")).is_err());
+    let annotations = store.list(&key).unwrap();
+    assert_eq!(annotations[0].body_value.as_deref(), Some("updated"));
+
+    store.delete(&key, "b").unwrap();
+    assert!(store.delete(&key, "b").is_err());
+    assert_eq!(ids(&store.list(&key).unwrap()), ["a"]);
+
+    // Importing skips annotations which are already stored.
+    let imported = vec![annotation("a", "again"), annotation("c", "three")];
+    assert_eq!(store.import(&key, imported.clone()).unwrap(), 1);
+    assert_eq!(store.import(&key, imported).unwrap(), 0);
+    assert_eq!(ids(&store.list(&key).unwrap()), ["a", "c"]);
+    assert_eq!(
+      store.list(&key).unwrap()[0].body_value.as_deref(),
+      Some("updated")
+    );
+
+    // Other editions of the same publication are kept separately.
+    let other = PublicationKey {
+      content_hash: "def456".into(),
+      ..key.clone()
+    };
+    assert!(store.list(&other).unwrap().is_empty());
+
+    // Deleted annotations are not imported again.
+    assert_eq!(store.import(&key, vec![annotation("b", "two")]).unwrap(), 0);
+
+    // No temporary files are left behind.
+    let mut names: Vec<_> = fs::read_dir(&store.dir)
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name())
+      .collect();
+    names.sort();
+    assert_eq!(names, [key.file_name().as_str(), REPLICA_FILE]);
+    assert_eq!(store.publications().unwrap(), [key]);
+  }
+
+  #[test]
+  fn test_store_reanchor() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = AnnotationStore::new(dir.path().join("annotations"));
+    let (old, new) = (
+      key(),
+      PublicationKey {
+        content_hash: "def456".into(),
+        ..key()
+      },
+    );
+    store.create(&old, annotation("a", "one")).unwrap();
+
+    let path = Path::new(concat!(
+      env!("CARGO_MANIFEST_DIR"),
+      "/../../../epubs/portable-epubs"
+    ));
+    let mut archive = Archive::load(FileZip(path.to_path_buf())).unwrap();
+    let epub = Epub::load(&mut archive).unwrap();
+    let rendition = &epub.renditions[0];
+
+    // Annotations move to the new edition rather than being copied, so they are not listed twice.
+    store.reanchor(&old, &new, &mut archive, rendition).unwrap();
+    assert_eq!(ids(&store.list(&new).unwrap()), ["a"]);
+    assert!(store.list(&old).unwrap().is_empty());
+
+    store.reanchor(&old, &new, &mut archive, rendition).unwrap();
+    assert_eq!(ids(&store.list(&new).unwrap()), ["a"]);
+  }
+
+  #[test]
+  fn test_store_migration() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = AnnotationStore::new(dir.path().to_path_buf());
+    let key = key();
+
+    let v0 = serde_json::to_string(&[annotation("a", "one")]).unwrap();
+    fs::write(store.path(&key), v0).unwrap();
+    assert_eq!(ids(&store.list(&key).unwrap()), ["a"]);
+
+    store.create(&key, annotation("b", "two")).unwrap();
+    let written: Value =
+      serde_json::from_str(&fs::read_to_string(store.path(&key)).unwrap()).unwrap();
+    assert_eq!(written["version"], SCHEMA_VERSION);
+    assert_eq!(written["identifier"], "urn:test:book");
+    assert_eq!(written["clocks"]["a"]["bodyValue"], json!([1, ""]));
+    assert_eq!(written["clocks"]["b"]["id"][0], 2);
+
+    fs::write(store.path(&key), r#"{"version": 99, "annotations": []}"#).unwrap();
+    assert!(store.list(&key).is_err());
+  }
+
+  #[test]
+  fn test_store_sync() {
+    let dir = tempfile::tempdir().unwrap();
+    let laptop = AnnotationStore::new(dir.path().join("laptop"));
+    let desktop = AnnotationStore::new(dir.path().join("desktop"));
+    let shared = dir.path().join("shared");
+    let key = key();
+
+    laptop.create(&key, annotation("a", "one")).unwrap();
+    laptop.create(&key, annotation("b", "two")).unwrap();
+    assert_eq!(laptop.sync(&shared).unwrap(), 0);
+    // The desktop learns about a publication it has never opened.
+    assert_eq!(desktop.sync(&shared).unwrap(), 1);
+    assert_eq!(ids(&desktop.list(&key).unwrap()), ["a", "b"]);
+
+    // Each device edits and deletes while offline.
+    laptop.update(&key, annotation("a", "edited")).unwrap();
+    laptop.create(&key, annotation("c", "three")).unwrap();
+    desktop.delete(&key, "b").unwrap();
+    desktop.update(&key, annotation("a", "concurrent")).unwrap();
+
+    for store in [&laptop, &desktop, &laptop] {
+      store.sync(&shared).unwrap();
+    }
+    let merged = laptop.list(&key).unwrap();
+    assert_eq!(ids(&merged), ["a", "c"]);
+    assert_eq!(
+      serde_json::to_value(&merged).unwrap(),
+      serde_json::to_value(desktop.list(&key).unwrap()).unwrap()
+    );
+    assert_eq!(laptop.sync(&shared).unwrap(), 0);
+
+    // Each device writes one file per publication.
+    assert_eq!(fs::read_dir(&shared).unwrap().count(), 2);
+  }
+}
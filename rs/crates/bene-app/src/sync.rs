@@ -0,0 +1,380 @@
+//! Conflict-free merging of annotations edited on several devices.
+//!
+//! Every top-level field of an annotation carries the [`Stamp`] of its last change, and merging
+//! keeps whichever value has the later stamp. Deleting an annotation leaves a tombstone which wins
+//! over concurrent edits, so a deleted annotation is never revived. Merging is commutative,
+//! associative and idempotent, so devices which exchange documents in any order converge, e.g.
+//! through a folder shared with Syncthing or Dropbox.
+
+use std::{
+  cmp::Ordering,
+  collections::{BTreeMap, BTreeSet},
+};
+
+use anyhow::{Result, bail, ensure};
+use bene_epub::annotation::RawAnnotation;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// A Lamport timestamp: a logical time, and the replica which made a change at that time.
+///
+/// Stamps are ordered by time and then by replica, so concurrent changes are ordered the same way
+/// on every device.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Stamp(pub u64, pub String);
+
+/// The stamps of the last change to each field of an annotation.
+///
+/// The `id` field is stamped when the annotation is created, which orders annotations.
+pub type Clock = BTreeMap<String, Stamp>;
+
+/// The annotations on one publication, with the history needed to merge them with other replicas.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Document {
+  pub annotations: Vec<RawAnnotation>,
+  #[serde(default)]
+  pub clocks: BTreeMap<String, Clock>,
+  #[serde(default)]
+  pub tombstones: BTreeMap<String, Stamp>,
+}
+
+impl Document {
+  /// Returns whether an annotation with `id` exists or has been deleted.
+  pub fn contains(&self, id: &str) -> bool {
+    self.tombstones.contains_key(id) || self.annotations.iter().any(|a| a.id == id)
+  }
+
+  /// Adds a new annotation, made by `replica`.
+  ///
+  /// # Errors
+  /// If an annotation with the same id exists or has been deleted.
+  pub fn insert(&mut self, annotation: RawAnnotation, replica: &str) -> Result<()> {
+    ensure!(
+      !self.tombstones.contains_key(&annotation.id),
+      "Annotation was deleted: {}",
+      annotation.id
+    );
+    ensure!(
+      !self.contains(&annotation.id),
+      "Annotation already exists: {}",
+      annotation.id
+    );
+    let stamp = self.next(replica);
+    let clock = fields(&annotation)
+      .into_iter()
+      .map(|(field, _)| (field, stamp.clone()))
+      .collect();
+    self.clocks.insert(annotation.id.clone(), clock);
+    self.annotations.push(annotation);
+    Ok(())
+  }
+
+  /// Replaces the annotation with the same id as `annotation`, stamping the fields which changed.
+  ///
+  /// # Errors
+  /// If no annotation has the same id.
+  pub fn update(&mut self, annotation: RawAnnotation, replica: &str) -> Result<()> {
+    let stamp = self.next(replica);
+    let Some(existing) = self.annotations.iter_mut().find(|a| a.id == annotation.id) else {
+      bail!("Annotation does not exist: {}", annotation.id);
+    };
+    let (old, new) = (fields(existing), fields(&annotation));
+    let clock = self.clocks.entry(annotation.id.clone()).or_default();
+    for field in old.keys().chain(new.keys()) {
+      if old.get(field) != new.get(field) {
+        clock.insert(field.clone(), stamp.clone());
+      }
+    }
+    *existing = annotation;
+    Ok(())
+  }
+
+  /// Deletes the annotation with the given `id`, leaving a tombstone.
+  ///
+  /// # Errors
+  /// If no annotation has the id.
+  pub fn remove(&mut self, id: &str, replica: &str) -> Result<()> {
+    let len = self.annotations.len();
+    self.annotations.retain(|a| a.id != id);
+    ensure!(
+      self.annotations.len() < len,
+      "Annotation does not exist: {id}"
+    );
+    let stamp = self.next(replica);
+    self.clocks.remove(id);
+    self.tombstones.insert(id.to_string(), stamp);
+    Ok(())
+  }
+
+  /// Combines the changes in `self` and `other`.
+  #[must_use]
+  pub fn merge(&self, other: &Document) -> Document {
+    let mut tombstones = self.tombstones.clone();
+    for (id, stamp) in &other.tombstones {
+      if tombstones.get(id).is_none_or(|existing| existing < stamp) {
+        tombstones.insert(id.clone(), stamp.clone());
+      }
+    }
+
+    let live = |document: &'_ Document| -> BTreeMap<String, (RawAnnotation, Clock)> {
+      document
+        .annotations
+        .iter()
+        .filter(|a| !tombstones.contains_key(&a.id))
+        .map(|a| {
+          let clock = document.clocks.get(&a.id).cloned().unwrap_or_default();
+          (a.id.clone(), (a.clone(), clock))
+        })
+        .collect()
+    };
+    let (mut ours, mut theirs) = (live(self), live(other));
+    let ids: BTreeSet<String> = ours.keys().chain(theirs.keys()).cloned().collect();
+
+    let mut merged: Vec<(RawAnnotation, Clock)> = ids
+      .into_iter()
+      .map(|id| match (ours.remove(&id), theirs.remove(&id)) {
+        (Some(ours), Some(theirs)) => merge_annotation(ours, theirs),
+        (Some(only), None) | (None, Some(only)) => only,
+        (None, None) => unreachable!(),
+      })
+      .collect();
+    merged.sort_by(|(a, a_clock), (b, b_clock)| {
+      (a_clock.get("id"), &a.id).cmp(&(b_clock.get("id"), &b.id))
+    });
+
+    let clocks = merged
+      .iter()
+      .map(|(annotation, clock)| (annotation.id.clone(), clock.clone()))
+      .collect();
+    Document {
+      annotations: merged
+        .into_iter()
+        .map(|(annotation, _)| annotation)
+        .collect(),
+      clocks,
+      tombstones,
+    }
+  }
+
+  /// Returns a stamp later than every change in the document.
+  fn next(&self, replica: &str) -> Stamp {
+    let time = self
+      .clocks
+      .values()
+      .flat_map(BTreeMap::values)
+      .chain(self.tombstones.values())
+      .map(|Stamp(time, _)| *time)
+      .max()
+      .unwrap_or(0);
+    Stamp(time + 1, replica.to_string())
+  }
+}
+
+fn fields(annotation: &RawAnnotation) -> Map<String, Value> {
+  match serde_json::to_value(annotation) {
+    Ok(Value::Object(fields)) => fields,
+    _ => Map::new(),
+  }
+}
+
+/// Orders two versions of a field by their stamps, and then by value for stamps which are equal.
+fn compare(a: (Option<&Stamp>, Option<&Value>), b: (Option<&Stamp>, Option<&Value>)) -> Ordering {
+  a.0
+    .cmp(&b.0)
+    .then_with(|| a.1.map(Value::to_string).cmp(&b.1.map(Value::to_string)))
+}
+
+/// Merges two versions of an annotation field by field.
+fn merge_annotation(
+  (ours, our_clock): (RawAnnotation, Clock),
+  (theirs, their_clock): (RawAnnotation, Clock),
+) -> (RawAnnotation, Clock) {
+  let (our_fields, their_fields) = (fields(&ours), fields(&theirs));
+  let names: BTreeSet<&String> = our_fields
+    .keys()
+    .chain(their_fields.keys())
+    .chain(our_clock.keys())
+    .chain(their_clock.keys())
+    .collect();
+
+  let mut merged = Map::new();
+  let mut clock = Clock::new();
+  for name in names {
+    let ours = (our_clock.get(name), our_fields.get(name));
+    let theirs = (their_clock.get(name), their_fields.get(name));
+    let (stamp, value) = if compare(ours, theirs).is_ge() {
+      ours
+    } else {
+      theirs
+    };
+    if let Some(stamp) = stamp {
+      clock.insert(name.clone(), stamp.clone());
+    }
+    if let Some(value) = value {
+      merged.insert(name.clone(), value.clone());
+    }
+  }
+
+  match serde_json::from_value(Value::Object(merged)) {
+    Ok(annotation) => (annotation, clock),
+    // Combining fields from both versions produced an invalid annotation, so we keep whichever
+    // version changed last instead.
+    Err(_) => {
+      let latest = |(annotation, clock): &(RawAnnotation, Clock)| {
+        let value = Value::Object(fields(annotation)).to_string();
+        (clock.values().max().cloned(), value)
+      };
+      let ours = (ours, our_clock);
+      let theirs = (theirs, their_clock);
+      if latest(&ours) >= latest(&theirs) {
+        ours
+      } else {
+        theirs
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use proptest::{collection::vec, option, prelude::*};
+  use serde_json::json;
+
+  use super::*;
+
+  const REPLICAS: [&str; 3] = ["a", "b", "c"];
+
+  fn annotation(id: &str, body: Option<&str>, motivation: Option<&str>) -> RawAnnotation {
+    let mut value = json!({
+      "@context": "http://www.w3.org/ns/anno.jsonld",
+      "id": id,
+      "type": "Annotation",
+      "target": "urn:test:book",
+    });
+    if let Some(body) = body {
+      value["bodyValue"] = json!(body);
+    }
+    if let Some(motivation) = motivation {
+      value["motivation"] = json!(motivation);
+    }
+    serde_json::from_value(value).unwrap()
+  }
+
+  fn json(document: &Document) -> Value {
+    serde_json::to_value(document).unwrap()
+  }
+
+  #[test]
+  fn test_merge() {
+    let mut a = Document::default();
+    a.insert(annotation("x", Some("one"), None), "a").unwrap();
+    a.insert(annotation("y", Some("two"), None), "a").unwrap();
+    let mut b = a.clone();
+
+    // Concurrent edits to different fields are both kept.
+    a.update(annotation("x", Some("edited"), None), "a")
+      .unwrap();
+    b.update(annotation("x", Some("one"), Some("highlighting")), "b")
+      .unwrap();
+    // Concurrent edits to the same field are resolved the same way on both sides.
+    a.update(annotation("y", Some("from a"), None), "a")
+      .unwrap();
+    b.update(annotation("y", Some("from b"), None), "b")
+      .unwrap();
+    // Deletes win over concurrent edits.
+    b.insert(annotation("z", None, None), "b").unwrap();
+    let mut c = b.clone();
+    b.remove("z", "b").unwrap();
+    c.update(annotation("z", Some("late"), None), "c").unwrap();
+
+    let merged = a.merge(&b).merge(&c);
+    assert_eq!(json(&merged), json(&c.merge(&b).merge(&a)));
+    let ids: Vec<&str> = merged.annotations.iter().map(|a| a.id.as_str()).collect();
+    assert_eq!(ids, ["x", "y"]);
+    let x = &merged.annotations[0];
+    assert_eq!(x.body_value.as_deref(), Some("edited"));
+    assert!(x.motivation.is_some());
+    assert_eq!(merged.annotations[1].body_value.as_deref(), Some("from b"));
+    assert!(merged.tombstones.contains_key("z"));
+
+    // Deleted annotations cannot be created again.
+    let mut merged = merged;
+    assert!(merged.insert(annotation("z", None, None), "a").is_err());
+    assert!(merged.contains("z"));
+  }
+
+  #[derive(Debug, Clone)]
+  enum Op {
+    Create(usize, u8, Option<u8>),
+    Edit(usize, u8, Option<u8>, Option<u8>),
+    Delete(usize, u8),
+    Sync(usize, usize),
+  }
+
+  fn op() -> impl Strategy<Value = Op> {
+    let replica = 0..REPLICAS.len();
+    let id = 0..4u8;
+    let value = option::of(0..3u8);
+    prop_oneof![
+      (replica.clone(), id.clone(), value.clone()).prop_map(|(r, id, v)| Op::Create(r, id, v)),
+      (replica.clone(), id.clone(), value.clone(), value)
+        .prop_map(|(r, id, v, m)| Op::Edit(r, id, v, m)),
+      (replica.clone(), id).prop_map(|(r, id)| Op::Delete(r, id)),
+      (replica.clone(), replica).prop_map(|(from, to)| Op::Sync(from, to)),
+    ]
+  }
+
+  /// Applies `ops` to one document per replica, ignoring operations which fail.
+  fn replicas(ops: &[Op]) -> Vec<Document> {
+    let mut documents = vec![Document::default(); REPLICAS.len()];
+    let text = |value: Option<u8>| value.map(|value| format!("v{value}"));
+    for op in ops {
+      let _ = match *op {
+        Op::Create(r, id, body) => documents[r].insert(
+          annotation(&format!("id{id}"), text(body).as_deref(), None),
+          REPLICAS[r],
+        ),
+        Op::Edit(r, id, body, motivation) => documents[r].update(
+          annotation(
+            &format!("id{id}"),
+            text(body).as_deref(),
+            text(motivation).as_deref(),
+          ),
+          REPLICAS[r],
+        ),
+        Op::Delete(r, id) => documents[r].remove(&format!("id{id}"), REPLICAS[r]),
+        Op::Sync(from, to) => {
+          documents[to] = documents[to].merge(&documents[from]);
+          Ok(())
+        }
+      };
+    }
+    documents
+  }
+
+  proptest! {
+    #[test]
+    fn merge_is_commutative(ops in vec(op(), 0 .. 40)) {
+      let documents = replicas(&ops);
+      let (a, b) = (&documents[0], &documents[1]);
+      prop_assert_eq!(json(&a.merge(b)), json(&b.merge(a)));
+    }
+
+    #[test]
+    fn merge_is_idempotent(ops in vec(op(), 0 .. 40)) {
+      let documents = replicas(&ops);
+      let (a, b) = (&documents[0], &documents[1]);
+      prop_assert_eq!(json(&a.merge(a)), json(a));
+      let merged = a.merge(b);
+      prop_assert_eq!(json(&merged.merge(b)), json(&merged));
+      prop_assert_eq!(json(&merged.merge(&merged)), json(&merged));
+    }
+
+    #[test]
+    fn merge_is_associative(ops in vec(op(), 0 .. 40)) {
+      let documents = replicas(&ops);
+      let (a, b, c) = (&documents[0], &documents[1], &documents[2]);
+      prop_assert_eq!(json(&a.merge(b).merge(c)), json(&a.merge(&b.merge(c))));
+    }
+  }
+}
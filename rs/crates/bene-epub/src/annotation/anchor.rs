@@ -0,0 +1,783 @@
+//! Anchoring of W3C selectors which describe content rather than EPUB CFIs.
+
+use std::{collections::HashMap, ops::Range, rc::Rc};
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use iref::IriRefBuf;
+
+use super::{EPUB_CFI_SPEC, css, raw, xpath};
+use crate::{
+  Archive, Rendition, ZipFormat, cfi,
+  dom::{Bias, Document, ElementInfo, Location, TextIndex},
+};
+
+/// The number of characters of context on either side of a [`quote`].
+const QUOTE_CONTEXT: usize = 32;
+
+/// Finds the byte range of a quote in the text of a document.
+type QuoteSearch = fn(&str, &raw::TextQuoteSelector) -> Option<Range<usize>>;
+
+/// A content document in the spine, parsed and indexed for anchoring.
+pub(crate) struct SpineDocument {
+  pub spine_index: usize,
+  pub document: Document,
+  pub text: TextIndex,
+}
+
+/// Resolves selectors against the content documents of a [`Rendition`].
+pub(crate) struct Anchorer<'a, F: ZipFormat> {
+  archive: &'a mut Archive<F>,
+  rendition: &'a Rendition,
+  /// The CFI step from the package element to its spine, see [`Rendition::spine_step`].
+  spine_step: u32,
+  documents: HashMap<usize, Rc<SpineDocument>>,
+}
+
+impl<'a, F: ZipFormat> Anchorer<'a, F> {
+  pub fn new(archive: &'a mut Archive<F>, rendition: &'a Rendition) -> Self {
+    Anchorer {
+      archive,
+      rendition,
+      spine_step: rendition.spine_step(),
+      documents: HashMap::new(),
+    }
+  }
+
+  /// Returns the spine indices of the documents that `source` could refer to.
+  ///
+  /// A `source` naming a manifest item in the spine refers to just that document. Any other
+  /// source, such as `.` for the whole publication, could refer to any document in the spine.
+  pub fn candidates(&self, source: &str) -> Vec<usize> {
+    let href = source.split('#').next().unwrap_or_default();
+    let href = href.strip_prefix("./").unwrap_or(href);
+    let spine = &self.rendition.package.spine.itemref;
+    let matching = spine.iter().position(|itemref| {
+      self
+        .rendition
+        .item(&itemref.idref)
+        .is_some_and(|item| item.href == href || self.rendition.file_path(&item.href) == href)
+    });
+    match matching {
+      Some(index) => vec![index],
+      None => (0..spine.len()).collect(),
+    }
+  }
+
+  /// Loads and indexes the content document at `spine_index`.
+  pub fn document(&mut self, spine_index: usize) -> Result<Rc<SpineDocument>> {
+    if let Some(document) = self.documents.get(&spine_index) {
+      return Ok(Rc::clone(document));
+    }
+
+    let itemref = &self.rendition.package.spine.itemref[spine_index];
+    let item = self
+      .rendition
+      .item(&itemref.idref)
+      .with_context(|| format!("Spine references missing manifest item: {}", itemref.idref))?;
+    let path = self.rendition.file_path(&item.href);
+    let bytes = self.archive.read_file(&path)?;
+    let xml = String::from_utf8(bytes).with_context(|| format!("File is not UTF-8: {path}"))?;
+    let document = Document::parse(&xml).with_context(|| format!("Failed to parse {path}"))?;
+    let text = document.text_index();
+    let document = Rc::new(SpineDocument {
+      spine_index,
+      document,
+      text,
+    });
+    self.documents.insert(spine_index, Rc::clone(&document));
+    Ok(document)
+  }
+
+  /// Anchors a selector in the document(s) identified by `source`.
+  ///
+  /// A `position` selector accompanying the selector is used as a hint for text quotes.
+  pub fn anchor(
+    &mut self,
+    source: &str,
+    selector: &raw::Selector,
+    position: Option<&raw::TextPositionSelector>,
+  ) -> Result<cfi::Fragment> {
+    let candidates = self.candidates(source);
+    // Positions only make sense relative to a single document.
+    ensure!(
+      candidates.len() == 1 || !uses_position(selector),
+      "Text position selector does not identify a single document: {source}"
+    );
+    let hint = position
+      .filter(|_| candidates.len() == 1)
+      .map(|position| position.start..position.end);
+
+    let mut error = None;
+    for spine_index in candidates {
+      // A document which cannot be read does not stop the search through the rest of the spine.
+      let document = match self.document(spine_index) {
+        Ok(document) => document,
+        Err(err) => {
+          error = Some(err);
+          continue;
+        }
+      };
+      match select(&document, selector, hint.clone()) {
+        Ok(range) => {
+          ensure!(!range.is_empty(), "Selected text is empty");
+          return self.fragment(&document, range);
+        }
+        Err(err) => error = Some(err),
+      }
+    }
+    Err(error.unwrap_or_else(|| anyhow!("Publication has no documents in its spine")))
+  }
+
+  /// Resolves a CFI into the content document it points into and a byte range of its text.
+  pub fn resolve(&mut self, fragment: &cfi::Fragment) -> Result<(Rc<SpineDocument>, Range<usize>)> {
+    let spine_index = spine_index(fragment)?;
+    ensure!(
+      spine_index < self.rendition.package.spine.itemref.len(),
+      "EPUB CFI points outside the spine: {fragment}"
+    );
+    let document = self.document(spine_index)?;
+    let range = cfi_text_range(&document, fragment)?;
+    Ok((document, range))
+  }
+
+  /// Anchors a target which selects `fragment` and quotes `quote`, re-anchoring it if `fragment`
+  /// no longer selects the quoted text, e.g. because the publication was edited.
+  ///
+  /// The quote is looked for in the document `fragment` points into and then in the rest of the
+  /// spine. If the quoted text itself was edited, the text between its prefix and suffix is used.
+  ///
+  /// Returns the CFI and quote of the selected text, and whether it moved.
+  pub fn reanchor(
+    &mut self,
+    fragment: &cfi::Fragment,
+    quote: &raw::TextQuoteSelector,
+  ) -> Result<(cfi::Fragment, raw::TextQuoteSelector, bool)> {
+    let mut first = spine_index(fragment).ok();
+    if let Ok((document, range)) = self.resolve(fragment) {
+      if Normalized::new(&document.text.text[range.clone()]).text
+        == Normalized::new(&quote.exact).text
+      {
+        return Ok((fragment.clone(), quote.clone(), false));
+      }
+      first = Some(document.spine_index);
+    }
+
+    let spine_len = self.rendition.package.spine.itemref.len();
+    let order = first
+      .filter(|index| *index < spine_len)
+      .into_iter()
+      .chain((0..spine_len).filter(|index| Some(*index) != first))
+      .collect::<Vec<_>>();
+    let strategies: [QuoteSearch; 2] = [|text, quote| find_quote(text, quote, None), find_context];
+    for strategy in strategies {
+      for &spine_index in &order {
+        let Ok(document) = self.document(spine_index) else {
+          continue;
+        };
+        if let Some(range) = strategy(&document.text.text, quote).filter(|range| !range.is_empty())
+        {
+          let fragment = self.fragment(&document, range.clone())?;
+          return Ok((fragment, self::quote(&document, range), true));
+        }
+      }
+    }
+    bail!(
+      "Quoted text was not found in the publication: {:?}",
+      quote.exact
+    )
+  }
+
+  /// Converts a byte range in the text content of `document` into a CFI range.
+  pub fn fragment(&self, document: &SpineDocument, range: Range<usize>) -> Result<cfi::Fragment> {
+    let text = &document.text;
+    let error = || anyhow!("Text range {range:?} is not within a text node");
+    let start = text.location(range.start, Bias::Start).ok_or_else(error)?;
+    let end = text.location(range.end, Bias::End).ok_or_else(error)?;
+    Ok(self.range_fragment(document.spine_index, start, end))
+  }
+
+  /// Builds a CFI range between two locations in the document at `spine_index`.
+  pub fn range_fragment(
+    &self,
+    spine_index: usize,
+    start: Location,
+    end: Location,
+  ) -> cfi::Fragment {
+    // The parent path holds the element steps shared by both ends of the range.
+    let common = start
+      .steps
+      .iter()
+      .zip(&end.steps)
+      .take(start.steps.len().min(end.steps.len()).saturating_sub(1))
+      .take_while(|(a, b)| a == b)
+      .count();
+
+    let mut components = self.spine_components(spine_index);
+    components.extend(
+      start.steps[..common]
+        .iter()
+        .map(|step| cfi::PathComponent::Step(*step)),
+    );
+    let relative = |location: Location| cfi::Path {
+      components: location.steps[common..]
+        .iter()
+        .map(|step| cfi::PathComponent::Step(*step))
+        .collect(),
+      offset: location.offset.map(cfi::Offset::Character),
+    };
+    cfi::Fragment {
+      path: cfi::Path {
+        components,
+        offset: None,
+      },
+      range: Some(cfi::Range {
+        from: relative(start),
+        to: relative(end),
+      }),
+    }
+  }
+
+  /// Replaces the steps of `fragment` into its content document with the steps into the document
+  /// at `spine_index`, as importers build CFIs without knowing where the package puts its spine.
+  pub fn with_spine_path(&self, fragment: &cfi::Fragment, spine_index: usize) -> cfi::Fragment {
+    let mut fragment = fragment.clone();
+    let components = &mut fragment.path.components;
+    if let Some(indirection) = components
+      .iter()
+      .position(|component| matches!(component, cfi::PathComponent::Indirection))
+    {
+      components.splice(..=indirection, self.spine_components(spine_index));
+    }
+    fragment
+  }
+
+  /// Returns the CFI components which step from the package document into the spine item at `spine_index`.
+  fn spine_components(&self, spine_index: usize) -> Vec<cfi::PathComponent> {
+    let mut components = vec![
+      cfi::PathComponent::Step(self.spine_step),
+      cfi::PathComponent::Step(2 * (u32::try_from(spine_index).unwrap_or(u32::MAX / 2) + 1)),
+    ];
+    if let Some(id) = &self.rendition.package.spine.itemref[spine_index].id {
+      components.push(cfi::PathComponent::Assertion(cfi::Assertion::Id(
+        id.clone(),
+      )));
+    }
+    components.push(cfi::PathComponent::Indirection);
+    components
+  }
+}
+
+/// Returns whether anchoring `selector` depends on character positions.
+fn uses_position(selector: &raw::Selector) -> bool {
+  match selector {
+    raw::Selector::TaggedSelector(raw::TaggedSelector::TextPositionSelector(_)) => true,
+    raw::Selector::TaggedSelector(raw::TaggedSelector::RangeSelector(range)) => {
+      uses_position(&range.start_selector) || uses_position(&range.end_selector)
+    }
+    _ => false,
+  }
+}
+
+/// Returns the byte range of the text content of `document` selected by `selector`.
+fn select(
+  document: &SpineDocument,
+  selector: &raw::Selector,
+  hint: Option<Range<u64>>,
+) -> Result<Range<usize>> {
+  let text = &document.text;
+  let raw::Selector::TaggedSelector(selector) = selector else {
+    bail!("Cannot dereference selector IRI: {selector:?}");
+  };
+  Ok(match selector {
+    raw::TaggedSelector::TextQuoteSelector(quote) => {
+      ensure!(!quote.exact.is_empty(), "Text quote is empty");
+      find_quote(&text.text, quote, hint)
+        .with_context(|| format!("Could not find quote in document: {:?}", quote.exact))?
+    }
+
+    raw::TaggedSelector::TextPositionSelector(position) => {
+      char_range(&text.text, position.start..position.end)
+        .context("Text position selector is out of bounds")?
+    }
+
+    raw::TaggedSelector::CssSelector(css) => {
+      let elements = document.document.elements();
+      let index = css::select(&elements, &css.value)?
+        .with_context(|| format!("CSS selector matched no elements: {}", css.value))?;
+      refine(
+        text,
+        element_text(text, &elements[index])?,
+        css.refined_by.as_deref(),
+      )?
+    }
+
+    raw::TaggedSelector::XPathSelector(xpath) => {
+      let elements = document.document.elements();
+      let index = xpath::select(&elements, &xpath.value)?
+        .with_context(|| format!("XPath selector matched no elements: {}", xpath.value))?;
+      refine(
+        text,
+        element_text(text, &elements[index])?,
+        xpath.refined_by.as_deref(),
+      )?
+    }
+
+    // The start selector gives the inclusive start of the range,
+    // and the start of the end selector gives its exclusive end.
+    raw::TaggedSelector::RangeSelector(range) => {
+      let start = select(document, &range.start_selector, None)
+        .context("Failed to anchor start of range")?
+        .start;
+      let end = select(document, &range.end_selector, None)
+        .context("Failed to anchor end of range")?
+        .start;
+      ensure!(start <= end, "Range selector ends before it starts");
+      start..end
+    }
+
+    raw::TaggedSelector::FragmentSelector(fragment) => {
+      ensure!(
+        fragment.conforms_to.as_ref().map(IriRefBuf::as_str) == Some(EPUB_CFI_SPEC),
+        "Cannot handle non-EPUB fragment selector"
+      );
+      let fragment = cfi::Fragment::parse(&fragment.value).context("Failed to parse EPUB CFI")?;
+      cfi_text_range(document, &fragment)?
+    }
+
+    selector @ (raw::TaggedSelector::DataPositionSelector(_)
+    | raw::TaggedSelector::SvgSelector(_)) => {
+      bail!("Cannot anchor selector to text: {selector:?}")
+    }
+  })
+}
+
+fn element_text(text: &TextIndex, element: &ElementInfo) -> Result<Range<usize>> {
+  text.element_range(&element.steps).with_context(|| {
+    format!(
+      "Selected <{}> element contains no text",
+      element.element.name
+    )
+  })
+}
+
+/// Narrows the text `range` of an element by a selector refining the one that selected it.
+///
+/// Text positions and quotes are evaluated relative to the element's text, as in the
+/// `RangeSelector`s produced by Hypothesis.
+fn refine(
+  text: &TextIndex,
+  range: Range<usize>,
+  refined_by: Option<&raw::Selector>,
+) -> Result<Range<usize>> {
+  let Some(selector) = refined_by else {
+    return Ok(range);
+  };
+  let scope = &text.text[range.clone()];
+  let inner = match selector {
+    raw::Selector::TaggedSelector(raw::TaggedSelector::TextPositionSelector(position)) => {
+      char_range(scope, position.start..position.end)
+        .context("Refining text position selector is out of bounds")?
+    }
+    raw::Selector::TaggedSelector(raw::TaggedSelector::TextQuoteSelector(quote)) => {
+      find_quote(scope, quote, None)
+        .with_context(|| format!("Could not find quote in element: {:?}", quote.exact))?
+    }
+    selector => bail!("Cannot refine an element selector by: {selector:?}"),
+  };
+  Ok(range.start + inner.start..range.start + inner.end)
+}
+
+/// Describes the text at `range` in `document` with some of its context.
+pub(crate) fn quote(document: &SpineDocument, range: Range<usize>) -> raw::TextQuoteSelector {
+  let text = &document.text.text;
+  let before = &text[..range.start];
+  let after = &text[range.end..];
+  let prefix_start = before
+    .char_indices()
+    .rev()
+    .nth(QUOTE_CONTEXT - 1)
+    .map_or(0, |(i, _)| i);
+  let suffix_end = after
+    .char_indices()
+    .nth(QUOTE_CONTEXT)
+    .map_or(after.len(), |(i, _)| i);
+  let non_empty = |s: &str| Some(s.to_string()).filter(|s| !s.is_empty());
+  raw::TextQuoteSelector {
+    exact: text[range].to_string(),
+    prefix: non_empty(&before[prefix_start..]),
+    suffix: non_empty(&after[..suffix_end]),
+  }
+}
+
+/// Returns the index in the spine of the content document that a CFI points into.
+fn spine_index(fragment: &cfi::Fragment) -> Result<usize> {
+  let components = &fragment.path.components;
+  let indirection = components
+    .iter()
+    .position(|component| *component == cfi::PathComponent::Indirection)
+    .context("EPUB CFI does not point into a content document")?;
+  let step = components[..indirection]
+    .iter()
+    .rev()
+    .find_map(|component| match component {
+      cfi::PathComponent::Step(step) => Some(*step),
+      _ => None,
+    })
+    .context("EPUB CFI does not step into the spine")?;
+  ensure!(
+    step >= 2 && step % 2 == 0,
+    "EPUB CFI does not point to a spine item"
+  );
+  Ok(usize::try_from(step / 2 - 1)?)
+}
+
+/// Resolves a CFI into a byte range of the text content of `document`.
+fn cfi_text_range(document: &SpineDocument, fragment: &cfi::Fragment) -> Result<Range<usize>> {
+  let components = &fragment.path.components;
+  let indirection = components
+    .iter()
+    .position(|component| *component == cfi::PathComponent::Indirection)
+    .context("EPUB CFI does not point into a content document")?;
+  ensure!(
+    spine_index(fragment)? == document.spine_index,
+    "EPUB CFI points into a different document"
+  );
+
+  let steps = |path: &cfi::Path| -> Result<Vec<u32>> {
+    path
+      .components
+      .iter()
+      .filter_map(|component| match component {
+        cfi::PathComponent::Step(step) => Some(Ok(*step)),
+        cfi::PathComponent::Assertion(_) => None,
+        cfi::PathComponent::Indirection => {
+          Some(Err(anyhow!("Nested indirections are not supported")))
+        }
+      })
+      .collect()
+  };
+  let offset = |path: &cfi::Path| {
+    path.offset.as_ref().map(|offset| match offset {
+      cfi::Offset::Character(n) => *n,
+    })
+  };
+  let resolve = |location: Location| {
+    document
+      .text
+      .position(&location)
+      .with_context(|| format!("EPUB CFI does not resolve to text: {fragment}"))
+  };
+
+  let parent = cfi::Path {
+    components: components[indirection + 1..].to_vec(),
+    offset: fragment.path.offset.clone(),
+  };
+  let parent_steps = steps(&parent)?;
+  match &fragment.range {
+    None => {
+      let position = resolve(Location {
+        steps: parent_steps,
+        offset: offset(&parent),
+      })?;
+      Ok(position..position)
+    }
+    Some(range) => {
+      let location = |path: &cfi::Path| -> Result<Location> {
+        Ok(Location {
+          steps: [parent_steps.clone(), steps(path)?].concat(),
+          offset: offset(path),
+        })
+      };
+      Ok(resolve(location(&range.from)?)?..resolve(location(&range.to)?)?)
+    }
+  }
+}
+
+/// Converts a range of character (Unicode scalar) positions into a byte range of `text`.
+fn char_range(text: &str, range: Range<u64>) -> Option<Range<usize>> {
+  let byte_pos = |pos: u64| {
+    let pos = usize::try_from(pos).ok()?;
+    text
+      .char_indices()
+      .map(|(i, _)| i)
+      .chain([text.len()])
+      .nth(pos)
+  };
+  Some(byte_pos(range.start)?..byte_pos(range.end)?)
+}
+
+/// Finds the byte range of the best match for `quote` in `text`.
+///
+/// If `hint` (in characters) already selects the quote, it is used directly. Otherwise every
+/// occurrence of the quote is scored by how much of its prefix and suffix match the surrounding
+/// text, with ties broken by proximity to `hint`. If the quote does not occur verbatim, the
+/// search is repeated with whitespace collapsed on both sides.
+pub(crate) fn find_quote(
+  text: &str,
+  quote: &raw::TextQuoteSelector,
+  hint: Option<Range<u64>>,
+) -> Option<Range<usize>> {
+  if let Some(range) = hint.clone().and_then(|hint| char_range(text, hint))
+    && text.get(range.clone()) == Some(quote.exact.as_str())
+  {
+    return Some(range);
+  }
+
+  let prefix = quote.prefix.as_deref().unwrap_or_default();
+  let suffix = quote.suffix.as_deref().unwrap_or_default();
+  let hint_start = hint.map(|hint| hint.start);
+  if let Some(range) = best_match(text, &quote.exact, prefix, suffix, hint_start) {
+    return Some(range);
+  }
+
+  let normalized = Normalized::new(text);
+  let range = best_match(
+    &normalized.text,
+    &Normalized::new(&quote.exact).text,
+    &Normalized::new(prefix).text,
+    &Normalized::new(suffix).text,
+    hint_start,
+  )?;
+  Some(normalized.original(range))
+}
+
+fn best_match(
+  text: &str,
+  exact: &str,
+  prefix: &str,
+  suffix: &str,
+  hint: Option<u64>,
+) -> Option<Range<usize>> {
+  if exact.is_empty() {
+    return None;
+  }
+
+  let mut best: Option<(usize, u64, Range<usize>)> = None;
+  let mut search_from = 0;
+  while let Some(offset) = text[search_from..].find(exact) {
+    let start = search_from + offset;
+    let end = start + exact.len();
+
+    let prefix_score = text[..start]
+      .chars()
+      .rev()
+      .zip(prefix.chars().rev())
+      .take_while(|(a, b)| a == b)
+      .count();
+    let suffix_score = text[end..]
+      .chars()
+      .zip(suffix.chars())
+      .take_while(|(a, b)| a == b)
+      .count();
+    let score = prefix_score + suffix_score;
+    let distance = hint.map_or(0, |hint| {
+      (text[..start].chars().count() as u64).abs_diff(hint)
+    });
+
+    let is_better = match &best {
+      None => true,
+      Some((best_score, best_distance, _)) => {
+        score > *best_score || (score == *best_score && distance < *best_distance)
+      }
+    };
+    if is_better {
+      best = Some((score, distance, start..end));
+    }
+
+    search_from = start + text[start..].chars().next().map_or(1, char::len_utf8);
+  }
+
+  best.map(|(_, _, range)| range)
+}
+
+/// Finds the text between the prefix and suffix of `quote`, for quotes whose text was edited.
+///
+/// If the text around the quote was edited too, the context on one side is shortened while the
+/// other side is matched in full. The text must be at most about twice as long as the quote, and
+/// among several candidates the one closest in length to the quote wins.
+fn find_context(text: &str, quote: &raw::TextQuoteSelector) -> Option<Range<usize>> {
+  let normalize = |s: Option<&str>| Normalized::new(s.unwrap_or_default()).text;
+  let prefix = normalize(quote.prefix.as_deref());
+  let suffix = normalize(quote.suffix.as_deref());
+  if prefix.trim().is_empty() || suffix.trim().is_empty() {
+    return None;
+  }
+  let tail = |s: &str, n: usize| {
+    s.chars()
+      .rev()
+      .take(n)
+      .collect::<Vec<_>>()
+      .into_iter()
+      .rev()
+      .collect::<String>()
+  };
+  let head = |s: &str, n: usize| s.chars().take(n).collect::<String>();
+  let shortened = [16, 8, 4, 2, 1];
+  let contexts = std::iter::once((prefix.clone(), suffix.clone()))
+    .chain(
+      shortened
+        .iter()
+        .map(|n| (prefix.clone(), head(&suffix, *n))),
+    )
+    .chain(
+      shortened
+        .iter()
+        .map(|n| (tail(&prefix, *n), suffix.clone())),
+    );
+
+  let normalized = Normalized::new(text);
+  let exact_len = Normalized::new(&quote.exact).text.len();
+  for (prefix, suffix) in contexts {
+    if prefix.trim().is_empty() || suffix.trim().is_empty() {
+      continue;
+    }
+    if let Some(range) = between(&normalized.text, &prefix, &suffix, exact_len) {
+      return Some(normalized.original(range));
+    }
+  }
+  None
+}
+
+/// Finds the text between `prefix` and `suffix` which is closest in length to `exact_len`.
+fn between(text: &str, prefix: &str, suffix: &str, exact_len: usize) -> Option<Range<usize>> {
+  let max_len = 2 * exact_len + QUOTE_CONTEXT;
+  let mut best: Option<(usize, Range<usize>)> = None;
+  for (start, _) in text.match_indices(prefix) {
+    let start = start + prefix.len();
+    let Some(len) = text[start..].find(suffix).filter(|len| *len <= max_len) else {
+      continue;
+    };
+    let distance = len.abs_diff(exact_len);
+    if len > 0 && best.as_ref().is_none_or(|(best, _)| distance < *best) {
+      best = Some((distance, start..start + len));
+    }
+  }
+  best.map(|(_, range)| range)
+}
+
+/// A string with runs of whitespace collapsed to a single space, remembering where each byte came from.
+struct Normalized {
+  text: String,
+  /// For each byte of `text`, the byte range of the original string it was derived from.
+  spans: Vec<Range<usize>>,
+}
+
+impl Normalized {
+  fn new(original: &str) -> Self {
+    let mut text = String::new();
+    let mut spans: Vec<Range<usize>> = Vec::new();
+    for (i, c) in original.char_indices() {
+      let end = i + c.len_utf8();
+      if c.is_whitespace() {
+        if text.ends_with(' ') {
+          spans.last_mut().unwrap().end = end;
+          continue;
+        }
+        text.push(' ');
+        spans.push(i..end);
+      } else {
+        text.push(c);
+        spans.extend(std::iter::repeat_n(i..end, c.len_utf8()));
+      }
+    }
+    Normalized { text, spans }
+  }
+
+  fn original(&self, range: Range<usize>) -> Range<usize> {
+    self.spans[range.start].start..self.spans[range.end - 1].end
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{Epub, test_utils};
+
+  fn quote(exact: &str, prefix: Option<&str>, suffix: Option<&str>) -> raw::TextQuoteSelector {
+    raw::TextQuoteSelector {
+      exact: exact.into(),
+      prefix: prefix.map(Into::into),
+      suffix: suffix.map(Into::into),
+    }
+  }
+
+  #[test]
+  fn test_find_quote() {
+    let text = "the cat sat on the mat with the cat";
+    assert_eq!(
+      find_quote(text, &quote("cat", None, None), None),
+      Some(4..7)
+    );
+    assert_eq!(
+      find_quote(text, &quote("cat", Some("with the "), None), None),
+      Some(32..35)
+    );
+    assert_eq!(
+      find_quote(text, &quote("cat", None, Some(" sat")), None),
+      Some(4..7)
+    );
+    assert_eq!(
+      find_quote(text, &quote("cat", None, None), Some(30..33)),
+      Some(32..35)
+    );
+    assert_eq!(
+      find_quote(text, &quote("the", None, None), Some(15..18)),
+      Some(15..18)
+    );
+    assert_eq!(find_quote(text, &quote("dog", None, None), None), None);
+  }
+
+  #[test]
+  fn test_anchor_skips_unreadable_documents() {
+    let package = test_utils::PACKAGE
+      .replace(
+        r#"<item id="chapter""#,
+        r#"<item id="broken" href="broken.xhtml" media-type="application/xhtml+xml" />
+    <item id="chapter""#,
+      )
+      .replace(
+        r#"<itemref idref="chapter" />"#,
+        r#"<itemref idref="broken" /><itemref idref="chapter" />"#,
+      );
+    let mut archive = test_utils::archive(&[
+      ("EPUB/package.opf", &package),
+      ("EPUB/broken.xhtml", "<html><body><p>Unclosed</body></html>"),
+    ]);
+    let epub = Epub::load(&mut archive).unwrap();
+    let mut anchorer = Anchorer::new(&mut archive, &epub.renditions[0]);
+    let selector = raw::Selector::TaggedSelector(raw::TaggedSelector::TextQuoteSelector(quote(
+      "emphatic", None, None,
+    )));
+    let fragment = anchorer.anchor(".", &selector, None).unwrap();
+    assert_eq!(fragment.to_string(), "epubcfi(/6/4!/4/4/2,/1:0,/1:8)");
+  }
+
+  #[test]
+  fn test_anchor_spine_step() {
+    // The guide belongs after the spine, but is sometimes put before it.
+    let package = test_utils::PACKAGE.replace(
+      "<spine>",
+      r#"<guide><reference type="text" href="chapter.xhtml" /></guide>
+  <spine>"#,
+    );
+    let mut archive = test_utils::archive(&[("EPUB/package.opf", &package)]);
+    let epub = Epub::load(&mut archive).unwrap();
+    let mut anchorer = Anchorer::new(&mut archive, &epub.renditions[0]);
+    let selector = raw::Selector::TaggedSelector(raw::TaggedSelector::TextQuoteSelector(quote(
+      "emphatic", None, None,
+    )));
+    let fragment = anchorer.anchor(".", &selector, None).unwrap();
+    assert_eq!(fragment.to_string(), "epubcfi(/8/2!/4/4/2,/1:0,/1:8)");
+    let (document, range) = anchorer.resolve(&fragment).unwrap();
+    assert_eq!(&document.text.text[range], "emphatic");
+  }
+
+  #[test]
+  fn test_find_quote_whitespace() {
+    let text = "Hello\n      world, again";
+    assert_eq!(
+      find_quote(text, &quote("Hello world", None, None), None),
+      Some(0..17)
+    );
+  }
+}
@@ -0,0 +1,516 @@
+//! Evaluation of a practical subset of CSS selectors, for [`raw::CssSelector`](super::raw::CssSelector).
+//!
+//! Supports type, universal, ID, class and attribute selectors, the structural pseudo-classes
+//! (`:first-child`, `:nth-of-type(2n+1)`, etc.), and all four combinators.
+
+use anyhow::{Context, Result, bail};
+
+use crate::dom::ElementInfo;
+
+/// Returns the index of the first element in `elements` (in document order) matched by `selector`.
+///
+/// # Errors
+/// If `selector` is not in the supported subset of CSS.
+pub(crate) fn select(elements: &[ElementInfo], selector: &str) -> Result<Option<usize>> {
+  let selectors = Parser::new(selector).selector_list()?;
+  Ok((0..elements.len()).find(|&index| {
+    selectors
+      .iter()
+      .any(|complex| matches_complex(elements, index, complex))
+  }))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Combinator {
+  Descendant,
+  Child,
+  NextSibling,
+  SubsequentSibling,
+}
+
+#[derive(Debug, Default)]
+struct Compound {
+  name: Option<String>,
+  conditions: Vec<Condition>,
+}
+
+#[derive(Debug)]
+enum Condition {
+  Id(String),
+  Class(String),
+  Attribute {
+    name: String,
+    op: Option<(AttributeOp, String)>,
+  },
+  Root,
+  NthChild {
+    nth: Nth,
+    of_type: bool,
+    from_end: bool,
+  },
+  OnlyChild {
+    of_type: bool,
+  },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum AttributeOp {
+  Equals,
+  Includes,
+  DashMatch,
+  Prefix,
+  Suffix,
+  Substring,
+}
+
+/// An `an+b` expression from e.g. `:nth-child(2n+1)`.
+#[derive(Debug, Clone, Copy)]
+struct Nth {
+  a: i64,
+  b: i64,
+}
+
+impl Nth {
+  fn matches(self, position: i64) -> bool {
+    if self.a == 0 {
+      position == self.b
+    } else {
+      let n = position - self.b;
+      n % self.a == 0 && n / self.a >= 0
+    }
+  }
+}
+
+/// A complex selector, stored as compounds in source order, each but the first preceded by a combinator.
+type Complex = Vec<(Option<Combinator>, Compound)>;
+
+fn matches_complex(
+  elements: &[ElementInfo],
+  index: usize,
+  complex: &[(Option<Combinator>, Compound)],
+) -> bool {
+  let Some(((combinator, compound), rest)) = complex.split_last() else {
+    return true;
+  };
+  if !matches_compound(elements, index, compound) {
+    return false;
+  }
+
+  let info = &elements[index];
+  match combinator {
+    None => true,
+    Some(Combinator::Child) => info
+      .parent
+      .is_some_and(|parent| matches_complex(elements, parent, rest)),
+    Some(Combinator::Descendant) => {
+      let mut ancestor = info.parent;
+      while let Some(parent) = ancestor {
+        if matches_complex(elements, parent, rest) {
+          return true;
+        }
+        ancestor = elements[parent].parent;
+      }
+      false
+    }
+    Some(Combinator::NextSibling) => previous_siblings(elements, index)
+      .next()
+      .is_some_and(|sibling| matches_complex(elements, sibling, rest)),
+    Some(Combinator::SubsequentSibling) => {
+      previous_siblings(elements, index).any(|sibling| matches_complex(elements, sibling, rest))
+    }
+  }
+}
+
+/// Returns the element siblings preceding `index`, nearest first.
+fn previous_siblings(elements: &[ElementInfo], index: usize) -> impl Iterator<Item = usize> {
+  let info = &elements[index];
+  let siblings = match info.parent {
+    Some(parent) => &elements[parent].children[..info.sibling_index],
+    None => &[],
+  };
+  siblings.iter().rev().copied()
+}
+
+fn matches_compound(elements: &[ElementInfo], index: usize, compound: &Compound) -> bool {
+  let info = &elements[index];
+  let element = info.element;
+  if let Some(name) = &compound.name
+    && !element.name.eq_ignore_ascii_case(name)
+  {
+    return false;
+  }
+
+  compound.conditions.iter().all(|condition| match condition {
+    Condition::Id(id) => element.attribute("id") == Some(id.as_str()),
+    Condition::Class(class) => element
+      .attribute("class")
+      .is_some_and(|classes| classes.split_whitespace().any(|c| c == class)),
+    Condition::Attribute { name, op } => match (element.attribute(name), op) {
+      (None, _) => false,
+      (Some(_), None) => true,
+      (Some(actual), Some((op, expected))) => match op {
+        AttributeOp::Equals => actual == expected,
+        AttributeOp::Includes => actual.split_whitespace().any(|word| word == expected),
+        AttributeOp::DashMatch => actual == expected || actual.starts_with(&format!("{expected}-")),
+        AttributeOp::Prefix => !expected.is_empty() && actual.starts_with(expected.as_str()),
+        AttributeOp::Suffix => !expected.is_empty() && actual.ends_with(expected.as_str()),
+        AttributeOp::Substring => !expected.is_empty() && actual.contains(expected.as_str()),
+      },
+    },
+    Condition::Root => info.parent.is_none(),
+    Condition::NthChild {
+      nth,
+      of_type,
+      from_end,
+    } => {
+      let (position, count) = sibling_position(elements, index, *of_type);
+      let position = if *from_end {
+        count - position + 1
+      } else {
+        position
+      };
+      nth.matches(position)
+    }
+    Condition::OnlyChild { of_type } => sibling_position(elements, index, *of_type).1 == 1,
+  })
+}
+
+/// Returns the 1-based position of `index` among its siblings and the number of siblings,
+/// counting only siblings with the same name if `of_type`.
+fn sibling_position(elements: &[ElementInfo], index: usize, of_type: bool) -> (i64, i64) {
+  let info = &elements[index];
+  let Some(parent) = info.parent else {
+    return (1, 1);
+  };
+  let siblings = elements[parent]
+    .children
+    .iter()
+    .filter(|&&sibling| !of_type || elements[sibling].element.name == info.element.name)
+    .collect::<Vec<_>>();
+  let position = siblings
+    .iter()
+    .position(|&&sibling| sibling == index)
+    .unwrap_or(0);
+  let to_i64 = |n: usize| i64::try_from(n).unwrap_or(i64::MAX);
+  (to_i64(position) + 1, to_i64(siblings.len()))
+}
+
+struct Parser<'a> {
+  input: &'a str,
+  pos: usize,
+}
+
+impl<'a> Parser<'a> {
+  fn new(input: &'a str) -> Self {
+    Parser { input, pos: 0 }
+  }
+
+  fn rest(&self) -> &'a str {
+    &self.input[self.pos..]
+  }
+
+  fn peek(&self) -> Option<char> {
+    self.rest().chars().next()
+  }
+
+  fn eat(&mut self, c: char) -> bool {
+    if self.peek() == Some(c) {
+      self.pos += c.len_utf8();
+      true
+    } else {
+      false
+    }
+  }
+
+  fn skip_whitespace(&mut self) -> bool {
+    let trimmed = self.rest().trim_start();
+    let skipped = self.rest().len() - trimmed.len();
+    self.pos += skipped;
+    skipped > 0
+  }
+
+  fn error<T>(&self, expected: &str) -> Result<T> {
+    bail!(
+      "Invalid CSS selector {:?}: expected {expected} at byte {}",
+      self.input,
+      self.pos
+    )
+  }
+
+  fn selector_list(&mut self) -> Result<Vec<Complex>> {
+    let mut selectors = Vec::new();
+    loop {
+      self.skip_whitespace();
+      selectors.push(self.complex()?);
+      if !self.eat(',') {
+        break;
+      }
+    }
+    if self.pos < self.input.len() {
+      return self.error("a combinator or `,`");
+    }
+    Ok(selectors)
+  }
+
+  fn complex(&mut self) -> Result<Complex> {
+    let mut complex = vec![(None, self.compound()?)];
+    loop {
+      let had_whitespace = self.skip_whitespace();
+      let combinator = match self.peek() {
+        Some('>') => Combinator::Child,
+        Some('+') => Combinator::NextSibling,
+        Some('~') => Combinator::SubsequentSibling,
+        Some(',') | None => break,
+        Some(_) if had_whitespace => Combinator::Descendant,
+        Some(_) => return self.error("a combinator"),
+      };
+      if combinator != Combinator::Descendant {
+        self.pos += 1;
+        self.skip_whitespace();
+      }
+      complex.push((Some(combinator), self.compound()?));
+    }
+    Ok(complex)
+  }
+
+  fn compound(&mut self) -> Result<Compound> {
+    let mut compound = Compound::default();
+    let universal = self.eat('*');
+    if !universal {
+      compound.name = self.ident();
+    }
+
+    loop {
+      let condition = if self.eat('#') {
+        match self.ident() {
+          Some(id) => Condition::Id(id),
+          None => return self.error("an ID"),
+        }
+      } else if self.eat('.') {
+        match self.ident() {
+          Some(class) => Condition::Class(class),
+          None => return self.error("a class name"),
+        }
+      } else if self.eat('[') {
+        self.attribute()?
+      } else if self.eat(':') {
+        self.pseudo_class()?
+      } else {
+        break;
+      };
+      compound.conditions.push(condition);
+    }
+
+    if !universal && compound.name.is_none() && compound.conditions.is_empty() {
+      return self.error("a selector");
+    }
+    Ok(compound)
+  }
+
+  fn ident(&mut self) -> Option<String> {
+    let len = self
+      .rest()
+      .find(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_' || !c.is_ascii()))
+      .unwrap_or(self.rest().len());
+    if len == 0 {
+      return None;
+    }
+    let ident = self.rest()[..len].to_string();
+    self.pos += len;
+    Some(ident)
+  }
+
+  fn attribute(&mut self) -> Result<Condition> {
+    self.skip_whitespace();
+    let Some(mut name) = self.ident() else {
+      return self.error("an attribute name");
+    };
+    // Namespaced attributes like `epub|type` are stored under their qualified name `epub:type`.
+    if !self.rest().starts_with("|=") && self.eat('|') {
+      match self.ident() {
+        Some(local) => name = format!("{name}:{local}"),
+        None => return self.error("an attribute name"),
+      }
+    }
+    self.skip_whitespace();
+
+    let op = match self.peek() {
+      Some(']') => None,
+      Some('=') => Some(AttributeOp::Equals),
+      Some(c) => {
+        let op = match c {
+          '~' => AttributeOp::Includes,
+          '|' => AttributeOp::DashMatch,
+          '^' => AttributeOp::Prefix,
+          '$' => AttributeOp::Suffix,
+          '*' => AttributeOp::Substring,
+          _ => return self.error("an attribute operator"),
+        };
+        self.pos += 1;
+        if self.peek() != Some('=') {
+          return self.error("`=`");
+        }
+        Some(op)
+      }
+      None => return self.error("`]`"),
+    };
+
+    let op = match op {
+      None => None,
+      Some(op) => {
+        self.pos += 1;
+        self.skip_whitespace();
+        let value = self.value()?;
+        self.skip_whitespace();
+        Some((op, value))
+      }
+    };
+
+    if !self.eat(']') {
+      return self.error("`]`");
+    }
+    Ok(Condition::Attribute { name, op })
+  }
+
+  fn value(&mut self) -> Result<String> {
+    match self.peek() {
+      Some(quote @ ('"' | '\'')) => {
+        self.pos += 1;
+        let Some(len) = self.rest().find(quote) else {
+          return self.error("a closing quote");
+        };
+        let value = self.rest()[..len].to_string();
+        self.pos += len + 1;
+        Ok(value)
+      }
+      _ => match self.ident() {
+        Some(value) => Ok(value),
+        None => self.error("an attribute value"),
+      },
+    }
+  }
+
+  fn pseudo_class(&mut self) -> Result<Condition> {
+    let Some(name) = self.ident() else {
+      return self.error("a pseudo-class");
+    };
+    let nth = |of_type, from_end, nth| Condition::NthChild {
+      nth,
+      of_type,
+      from_end,
+    };
+    let first = Nth { a: 0, b: 1 };
+    Ok(match name.to_ascii_lowercase().as_str() {
+      "root" => Condition::Root,
+      "first-child" => nth(false, false, first),
+      "last-child" => nth(false, true, first),
+      "first-of-type" => nth(true, false, first),
+      "last-of-type" => nth(true, true, first),
+      "only-child" => Condition::OnlyChild { of_type: false },
+      "only-of-type" => Condition::OnlyChild { of_type: true },
+      "nth-child" => nth(false, false, self.nth_argument()?),
+      "nth-last-child" => nth(false, true, self.nth_argument()?),
+      "nth-of-type" => nth(true, false, self.nth_argument()?),
+      "nth-last-of-type" => nth(true, true, self.nth_argument()?),
+      _ => bail!("Unsupported CSS pseudo-class: :{name}"),
+    })
+  }
+
+  fn nth_argument(&mut self) -> Result<Nth> {
+    if !self.eat('(') {
+      return self.error("`(`");
+    }
+    let Some(len) = self.rest().find(')') else {
+      return self.error("`)`");
+    };
+    let argument = self.rest()[..len].to_string();
+    self.pos += len + 1;
+    parse_nth(&argument)
+  }
+}
+
+fn parse_nth(argument: &str) -> Result<Nth> {
+  let argument = argument
+    .chars()
+    .filter(|c| !c.is_whitespace())
+    .collect::<String>()
+    .to_ascii_lowercase();
+  let parse_int = |s: &str| {
+    s.strip_prefix('+')
+      .unwrap_or(s)
+      .parse::<i64>()
+      .ok()
+      .filter(|_| !s.is_empty())
+  };
+
+  let nth = match argument.as_str() {
+    "odd" => Some(Nth { a: 2, b: 1 }),
+    "even" => Some(Nth { a: 2, b: 0 }),
+    _ => match argument.split_once('n') {
+      None => parse_int(&argument).map(|b| Nth { a: 0, b }),
+      Some((a, b)) => {
+        let a = match a {
+          "" | "+" => Some(1),
+          "-" => Some(-1),
+          a => parse_int(a),
+        };
+        let b = if b.is_empty() { Some(0) } else { parse_int(b) };
+        a.zip(b).map(|(a, b)| Nth { a, b })
+      }
+    },
+  };
+
+  nth.with_context(|| format!("Invalid an+b expression: {argument:?}"))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::dom::Document;
+
+  const DOC: &str = r#"<html><body>
+    <section id="intro" class="lead wide"><h2>Intro</h2><p>One</p><p lang="en-US">Two</p></section>
+    <section><h2>Body</h2><p>Three</p><aside><p>Four</p></aside><p data-x="hello world">Five</p></section>
+  </body></html>"#;
+
+  fn select_text(selector: &str) -> Option<String> {
+    let doc = Document::parse(DOC).unwrap();
+    let elements = doc.elements();
+    let index = select(&elements, selector).unwrap()?;
+    match &elements[index].element.children[..] {
+      [crate::dom::Node::Text(text)] => Some(text.clone()),
+      _ => Some(elements[index].element.name.clone()),
+    }
+  }
+
+  #[test]
+  fn test_css_select() {
+    assert_eq!(select_text("p").as_deref(), Some("One"));
+    assert_eq!(
+      select_text("#intro > p:nth-of-type(2)").as_deref(),
+      Some("Two")
+    );
+    assert_eq!(select_text(".wide p:last-child").as_deref(), Some("Two"));
+    assert_eq!(select_text("section + section p").as_deref(), Some("Three"));
+    assert_eq!(select_text("aside p").as_deref(), Some("Four"));
+    assert_eq!(
+      select_text("section > p:nth-child(2n+4)").as_deref(),
+      Some("Five")
+    );
+    assert_eq!(select_text("h2 ~ aside").as_deref(), Some("aside"));
+    assert_eq!(select_text("[lang|=en]").as_deref(), Some("Two"));
+    assert_eq!(select_text("p[data-x~='world']").as_deref(), Some("Five"));
+    assert_eq!(select_text("h3, aside > *").as_deref(), Some("Four"));
+    assert_eq!(select_text(":root").as_deref(), Some("html"));
+    assert_eq!(select_text("table"), None);
+  }
+
+  #[test]
+  fn test_css_parse_errors() {
+    let doc = Document::parse(DOC).unwrap();
+    let elements = doc.elements();
+    for selector in ["", "p >", "p[", "p:hover", "p:nth-child(x)", "#"] {
+      assert!(select(&elements, selector).is_err(), "{selector}");
+    }
+  }
+}
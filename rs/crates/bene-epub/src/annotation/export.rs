@@ -0,0 +1,404 @@
+//! Exporting annotations with their highlighted text to Markdown, HTML and CSV.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{Context, Result, bail};
+use log::warn;
+use quick_xml::escape::escape;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use super::{Annotation, Body, anchor::Anchorer};
+use crate::{Archive, Epub, MetaField, Rendition, ZipFormat, nav::TocEntry};
+
+/// A file format that annotations can be exported to.
+#[derive(Serialize, Deserialize, TS, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[ts(export)]
+pub enum Format {
+  Markdown,
+  Html,
+  Csv,
+}
+
+impl Format {
+  /// Returns the conventional file extension for the format.
+  pub fn extension(self) -> &'static str {
+    match self {
+      Format::Markdown => "md",
+      Format::Html => "html",
+      Format::Csv => "csv",
+    }
+  }
+}
+
+impl FromStr for Format {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    Ok(match s.to_ascii_lowercase().as_str() {
+      "markdown" | "md" => Format::Markdown,
+      "html" => Format::Html,
+      "csv" => Format::Csv,
+      _ => bail!("Unknown export format `{s}`, expected one of: markdown, html, csv"),
+    })
+  }
+}
+
+/// An annotation resolved against its publication, ready to be rendered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+  pub id: String,
+  /// The title of the table of contents entry containing the highlighted text.
+  pub chapter: Option<String>,
+  /// The highlighted text with whitespace collapsed, if the annotation's target could be resolved.
+  pub text: Option<String>,
+  /// The annotation's comments and linked resources.
+  pub notes: Vec<String>,
+  pub tags: Vec<String>,
+  pub created: Option<String>,
+  pub modified: Option<String>,
+  /// The EPUB CFI of the annotation's first target.
+  pub cfi: Option<String>,
+  /// The spine index and byte offset of the highlighted text, for sorting in reading order.
+  position: Option<(usize, usize)>,
+}
+
+/// Renders `annotations` on the first rendition of `epub` in the given format.
+///
+/// # Errors
+/// If `epub` has no renditions.
+pub fn export<F: ZipFormat>(
+  archive: &mut Archive<F>,
+  epub: &Epub,
+  annotations: &[Annotation],
+  format: Format,
+) -> Result<String> {
+  let rendition = epub.renditions.first().context("EPUB has no renditions")?;
+  let entries = entries(archive, rendition, annotations);
+  let mut output = String::new();
+  render(format, &title(rendition), &entries, &mut output)?;
+  Ok(output)
+}
+
+/// Resolves the highlighted text and chapter of each annotation, sorted in reading order.
+///
+/// Annotations whose targets cannot be resolved are kept without text, after all others.
+pub fn entries<F: ZipFormat>(
+  archive: &mut Archive<F>,
+  rendition: &Rendition,
+  annotations: &[Annotation],
+) -> Vec<Entry> {
+  let toc = rendition.toc(archive).unwrap_or_else(|err| {
+    warn!("Failed to read table of contents: {err:?}");
+    Vec::new()
+  });
+  let chapters = chapter_titles(rendition, &toc);
+
+  let mut anchorer = Anchorer::new(archive, rendition);
+  let mut entries = annotations
+    .iter()
+    .map(|annotation| {
+      let target = annotation.targets.first();
+      let resolved = target.and_then(|target| match anchorer.resolve(&target.selector) {
+        Ok(resolved) => Some(resolved),
+        Err(err) => {
+          warn!("Failed to resolve annotation {}: {err:?}", annotation.id);
+          None
+        }
+      });
+      let (text, chapter, position) = match resolved {
+        Some((document, range)) => (
+          Some(collapse_whitespace(&document.text.text[range.clone()])),
+          chapters[document.spine_index].clone(),
+          Some((document.spine_index, range.start)),
+        ),
+        None => (None, None, None),
+      };
+
+      let mut notes = Vec::new();
+      for body in &annotation.bodies {
+        push_notes(body, &mut notes);
+      }
+
+      Entry {
+        id: annotation.id.clone(),
+        chapter,
+        text,
+        notes,
+        tags: annotation.tags().map(String::from).collect(),
+        created: annotation.created.clone(),
+        modified: annotation.modified.clone(),
+        cfi: target.map(|target| target.selector.to_string()),
+        position,
+      }
+    })
+    .collect::<Vec<_>>();
+  entries.sort_by_key(|entry| (entry.position.is_none(), entry.position));
+  entries
+}
+
+/// Writes `entries` in the given format, under the heading `title`.
+///
+/// # Errors
+/// If `output` fails to be written.
+pub fn render(
+  format: Format,
+  title: &str,
+  entries: &[Entry],
+  output: &mut impl fmt::Write,
+) -> fmt::Result {
+  match format {
+    Format::Markdown => markdown(title, entries, output),
+    Format::Html => html(title, entries, output),
+    Format::Csv => csv(entries, output),
+  }
+}
+
+/// The heading for annotations which could not be placed in a chapter.
+const OTHER_HEADING: &str = "Other annotations";
+
+fn markdown(title: &str, entries: &[Entry], output: &mut impl fmt::Write) -> fmt::Result {
+  writeln!(output, "# {title}")?;
+  for (i, entry) in entries.iter().enumerate() {
+    if i == 0 || entries[i - 1].chapter != entry.chapter {
+      let chapter = entry.chapter.as_deref().unwrap_or(OTHER_HEADING);
+      write!(output, "\n## {chapter}\n")?;
+    }
+    if let Some(text) = &entry.text {
+      write!(output, "\n> {text}\n")?;
+    }
+    for note in &entry.notes {
+      write!(output, "\n{note}\n")?;
+    }
+    if !entry.tags.is_empty() {
+      write!(output, "\n*Tags: {}*\n", entry.tags.join(", "))?;
+    }
+  }
+  Ok(())
+}
+
+const HTML_STYLE: &str =
+  "body { font-family: serif; max-width: 40em; margin: 2em auto; line-height: 1.5; }
+blockquote { margin: 1em 0; padding-left: 1em; border-left: 3px solid #e0c050; }
+.note { white-space: pre-wrap; }
+.tags { font-style: italic; color: #666; }";
+
+fn html(title: &str, entries: &[Entry], output: &mut impl fmt::Write) -> fmt::Result {
+  let title = escape(title);
+  writeln!(output, "<!DOCTYPE html>")?;
+  writeln!(output, "<html>")?;
+  writeln!(output, "<head>")?;
+  writeln!(output, r#"<meta charset="utf-8">"#)?;
+  writeln!(output, "<title>{title}</title>")?;
+  writeln!(output, "<style>\n{HTML_STYLE}\n</style>")?;
+  writeln!(output, "</head>")?;
+  writeln!(output, "<body>")?;
+  writeln!(output, "<h1>{title}</h1>")?;
+  for (i, entry) in entries.iter().enumerate() {
+    if i == 0 || entries[i - 1].chapter != entry.chapter {
+      let chapter = entry.chapter.as_deref().unwrap_or(OTHER_HEADING);
+      writeln!(output, "<h2>{}</h2>", escape(chapter))?;
+    }
+    writeln!(output, r#"<article id="{}">"#, escape(&entry.id))?;
+    if let Some(text) = &entry.text {
+      writeln!(output, "<blockquote>{}</blockquote>", escape(text))?;
+    }
+    for note in &entry.notes {
+      writeln!(output, r#"<p class="note">{}</p>"#, escape(note))?;
+    }
+    if !entry.tags.is_empty() {
+      let tags = escape(entry.tags.join(", "));
+      writeln!(output, r#"<p class="tags">Tags: {tags}</p>"#)?;
+    }
+    writeln!(output, "</article>")?;
+  }
+  writeln!(output, "</body>")?;
+  writeln!(output, "</html>")
+}
+
+fn csv(entries: &[Entry], output: &mut impl fmt::Write) -> fmt::Result {
+  let header = [
+    "id", "chapter", "text", "notes", "tags", "created", "modified", "cfi",
+  ];
+  csv_row(&header.map(String::from), output)?;
+  for entry in entries {
+    let optional = |value: &Option<String>| value.clone().unwrap_or_default();
+    csv_row(
+      &[
+        entry.id.clone(),
+        optional(&entry.chapter),
+        optional(&entry.text),
+        entry.notes.join("\n\n"),
+        entry.tags.join(", "),
+        optional(&entry.created),
+        optional(&entry.modified),
+        optional(&entry.cfi),
+      ],
+      output,
+    )?;
+  }
+  Ok(())
+}
+
+/// Writes a row of an RFC 4180 CSV file, quoting fields where necessary.
+fn csv_row(fields: &[String], output: &mut impl fmt::Write) -> fmt::Result {
+  for (i, field) in fields.iter().enumerate() {
+    if i > 0 {
+      output.write_char(',')?;
+    }
+    if field.contains([',', '"', '\n', '\r']) {
+      write!(output, "\"{}\"", field.replace('"', "\"\""))?;
+    } else {
+      output.write_str(field)?;
+    }
+  }
+  output.write_str("\r\n")
+}
+
+/// Returns the title of the table of contents entry containing each document in the spine.
+///
+/// Documents without an entry of their own belong to the closest preceding entry.
+fn chapter_titles(rendition: &Rendition, toc: &[TocEntry]) -> Vec<Option<String>> {
+  let mut current = None;
+  (0..rendition.package.spine.itemref.len())
+    .map(|spine_index| {
+      let path = rendition.spine_path(spine_index);
+      let entry = toc
+        .iter()
+        .flat_map(TocEntry::flatten)
+        .find(|entry| path.is_some() && entry.path() == path.as_deref());
+      if let Some(entry) = entry {
+        current = Some(entry.title.clone());
+      }
+      current.clone()
+    })
+    .collect()
+}
+
+fn push_notes(body: &Body, notes: &mut Vec<String>) {
+  match body {
+    Body::Text { value, purpose, .. } => {
+      // Tags are exported separately.
+      if !purpose.iter().any(|p| p == "tagging") {
+        notes.push(value.clone());
+      }
+    }
+    Body::Resource { id, .. } => notes.push(id.clone()),
+    Body::Choice { items } => {
+      if let Some(item) = items.first() {
+        push_notes(item, notes);
+      }
+    }
+  }
+}
+
+fn title(rendition: &Rendition) -> String {
+  let fields = &rendition.package.metadata.fields;
+  fields
+    .iter()
+    .find_map(|field| match field {
+      MetaField::Title(title) => Some(title.trim().to_string()),
+      _ => None,
+    })
+    .unwrap_or_else(|| "Annotations".into())
+}
+
+fn collapse_whitespace(s: &str) -> String {
+  s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test_utils;
+
+  const ANNOTATIONS: &str = r#"[
+    {
+      "@context": "http://www.w3.org/ns/anno.jsonld",
+      "id": "stray",
+      "type": "Annotation",
+      "bodyValue": "A stray note",
+      "target": "chapter.xhtml#epubcfi(/6/4!/4/2,/1:0,/1:5)"
+    },
+    {
+      "@context": "http://www.w3.org/ns/anno.jsonld",
+      "id": "second",
+      "type": "Annotation",
+      "target": "chapter.xhtml#epubcfi(/6/2!/4/4,/1:0,/3:10)"
+    },
+    {
+      "@context": "http://www.w3.org/ns/anno.jsonld",
+      "id": "first",
+      "type": "Annotation",
+      "created": "2024-05-01T10:00:00Z",
+      "body": [
+        {"type": "TextualBody", "value": "A \"quoted\" comment, with a comma"},
+        {"type": "TextualBody", "value": "greeting", "purpose": "tagging"}
+      ],
+      "target": "chapter.xhtml#epubcfi(/6/2!/4/2,/1:0,/1:5)"
+    }
+  ]"#;
+
+  fn export_as(format: Format) -> String {
+    let mut archive = test_utils::archive(&[("EPUB/annotations.json", ANNOTATIONS)]);
+    let epub = Epub::load(&mut archive).unwrap();
+    let annotations = epub.renditions[0].annotations().to_vec();
+    assert_eq!(annotations.len(), 3);
+    export(&mut archive, &epub, &annotations, format).unwrap()
+  }
+
+  #[test]
+  fn test_export_markdown() {
+    assert_eq!(
+      export_as(Format::Markdown),
+      r#"# Test Book
+
+## The Chapter
+
+> Hello
+
+A "quoted" comment, with a comma
+
+*Tags: greeting*
+
+> Another emphatic paragraph
+
+## Other annotations
+
+A stray note
+"#
+    );
+  }
+
+  #[test]
+  fn test_export_html() {
+    let html = export_as(Format::Html);
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert!(html.contains("<h1>Test Book</h1>\n<h2>The Chapter</h2>\n<article id=\"first\">\n<blockquote>Hello</blockquote>\n<p class=\"note\">A &quot;quoted&quot; comment, with a comma</p>\n<p class=\"tags\">Tags: greeting</p>\n</article>"));
+    assert!(html.ends_with("</html>\n"));
+  }
+
+  #[test]
+  fn test_export_csv() {
+    let csv = export_as(Format::Csv);
+    let lines = csv.split("\r\n").collect::<Vec<_>>();
+    assert_eq!(
+      lines,
+      [
+        "id,chapter,text,notes,tags,created,modified,cfi",
+        r#"first,The Chapter,Hello,"A ""quoted"" comment, with a comma",greeting,2024-05-01T10:00:00Z,,"epubcfi(/6/2!/4/2,/1:0,/1:5)""#,
+        r#"second,The Chapter,Another emphatic paragraph,,,,,"epubcfi(/6/2!/4/4,/1:0,/3:10)""#,
+        r#"stray,,,A stray note,,,,"epubcfi(/6/4!/4/2,/1:0,/1:5)""#,
+        "",
+      ]
+    );
+  }
+
+  #[test]
+  fn test_format_from_str() {
+    assert_eq!("md".parse::<Format>().unwrap(), Format::Markdown);
+    assert_eq!("HTML".parse::<Format>().unwrap(), Format::Html);
+    assert!("pdf".parse::<Format>().is_err());
+  }
+}
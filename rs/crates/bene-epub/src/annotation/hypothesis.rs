@@ -0,0 +1,615 @@
+//! Conversion between Hypothesis' JSON annotations and W3C Web Annotations.
+//!
+//! Hypothesis anchors annotations with a bundle of a `RangeSelector` (`XPath`s relative to
+//! `<body>` with character offsets), a `TextPositionSelector` and a `TextQuoteSelector`. Replies
+//! have no selectors, and instead list the IDs of the annotations above them in `references`.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result, anyhow};
+use iref::IriRefBuf;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+
+use super::{
+  ANNOTATION_CONTEXT, Agent, Annotation, Audience, Body, RawAnnotation,
+  anchor::{self, Anchorer},
+  from_raw, process,
+  raw::{self, IntoVec},
+};
+use crate::{Archive, Rendition, ZipFormat, cfi};
+
+/// The prefix of the IRIs of annotations on the public Hypothesis service.
+const ANNOTATIONS: &str = "https://hypothes.is/a/";
+
+/// The prefix of the IRIs of groups on the public Hypothesis service.
+const GROUPS: &str = "https://hypothes.is/groups/";
+
+/// The group of annotations which anyone can read.
+const PUBLIC_GROUP: &str = "__world__";
+
+/// The file exported by the Hypothesis client, a page of API search results, or a bare array.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Export {
+  File {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    export_date: Option<String>,
+    annotations: Vec<HypothesisAnnotation>,
+  },
+  Search {
+    rows: Vec<HypothesisAnnotation>,
+  },
+  Annotations(Vec<HypothesisAnnotation>),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct HypothesisAnnotation {
+  id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  created: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  updated: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  user: Option<String>,
+  uri: String,
+  /// The annotation's comment, in Markdown.
+  #[serde(default)]
+  text: String,
+  #[serde(default)]
+  tags: Vec<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  group: Option<String>,
+  #[serde(default)]
+  target: Vec<HypothesisTarget>,
+  /// The IDs of the annotations that a reply is in reply to, from the top of the thread down.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  references: Vec<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  user_info: Option<UserInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct UserInfo {
+  display_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct HypothesisTarget {
+  source: String,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  selector: Vec<HypothesisSelector>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+enum HypothesisSelector {
+  TextQuoteSelector {
+    exact: String,
+    #[serde(default)]
+    prefix: String,
+    #[serde(default)]
+    suffix: String,
+  },
+  TextPositionSelector {
+    start: u64,
+    end: u64,
+  },
+  #[serde(rename_all = "camelCase")]
+  RangeSelector {
+    start_container: String,
+    start_offset: u64,
+    end_container: String,
+    end_offset: u64,
+  },
+  /// Selectors specific to other kinds of documents, e.g. `PageSelector` for PDFs.
+  #[serde(other)]
+  Other,
+}
+
+/// Converts Hypothesis annotations into W3C Web Annotations.
+///
+/// `input` can be a file exported from the Hypothesis client, a response from its search API,
+/// or an array of annotations. Annotations are identified by their IRIs on the public Hypothesis
+/// service, which replies use as their targets.
+///
+/// # Errors
+/// If `input` is not Hypothesis JSON, or a target's `source` is not a valid IRI.
+pub fn import(input: &str) -> Result<Vec<RawAnnotation>> {
+  let export =
+    serde_json::from_str::<Export>(input).context("Input is not a Hypothesis annotation export")?;
+  let (Export::File { annotations, .. }
+  | Export::Search { rows: annotations }
+  | Export::Annotations(annotations)) = export;
+  annotations.into_iter().map(to_raw).collect()
+}
+
+fn to_raw(annotation: HypothesisAnnotation) -> Result<RawAnnotation> {
+  let iri = |s: String| {
+    IriRefBuf::new(s).map_err(|err| anyhow!("Hypothesis annotation has an invalid IRI: {}", err.0))
+  };
+
+  let has_selectors = annotation
+    .target
+    .iter()
+    .any(|target| !target.selector.is_empty());
+  let motivation = if !annotation.references.is_empty() {
+    "replying"
+  } else if annotation.text.trim().is_empty() && has_selectors {
+    "highlighting"
+  } else {
+    "commenting"
+  };
+
+  let mut bodies = Vec::new();
+  if !annotation.text.trim().is_empty() {
+    bodies.push(Body::Text {
+      value: annotation.text,
+      format: Some("text/markdown".into()),
+      language: None,
+      purpose: Vec::new(),
+    });
+  }
+  bodies.extend(annotation.tags.into_iter().map(|tag| Body::Text {
+    value: tag,
+    format: None,
+    language: None,
+    purpose: vec!["tagging".into()],
+  }));
+
+  let creator = annotation.user.map(|user| Agent {
+    id: Some(user),
+    kind: Some("Person".into()),
+    name: annotation.user_info.and_then(|info| info.display_name),
+    nickname: None,
+    homepage: None,
+  });
+  let audience = annotation.group.map(|group| Audience {
+    id: Some(format!("{GROUPS}{group}")),
+    kind: Vec::new(),
+  });
+
+  // A reply targets the annotation it replies to, which is the last of its references.
+  let targets = match annotation.references.last() {
+    Some(parent) => vec![raw::Target::Iri(iri(format!("{ANNOTATIONS}{parent}"))?)],
+    None => annotation
+      .target
+      .into_iter()
+      .map(|target| {
+        let source = iri(target.source)?;
+        let mut selectors = target
+          .selector
+          .into_iter()
+          .filter_map(selector_to_raw)
+          .collect::<Vec<_>>();
+        // Quotes are tried first, since they survive changes to the document's structure.
+        selectors.sort_by_key(|selector| match selector {
+          raw::TaggedSelector::TextQuoteSelector(_) => 0,
+          raw::TaggedSelector::TextPositionSelector(_) => 1,
+          _ => 2,
+        });
+        let selectors = selectors
+          .into_iter()
+          .map(raw::Selector::TaggedSelector)
+          .collect();
+        Ok(match raw::Variable::from_vec(selectors) {
+          Some(selector) => raw::Target::SpecificResource(raw::SpecificResource {
+            id: None,
+            r#type: Some("SpecificResource".into()),
+            source: Box::new(raw::Target::Iri(source)),
+            purpose: None,
+            selector: Some(selector),
+          }),
+          None => raw::Target::Iri(source),
+        })
+      })
+      .collect::<Result<Vec<_>>>()?,
+  };
+
+  Ok(RawAnnotation {
+    context: ANNOTATION_CONTEXT.into(),
+    id: format!("{ANNOTATIONS}{}", annotation.id),
+    r#type: "Annotation".into(),
+    motivation: Some(raw::Variable::One(motivation.into())),
+    created: annotation.created,
+    creator: creator.map(|creator| raw::Variable::One(creator.to_raw())),
+    modified: annotation.updated,
+    audience: audience.map(|audience| raw::Variable::One(audience.to_raw())),
+    rights: None,
+    body: raw::Variable::from_vec(bodies.iter().map(Body::to_raw).collect()),
+    body_value: None,
+    target: raw::Variable::from_vec(targets).unwrap_or(raw::Variable::Many(SmallVec::new())),
+  })
+}
+
+fn selector_to_raw(selector: HypothesisSelector) -> Option<raw::TaggedSelector> {
+  let non_empty = |s: String| (!s.is_empty()).then_some(s);
+  // Hypothesis' XPaths start from <body>, and its offsets count characters of an element's text.
+  let point = |container: String, offset: u64| {
+    Box::new(raw::Selector::TaggedSelector(
+      raw::TaggedSelector::XPathSelector(raw::XPathSelector {
+        value: format!("/html/body{container}"),
+        refined_by: Some(Box::new(raw::Selector::TaggedSelector(
+          raw::TaggedSelector::TextPositionSelector(raw::TextPositionSelector {
+            start: offset,
+            end: offset,
+          }),
+        ))),
+      }),
+    ))
+  };
+  Some(match selector {
+    HypothesisSelector::TextQuoteSelector {
+      exact,
+      prefix,
+      suffix,
+    } => raw::TaggedSelector::TextQuoteSelector(raw::TextQuoteSelector {
+      exact,
+      prefix: non_empty(prefix),
+      suffix: non_empty(suffix),
+    }),
+    HypothesisSelector::TextPositionSelector { start, end } => {
+      raw::TaggedSelector::TextPositionSelector(raw::TextPositionSelector { start, end })
+    }
+    HypothesisSelector::RangeSelector {
+      start_container,
+      start_offset,
+      end_container,
+      end_offset,
+    } => raw::TaggedSelector::RangeSelector(raw::RangeSelector {
+      start_selector: point(start_container, start_offset),
+      end_selector: point(end_container, end_offset),
+    }),
+    HypothesisSelector::Other => return None,
+  })
+}
+
+/// Converts W3C Web Annotations on the publication of `rendition` into a Hypothesis export file.
+///
+/// Annotations are anchored to find their quotes and text positions, and annotations which
+/// cannot be anchored are skipped with a warning. Replies, whose targets are other annotations,
+/// are kept with the chain of annotations they reply to. The Hypothesis `uri` of every
+/// annotation is `uri`.
+///
+/// # Errors
+/// If the export cannot be serialized, which should not happen in practice.
+pub fn export<F: ZipFormat>(
+  archive: &mut Archive<F>,
+  rendition: &Rendition,
+  uri: &str,
+  annotations: Vec<RawAnnotation>,
+) -> Result<String> {
+  let (replies, annotations): (Vec<_>, Vec<_>) = annotations.into_iter().partition(|annotation| {
+    let motivation = annotation.motivation.clone().into_vec();
+    motivation.iter().any(|motivation| motivation == "replying")
+  });
+
+  let (annotations, diagnostics) = process(annotations, archive, rendition);
+  for diagnostic in diagnostics {
+    warn!("Not exporting {diagnostic}");
+  }
+
+  let mut exported = Vec::new();
+  let mut anchorer = Anchorer::new(archive, rendition);
+  for annotation in annotations {
+    let target = annotation
+      .targets
+      .iter()
+      .map(|target| HypothesisTarget {
+        source: uri.to_string(),
+        selector: selectors(&mut anchorer, &target.selector).unwrap_or_else(|err| {
+          warn!(
+            "Not exporting selector of annotation {}: {err:#}",
+            annotation.id
+          );
+          Vec::new()
+        }),
+      })
+      .collect();
+    exported.push(from_annotation(&annotation, uri, target, Vec::new()));
+  }
+
+  let parents = replies
+    .iter()
+    .filter_map(|reply| Some((reply.id.clone(), reply_parent(reply)?)))
+    .collect::<HashMap<_, _>>();
+  for reply in replies {
+    let Some(parent) = parents.get(&reply.id).cloned() else {
+      warn!(
+        "Not exporting reply {}: it does not target an annotation",
+        reply.id
+      );
+      continue;
+    };
+    // Walk up the thread, guarding against cycles.
+    let mut references = vec![parent];
+    while let Some(grandparent) = references.last().and_then(|id| parents.get(id))
+      && references.len() <= parents.len()
+    {
+      references.push(grandparent.clone());
+    }
+    references.reverse();
+    let references = references.iter().map(|id| hypothesis_id(id)).collect();
+
+    let annotation = from_raw(reply, Vec::new(), &mut |id, err| {
+      warn!("Not exporting part of annotation {id}: {err:#}");
+    });
+    let target = vec![HypothesisTarget {
+      source: uri.to_string(),
+      selector: Vec::new(),
+    }];
+    exported.push(from_annotation(&annotation, uri, target, references));
+  }
+
+  let export = Export::File {
+    export_date: None,
+    annotations: exported,
+  };
+  Ok(serde_json::to_string_pretty(&export)?)
+}
+
+/// Returns the ID of the annotation that `reply` targets.
+fn reply_parent(reply: &RawAnnotation) -> Option<String> {
+  reply
+    .target
+    .clone()
+    .into_vec()
+    .into_iter()
+    .find_map(|target| match target {
+      raw::Target::Iri(iri) => Some(iri.to_string()),
+      raw::Target::ExternalWebResource(resource) => Some(resource.id),
+      raw::Target::SpecificResource(_) => None,
+    })
+}
+
+/// Strips the Hypothesis service's prefix from an annotation IRI.
+fn hypothesis_id(id: &str) -> String {
+  id.strip_prefix(ANNOTATIONS).unwrap_or(id).to_string()
+}
+
+fn from_annotation(
+  annotation: &Annotation,
+  uri: &str,
+  target: Vec<HypothesisTarget>,
+  references: Vec<String>,
+) -> HypothesisAnnotation {
+  fn text(body: &Body) -> Option<&str> {
+    match body {
+      Body::Text { value, purpose, .. } if !purpose.iter().any(|p| p == "tagging") => Some(value),
+      Body::Text { .. } => None,
+      Body::Resource { id, .. } => Some(id),
+      Body::Choice { items } => items.first().and_then(text),
+    }
+  }
+
+  let creator = annotation.creator.first();
+  HypothesisAnnotation {
+    id: hypothesis_id(&annotation.id),
+    created: annotation.created.clone(),
+    updated: annotation
+      .modified
+      .clone()
+      .or_else(|| annotation.created.clone()),
+    user: creator.and_then(|creator| creator.id.clone()),
+    uri: uri.to_string(),
+    text: annotation
+      .bodies
+      .iter()
+      .filter_map(text)
+      .collect::<Vec<_>>()
+      .join("\n\n"),
+    tags: annotation.tags().map(String::from).collect(),
+    group: Some(
+      annotation
+        .audience
+        .iter()
+        .find_map(|audience| audience.id.as_deref()?.strip_prefix(GROUPS))
+        .unwrap_or(PUBLIC_GROUP)
+        .to_string(),
+    ),
+    target,
+    references,
+    user_info: creator
+      .and_then(|creator| creator.name.clone())
+      .map(|name| UserInfo {
+        display_name: Some(name),
+      }),
+  }
+}
+
+/// Describes the text selected by `fragment` with Hypothesis' quote and position selectors.
+fn selectors<F: ZipFormat>(
+  anchorer: &mut Anchorer<'_, F>,
+  fragment: &cfi::Fragment,
+) -> Result<Vec<HypothesisSelector>> {
+  let (document, range) = anchorer.resolve(fragment)?;
+  if range.is_empty() {
+    return Ok(Vec::new());
+  }
+  let start = document.text.text[..range.start].chars().count();
+  let quote = anchor::quote(&document, range);
+  let end = start + quote.exact.chars().count();
+  Ok(vec![
+    HypothesisSelector::TextPositionSelector {
+      start: start as u64,
+      end: end as u64,
+    },
+    HypothesisSelector::TextQuoteSelector {
+      exact: quote.exact,
+      prefix: quote.prefix.unwrap_or_default(),
+      suffix: quote.suffix.unwrap_or_default(),
+    },
+  ])
+}
+
+#[cfg(test)]
+mod test {
+  use serde_json::Value;
+
+  use super::*;
+  use crate::{Epub, test_utils};
+
+  const EXPORT: &str = include_str!("fixtures/hypothesis-export.json");
+  const SEARCH: &str = include_str!("fixtures/hypothesis-search.json");
+
+  fn cfis(annotations: &[Annotation]) -> Vec<(String, String)> {
+    annotations
+      .iter()
+      .map(|annotation| {
+        (
+          annotation.id.clone(),
+          annotation.targets[0].selector.to_string(),
+        )
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_import() {
+    let raw = import(EXPORT).unwrap();
+    assert_eq!(raw.len(), 5);
+
+    let mut archive = test_utils::archive(&[]);
+    let epub = Epub::load(&mut archive).unwrap();
+    let (annotations, diagnostics) = process(raw, &mut archive, &epub.renditions[0]);
+
+    // Replies and page notes have no location in the publication.
+    assert_eq!(
+      cfis(&annotations),
+      [
+        (
+          "https://hypothes.is/a/AqM7wNXFEe-xTKtmJcXqhg".into(),
+          "epubcfi(/6/2!/4/2,/1:6,/1:11)".into()
+        ),
+        (
+          "https://hypothes.is/a/Q8Hn1tXFEe-4pWPd0wHp2A".into(),
+          "epubcfi(/6/2!/4/4/2,/1:0,/1:8)".into()
+        ),
+      ]
+    );
+    assert_eq!(diagnostics.len(), 6);
+
+    let comment = &annotations[0];
+    assert_eq!(comment.motivation, ["commenting"]);
+    assert_eq!(
+      comment.created.as_deref(),
+      Some("2024-02-01T10:00:00.000000+00:00")
+    );
+    assert_eq!(
+      comment.modified.as_deref(),
+      Some("2024-02-02T09:30:00.000000+00:00")
+    );
+    assert_eq!(
+      comment.creator[0].id.as_deref(),
+      Some("acct:alice@hypothes.is")
+    );
+    assert_eq!(comment.creator[0].name.as_deref(), Some("Alice"));
+    assert_eq!(comment.tags().collect::<Vec<_>>(), ["greetings", "intro"]);
+    assert!(matches!(
+      &comment.bodies[0],
+      Body::Text { value, format: Some(format), .. }
+        if value == "A *friendly* greeting." && format == "text/markdown"
+    ));
+
+    let highlight = &annotations[1];
+    assert_eq!(highlight.motivation, ["highlighting"]);
+    assert_eq!(
+      highlight.audience[0].id.as_deref(),
+      Some("https://hypothes.is/groups/Kd4x8pQ2")
+    );
+  }
+
+  #[test]
+  fn test_import_replies() {
+    let raw = import(EXPORT).unwrap();
+    let reply = &raw[2];
+    assert_eq!(reply.motivation.clone().into_vec().as_slice(), ["replying"]);
+    assert_eq!(
+      reply_parent(reply).as_deref(),
+      Some("https://hypothes.is/a/G1f0_NXFEe-yKSvP7kTgzw")
+    );
+  }
+
+  #[test]
+  fn test_import_search() {
+    let raw = import(SEARCH).unwrap();
+    let mut archive = test_utils::archive(&[]);
+    let epub = Epub::load(&mut archive).unwrap();
+    let (annotations, diagnostics) = process(raw, &mut archive, &epub.renditions[0]);
+    assert!(diagnostics.is_empty(), "{diagnostics:?}");
+    assert_eq!(
+      annotations[0].targets[0].selector.to_string(),
+      "epubcfi(/6/2!/4/4,/3:1,/3:10)"
+    );
+    assert_eq!(annotations[0].tags().collect::<Vec<_>>(), ["paragraphs"]);
+
+    assert!(
+      import(r#"{"rows": [{"id": "a", "uri": "x", "target": [{"source": "not an iri"}]}]}"#)
+        .is_err()
+    );
+    assert!(import("{}").is_err());
+  }
+
+  #[test]
+  fn test_export() {
+    let mut archive = test_utils::archive(&[]);
+    let epub = Epub::load(&mut archive).unwrap();
+    let rendition = &epub.renditions[0];
+    let exported = export(
+      &mut archive,
+      rendition,
+      "urn:test:book",
+      import(EXPORT).unwrap(),
+    )
+    .unwrap();
+
+    let value = serde_json::from_str::<Value>(&exported).unwrap();
+    let annotations = value["annotations"].as_array().unwrap();
+    let ids = annotations
+      .iter()
+      .map(|annotation| annotation["id"].as_str().unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      ids,
+      [
+        "AqM7wNXFEe-xTKtmJcXqhg",
+        "Q8Hn1tXFEe-4pWPd0wHp2A",
+        "G1f0_NXFEe-yKSvP7kTgzw",
+        "LzP8ZNXFEe-3Fzub2tT1Hg"
+      ]
+    );
+
+    let comment = &annotations[0];
+    assert_eq!(comment["text"], "A *friendly* greeting.");
+    assert_eq!(comment["tags"], serde_json::json!(["greetings", "intro"]));
+    assert_eq!(comment["group"], "__world__");
+    assert_eq!(comment["user"], "acct:alice@hypothes.is");
+    assert_eq!(comment["updated"], "2024-02-02T09:30:00.000000+00:00");
+    assert_eq!(
+      comment["target"][0]["selector"],
+      serde_json::json!([
+        {"type": "TextPositionSelector", "start": 11, "end": 16},
+        {
+          "type": "TextQuoteSelector",
+          "exact": "world",
+          "prefix": "\n    Hello ",
+          "suffix": ", this is a test.\n    Another em"
+        }
+      ])
+    );
+    assert_eq!(annotations[1]["group"], "Kd4x8pQ2");
+
+    assert_eq!(
+      annotations[3]["references"],
+      serde_json::json!(["AqM7wNXFEe-xTKtmJcXqhg", "G1f0_NXFEe-yKSvP7kTgzw"])
+    );
+    assert_eq!(annotations[3]["text"], "Thanks!");
+    assert!(annotations[3]["target"][0].get("selector").is_none());
+
+    // The export anchors to the same places when imported again.
+    let (reimported, _) = process(import(&exported).unwrap(), &mut archive, rendition);
+    let (original, _) = process(import(EXPORT).unwrap(), &mut archive, rendition);
+    assert_eq!(cfis(&reimported), cfis(&original));
+  }
+}
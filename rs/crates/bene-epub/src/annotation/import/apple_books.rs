@@ -0,0 +1,114 @@
+//! Apple Books annotations, as rows of the `ZAEANNOTATION` table in JSON.
+//!
+//! Apple Books keeps annotations in `AEAnnotation_*.sqlite`, which can be exported with e.g.
+//! `sqlite3 -json AEAnnotation.sqlite 'SELECT * FROM ZAEANNOTATION'`. The table does not name
+//! the book, so rows can be joined with `ZBKLIBRARYASSET` to add its `ZTITLE` and `ZAUTHOR`.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use super::{Book, Entry};
+use crate::{cfi, write::timestamp};
+
+/// Seconds between the Unix epoch and Core Data's reference date, 2001-01-01.
+const REFERENCE_DATE: f64 = 978_307_200.0;
+
+#[derive(Deserialize)]
+struct Row {
+  #[serde(rename = "Z_PK")]
+  key: Option<i64>,
+  #[serde(rename = "ZANNOTATIONSELECTEDTEXT")]
+  text: Option<String>,
+  #[serde(rename = "ZANNOTATIONNOTE")]
+  note: Option<String>,
+  /// An EPUB CFI, e.g. `epubcfi(/6/4[chapter]!/4/2/1,:0,:11)`.
+  #[serde(rename = "ZANNOTATIONLOCATION")]
+  location: Option<String>,
+  /// Seconds since [`REFERENCE_DATE`].
+  #[serde(rename = "ZANNOTATIONCREATIONDATE")]
+  created: Option<f64>,
+  #[serde(rename = "ZANNOTATIONDELETED")]
+  deleted: Option<i64>,
+  #[serde(rename = "ZTITLE")]
+  title: Option<String>,
+  #[serde(rename = "ZAUTHOR")]
+  author: Option<String>,
+}
+
+pub(super) fn parse(input: &str) -> Result<Vec<Entry>> {
+  let rows = serde_json::from_str::<Vec<Row>>(input)
+    .context("Apple Books export is not an array of ZAEANNOTATION rows")?;
+  let entries = rows
+    .into_iter()
+    .enumerate()
+    .filter(|(_, row)| row.deleted != Some(1))
+    .map(|(i, row)| {
+      let non_empty = |s: Option<String>| s.filter(|s| !s.trim().is_empty());
+      Entry {
+        origin: match row.key {
+          Some(key) => format!("Apple Books annotation {key}"),
+          None => format!("Apple Books annotation {}", i + 1),
+        },
+        book: Book {
+          title: non_empty(row.title),
+          authors: non_empty(row.author).into_iter().collect(),
+          identifiers: Vec::new(),
+        },
+        text: non_empty(row.text),
+        note: non_empty(row.note),
+        created: row.created.and_then(created),
+        cfi: row
+          .location
+          .and_then(|location| cfi::Fragment::parse(&location).ok()),
+        spine_index: None,
+      }
+    })
+    .collect();
+  Ok(entries)
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn created(secs: f64) -> Option<String> {
+  let secs = secs + REFERENCE_DATE;
+  (secs.is_finite() && secs >= 0.0).then(|| timestamp(secs as u64))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    let rows = r#"[
+      {"Z_PK": 7, "ZANNOTATIONSELECTEDTEXT": "Hello world", "ZANNOTATIONNOTE": "A note",
+       "ZANNOTATIONLOCATION": "epubcfi(/6/2[chapter]!/4/2,/1:0,/1:11)",
+       "ZANNOTATIONCREATIONDATE": 725760000.5, "ZANNOTATIONDELETED": 0, "ZTITLE": "Test Book"},
+      {"Z_PK": 8, "ZANNOTATIONSELECTEDTEXT": "Gone", "ZANNOTATIONDELETED": 1},
+      {"Z_PK": 9, "ZANNOTATIONSELECTEDTEXT": "Elsewhere", "ZANNOTATIONLOCATION": "not a cfi", "ZANNOTATIONNOTE": ""},
+      {"Z_PK": 10, "ZANNOTATIONSELECTEDTEXT": "Hello world",
+       "ZANNOTATIONLOCATION": "epubcfi(/6/4[chapter]!/4/2/1,:0,:11)"}
+    ]"#;
+    let entries = parse(rows).unwrap();
+    assert_eq!(entries.len(), 3);
+
+    let entry = &entries[0];
+    assert_eq!(entry.origin, "Apple Books annotation 7");
+    assert_eq!(entry.book.title.as_deref(), Some("Test Book"));
+    assert_eq!(entry.text.as_deref(), Some("Hello world"));
+    assert_eq!(entry.note.as_deref(), Some("A note"));
+    assert_eq!(entry.created.as_deref(), Some("2024-01-01T00:00:00Z"));
+    assert_eq!(
+      entry.cfi.as_ref().map(ToString::to_string).as_deref(),
+      Some("epubcfi(/6/2[chapter]!/4/2,/1:0,/1:11)")
+    );
+
+    assert_eq!(entries[1].cfi, None);
+    assert_eq!(entries[1].note, None);
+
+    // Apple Books leaves out the steps of a range's paths when they select a single text node.
+    let cfi = entries[2].cfi.as_ref().unwrap();
+    assert_eq!(cfi.range.as_ref().unwrap().from.components, Vec::new());
+    assert_eq!(cfi.to_string(), "epubcfi(/6/4[chapter]!/4/2/1,:0,:11)");
+    assert!(parse("{}").is_err());
+  }
+}
@@ -0,0 +1,105 @@
+//! Annotations exported from the calibre E-book viewer.
+//!
+//! The viewer exports `{"type": "calibre_annotation_collection", "annotations": [...]}`, where
+//! each highlight has CFIs relative to its content document and the index of that document in
+//! the spine. Bookmarks only record a scroll position, so they are not imported.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use super::{Book, Entry};
+use crate::cfi;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Export {
+  Collection { annotations: Vec<Highlight> },
+  Annotations(Vec<Highlight>),
+}
+
+#[derive(Deserialize)]
+struct Highlight {
+  #[serde(rename = "type")]
+  kind: String,
+  #[serde(default)]
+  removed: bool,
+  timestamp: Option<String>,
+  start_cfi: Option<String>,
+  end_cfi: Option<String>,
+  highlighted_text: Option<String>,
+  notes: Option<String>,
+  spine_index: Option<usize>,
+}
+
+pub(super) fn parse(input: &str) -> Result<Vec<Entry>> {
+  let export = serde_json::from_str::<Export>(input)
+    .context("calibre export is not a collection of annotations")?;
+  let (Export::Collection { annotations } | Export::Annotations(annotations)) = export;
+  let entries = annotations
+    .into_iter()
+    .enumerate()
+    .filter(|(_, highlight)| highlight.kind == "highlight" && !highlight.removed)
+    .map(|(i, highlight)| {
+      let non_empty = |s: Option<String>| s.filter(|s| !s.trim().is_empty());
+      let cfi = match (
+        &highlight.start_cfi,
+        &highlight.end_cfi,
+        highlight.spine_index,
+      ) {
+        (Some(start), Some(end), Some(spine_index)) => fragment(spine_index, start, end),
+        _ => None,
+      };
+      Entry {
+        origin: format!("calibre annotation {}", i + 1),
+        book: Book::default(),
+        text: non_empty(highlight.highlighted_text),
+        note: non_empty(highlight.notes),
+        created: highlight.timestamp,
+        cfi,
+        spine_index: highlight.spine_index,
+      }
+    })
+    .collect();
+  Ok(entries)
+}
+
+/// Builds a CFI range from calibre's CFIs, which start at the document node, e.g. `/2/4/2/1:0`.
+///
+/// The spine is assumed to be the package's third child, and [`super::import`] corrects the steps
+/// into the document once the package is known.
+fn fragment(spine_index: usize, start: &str, end: &str) -> Option<cfi::Fragment> {
+  // The first step selects the root <html> element, where a CFI's indirection already points.
+  let start = start.strip_prefix("/2")?;
+  let end = end.strip_prefix("/2")?;
+  let spine_step = 2 * (spine_index + 1);
+  cfi::Fragment::parse(&format!("epubcfi(/6/{spine_step}!,{start},{end})")).ok()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    let export = r#"{"type": "calibre_annotation_collection", "version": 1, "annotations": [
+      {"type": "highlight", "uuid": "a", "timestamp": "2024-01-01T00:00:00.000Z",
+       "start_cfi": "/2/4/2/1:0", "end_cfi": "/2/4/2/1:5", "highlighted_text": "Hello",
+       "notes": "A note", "spine_index": 0, "spine_name": "EPUB/chapter.xhtml"},
+      {"type": "bookmark", "title": "Bookmark", "pos": "epubcfi(/2/4/2/1:0)", "pos_type": "epubcfi"},
+      {"type": "highlight", "uuid": "b", "removed": true}
+    ]}"#;
+    let entries = parse(export).unwrap();
+    assert_eq!(entries.len(), 1);
+    let entry = &entries[0];
+    assert_eq!(entry.text.as_deref(), Some("Hello"));
+    assert_eq!(entry.note.as_deref(), Some("A note"));
+    assert_eq!(entry.created.as_deref(), Some("2024-01-01T00:00:00.000Z"));
+    assert_eq!(
+      entry.cfi.as_ref().map(ToString::to_string).as_deref(),
+      Some("epubcfi(/6/2!,/4/2/1:0,/4/2/1:5)")
+    );
+
+    assert_eq!(parse("[]").unwrap(), []);
+    assert!(parse("{}").is_err());
+  }
+}
@@ -0,0 +1,273 @@
+//! Kindle's `My Clippings.txt`.
+//!
+//! Each clipping is a title line, a details line, a blank line and the clipped text,
+//! followed by a line of `=` signs:
+//!
+//! ```text
+//! Test Book (Test Author)
+//! - Your Highlight on page 5 | Location 73-74 | Added on Monday, January 1, 2024 12:00:00 AM
+//!
+//! Hello world
+//! ==========
+//! ```
+//!
+//! Notes are separate clippings at the end location of the highlight they comment on.
+
+use super::{Book, Entry};
+
+const SEPARATOR: &str = "==========";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+  Highlight,
+  Note,
+  Bookmark,
+}
+
+struct Clipping {
+  header: String,
+  kind: Kind,
+  /// The first and last Kindle location of the clipping.
+  location: Option<(u32, u32)>,
+  entry: Entry,
+}
+
+pub(super) fn parse(input: &str) -> Vec<Entry> {
+  let mut clippings: Vec<Clipping> = Vec::new();
+  for (i, chunk) in input.split(SEPARATOR).enumerate() {
+    let mut lines = chunk
+      .lines()
+      .map(|line| line.trim_start_matches('\u{feff}').trim_end())
+      .skip_while(|line| line.is_empty());
+    let Some(header) = lines.next() else {
+      continue;
+    };
+    let details = lines.next().unwrap_or_default();
+    let content = lines.collect::<Vec<_>>().join("\n").trim().to_string();
+    let content = (!content.is_empty()).then_some(content);
+
+    let lower = details.to_lowercase();
+    let kind = if lower.contains("bookmark") {
+      Kind::Bookmark
+    } else if lower.contains("note") {
+      Kind::Note
+    } else {
+      Kind::Highlight
+    };
+    let (text, note) = match kind {
+      Kind::Highlight => (content, None),
+      Kind::Note => (None, content),
+      Kind::Bookmark => (None, None),
+    };
+
+    clippings.push(Clipping {
+      header: header.to_string(),
+      kind,
+      location: location(&lower),
+      entry: Entry {
+        origin: format!("Kindle clipping {}", i + 1),
+        book: book(header),
+        text,
+        note,
+        created: details
+          .split_once("Added on ")
+          .and_then(|(_, date)| timestamp(date)),
+        cfi: None,
+        spine_index: None,
+      },
+    });
+  }
+
+  // Attach each note to the highlight in the same book which ends where the note is.
+  let mut attached = vec![false; clippings.len()];
+  for i in 0..clippings.len() {
+    if clippings[i].kind != Kind::Note {
+      continue;
+    }
+    let Some((note_location, _)) = clippings[i].location else {
+      continue;
+    };
+    let highlight = (0..clippings.len()).rev().find(|&j| {
+      let clipping = &clippings[j];
+      clipping.kind == Kind::Highlight
+        && clipping.header == clippings[i].header
+        && clipping.entry.note.is_none()
+        && clipping
+          .location
+          .is_some_and(|(start, end)| start <= note_location && note_location <= end)
+    });
+    if let Some(j) = highlight {
+      clippings[j].entry.note = clippings[i].entry.note.take();
+      attached[i] = true;
+    }
+  }
+
+  clippings
+    .into_iter()
+    .zip(attached)
+    .filter(|(_, attached)| !attached)
+    .map(|(clipping, _)| clipping.entry)
+    .collect()
+}
+
+/// Splits a title line like `Title (Author One;Author Two)` into its parts.
+fn book(header: &str) -> Book {
+  let header = header.trim();
+  let (title, authors) = match header
+    .strip_suffix(')')
+    .and_then(|rest| rest.rsplit_once(" ("))
+  {
+    Some((title, authors)) => (title, authors.split(';').map(str::trim).collect()),
+    None => (header, Vec::new()),
+  };
+  Book {
+    title: Some(title.trim().to_string()),
+    authors: authors
+      .into_iter()
+      .filter(|author: &&str| !author.is_empty())
+      .map(String::from)
+      .collect(),
+    identifiers: Vec::new(),
+  }
+}
+
+/// Finds a location like `Location 73-74` or `location 73` in a lowercased details line.
+fn location(details: &str) -> Option<(u32, u32)> {
+  let (_, rest) = details.split_once("location ")?;
+  let range = rest.split(|c: char| c.is_whitespace() || c == '|').next()?;
+  let (start, end) = range.split_once('-').unwrap_or((range, range));
+  let start = start.parse().ok()?;
+  // Kindle abbreviates the end of a range, e.g. `1234-56` for 1234 to 1256.
+  let end = match end.parse::<u32>().ok()? {
+    end if end >= start => end,
+    end => {
+      let digits = end.to_string().len();
+      let scale = 10u32.checked_pow(u32::try_from(digits).ok()?)?;
+      start - start % scale + end
+    }
+  };
+  Some((start, end))
+}
+
+const MONTHS: [&str; 12] = [
+  "january",
+  "february",
+  "march",
+  "april",
+  "may",
+  "june",
+  "july",
+  "august",
+  "september",
+  "october",
+  "november",
+  "december",
+];
+
+/// Converts a date like `Monday, January 1, 2024 12:00:00 AM` into an `xsd:dateTime`.
+///
+/// Kindle writes dates in the device's local time, so the result has no time zone.
+fn timestamp(date: &str) -> Option<String> {
+  let date = date.split_once(", ").map_or(date, |(_, date)| date);
+  let tokens = date
+    .split(|c: char| c.is_whitespace() || c == ',')
+    .filter(|token| !token.is_empty())
+    .collect::<Vec<_>>();
+  let month = |name: &str| {
+    let name = name.to_lowercase();
+    MONTHS
+      .iter()
+      .position(|month| *month == name)
+      .map(|i| i + 1)
+  };
+  // US devices write `January 1, 2024`, and others write `1 January 2024`.
+  let (month, day, rest) = match tokens.as_slice() {
+    [name, day, rest @ ..] if month(name).is_some() => {
+      (month(name)?, day.parse::<u32>().ok()?, rest)
+    }
+    [day, name, rest @ ..] => (month(name)?, day.parse::<u32>().ok()?, rest),
+    _ => return None,
+  };
+  let [year, time, meridiem @ ..] = rest else {
+    return None;
+  };
+  let year = year.parse::<u32>().ok()?;
+
+  let mut parts = time.split(':').map(str::parse::<u32>);
+  let mut hour = parts.next()?.ok()?;
+  let minute = parts.next()?.ok()?;
+  let second = parts.next().unwrap_or(Ok(0)).ok()?;
+  match meridiem.first().map(|m| m.to_uppercase()).as_deref() {
+    Some("AM") if hour == 12 => hour = 0,
+    Some("PM") if hour < 12 => hour += 12,
+    _ => {}
+  }
+  Some(format!(
+    "{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}"
+  ))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const CLIPPINGS: &str = "\u{feff}Test Book (Test Author)
+- Your Highlight on page 1 | Location 10-12 | Added on Monday, January 1, 2024 12:05:30 PM
+
+Hello world
+==========
+Test Book (Test Author)
+- Your Note on page 1 | Location 12 | Added on Monday, January 1, 2024 12:06:00 PM
+
+A note on the highlight
+==========
+Other Book: A Novel (Someone Else;Another Person)
+- Your Bookmark on Location 40 | Added on Tuesday, 2 January 2024 18:00:00
+
+==========
+";
+
+  #[test]
+  fn test_parse() {
+    let entries = parse(CLIPPINGS);
+    assert_eq!(entries.len(), 2);
+
+    let highlight = &entries[0];
+    assert_eq!(highlight.origin, "Kindle clipping 1");
+    assert_eq!(highlight.book.title.as_deref(), Some("Test Book"));
+    assert_eq!(highlight.book.authors, ["Test Author"]);
+    assert_eq!(highlight.text.as_deref(), Some("Hello world"));
+    assert_eq!(highlight.note.as_deref(), Some("A note on the highlight"));
+    assert_eq!(highlight.created.as_deref(), Some("2024-01-01T12:05:30"));
+
+    let bookmark = &entries[1];
+    assert_eq!(bookmark.origin, "Kindle clipping 3");
+    assert_eq!(bookmark.book.title.as_deref(), Some("Other Book: A Novel"));
+    assert_eq!(bookmark.book.authors, ["Someone Else", "Another Person"]);
+    assert_eq!(bookmark.text, None);
+    assert_eq!(bookmark.created.as_deref(), Some("2024-01-02T18:00:00"));
+  }
+
+  #[test]
+  fn test_location() {
+    assert_eq!(
+      location("- your highlight at location 73-74 |"),
+      Some((73, 74))
+    );
+    assert_eq!(
+      location("- your highlight on location 1234-56"),
+      Some((1234, 1256))
+    );
+    assert_eq!(location("- your note on location 9 | added"), Some((9, 9)));
+    assert_eq!(location("- your highlight on page 4"), None);
+  }
+
+  #[test]
+  fn test_timestamp() {
+    assert_eq!(
+      timestamp("Sunday, December 31, 2023 12:00:00 AM").as_deref(),
+      Some("2023-12-31T00:00:00")
+    );
+    assert_eq!(timestamp("not a date"), None);
+  }
+}
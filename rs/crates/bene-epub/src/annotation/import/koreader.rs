@@ -0,0 +1,147 @@
+//! `KOReader`'s sidecar files, e.g. `book.sdr/metadata.epub.lua`.
+//!
+//! Recent versions keep highlights in an `annotations` array. Older versions keep them in a
+//! `highlight` table keyed by page, which is read when there is no `annotations` array.
+
+use anyhow::{Result, bail};
+use serde_json::Value;
+
+use super::{Book, Entry, lua};
+
+pub(super) fn parse(input: &str) -> Result<Vec<Entry>> {
+  let sidecar = lua::parse(input)?;
+  let Value::Object(sidecar) = sidecar else {
+    bail!("KOReader sidecar is not a table");
+  };
+
+  let props = sidecar.get("doc_props");
+  let prop = |name: &str| {
+    props
+      .and_then(|props| props.get(name))
+      .and_then(Value::as_str)
+      .map(str::trim)
+      .filter(|value| !value.is_empty())
+  };
+  // Multiple authors and identifiers are separated by newlines.
+  let list = |name: &str| {
+    prop(name)
+      .map(|value| value.lines().map(|line| line.trim().to_string()).collect())
+      .unwrap_or_default()
+  };
+  let book = Book {
+    title: prop("title").map(String::from),
+    authors: list("authors"),
+    identifiers: list("identifiers"),
+  };
+
+  let items: Vec<&Value> = match (sidecar.get("annotations"), sidecar.get("highlight")) {
+    (Some(Value::Array(annotations)), _) => annotations.iter().collect(),
+    (_, Some(Value::Object(pages))) => pages
+      .values()
+      .flat_map(|highlights| match highlights {
+        Value::Array(highlights) => highlights.iter().collect(),
+        Value::Object(highlights) => highlights.values().collect(),
+        _ => Vec::new(),
+      })
+      .collect(),
+    (_, Some(Value::Array(pages))) => pages.iter().filter_map(Value::as_array).flatten().collect(),
+    _ => Vec::new(),
+  };
+
+  let entries = items
+    .into_iter()
+    .enumerate()
+    .filter_map(|(i, item)| {
+      let field = |name: &str| {
+        item
+          .get(name)
+          .and_then(Value::as_str)
+          .map(str::trim)
+          .filter(|value| !value.is_empty())
+      };
+      // Entries without text are page bookmarks, which have no precise location to import.
+      let text = field("text")?;
+      Some(Entry {
+        origin: format!("KOReader annotation {}", i + 1),
+        book: book.clone(),
+        text: Some(text.to_string()),
+        note: field("note").map(String::from),
+        created: field("datetime").map(|datetime| datetime.replacen(' ', "T", 1)),
+        cfi: None,
+        spine_index: field("pos0").and_then(spine_index),
+      })
+    })
+    .collect();
+  Ok(entries)
+}
+
+/// Reads the spine index from a position like `/body/DocFragment[3]/body/p[2]/text().5`.
+fn spine_index(xpointer: &str) -> Option<usize> {
+  let (_, rest) = xpointer.split_once("DocFragment[")?;
+  let (index, _) = rest.split_once(']')?;
+  index.parse::<usize>().ok()?.checked_sub(1)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    let sidecar = r#"-- we can read Lua syntax here!
+return {
+    ["annotations"] = {
+        [1] = {
+            ["chapter"] = "The Chapter",
+            ["datetime"] = "2024-01-01 12:00:00",
+            ["note"] = "A note",
+            ["pos0"] = "/body/DocFragment[1]/body/p[1]/text().0",
+            ["pos1"] = "/body/DocFragment[1]/body/p[1]/text().11",
+            ["text"] = "Hello world",
+        },
+        [2] = {
+            ["datetime"] = "2024-01-02 12:00:00",
+            ["page"] = "/body/DocFragment[1]/body/p[2]/text().0",
+        },
+    },
+    ["doc_props"] = {
+        ["authors"] = "Test Author\nSomeone Else",
+        ["identifiers"] = "urn:test:book",
+        ["title"] = "Test Book",
+    },
+}"#;
+    let entries = parse(sidecar).unwrap();
+    assert_eq!(
+      entries,
+      [Entry {
+        origin: "KOReader annotation 1".into(),
+        book: Book {
+          title: Some("Test Book".into()),
+          authors: vec!["Test Author".into(), "Someone Else".into()],
+          identifiers: vec!["urn:test:book".into()],
+        },
+        text: Some("Hello world".into()),
+        note: Some("A note".into()),
+        created: Some("2024-01-01T12:00:00".into()),
+        cfi: None,
+        spine_index: Some(0),
+      }]
+    );
+  }
+
+  #[test]
+  fn test_parse_legacy() {
+    let sidecar = r#"return {
+    ["highlight"] = {
+        [4] = {
+            [1] = { ["datetime"] = "2020-05-06 07:08:09", ["text"] = "this is a test", ["pos0"] = "/body/DocFragment[2]/body/p/text().13" },
+        },
+    },
+}"#;
+    let entries = parse(sidecar).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].text.as_deref(), Some("this is a test"));
+    assert_eq!(entries[0].spine_index, Some(1));
+    assert_eq!(entries[0].book, Book::default());
+  }
+}
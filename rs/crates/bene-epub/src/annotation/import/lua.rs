@@ -0,0 +1,322 @@
+//! A reader for the Lua table literals that `KOReader` writes to its sidecar files.
+
+use anyhow::{Context, Result, bail, ensure};
+use serde_json::{Map, Number, Value};
+
+/// Parses a chunk of the form `return <value>` into JSON.
+///
+/// Tables whose keys are exactly `1..=n` become arrays, and all other tables become objects.
+pub(super) fn parse(input: &str) -> Result<Value> {
+  let mut parser = Parser { input, pos: 0 };
+  parser.skip_trivia();
+  if parser.rest().starts_with("return") {
+    parser.pos += "return".len();
+  }
+  let value = parser.value()?;
+  parser.skip_trivia();
+  ensure!(
+    parser.rest().is_empty(),
+    "Unexpected input at byte {}",
+    parser.pos
+  );
+  Ok(value)
+}
+
+struct Parser<'a> {
+  input: &'a str,
+  pos: usize,
+}
+
+impl Parser<'_> {
+  fn rest(&self) -> &str {
+    &self.input[self.pos..]
+  }
+
+  fn peek(&self) -> Option<char> {
+    self.rest().chars().next()
+  }
+
+  fn eat(&mut self, c: char) -> bool {
+    let eaten = self.peek() == Some(c);
+    if eaten {
+      self.pos += c.len_utf8();
+    }
+    eaten
+  }
+
+  fn expect(&mut self, c: char) -> Result<()> {
+    ensure!(self.eat(c), "Expected `{c}` at byte {}", self.pos);
+    Ok(())
+  }
+
+  /// Skips whitespace and comments.
+  fn skip_trivia(&mut self) {
+    loop {
+      let rest = self.rest();
+      let whitespace = rest.len() - rest.trim_start().len();
+      self.pos += whitespace;
+      if !self.rest().starts_with("--") {
+        return;
+      }
+      self.pos += 2;
+      match self.long_bracket_level() {
+        Some(level) => {
+          let _ = self.long_string(level);
+        }
+        None => {
+          let line = self.rest().find('\n').unwrap_or(self.rest().len());
+          self.pos += line;
+        }
+      }
+    }
+  }
+
+  fn value(&mut self) -> Result<Value> {
+    self.skip_trivia();
+    match self.peek() {
+      Some('{') => self.table(),
+      Some('"' | '\'') => Ok(Value::String(self.quoted_string()?)),
+      Some('[') => {
+        let level = self
+          .long_bracket_level()
+          .with_context(|| format!("Unexpected `[` at byte {}", self.pos))?;
+        Ok(Value::String(self.long_string(level)?))
+      }
+      Some(c) if c == '-' || c == '.' || c.is_ascii_digit() => self.number(),
+      _ => {
+        let name = self.name();
+        match name {
+          "true" => Ok(Value::Bool(true)),
+          "false" => Ok(Value::Bool(false)),
+          "nil" => Ok(Value::Null),
+          _ => bail!("Unexpected input at byte {}", self.pos),
+        }
+      }
+    }
+  }
+
+  fn name(&mut self) -> &str {
+    let start = self.pos;
+    let len = self
+      .rest()
+      .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+      .unwrap_or(self.rest().len());
+    self.pos += len;
+    &self.input[start..self.pos]
+  }
+
+  fn table(&mut self) -> Result<Value> {
+    self.expect('{')?;
+    let mut fields = Vec::new();
+    let mut next_index = 1;
+    loop {
+      self.skip_trivia();
+      if self.eat('}') {
+        break;
+      }
+
+      let key = if self.rest().starts_with('[') && self.long_bracket_level().is_none() {
+        // An explicit key, e.g. `["title"] = ...` or `[1] = ...`.
+        self.pos += 1;
+        let key = self.value()?;
+        self.skip_trivia();
+        self.expect(']')?;
+        self.skip_trivia();
+        self.expect('=')?;
+        key
+      } else {
+        // A name key, e.g. `title = ...`, or else a positional value.
+        let start = self.pos;
+        let name = self.name().to_string();
+        self.skip_trivia();
+        if !name.is_empty() && self.rest().starts_with('=') && !self.rest().starts_with("==") {
+          self.pos += 1;
+          Value::String(name)
+        } else {
+          self.pos = start;
+          next_index += 1;
+          Value::from(next_index - 1)
+        }
+      };
+      let value = self.value()?;
+      fields.push((key, value));
+
+      self.skip_trivia();
+      if !self.eat(',') && !self.eat(';') {
+        self.skip_trivia();
+        self.expect('}')?;
+        break;
+      }
+    }
+    Ok(table_value(fields))
+  }
+
+  fn quoted_string(&mut self) -> Result<String> {
+    let start = self.pos;
+    let quote = self.peek().context("Expected a string")?;
+    self.pos += 1;
+    let mut s = String::new();
+    loop {
+      let c = self
+        .peek()
+        .with_context(|| format!("Unterminated string at byte {start}"))?;
+      self.pos += c.len_utf8();
+      match c {
+        c if c == quote => return Ok(s),
+        '\\' => {
+          let escape = self
+            .peek()
+            .with_context(|| format!("Unterminated string at byte {start}"))?;
+          self.pos += escape.len_utf8();
+          match escape {
+            'n' | '\n' => s.push('\n'),
+            't' => s.push('\t'),
+            'r' => s.push('\r'),
+            'a' => s.push('\u{7}'),
+            'b' => s.push('\u{8}'),
+            'f' => s.push('\u{c}'),
+            'v' => s.push('\u{b}'),
+            'x' => {
+              let hex = self.rest().get(..2).context("Invalid `\\x` escape")?;
+              s.push(char::from(u8::from_str_radix(hex, 16)?));
+              self.pos += 2;
+            }
+            'z' => {
+              let rest = self.rest();
+              let whitespace = rest.len() - rest.trim_start().len();
+              self.pos += whitespace;
+            }
+            c if c.is_ascii_digit() => {
+              let len = self
+                .rest()
+                .chars()
+                .take(2)
+                .take_while(char::is_ascii_digit)
+                .count();
+              let digits = &self.input[self.pos - 1..self.pos + len];
+              self.pos += len;
+              s.push(char::from(digits.parse::<u8>()?));
+            }
+            c => s.push(c),
+          }
+        }
+        c => s.push(c),
+      }
+    }
+  }
+
+  /// Returns the level of the long bracket (e.g. 1 for `[=[`) at the current position, if any.
+  fn long_bracket_level(&self) -> Option<usize> {
+    let rest = self.rest().strip_prefix('[')?;
+    let level = rest.chars().take_while(|c| *c == '=').count();
+    rest[level..].starts_with('[').then_some(level)
+  }
+
+  fn long_string(&mut self, level: usize) -> Result<String> {
+    let start = self.pos;
+    self.pos += level + 2;
+    let close = format!("]{}]", "=".repeat(level));
+    let len = self
+      .rest()
+      .find(&close)
+      .with_context(|| format!("Unterminated long string at byte {start}"))?;
+    let s = &self.rest()[..len];
+    // A newline immediately after the opening bracket is skipped.
+    let s = s.strip_prefix('\n').unwrap_or(s).to_string();
+    self.pos += len + close.len();
+    Ok(s)
+  }
+
+  fn number(&mut self) -> Result<Value> {
+    let start = self.pos;
+    let len = self
+      .rest()
+      .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+')))
+      .unwrap_or(self.rest().len());
+    let s = &self.input[start..start + len];
+    self.pos += len;
+    let error = || format!("Invalid number `{s}` at byte {start}");
+    if let Ok(n) = s.parse::<i64>() {
+      return Ok(Value::from(n));
+    }
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+      return Ok(Value::from(
+        i64::from_str_radix(hex, 16).with_context(error)?,
+      ));
+    }
+    let n = s.parse::<f64>().with_context(error)?;
+    Ok(Number::from_f64(n).map_or(Value::Null, Value::Number))
+  }
+}
+
+/// Converts the fields of a table into an array if its keys are `1..=n`, or an object otherwise.
+fn table_value(fields: Vec<(Value, Value)>) -> Value {
+  let mut indices = fields
+    .iter()
+    .map(|(key, _)| key.as_u64())
+    .collect::<Option<Vec<_>>>();
+  if let Some(indices) = &mut indices {
+    indices.sort_unstable();
+    if indices.iter().copied().eq(1..=fields.len() as u64) {
+      let mut fields = fields;
+      fields.sort_by_key(|(key, _)| key.as_u64());
+      return Value::Array(fields.into_iter().map(|(_, value)| value).collect());
+    }
+  }
+
+  let object = fields
+    .into_iter()
+    .map(|(key, value)| {
+      let key = match key {
+        Value::String(s) => s,
+        key => key.to_string(),
+      };
+      (key, value)
+    })
+    .collect::<Map<_, _>>();
+  Value::Object(object)
+}
+
+#[cfg(test)]
+mod test {
+  use serde_json::json;
+
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    let input = r#"-- we can read Lua syntax here!
+return {
+    ["annotations"] = {
+        [2] = { ["text"] = "second", },
+        [1] = { ["text"] = "first\n\"line\"\65", ["pos0"] = 3.5, },
+    },
+    ["highlight"] = {
+        [12] = { "a", 'b'; [[c]] },
+    },
+    plain = true, --[[ a long
+    comment ]] other = nil,
+    [ [==[key]==] ] = -7,
+}"#;
+    assert_eq!(
+      parse(input).unwrap(),
+      json!({
+        "annotations": [
+          {"text": "first\n\"line\"A", "pos0": 3.5},
+          {"text": "second"},
+        ],
+        "highlight": {"12": ["a", "b", "c"]},
+        "plain": true,
+        "other": null,
+        "key": -7,
+      })
+    );
+  }
+
+  #[test]
+  fn test_parse_errors() {
+    assert!(parse("return { [1] = }").is_err());
+    assert!(parse("return { \"unterminated }").is_err());
+    assert!(parse("return {} extra").is_err());
+  }
+}
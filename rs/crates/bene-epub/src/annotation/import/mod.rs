@@ -0,0 +1,470 @@
+//! Importing highlights and notes exported by other reading systems.
+//!
+//! Each [`Source`] parses its export format into [`Entry`]s, which [`import`] then matches to a
+//! publication and anchors into [`Annotation`]s. Exports rarely carry usable locations, so entries
+//! are anchored by their highlighted text when their location is missing or does not match.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use super::{Annotation, Body, Diagnostic, Target, anchor::Anchorer, quote_targets, raw};
+use crate::{Archive, MetaField, Rendition, ZipFormat, cfi};
+
+mod apple_books;
+mod calibre;
+mod kindle;
+mod koreader;
+mod lua;
+
+/// A reading system whose exported annotations can be imported.
+#[derive(Serialize, Deserialize, TS, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[ts(export)]
+pub enum Source {
+  /// Kindle's `My Clippings.txt`.
+  Kindle,
+  /// A `KOReader` sidecar file, e.g. `metadata.epub.lua`.
+  Koreader,
+  /// Rows of Apple Books' `ZAEANNOTATION` table as JSON, e.g. from `sqlite3 -json`.
+  AppleBooks,
+  /// Annotations exported from the calibre E-book viewer.
+  Calibre,
+}
+
+impl Source {
+  /// Parses an export from this source into entries.
+  ///
+  /// # Errors
+  /// If `input` is not in the source's export format.
+  pub fn parse(self, input: &str) -> Result<Vec<Entry>> {
+    match self {
+      Source::Kindle => Ok(kindle::parse(input)),
+      Source::Koreader => koreader::parse(input),
+      Source::AppleBooks => apple_books::parse(input),
+      Source::Calibre => calibre::parse(input),
+    }
+  }
+
+  fn id(self) -> &'static str {
+    match self {
+      Source::Kindle => "kindle",
+      Source::Koreader => "koreader",
+      Source::AppleBooks => "apple-books",
+      Source::Calibre => "calibre",
+    }
+  }
+}
+
+impl FromStr for Source {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    Ok(match s.to_ascii_lowercase().replace('_', "-").as_str() {
+      "kindle" => Source::Kindle,
+      "koreader" => Source::Koreader,
+      "apple-books" | "applebooks" | "ibooks" => Source::AppleBooks,
+      "calibre" => Source::Calibre,
+      _ => bail!(
+        "Unknown import source `{s}`, expected one of: kindle, koreader, apple-books, calibre"
+      ),
+    })
+  }
+}
+
+impl fmt::Display for Source {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Source::Kindle => "Kindle",
+      Source::Koreader => "KOReader",
+      Source::AppleBooks => "Apple Books",
+      Source::Calibre => "Calibre",
+    })
+  }
+}
+
+/// The publication an entry was made in, as far as the export describes it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Book {
+  pub title: Option<String>,
+  pub authors: Vec<String>,
+  pub identifiers: Vec<String>,
+}
+
+impl Book {
+  /// Returns whether the book could be the publication of `rendition`.
+  ///
+  /// A shared identifier is a match. Otherwise the titles must agree, ignoring case, punctuation
+  /// and subtitles, and the authors must share a name if both are known. A book without a title
+  /// matches any publication.
+  pub fn matches(&self, rendition: &Rendition) -> bool {
+    let fields = &rendition.package.metadata.fields;
+    let identifiers = fields
+      .iter()
+      .filter_map(|field| match field {
+        MetaField::Identifier(identifier) => Some(identifier_key(identifier)),
+        _ => None,
+      })
+      .collect::<Vec<_>>();
+    if self
+      .identifiers
+      .iter()
+      .map(|identifier| identifier_key(identifier))
+      .any(|key| !key.is_empty() && identifiers.contains(&key))
+    {
+      return true;
+    }
+
+    let Some(title) = &self.title else {
+      return true;
+    };
+    let title = words(title);
+    let title_matches = fields.iter().any(|field| match field {
+      MetaField::Title(other) => same_title(&title, &words(other)),
+      _ => false,
+    });
+
+    let creators = fields
+      .iter()
+      .filter_map(|field| match field {
+        MetaField::Creator(creator) => Some(words(creator)),
+        _ => None,
+      })
+      .collect::<Vec<_>>();
+    let author_matches = self.authors.is_empty()
+      || creators.is_empty()
+      || self.authors.iter().any(|author| {
+        words(author)
+          .split(' ')
+          .filter(|name| name.len() > 1)
+          .any(|name| {
+            creators
+              .iter()
+              .any(|creator| creator.split(' ').any(|other| other == name))
+          })
+      });
+
+    title_matches && author_matches
+  }
+}
+
+/// A highlight, note or bookmark read from an export.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+  /// Where the entry came from in the export, for reporting problems, e.g. `Kindle clipping 3`.
+  pub origin: String,
+  pub book: Book,
+  /// The highlighted text.
+  pub text: Option<String>,
+  /// The reader's note on the highlight.
+  pub note: Option<String>,
+  /// When the entry was created, as an `xsd:dateTime`.
+  pub created: Option<String>,
+  /// The location of the entry, if the export has an EPUB CFI for it.
+  pub cfi: Option<cfi::Fragment>,
+  /// The spine index of the content document containing the entry, if known.
+  pub spine_index: Option<usize>,
+}
+
+impl Entry {
+  /// Derives a stable ID, so that importing the same export twice yields the same annotations.
+  fn id(&self, source: Source) -> String {
+    // 64-bit FNV-1a, which is stable across platforms and releases unlike `DefaultHasher`.
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    let cfi = self.cfi.as_ref().map(ToString::to_string);
+    let spine_index = self.spine_index.map(|index| index.to_string());
+    for part in [&self.text, &self.created, &cfi, &spine_index] {
+      for byte in part.as_deref().unwrap_or_default().bytes().chain([0]) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+      }
+    }
+    format!("urn:bene:import:{}:{hash:016x}", source.id())
+  }
+}
+
+/// The result of importing entries into a publication.
+#[derive(Clone, Debug, Default)]
+pub struct Import {
+  pub annotations: Vec<Annotation>,
+  /// Entries for the publication which could not be anchored in it.
+  pub unanchored: Vec<Diagnostic>,
+  /// The number of entries skipped because they belong to other publications.
+  pub other_publications: usize,
+}
+
+/// Anchors the `entries` that belong to the publication of `rendition` into annotations.
+///
+/// An entry's CFI is used if it points to its highlighted text. Otherwise the highlighted text
+/// is searched for, first in the entry's content document and then in the whole publication.
+pub fn import<F: ZipFormat>(
+  archive: &mut Archive<F>,
+  rendition: &Rendition,
+  source: Source,
+  entries: Vec<Entry>,
+) -> Import {
+  let mut anchorer = Anchorer::new(archive, rendition);
+  let mut import = Import::default();
+  for entry in entries {
+    if !entry.book.matches(rendition) {
+      import.other_publications += 1;
+      continue;
+    }
+
+    let id = entry.id(source);
+    match anchor(&mut anchorer, rendition, &entry) {
+      Ok(selector) => {
+        let mut annotation = annotation(id, entry, selector);
+        quote_targets(&mut anchorer, &mut annotation.targets);
+        import.annotations.push(annotation);
+      }
+      Err(err) => import.unanchored.push(Diagnostic {
+        annotation: id,
+        message: format!("{}: {err:#}", entry.origin),
+      }),
+    }
+  }
+  import
+}
+
+fn anchor<F: ZipFormat>(
+  anchorer: &mut Anchorer<'_, F>,
+  rendition: &Rendition,
+  entry: &Entry,
+) -> Result<cfi::Fragment> {
+  let mut spine_index = entry.spine_index;
+  if let Some(fragment) = &entry.cfi {
+    match anchorer.resolve(fragment) {
+      Ok((document, range)) => {
+        let matches = entry.text.as_deref().is_none_or(|text| {
+          collapse_whitespace(&document.text.text[range.clone()]) == collapse_whitespace(text)
+        });
+        if matches {
+          return if range.is_empty() {
+            Ok(anchorer.with_spine_path(fragment, document.spine_index))
+          } else {
+            anchorer.fragment(&document, range)
+          };
+        }
+        spine_index = Some(document.spine_index);
+      }
+      Err(err) if entry.text.is_none() => return Err(err),
+      Err(_) => {}
+    }
+  }
+
+  let text = entry
+    .text
+    .as_ref()
+    .context("Entry has neither highlighted text nor a location")?;
+  let selector = raw::Selector::TaggedSelector(raw::TaggedSelector::TextQuoteSelector(
+    raw::TextQuoteSelector {
+      exact: text.clone(),
+      prefix: None,
+      suffix: None,
+    },
+  ));
+  let document = spine_index.and_then(|index| rendition.spine_path(index));
+  if let Some(path) = document
+    && let Ok(fragment) = anchorer.anchor(&path, &selector, None)
+  {
+    return Ok(fragment);
+  }
+  anchorer
+    .anchor(".", &selector, None)
+    .context("Highlighted text was not found in the publication")
+}
+
+fn annotation(id: String, entry: Entry, selector: cfi::Fragment) -> Annotation {
+  let motivation = if entry.note.is_some() {
+    "commenting"
+  } else if entry.text.is_some() {
+    "highlighting"
+  } else {
+    "bookmarking"
+  };
+  Annotation {
+    id,
+    motivation: vec![motivation.into()],
+    created: entry.created,
+    creator: Vec::new(),
+    modified: None,
+    audience: Vec::new(),
+    rights: Vec::new(),
+    targets: vec![Target {
+      selector,
+      quote: None,
+    }],
+    bodies: entry
+      .note
+      .into_iter()
+      .map(|value| Body::Text {
+        value,
+        format: None,
+        language: None,
+        purpose: Vec::new(),
+      })
+      .collect(),
+  }
+}
+
+/// Lowercases `s` and reduces it to its alphanumeric words separated by single spaces.
+fn words(s: &str) -> String {
+  s.split(|c: char| !c.is_alphanumeric())
+    .filter(|word| !word.is_empty())
+    .map(str::to_lowercase)
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+/// Returns whether two titles reduced by [`words`] are equal, or one extends the other with
+/// more words, e.g. a subtitle.
+fn same_title(a: &str, b: &str) -> bool {
+  let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+  !short.is_empty()
+    && long
+      .strip_prefix(short)
+      .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+}
+
+/// Reduces an identifier like `urn:isbn:978-0-00-000000-0` to its alphanumeric value.
+fn identifier_key(identifier: &str) -> String {
+  let value = identifier.rsplit(':').next().unwrap_or(identifier);
+  value
+    .chars()
+    .filter(char::is_ascii_alphanumeric)
+    .collect::<String>()
+    .to_ascii_lowercase()
+}
+
+fn collapse_whitespace(s: &str) -> String {
+  s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{Epub, test_utils};
+
+  fn entry(text: Option<&str>, cfi: Option<&str>) -> Entry {
+    Entry {
+      origin: "test entry".into(),
+      book: Book::default(),
+      text: text.map(String::from),
+      note: None,
+      created: None,
+      cfi: cfi.map(|cfi| cfi::Fragment::parse(cfi).unwrap()),
+      spine_index: None,
+    }
+  }
+
+  #[test]
+  fn test_book_matches() {
+    let mut archive = test_utils::archive(&[]);
+    let epub = Epub::load(&mut archive).unwrap();
+    let rendition = &epub.renditions[0];
+    let book = |title: Option<&str>, authors: &[&str], identifiers: &[&str]| Book {
+      title: title.map(String::from),
+      authors: authors.iter().map(|s| (*s).to_string()).collect(),
+      identifiers: identifiers.iter().map(|s| (*s).to_string()).collect(),
+    };
+
+    assert!(book(None, &[], &[]).matches(rendition));
+    assert!(book(Some("test book"), &[], &[]).matches(rendition));
+    assert!(book(Some("Test Book: A Subtitle"), &["Author, Test"], &[]).matches(rendition));
+    assert!(book(Some("Another Title"), &[], &["test:book"]).matches(rendition));
+    assert!(!book(Some("Test Books"), &[], &[]).matches(rendition));
+    assert!(!book(Some("Test Book"), &["Someone Else"], &[]).matches(rendition));
+  }
+
+  #[test]
+  fn test_import() {
+    let mut archive = test_utils::archive(&[]);
+    let epub = Epub::load(&mut archive).unwrap();
+    let rendition = &epub.renditions[0];
+
+    let mut noted = entry(Some("Hello world"), None);
+    noted.note = Some("A note".into());
+    let mut other_book = entry(Some("Hello world"), None);
+    other_book.book.title = Some("Other Book".into());
+    let entries = vec![
+      noted,
+      // A CFI which points at the wrong text falls back to the quote.
+      entry(
+        Some("emphatic paragraph"),
+        Some("epubcfi(/6/2!/4/2,/1:0,/1:5)"),
+      ),
+      entry(None, Some("epubcfi(/6/2!/4/4/2,/1:0,/1:8)")),
+      entry(Some("Not in the book"), None),
+      entry(None, None),
+      other_book,
+    ];
+    let import = import(&mut archive, rendition, Source::Kindle, entries.clone());
+
+    let cfis = import
+      .annotations
+      .iter()
+      .map(|annotation| annotation.targets[0].selector.to_string())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      cfis,
+      [
+        "epubcfi(/6/2!/4/2,/1:0,/1:11)",
+        "epubcfi(/6/2!/4/4,/2/1:0,/3:10)",
+        "epubcfi(/6/2!/4/4/2,/1:0,/1:8)",
+      ]
+    );
+    let first = &import.annotations[0];
+    assert_eq!(first.motivation, ["commenting"]);
+    assert!(matches!(&first.bodies[..], [Body::Text { value, .. }] if value == "A note"));
+    assert_eq!(import.annotations[1].motivation, ["highlighting"]);
+    assert_eq!(import.annotations[2].motivation, ["bookmarking"]);
+    assert!(first.id.starts_with("urn:bene:import:kindle:"));
+
+    assert_eq!(import.unanchored.len(), 2);
+    assert!(import.unanchored[0].message.starts_with("test entry: "));
+    assert_eq!(import.other_publications, 1);
+
+    // Importing the same entries again yields the same IDs.
+    let again = super::import(&mut archive, rendition, Source::Kindle, entries);
+    assert_eq!(again.annotations[0].id, first.id);
+  }
+
+  #[test]
+  fn test_import_spine_step() {
+    // The guide belongs after the spine, but is sometimes put before it.
+    let package = test_utils::PACKAGE.replace(
+      "<spine>",
+      r#"<guide><reference type="text" href="chapter.xhtml" /></guide>
+  <spine>"#,
+    );
+    let mut archive = test_utils::archive(&[("EPUB/package.opf", &package)]);
+    let epub = Epub::load(&mut archive).unwrap();
+    let rendition = &epub.renditions[0];
+
+    // Importers assume that the spine is the package's third child.
+    let entries = vec![
+      entry(None, Some("epubcfi(/6/2!/4/4/2,/1:0,/1:8)")),
+      entry(None, Some("epubcfi(/6/2!/4/2/1:6)")),
+    ];
+    let import = import(&mut archive, rendition, Source::Calibre, entries);
+    let cfis = import
+      .annotations
+      .iter()
+      .map(|annotation| annotation.targets[0].selector.to_string())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      cfis,
+      ["epubcfi(/8/2!/4/4/2,/1:0,/1:8)", "epubcfi(/8/2!/4/2/1:6)"]
+    );
+  }
+
+  #[test]
+  fn test_source_from_str() {
+    assert_eq!("apple_books".parse::<Source>().unwrap(), Source::AppleBooks);
+    assert_eq!("KOReader".parse::<Source>().unwrap(), Source::Koreader);
+    assert!("kobo".parse::<Source>().is_err());
+  }
+}
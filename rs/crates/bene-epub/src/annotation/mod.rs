@@ -1,86 +1,1196 @@
-use anyhow::{Context, Result, bail, ensure};
-use iref::IriRefBuf;
-use serde::Serialize;
+//! W3C Web Annotations attached to an EPUB via a `ppub:annotations` manifest item.
+
+use std::fmt;
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use iref::{IriRef, IriRefBuf};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
 use ts_rs::TS;
 
+mod anchor;
+mod css;
+pub mod export;
+pub mod hypothesis;
+pub mod import;
 mod raw;
+mod xpath;
 
 #[cfg(test)]
 mod raw_tests;
 
 pub use raw::Annotation as RawAnnotation;
 
-use crate::{annotation::raw::IntoVec, cfi};
+use self::anchor::Anchorer;
+use crate::{Archive, Rendition, ZipFormat, annotation::raw::IntoVec, cfi};
+
+/// The `conformsTo` IRI of a [`raw::FragmentSelector`] containing an EPUB CFI.
+const EPUB_CFI_SPEC: &str = "http://www.idpf.org/epub/linking/cfi/epub-cfi.html";
+
+/// The JSON-LD context of W3C Web Annotations.
+const ANNOTATION_CONTEXT: &str = "http://www.w3.org/ns/anno.jsonld";
 
-#[derive(Serialize, TS, Clone)]
+#[derive(Serialize, Deserialize, TS, Clone, Debug, PartialEq, Eq)]
 #[ts(export)]
 pub struct Annotation {
+  pub id: String,
+  /// Why the annotation was made, e.g. `highlighting` or `commenting`.
+  pub motivation: Vec<String>,
+  /// When the annotation was created, as an `xsd:dateTime`.
+  pub created: Option<String>,
+  pub creator: Vec<Agent>,
+  /// When the annotation was last modified, as an `xsd:dateTime`.
+  pub modified: Option<String>,
+  /// The intended audience of the annotation.
+  pub audience: Vec<Audience>,
+  /// IRIs of the licenses under which the annotation may be used.
+  pub rights: Vec<String>,
+  pub targets: Vec<Target>,
+  pub bodies: Vec<Body>,
+}
+
+impl Annotation {
+  /// Converts the annotation into a W3C Web Annotation.
+  ///
+  /// Each target becomes a `SpecificResource` of `source`, which should identify the
+  /// publication, with its CFI in a `FragmentSelector` and its quote in a `TextQuoteSelector`.
+  pub fn to_raw(&self, source: &IriRef) -> RawAnnotation {
+    let targets = self
+      .targets
+      .iter()
+      .map(|target| {
+        let selector = raw::FragmentSelector {
+          value: target.selector.to_string(),
+          conforms_to: Some(epub_cfi_spec()),
+        };
+        let mut selectors = vec![raw::Selector::TaggedSelector(
+          raw::TaggedSelector::FragmentSelector(selector),
+        )];
+        if let Some(quote) = &target.quote {
+          selectors.push(raw::Selector::TaggedSelector(
+            raw::TaggedSelector::TextQuoteSelector(quote.to_raw()),
+          ));
+        }
+        raw::Target::SpecificResource(raw::SpecificResource {
+          id: None,
+          r#type: Some("SpecificResource".into()),
+          source: Box::new(raw::Target::Iri(source.to_owned())),
+          purpose: None,
+          selector: raw::Variable::from_vec(selectors),
+        })
+      })
+      .collect::<Vec<_>>();
+
+    RawAnnotation {
+      context: ANNOTATION_CONTEXT.into(),
+      id: self.id.clone(),
+      r#type: "Annotation".into(),
+      motivation: raw::Variable::from_vec(self.motivation.clone()),
+      created: self.created.clone(),
+      creator: raw::Variable::from_vec(self.creator.iter().map(Agent::to_raw).collect()),
+      modified: self.modified.clone(),
+      audience: raw::Variable::from_vec(self.audience.iter().map(Audience::to_raw).collect()),
+      rights: raw::Variable::from_vec(iris(&self.rights)),
+      body: raw::Variable::from_vec(self.bodies.iter().map(Body::to_raw).collect()),
+      body_value: None,
+      target: raw::Variable::from_vec(targets).unwrap_or(raw::Variable::Many(SmallVec::new())),
+    }
+  }
+
+  /// Returns the values of the annotation's textual bodies with the `tagging` purpose.
+  pub fn tags(&self) -> impl Iterator<Item = &str> {
+    self.bodies.iter().filter_map(|body| match body {
+      Body::Text { value, purpose, .. } if purpose.iter().any(|p| p == "tagging") => {
+        Some(value.as_str())
+      }
+      _ => None,
+    })
+  }
+}
+
+/// A person or piece of software responsible for an annotation.
+#[derive(Serialize, Deserialize, TS, Clone, Debug, PartialEq, Eq)]
+#[ts(export)]
+pub struct Agent {
+  pub id: Option<String>,
+  /// The class of agent, e.g. `Person`, `Organization` or `Software`.
+  #[serde(rename = "type")]
+  pub kind: Option<String>,
+  pub name: Option<String>,
+  pub nickname: Option<String>,
+  pub homepage: Option<String>,
+}
+
+impl Agent {
+  fn from_raw(agent: raw::Agent) -> Self {
+    match agent {
+      raw::Agent::Iri(iri) => Agent {
+        id: Some(iri.to_string()),
+        kind: None,
+        name: None,
+        nickname: None,
+        homepage: None,
+      },
+      raw::Agent::Details(details) => Agent {
+        id: details.id.map(|id| id.to_string()),
+        kind: details.r#type.into_vec().into_iter().next(),
+        name: details.name.into_vec().into_iter().next(),
+        nickname: details.nickname,
+        homepage: details
+          .homepage
+          .into_vec()
+          .into_iter()
+          .next()
+          .map(|homepage| homepage.to_string()),
+      },
+    }
+  }
+
+  fn to_raw(&self) -> raw::Agent {
+    let id = self.id.as_deref().and_then(iri);
+    match id {
+      Some(id)
+        if self.kind.is_none()
+          && self.name.is_none()
+          && self.nickname.is_none()
+          && self.homepage.is_none() =>
+      {
+        raw::Agent::Iri(id)
+      }
+      id => raw::Agent::Details(Box::new(raw::AgentDetails {
+        id,
+        r#type: self.kind.clone().map(raw::Variable::One),
+        name: self.name.clone().map(raw::Variable::One),
+        nickname: self.nickname.clone(),
+        email: None,
+        email_sha1: None,
+        homepage: self
+          .homepage
+          .as_deref()
+          .and_then(iri)
+          .map(raw::Variable::One),
+      })),
+    }
+  }
+}
+
+/// A group of people for whom an annotation is intended, e.g. `schema:EducationalAudience`.
+#[derive(Serialize, Deserialize, TS, Clone, Debug, PartialEq, Eq)]
+#[ts(export)]
+pub struct Audience {
+  pub id: Option<String>,
+  #[serde(rename = "type")]
+  pub kind: Vec<String>,
+}
+
+impl Audience {
+  fn from_raw(audience: raw::Audience) -> Self {
+    match audience {
+      raw::Audience::Iri(iri) => Audience {
+        id: Some(iri.to_string()),
+        kind: Vec::new(),
+      },
+      raw::Audience::Details(details) => Audience {
+        id: details.id.map(|id| id.to_string()),
+        kind: details.r#type.into_vec().into_vec(),
+      },
+    }
+  }
+
+  fn to_raw(&self) -> raw::Audience {
+    match self.id.as_deref().and_then(iri) {
+      Some(id) if self.kind.is_empty() => raw::Audience::Iri(id),
+      id => raw::Audience::Details(raw::AudienceDetails {
+        id,
+        r#type: raw::Variable::from_vec(self.kind.clone()),
+      }),
+    }
+  }
+}
+
+/// A location in the publication selected by an annotation.
+#[derive(Serialize, Deserialize, TS, Clone, Debug, PartialEq, Eq)]
+#[ts(export)]
+pub struct Target {
   pub selector: cfi::Fragment,
-  pub body: Option<String>,
+  /// The text selected by `selector`, used to re-anchor the target if the publication is edited.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  #[ts(optional)]
+  pub quote: Option<TextQuote>,
+}
+
+/// Text selected by a target, with some of the text around it.
+#[derive(Serialize, Deserialize, TS, Clone, Debug, PartialEq, Eq)]
+#[ts(export)]
+pub struct TextQuote {
+  pub exact: String,
+  pub prefix: Option<String>,
+  pub suffix: Option<String>,
+}
+
+impl TextQuote {
+  fn from_raw(quote: raw::TextQuoteSelector) -> Self {
+    TextQuote {
+      exact: quote.exact,
+      prefix: quote.prefix,
+      suffix: quote.suffix,
+    }
+  }
+
+  fn to_raw(&self) -> raw::TextQuoteSelector {
+    raw::TextQuoteSelector {
+      exact: self.exact.clone(),
+      prefix: self.prefix.clone(),
+      suffix: self.suffix.clone(),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, TS, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type")]
+#[ts(export)]
+pub enum Body {
+  /// Text embedded in the annotation, e.g. a comment or a tag.
+  Text {
+    value: String,
+    format: Option<String>,
+    language: Option<String>,
+    /// Why the body was included, e.g. `tagging` or `describing`.
+    purpose: Vec<String>,
+  },
+
+  /// A resource referenced by IRI, e.g. a linked web page or audio recording.
+  Resource {
+    id: String,
+    format: Option<String>,
+    purpose: Vec<String>,
+  },
+
+  /// A set of alternative bodies, of which the reader should show one.
+  Choice { items: Vec<Body> },
+}
+
+impl Body {
+  fn to_raw(&self) -> raw::Body {
+    let fields = |format: &Option<String>, language: &Option<String>| raw::ResourceFields {
+      format: format.as_ref().and_then(|format| format.parse().ok()),
+      language: language.clone().map(raw::Variable::One),
+      processing_language: None,
+      text_direction: None,
+      rights: None,
+    };
+    match self {
+      Body::Text {
+        value,
+        format,
+        language,
+        purpose,
+      } => raw::Body::TextualBody(raw::TextualBody {
+        id: None,
+        r#type: Some("TextualBody".into()),
+        value: value.clone(),
+        purpose: raw::Variable::from_vec(purpose.clone()),
+        fields: fields(format, language),
+      }),
+      Body::Resource {
+        id,
+        format,
+        purpose,
+      } => {
+        let resource = raw::ExternalWebResource {
+          id: id.clone(),
+          fields: fields(format, &None),
+        };
+        // Only a specific resource can say why it was included.
+        if purpose.is_empty() {
+          raw::Body::ExternalWebResource(resource)
+        } else {
+          raw::Body::SpecificResource(raw::SpecificResource {
+            id: None,
+            r#type: Some("SpecificResource".into()),
+            source: Box::new(raw::Target::ExternalWebResource(resource)),
+            purpose: raw::Variable::from_vec(purpose.clone()),
+            selector: None,
+          })
+        }
+      }
+      Body::Choice { items } => raw::Body::Choice(raw::Choice {
+        r#type: "Choice".into(),
+        items: items.iter().map(Body::to_raw).collect(),
+      }),
+    }
+  }
+}
+
+/// A problem with a single annotation that caused all or part of it to be skipped.
+#[derive(Serialize, TS, Clone, Debug)]
+#[ts(export)]
+pub struct Diagnostic {
+  /// The [`Annotation::id`] of the offending annotation.
+  pub annotation: String,
+  pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "annotation {}: {}", self.annotation, self.message)
+  }
 }
 
-pub fn process(annotations: Vec<RawAnnotation>) -> Result<Vec<Annotation>> {
-  annotations
+/// Converts parsed W3C annotations into [`Annotation`]s selected by EPUB CFIs.
+///
+/// Selectors which describe text rather than a CFI are anchored against the content
+/// documents of `rendition`, read from `archive`.
+///
+/// Targets and bodies which cannot be interpreted are skipped and reported as [`Diagnostic`]s.
+/// An annotation is dropped entirely if none of its targets can be interpreted.
+pub fn process<F: ZipFormat>(
+  annotations: Vec<RawAnnotation>,
+  archive: &mut Archive<F>,
+  rendition: &Rendition,
+) -> (Vec<Annotation>, Vec<Diagnostic>) {
+  let (anchored, diagnostics) = anchor_all(annotations, archive, rendition);
+  let processed = anchored
     .into_iter()
-    .map(|raw_annot| {
-      let mut raw_targets = raw_annot.target.into_vec();
-      ensure!(
-        raw_targets.len() == 1,
-        "cannot handle multiple annotation targets"
+    .filter_map(|(_, anchored)| anchored.map(|(annotation, _)| annotation))
+    .collect();
+  (processed, diagnostics)
+}
+
+/// How annotations fared when anchored against a publication which may have been edited since
+/// they were made.
+#[derive(Serialize, TS, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct AnchorReport {
+  /// The ids of annotations whose targets still select the text they were made on.
+  pub intact: Vec<String>,
+  /// The ids of annotations with a target whose text was found somewhere else.
+  pub moved: Vec<String>,
+  /// Annotations none of whose targets could be anchored.
+  pub orphaned: Vec<Diagnostic>,
+}
+
+/// Anchors `annotations` against `rendition`, re-anchoring targets whose CFI no longer selects
+/// their quoted text.
+///
+/// Returns the annotations with the targets of anchored ones rewritten as CFIs on `source` along
+/// with their quotes, so that they can be stored for the edited publication. Orphaned annotations
+/// are returned unchanged, so that they anchor again if their text is restored.
+pub fn reanchor<F: ZipFormat>(
+  annotations: Vec<RawAnnotation>,
+  archive: &mut Archive<F>,
+  rendition: &Rendition,
+  source: &IriRef,
+) -> (Vec<RawAnnotation>, AnchorReport) {
+  let (anchored, diagnostics) = anchor_all(annotations, archive, rendition);
+  let mut report = AnchorReport::default();
+  let annotations = anchored
+    .into_iter()
+    .map(|(mut raw_annot, anchored)| {
+      match anchored {
+        Some((annotation, moved)) => {
+          if moved {
+            report.moved.push(raw_annot.id.clone());
+          } else {
+            report.intact.push(raw_annot.id.clone());
+          }
+          raw_annot.target = annotation.to_raw(source).target;
+        }
+        None => report.orphaned.push(Diagnostic {
+          annotation: raw_annot.id.clone(),
+          message: diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.annotation == raw_annot.id)
+            .map(|diagnostic| &diagnostic.message)
+            .join("; "),
+        }),
+      }
+      raw_annot
+    })
+    .collect();
+  (annotations, report)
+}
+
+/// Records the text selected by each target of `annotation` as its [`TextQuote`], so that the
+/// target can be re-anchored if the publication is edited.
+///
+/// Targets which cannot be anchored keep their previous quote.
+pub fn fingerprint<F: ZipFormat>(
+  annotation: &mut Annotation,
+  archive: &mut Archive<F>,
+  rendition: &Rendition,
+) {
+  quote_targets(
+    &mut Anchorer::new(archive, rendition),
+    &mut annotation.targets,
+  );
+}
+
+fn quote_targets<F: ZipFormat>(anchorer: &mut Anchorer<'_, F>, targets: &mut [Target]) {
+  for target in targets {
+    if let Ok((document, range)) = anchorer.resolve(&target.selector)
+      && !range.is_empty()
+    {
+      target.quote = Some(TextQuote::from_raw(anchor::quote(&document, range)));
+    }
+  }
+}
+
+/// Anchors the targets of each annotation.
+///
+/// Returns each annotation along with its processed form and whether any of its targets moved,
+/// or `None` if none of its targets could be anchored.
+#[allow(clippy::type_complexity)]
+fn anchor_all<F: ZipFormat>(
+  annotations: Vec<RawAnnotation>,
+  archive: &mut Archive<F>,
+  rendition: &Rendition,
+) -> (
+  Vec<(RawAnnotation, Option<(Annotation, bool)>)>,
+  Vec<Diagnostic>,
+) {
+  let mut anchorer = Anchorer::new(archive, rendition);
+  let mut diagnostics = Vec::new();
+  let mut report = |id: &str, err: anyhow::Error| {
+    diagnostics.push(Diagnostic {
+      annotation: id.to_string(),
+      message: format!("{err:#}"),
+    });
+  };
+
+  let mut results = Vec::new();
+  for raw_annot in annotations {
+    let mut targets = Vec::new();
+    let mut moved = false;
+    for raw_target in raw_annot.target.clone().into_vec() {
+      match process_target(&mut anchorer, raw_target) {
+        Ok((target, target_moved)) => {
+          targets.push(target);
+          moved |= target_moved;
+        }
+        Err(err) => report(&raw_annot.id, err.context("Skipping annotation target")),
+      }
+    }
+    if targets.is_empty() {
+      report(
+        &raw_annot.id,
+        anyhow!("Skipping annotation with no usable targets"),
       );
-      let raw_target = raw_targets.remove(0);
-      let selector = match raw_target {
-        raw::Target::Iri(iri) => match iri.fragment() {
-          Some(fragment) => fragment.to_string(),
-          None => bail!("expected epub fragment on IRI"),
-        },
+      results.push((raw_annot, None));
+      continue;
+    }
+    let mut stripped = raw_annot.clone();
+    stripped.target = raw::Variable::Many(SmallVec::new());
+    let annotation = from_raw(stripped, targets, &mut report);
+    results.push((raw_annot, Some((annotation, moved))));
+  }
 
-        raw::Target::SpecificResource(resource) => {
-          let mut raw_selectors = resource.selector.into_vec();
-          ensure!(
-            raw_selectors.len() == 1,
-            "cannot handle multiple annotation selectors"
-          );
-          let raw_selector = raw_selectors.remove(0);
-          match raw_selector {
-            raw::Selector::TaggedSelector(raw::TaggedSelector::FragmentSelector(fragment)) => {
-              ensure!(
-                matches!(
-                  fragment.conforms_to.as_ref().map(IriRefBuf::as_str),
-                  Some("http://www.idpf.org/epub/linking/cfi/epub-cfi.html")
-                ),
-                "cannot handle non-epub fragment selector"
-              );
-              fragment.value
-            }
-            selector => bail!("cannot handle annotation selector: {selector:#?}"),
+  (results, diagnostics)
+}
+
+/// Converts everything but the targets of `raw_annot`, which are given already processed.
+///
+/// Bodies which cannot be interpreted are skipped and passed to `report`.
+fn from_raw(
+  raw_annot: RawAnnotation,
+  targets: Vec<Target>,
+  report: &mut impl FnMut(&str, anyhow::Error),
+) -> Annotation {
+  let id = raw_annot.id;
+  let mut bodies = Vec::new();
+  if let Some(body_value) = raw_annot.body_value {
+    bodies.push(Body::Text {
+      value: body_value,
+      format: None,
+      language: None,
+      purpose: Vec::new(),
+    });
+  }
+  for raw_body in raw_annot.body.into_vec() {
+    match process_body(raw_body) {
+      Ok(body) => bodies.push(body),
+      Err(err) => report(&id, err.context("Skipping annotation body")),
+    }
+  }
+
+  Annotation {
+    id,
+    motivation: raw_annot.motivation.into_vec().into_vec(),
+    created: raw_annot.created,
+    creator: raw_annot
+      .creator
+      .into_vec()
+      .into_iter()
+      .map(Agent::from_raw)
+      .collect(),
+    modified: raw_annot.modified,
+    audience: raw_annot
+      .audience
+      .into_vec()
+      .into_iter()
+      .map(Audience::from_raw)
+      .collect(),
+    rights: raw_annot
+      .rights
+      .into_vec()
+      .into_iter()
+      .map(|iri| iri.to_string())
+      .collect(),
+    targets,
+    bodies,
+  }
+}
+
+/// Anchors a target, returning whether it had to be re-anchored.
+fn process_target<F: ZipFormat>(
+  anchorer: &mut Anchorer<'_, F>,
+  raw_target: raw::Target,
+) -> Result<(Target, bool)> {
+  let mut quote = None;
+  let selector = match raw_target {
+    raw::Target::Iri(iri) => iri_selector(&iri)?,
+
+    raw::Target::ExternalWebResource(resource) => {
+      let iri = IriRefBuf::new(resource.id)
+        .map_err(|err| anyhow!("Target is not a valid IRI: {}", err.0))?;
+      iri_selector(&iri)?
+    }
+
+    raw::Target::SpecificResource(resource) => {
+      let raw_selectors = resource.selector.into_vec();
+      if raw_selectors.is_empty() {
+        return process_target(anchorer, *resource.source);
+      }
+      let source = resource_id(*resource.source);
+
+      // A CFI alongside a quote is checked against the quote, as produced by `Annotation::to_raw`.
+      quote = raw_selectors.iter().find_map(|selector| match selector {
+        raw::Selector::TaggedSelector(raw::TaggedSelector::TextQuoteSelector(quote)) => {
+          Some(quote.clone())
+        }
+        _ => None,
+      });
+      let cfi = raw_selectors.iter().find_map(|selector| match selector {
+        raw::Selector::TaggedSelector(raw::TaggedSelector::FragmentSelector(fragment))
+          if fragment.conforms_to.as_ref().map(IriRefBuf::as_str) == Some(EPUB_CFI_SPEC) =>
+        {
+          Some(fragment.value.clone())
+        }
+        _ => None,
+      });
+      if let (Some(quote), Some(cfi)) = (&quote, cfi) {
+        let (selector, quote, moved) = anchorer.reanchor(&parse_cfi(&cfi)?, quote)?;
+        let target = Target {
+          selector,
+          quote: Some(TextQuote::from_raw(quote)),
+        };
+        return Ok((target, moved));
+      }
+
+      // A position selector alongside a quote selector is used as a hint for the quote,
+      // as produced by e.g. Hypothesis.
+      let position = raw_selectors.iter().find_map(|selector| match selector {
+        raw::Selector::TaggedSelector(raw::TaggedSelector::TextPositionSelector(position)) => {
+          Some(position.clone())
+        }
+        _ => None,
+      });
+      let has_quote = raw_selectors.iter().any(|selector| {
+        matches!(
+          selector,
+          raw::Selector::TaggedSelector(raw::TaggedSelector::TextQuoteSelector(_))
+        )
+      });
+
+      // Otherwise, multiple selectors on one resource are equivalent alternatives,
+      // so we use the first one we understand.
+      let mut errors = Vec::new();
+      let mut selectors = raw_selectors
+        .into_iter()
+        .filter_map(|selector| match selector {
+          raw::Selector::TaggedSelector(raw::TaggedSelector::TextPositionSelector(_))
+            if has_quote =>
+          {
+            None
           }
+          selector => Some(process_selector(
+            anchorer,
+            &source,
+            selector,
+            position.as_ref(),
+          )),
+        });
+      loop {
+        match selectors.next() {
+          Some(Ok(selector)) => break selector,
+          Some(Err(err)) => errors.push(format!("{err:#}")),
+          None if errors.len() == 1 => bail!("{}", errors.remove(0)),
+          None => bail!(
+            "None of the target's selectors are usable: {}",
+            errors.iter().join("; ")
+          ),
         }
+      }
+    }
+  };
+  let target = Target {
+    selector,
+    quote: quote.map(TextQuote::from_raw),
+  };
+  Ok((target, false))
+}
+
+fn iri_selector(iri: &IriRefBuf) -> Result<cfi::Fragment> {
+  match iri.fragment() {
+    Some(fragment) => parse_cfi(fragment.as_str()),
+    None => bail!("Expected an EPUB CFI fragment on target IRI: {iri}"),
+  }
+}
 
-        raw::Target::ExternalWebResource(_) => todo!(),
+fn process_selector<F: ZipFormat>(
+  anchorer: &mut Anchorer<'_, F>,
+  source: &str,
+  raw_selector: raw::Selector,
+  position: Option<&raw::TextPositionSelector>,
+) -> Result<cfi::Fragment> {
+  match raw_selector {
+    raw::Selector::TaggedSelector(raw::TaggedSelector::FragmentSelector(fragment)) => {
+      ensure!(
+        fragment.conforms_to.as_ref().map(IriRefBuf::as_str) == Some(EPUB_CFI_SPEC),
+        "Cannot handle non-EPUB fragment selector"
+      );
+      parse_cfi(&fragment.value)
+    }
+    selector => anchorer.anchor(source, &selector, position),
+  }
+}
+
+fn parse_cfi(s: &str) -> Result<cfi::Fragment> {
+  cfi::Fragment::parse(s).context("Failed to parse EPUB CFI")
+}
+
+fn process_body(raw_body: raw::Body) -> Result<Body> {
+  Ok(match raw_body {
+    raw::Body::Iri(iri) => Body::Resource {
+      id: iri.to_string(),
+      format: None,
+      purpose: Vec::new(),
+    },
+
+    raw::Body::TextualBody(body) => Body::Text {
+      value: body.value,
+      format: body.fields.format.map(|format| format.to_string()),
+      language: body.fields.language.into_vec().into_iter().next(),
+      purpose: body.purpose.into_vec().into_vec(),
+    },
+
+    raw::Body::SpecificResource(resource) => {
+      let format = match &*resource.source {
+        raw::Target::ExternalWebResource(source) => source.fields.format.as_ref(),
+        _ => None,
       };
+      Body::Resource {
+        format: format.map(ToString::to_string),
+        id: resource_id(*resource.source),
+        purpose: resource.purpose.into_vec().into_vec(),
+      }
+    }
 
-      let body = if let Some(body_value) = raw_annot.body_value {
-        Some(body_value)
-      } else if let Some(raw_bodies) = raw_annot.body {
-        let mut raw_bodies = raw_bodies.into_vec();
-        ensure!(
-          raw_bodies.len() == 1,
-          "cannot handle multiple annotation bodies"
-        );
-        let raw_body = raw_bodies.remove(0);
-        match raw_body {
-          raw::Body::TextualBody(raw_body) => Some(raw_body.value),
-          _ => todo!(),
+    raw::Body::Choice(choice) => {
+      ensure!(!choice.items.is_empty(), "Choice body has no items");
+      let items = choice
+        .items
+        .into_iter()
+        .map(process_body)
+        .collect::<Result<Vec<_>>>()?;
+      Body::Choice { items }
+    }
+
+    raw::Body::ExternalWebResource(resource) => Body::Resource {
+      id: resource.id,
+      format: resource.fields.format.map(|format| format.to_string()),
+      purpose: Vec::new(),
+    },
+  })
+}
+
+/// Parses `s` as an IRI reference, or `None` if it is invalid.
+fn iri(s: &str) -> Option<IriRefBuf> {
+  IriRefBuf::new(s.to_string()).ok()
+}
+
+fn iris(strings: &[String]) -> Vec<IriRefBuf> {
+  strings.iter().filter_map(|s| iri(s)).collect()
+}
+
+fn epub_cfi_spec() -> IriRefBuf {
+  IriRefBuf::new(EPUB_CFI_SPEC.to_string()).expect("EPUB CFI spec URI is a valid IRI")
+}
+
+/// Returns the IRI of the resource ultimately referenced by `source`.
+fn resource_id(source: raw::Target) -> String {
+  match source {
+    raw::Target::Iri(iri) => iri.to_string(),
+    raw::Target::SpecificResource(resource) => resource_id(*resource.source),
+    raw::Target::ExternalWebResource(resource) => resource.id,
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{Epub, test_utils};
+
+  fn process_one(json: &str) -> (Vec<Annotation>, Vec<Diagnostic>) {
+    let mut archive = test_utils::archive(&[]);
+    let epub = Epub::load(&mut archive).unwrap();
+    process(
+      vec![RawAnnotation::parse(json).unwrap()],
+      &mut archive,
+      &epub.renditions[0],
+    )
+  }
+
+  fn selector(json: &str) -> String {
+    let (annotations, diagnostics) = process_one(&format!(
+      r#"{{
+        "@context": "http://www.w3.org/ns/anno.jsonld",
+        "id": "anno",
+        "type": "Annotation",
+        "target": {{"source": "chapter.xhtml", "selector": {json}}}
+      }}"#
+    ));
+    assert!(diagnostics.is_empty(), "{diagnostics:?}");
+    annotations[0].targets[0].selector.to_string()
+  }
+
+  #[test]
+  fn test_process_multiple_targets_and_bodies() {
+    let (annotations, diagnostics) = process_one(
+      r#"{
+        "@context": "http://www.w3.org/ns/anno.jsonld",
+        "id": "anno",
+        "type": "Annotation",
+        "body": [
+          "http://example.org/note1",
+          {"type": "TextualBody", "value": "tag1", "format": "text/plain", "language": ["en", "fr"]},
+          {"type": "SpecificResource", "source": {"source": "http://example.org/city1"}},
+          {"type": "Choice", "items": [
+            {"type": "TextualBody", "value": "hello"},
+            {"id": "http://example.org/note2", "format": "text/html"}
+          ]}
+        ],
+        "target": [
+          "index.xhtml#epubcfi(/6/2!/4/2,/1:0,/1:5)",
+          {
+            "source": ".",
+            "selector": [
+              {"type": "CssSelector", "value": "p"},
+              {"type": "FragmentSelector", "conformsTo": "http://www.idpf.org/epub/linking/cfi/epub-cfi.html", "value": "epubcfi(/6/2!/4/4)"}
+            ]
+          }
+        ]
+      }"#,
+    );
+    assert!(diagnostics.is_empty(), "{diagnostics:?}");
+    let annotation = &annotations[0];
+    assert_eq!(annotation.targets.len(), 2);
+    assert_eq!(
+      annotation.bodies,
+      vec![
+        Body::Resource {
+          id: "http://example.org/note1".into(),
+          format: None,
+          purpose: Vec::new()
+        },
+        Body::Text {
+          value: "tag1".into(),
+          format: Some("text/plain".into()),
+          language: Some("en".into()),
+          purpose: Vec::new()
+        },
+        Body::Resource {
+          id: "http://example.org/city1".into(),
+          format: None,
+          purpose: Vec::new()
+        },
+        Body::Choice {
+          items: vec![
+            Body::Text {
+              value: "hello".into(),
+              format: None,
+              language: None,
+              purpose: Vec::new()
+            },
+            Body::Resource {
+              id: "http://example.org/note2".into(),
+              format: Some("text/html".into()),
+              purpose: Vec::new()
+            }
+          ]
         }
-      } else {
-        None
-      };
+      ]
+    );
+  }
 
-      let selector = cfi::Fragment::parse(&selector)
-        .with_context(|| format!("Failed to parse EPUB CFI fragment in annotation:\n{selector}"))?;
+  #[test]
+  fn test_process_diagnostics() {
+    let (annotations, diagnostics) = process_one(
+      r#"{
+        "@context": "http://www.w3.org/ns/anno.jsonld",
+        "id": "anno",
+        "type": "Annotation",
+        "body": {"type": "Choice", "items": []},
+        "target": [
+          {"id": "http://example.gov/patent1.pdf", "format": "application/pdf"},
+          "index.xhtml#epubcfi(/6/2!/4/2)"
+        ]
+      }"#,
+    );
+    assert_eq!(annotations[0].targets.len(), 1);
+    assert!(annotations[0].bodies.is_empty());
+    assert_eq!(diagnostics.len(), 2);
+    assert!(diagnostics.iter().all(|d| d.annotation == "anno"));
 
-      Ok(Annotation { selector, body })
-    })
-    .collect()
+    let (annotations, diagnostics) = process_one(
+      r#"{
+        "@context": "http://www.w3.org/ns/anno.jsonld",
+        "id": "anno",
+        "type": "Annotation",
+        "target": {"source": ".", "selector": {"type": "SvgSelector", "value": "<svg/>"}}
+      }"#,
+    );
+    assert!(annotations.is_empty());
+    assert_eq!(diagnostics.len(), 2);
+  }
+
+  #[test]
+  fn test_process_text_quote_selector() {
+    assert_eq!(
+      selector(r#"{"type": "TextQuoteSelector", "exact": "world"}"#),
+      "epubcfi(/6/2!/4/2,/1:6,/1:11)"
+    );
+    assert_eq!(
+      selector(r#"{"type": "TextQuoteSelector", "exact": "world", "prefix": "about the "}"#),
+      "epubcfi(/6/2!/4/4,/3:21,/3:26)"
+    );
+    assert_eq!(
+      selector(r#"{"type": "TextQuoteSelector", "exact": "Another emphatic"}"#),
+      "epubcfi(/6/2!/4/4,/1:0,/2/1:8)"
+    );
+  }
+
+  #[test]
+  fn test_process_text_position_selector() {
+    let text = "\n    Hello world, this is a test.\n    Another ";
+    let start = text.chars().count();
+    assert_eq!(
+      selector(&format!(
+        r#"{{"type": "TextPositionSelector", "start": {start}, "end": {}}}"#,
+        start + 8
+      )),
+      "epubcfi(/6/2!/4/4/2,/1:0,/1:8)"
+    );
+    assert_eq!(
+      selector(&format!(
+        r#"[
+          {{"type": "TextPositionSelector", "start": {start}, "end": {}}},
+          {{"type": "TextQuoteSelector", "exact": "world", "prefix": "the "}}
+        ]"#,
+        start + 100
+      )),
+      "epubcfi(/6/2!/4/4,/3:21,/3:26)"
+    );
+  }
+
+  #[test]
+  fn test_process_css_selector() {
+    assert_eq!(
+      selector(r##"{"type": "CssSelector", "value": "#first"}"##),
+      "epubcfi(/6/2!/4/2,/1:0,/1:28)"
+    );
+    assert_eq!(
+      selector(r#"{"type": "CssSelector", "value": "p > em"}"#),
+      "epubcfi(/6/2!/4/4/2,/1:0,/1:8)"
+    );
+  }
+
+  #[test]
+  fn test_process_xpath_selector() {
+    assert_eq!(
+      selector(r#"{"type": "XPathSelector", "value": "/html/body/p[2]"}"#),
+      "epubcfi(/6/2!/4/4,/1:0,/3:27)"
+    );
+  }
+
+  #[test]
+  fn test_process_refined_selector() {
+    assert_eq!(
+      selector(
+        r#"{
+          "type": "XPathSelector",
+          "value": "/html/body/p[1]",
+          "refinedBy": {"type": "TextPositionSelector", "start": 6, "end": 11}
+        }"#
+      ),
+      "epubcfi(/6/2!/4/2,/1:6,/1:11)"
+    );
+    assert_eq!(
+      selector(
+        r#"{
+          "type": "CssSelector",
+          "value": "p:nth-child(2)",
+          "refinedBy": {"type": "TextQuoteSelector", "exact": "world"}
+        }"#
+      ),
+      "epubcfi(/6/2!/4/4,/3:21,/3:26)"
+    );
+  }
+
+  #[test]
+  fn test_process_range_selector() {
+    assert_eq!(
+      selector(
+        r#"{
+          "type": "RangeSelector",
+          "startSelector": {"type": "TextQuoteSelector", "exact": "Hello"},
+          "endSelector": {"type": "CssSelector", "value": "em"}
+        }"#
+      ),
+      "epubcfi(/6/2!/4,/2/1:0,/4/1:8)"
+    );
+    assert_eq!(
+      selector(
+        r#"{
+          "type": "RangeSelector",
+          "startSelector": {
+            "type": "FragmentSelector",
+            "conformsTo": "http://www.idpf.org/epub/linking/cfi/epub-cfi.html",
+            "value": "epubcfi(/6/2!/4/2/1:6)"
+          },
+          "endSelector": {"type": "TextQuoteSelector", "exact": "this"}
+        }"#
+      ),
+      "epubcfi(/6/2!/4/2,/1:6,/1:13)"
+    );
+  }
+
+  #[test]
+  fn test_process_unmatched_selectors() {
+    for json in [
+      r#"{"type": "CssSelector", "value": "table"}"#,
+      r#"{"type": "XPathSelector", "value": "//p[3]"}"#,
+      r#"{
+        "type": "RangeSelector",
+        "startSelector": {"type": "CssSelector", "value": "em"},
+        "endSelector": {"type": "TextQuoteSelector", "exact": "Hello"}
+      }"#,
+    ] {
+      let (annotations, diagnostics) = process_one(&format!(
+        r#"{{
+          "@context": "http://www.w3.org/ns/anno.jsonld",
+          "id": "anno",
+          "type": "Annotation",
+          "target": {{"source": "chapter.xhtml", "selector": {json}}}
+        }}"#
+      ));
+      assert!(annotations.is_empty(), "{json}");
+      assert_eq!(diagnostics.len(), 2, "{json}");
+    }
+  }
+
+  #[test]
+  fn test_process_annotation_model() {
+    let (annotations, diagnostics) = process_one(
+      r#"{
+        "@context": "http://www.w3.org/ns/anno.jsonld",
+        "id": "anno",
+        "type": "Annotation",
+        "motivation": "bookmarking",
+        "creator": {
+          "id": "http://example.org/user1",
+          "type": "Person",
+          "name": "My Pseudonym",
+          "nickname": "pseudo",
+          "email_sha1": "58bad08927902ff9307b621c54716dcc5083e339"
+        },
+        "created": "2015-01-28T12:00:00Z",
+        "modified": "2015-01-29T09:00:00Z",
+        "audience": {
+          "id": "http://example.edu/roles/teacher",
+          "type": "schema:EducationalAudience",
+          "schema:educationalRole": "teacher"
+        },
+        "rights": "https://creativecommons.org/publicdomain/zero/1.0/",
+        "body": [
+          {"type": "TextualBody", "value": "readme", "purpose": "tagging"},
+          {"type": "TextualBody", "value": "A good description", "purpose": "describing"},
+          {"type": "SpecificResource", "source": "http://example.org/city1", "purpose": ["identifying", "tagging"]}
+        ],
+        "target": "index.xhtml#epubcfi(/6/2!/4/2,/1:0,/1:5)"
+      }"#,
+    );
+    assert!(diagnostics.is_empty(), "{diagnostics:?}");
+    let annotation = &annotations[0];
+    assert_eq!(annotation.motivation, ["bookmarking"]);
+    assert_eq!(
+      annotation.creator,
+      [Agent {
+        id: Some("http://example.org/user1".into()),
+        kind: Some("Person".into()),
+        name: Some("My Pseudonym".into()),
+        nickname: Some("pseudo".into()),
+        homepage: None,
+      }]
+    );
+    assert_eq!(annotation.created.as_deref(), Some("2015-01-28T12:00:00Z"));
+    assert_eq!(annotation.modified.as_deref(), Some("2015-01-29T09:00:00Z"));
+    assert_eq!(
+      annotation.audience,
+      [Audience {
+        id: Some("http://example.edu/roles/teacher".into()),
+        kind: vec!["schema:EducationalAudience".into()],
+      }]
+    );
+    assert_eq!(
+      annotation.rights,
+      ["https://creativecommons.org/publicdomain/zero/1.0/"]
+    );
+    assert_eq!(annotation.tags().collect::<Vec<_>>(), ["readme"]);
+    assert_eq!(
+      annotation.bodies[2],
+      Body::Resource {
+        id: "http://example.org/city1".into(),
+        format: None,
+        purpose: vec!["identifying".into(), "tagging".into()],
+      }
+    );
+  }
+
+  #[test]
+  fn test_to_raw() {
+    let (annotations, diagnostics) = process_one(
+      r#"{
+        "@context": "http://www.w3.org/ns/anno.jsonld",
+        "id": "anno",
+        "type": "Annotation",
+        "motivation": ["commenting", "tagging"],
+        "created": "2015-01-28T12:00:00Z",
+        "modified": "2015-01-29T09:00:00Z",
+        "creator": [
+          "http://example.org/user1",
+          {"type": "Person", "name": "My Pseudonym", "nickname": "pseudo"}
+        ],
+        "audience": ["http://example.edu/roles/teacher", {"type": "schema:EducationalAudience"}],
+        "rights": "https://creativecommons.org/publicdomain/zero/1.0/",
+        "body": [
+          {"type": "TextualBody", "value": "tag1", "language": "en", "purpose": "tagging"},
+          {"type": "SpecificResource", "source": "http://example.org/city1", "purpose": "identifying"},
+          {"id": "http://example.org/note1", "format": "text/html"},
+          {"type": "Choice", "items": [{"type": "TextualBody", "value": "hello"}]}
+        ],
+        "target": [
+          "index.xhtml#epubcfi(/6/2!/4/2,/1:0,/1:5)",
+          {"source": "chapter.xhtml", "selector": {"type": "TextQuoteSelector", "exact": "emphatic"}}
+        ]
+      }"#,
+    );
+    assert!(diagnostics.is_empty(), "{diagnostics:?}");
+
+    let source = IriRef::new("urn:test:book").unwrap();
+    let raw = annotations[0].to_raw(source);
+    let json = serde_json::to_value(&raw).unwrap();
+    assert_eq!(json["@context"], ANNOTATION_CONTEXT);
+    assert_eq!(json["type"], "Annotation");
+    assert_eq!(json["target"][1]["source"], "urn:test:book");
+    assert_eq!(
+      json["target"][1]["selector"],
+      serde_json::json!([
+        {
+          "type": "FragmentSelector",
+          "value": "epubcfi(/6/2!/4/4/2,/1:0,/1:8)",
+          "conformsTo": EPUB_CFI_SPEC,
+        },
+        {"type": "TextQuoteSelector", "exact": "emphatic"},
+      ])
+    );
+
+    let (reprocessed, diagnostics) = process_one(&raw.to_json().unwrap());
+    assert!(diagnostics.is_empty(), "{diagnostics:?}");
+    assert_eq!(
+      serde_json::to_value(&reprocessed[0]).unwrap(),
+      serde_json::to_value(&annotations[0]).unwrap()
+    );
+  }
+
+  #[test]
+  fn test_reanchor() {
+    let source = IriRef::new("urn:test:book").unwrap();
+    let mut archive = test_utils::archive(&[]);
+    let epub = Epub::load(&mut archive).unwrap();
+    let raw = |id: &str, cfi: &str| {
+      RawAnnotation::parse(&format!(
+        r#"{{
+          "@context": "http://www.w3.org/ns/anno.jsonld",
+          "id": "{id}",
+          "type": "Annotation",
+          "target": "urn:test:book#{cfi}"
+        }}"#
+      ))
+      .unwrap()
+    };
+    let raws = vec![
+      raw("world", "epubcfi(/6/2!/4/2,/1:6,/1:11)"),
+      raw("test", "epubcfi(/6/2!/4/2,/1:23,/1:27)"),
+      raw("emphatic", "epubcfi(/6/2!/4/4/2,/1:0,/1:8)"),
+      raw("paragraph", "epubcfi(/6/2!/4/4,/3:1,/3:10)"),
+    ];
+    let (mut annotations, diagnostics) = process(raws, &mut archive, &epub.renditions[0]);
+    assert!(diagnostics.is_empty(), "{diagnostics:?}");
+    for annotation in &mut annotations {
+      fingerprint(annotation, &mut archive, &epub.renditions[0]);
+    }
+    assert_eq!(
+      annotations[1].targets[0].quote.as_ref().unwrap().exact,
+      "test"
+    );
+    let stored = annotations.iter().map(|a| a.to_raw(source)).collect();
+
+    // The author rewords the first paragraph and replaces the second.
+    let edited = test_utils::CHAPTER.replace("a test", "a trial").replace(
+      "<p>Another <em>emphatic</em> paragraph about the world.</p>",
+      "<p>A new paragraph about the world.</p>",
+    );
+    let mut archive = test_utils::archive(&[("EPUB/chapter.xhtml", &edited)]);
+    let epub = Epub::load(&mut archive).unwrap();
+    let (reanchored, report) = reanchor(stored, &mut archive, &epub.renditions[0], source);
+    assert_eq!(report.intact, ["world"]);
+    assert_eq!(report.moved, ["test", "paragraph"]);
+    assert_eq!(report.orphaned.len(), 1);
+    assert_eq!(report.orphaned[0].annotation, "emphatic");
+    assert!(
+      report.orphaned[0].message.contains("not found"),
+      "{}",
+      report.orphaned[0].message
+    );
+
+    // Re-anchored annotations are intact from then on.
+    let (reanchored, report) = reanchor(reanchored, &mut archive, &epub.renditions[0], source);
+    assert_eq!(report.intact, ["world", "test", "paragraph"]);
+    let (annotations, _) = process(reanchored, &mut archive, &epub.renditions[0]);
+    let selectors: Vec<String> = annotations
+      .iter()
+      .map(|a| a.targets[0].selector.to_string())
+      .collect();
+    assert_eq!(
+      selectors,
+      [
+        "epubcfi(/6/2!/4/2,/1:6,/1:11)",
+        "epubcfi(/6/2!/4/2,/1:23,/1:28)",
+        "epubcfi(/6/2!/4/4,/1:6,/1:15)",
+      ]
+    );
+  }
 }
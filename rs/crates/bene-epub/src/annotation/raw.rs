@@ -4,10 +4,10 @@ use anyhow::Result;
 use format_serde_error::SerdeError;
 use iref::IriRefBuf;
 use mediatype::MediaTypeBuf;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use smallvec::{SmallVec, smallvec};
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Variable<T> {
   One(T),
@@ -31,6 +31,17 @@ impl<T> IntoVec for Variable<T> {
   }
 }
 
+impl<T> Variable<T> {
+  /// Packs `items` into a [`Variable`], or `None` if there are no items.
+  pub fn from_vec(mut items: Vec<T>) -> Option<Self> {
+    match items.len() {
+      0 => None,
+      1 => items.pop().map(Variable::One),
+      _ => Some(Variable::Many(items.into())),
+    }
+  }
+}
+
 impl<T> IntoVec for Option<Variable<T>> {
   type T = T;
   fn into_vec(self) -> SmallVec<[Self::T; 2]> {
@@ -41,7 +52,7 @@ impl<T> IntoVec for Option<Variable<T>> {
   }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Annotation {
   #[serde(rename = "@context")]
@@ -51,32 +62,118 @@ pub struct Annotation {
 
   pub r#type: String,
 
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub motivation: Option<Variable<String>>,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub created: Option<String>,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub creator: Option<Variable<Agent>>,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub modified: Option<String>,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub audience: Option<Variable<Audience>>,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub rights: Option<Variable<IriRefBuf>>,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub body: Option<Variable<Body>>,
 
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub body_value: Option<String>,
 
   pub target: Variable<Target>,
 }
 
 impl Annotation {
+  /// Parses an annotation from a JSON-LD string.
+  ///
+  /// # Errors
+  /// If `s` is not JSON in the shape of a W3C Web Annotation.
   pub fn parse(s: &str) -> Result<Self> {
     serde_json::from_str(s).map_err(|err| SerdeError::new(s.to_string(), err).into())
   }
+
+  /// Serializes the annotation as a JSON-LD string.
+  ///
+  /// # Errors
+  /// If the annotation cannot be represented as JSON, which should not happen in practice.
+  pub fn to_json(&self) -> Result<String> {
+    Ok(serde_json::to_string_pretty(self)?)
+  }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Agent {
+  Iri(IriRefBuf),
+  Details(Box<AgentDetails>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentDetails {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub id: Option<IriRefBuf>,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub r#type: Option<Variable<String>>,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub name: Option<Variable<String>>,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub nickname: Option<String>,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub email: Option<Variable<String>>,
+
+  #[serde(rename = "email_sha1", skip_serializing_if = "Option::is_none")]
+  pub email_sha1: Option<Variable<String>>,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub homepage: Option<Variable<IriRefBuf>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Audience {
+  Iri(IriRefBuf),
+  Details(AudienceDetails),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudienceDetails {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub id: Option<IriRefBuf>,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub r#type: Option<Variable<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ResourceFields {
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub format: Option<MediaTypeBuf>,
 
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub language: Option<Variable<String>>,
 
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub processing_language: Option<String>,
 
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub text_direction: Option<String>,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub rights: Option<Variable<IriRefBuf>>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Body {
   Iri(IriRefBuf),
@@ -86,39 +183,48 @@ pub enum Body {
   ExternalWebResource(ExternalWebResource),
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SpecificResource {
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub id: Option<String>,
 
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub r#type: Option<String>,
 
   pub source: Box<Target>,
 
-  pub purpose: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub purpose: Option<Variable<String>>,
 
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub selector: Option<Variable<Selector>>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Choice {
   pub r#type: String,
 
   pub items: Vec<Body>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TextualBody {
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub id: Option<String>,
 
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub r#type: Option<String>,
 
   pub value: String,
 
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub purpose: Option<Variable<String>>,
+
   #[serde(flatten)]
   pub fields: ResourceFields,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Target {
   Iri(IriRefBuf),
@@ -126,7 +232,7 @@ pub enum Target {
   ExternalWebResource(ExternalWebResource),
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExternalWebResource {
   pub id: String,
 
@@ -134,14 +240,14 @@ pub struct ExternalWebResource {
   pub fields: ResourceFields,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Selector {
   Iri(IriRefBuf),
   TaggedSelector(TaggedSelector),
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[allow(clippy::enum_variant_names)]
 pub enum TaggedSelector {
@@ -155,49 +261,60 @@ pub enum TaggedSelector {
   RangeSelector(RangeSelector),
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FragmentSelector {
   pub value: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub conforms_to: Option<IriRefBuf>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct CssSelector {
   pub value: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub refined_by: Option<Box<Selector>>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct XPathSelector {
   pub value: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub refined_by: Option<Box<Selector>>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TextQuoteSelector {
   pub exact: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub prefix: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub suffix: Option<String>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TextPositionSelector {
   pub start: u64,
   pub end: u64,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DataPositionSelector {
   pub start: u64,
   pub end: u64,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SvgSelector {
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub id: Option<IriRefBuf>,
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub value: Option<String>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RangeSelector {
   pub start_selector: Box<Selector>,
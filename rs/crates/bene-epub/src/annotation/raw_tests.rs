@@ -1,5 +1,14 @@
 use super::raw::*;
 
+/// Parses `json`, checking that the annotation survives a round-trip through serialization.
+fn parse(json: &str) -> Annotation {
+  let annotation = Annotation::parse(json).unwrap();
+  let serialized = annotation.to_json().unwrap();
+  let reparsed = Annotation::parse(&serialized).unwrap();
+  assert_eq!(reparsed.to_json().unwrap(), serialized);
+  annotation
+}
+
 #[test]
 fn test_example_1_simple_annotation() {
   let json = r#"{
@@ -10,7 +19,7 @@ fn test_example_1_simple_annotation() {
       "target": "http://example.com/page1"
   }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno1");
   println!("{annotation:#?}");
 }
@@ -35,7 +44,7 @@ fn test_example_2_external_web_resources() {
       }
   }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno2");
   println!("{annotation:#?}");
 }
@@ -56,7 +65,7 @@ fn test_example_3_resource_classes() {
       }
   }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno3");
   println!("{annotation:#?}");
 }
@@ -75,7 +84,7 @@ fn test_example_4_fragment_selector() {
       }
   }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno4");
 }
 
@@ -94,7 +103,7 @@ fn test_example_5_embedded_textual_body() {
       "target": "http://example.org/photo1"
   }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno5");
 }
 
@@ -110,7 +119,7 @@ fn test_example_6_body_value() {
 
   // This example uses bodyValue which we haven't implemented yet
   // It should still parse but bodyValue will be ignored
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno6");
 }
 
@@ -128,7 +137,7 @@ fn test_example_7_textual_body_equivalent() {
             "target": "http://example.org/target1"
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno7");
 }
 
@@ -141,7 +150,7 @@ fn test_example_8_no_body() {
             "target": "http://example.org/ebook1"
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno8");
   assert!(annotation.body.is_none());
 }
@@ -165,7 +174,7 @@ fn test_example_9_multiple_bodies_targets() {
             ]
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno9");
 }
 
@@ -191,7 +200,7 @@ fn test_example_10_choice() {
             "target": "http://example.org/website1"
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno10");
 }
 
@@ -214,7 +223,7 @@ fn test_example_11_agents_and_timestamps() {
             "target": "http://example.com/restaurant1"
         }"#;
 
-  let _annotation = Annotation::parse(json).unwrap();
+  let _annotation = parse(json);
 }
 
 #[test]
@@ -240,7 +249,7 @@ fn test_example_12_agent_details() {
             "target": "http://example.com/restaurant1"
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno12");
 }
 
@@ -259,7 +268,7 @@ fn test_example_13_audience() {
             "target": "http://example.com/textbook1"
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno13");
 }
 
@@ -278,7 +287,7 @@ fn test_example_14_accessibility() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno14");
 }
 
@@ -304,7 +313,7 @@ fn test_example_15_motivation_and_purpose() {
             "target": "http://example.com/page1"
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno15");
 }
 
@@ -322,7 +331,7 @@ fn test_example_16_rights() {
             "target": "http://example.com/product1"
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno16");
 }
 
@@ -341,7 +350,7 @@ fn test_example_17_canonical_and_via() {
             "target": "http://example.com/product1"
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno17");
 }
 
@@ -362,7 +371,7 @@ fn test_example_18_specific_resource_purpose() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno18");
 }
 
@@ -382,7 +391,7 @@ fn test_example_19_selectors() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno19");
 }
 
@@ -404,7 +413,7 @@ fn test_example_20_fragment_selector_detailed() {
             "target": "http://example.org/image1"
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno20");
 }
 
@@ -424,7 +433,7 @@ fn test_example_21_css_selector() {
             }
         }"##;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno21");
 }
 
@@ -444,7 +453,7 @@ fn test_example_22_xpath_selector() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno22");
 }
 
@@ -466,7 +475,7 @@ fn test_example_23_text_quote_selector() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno23");
 }
 
@@ -487,7 +496,7 @@ fn test_example_24_text_position_selector() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno24");
 }
 
@@ -508,7 +517,7 @@ fn test_example_25_data_position_selector() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno25");
 }
 
@@ -528,7 +537,7 @@ fn test_example_26_svg_selector_external() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno26");
 }
 
@@ -548,7 +557,7 @@ fn test_example_27_svg_selector_embedded() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno27");
 }
 
@@ -575,7 +584,7 @@ fn test_example_28_range_selector() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno28");
 }
 
@@ -601,7 +610,7 @@ fn test_example_29_refined_selector() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno29");
 }
 
@@ -620,7 +629,7 @@ fn test_example_30_state() {
             }
         }"#;
 
-  Annotation::parse(json).unwrap();
+  parse(json);
 }
 
 #[test]
@@ -640,7 +649,7 @@ fn test_example_31_time_state() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno31");
 }
 
@@ -660,7 +669,7 @@ fn test_example_32_http_request_state() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno32");
 }
 
@@ -689,7 +698,7 @@ fn test_example_33_refined_state() {
             }
         }"#;
 
-  let _annotation = Annotation::parse(json).unwrap();
+  let _annotation = parse(json);
 }
 
 #[test]
@@ -706,7 +715,7 @@ fn test_example_34_stylesheet_external() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno34");
 }
 
@@ -727,7 +736,7 @@ fn test_example_35_stylesheet_embedded() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno35");
 }
 
@@ -749,7 +758,7 @@ fn test_example_36_rendered_via() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno36");
 }
 
@@ -766,7 +775,7 @@ fn test_example_37_scope() {
             }
         }"#;
 
-  let annotation = Annotation::parse(json).unwrap();
+  let annotation = parse(json);
   assert_eq!(annotation.id.as_str(), "http://example.org/anno37");
 }
 
@@ -855,7 +864,7 @@ fn test_example_38_complex_annotation() {
             }
         }"#;
 
-  let _annotation = Annotation::parse(json).unwrap();
+  let _annotation = parse(json);
 }
 
 #[test]
@@ -873,5 +882,37 @@ fn test_portable_epub() {
       }
     }
   }"#;
-  let _annotation = Annotation::parse(json).unwrap();
+  let _annotation = parse(json);
+}
+
+#[test]
+fn test_serialize() {
+  let json = r#"{
+  "@context": "http://www.w3.org/ns/anno.jsonld",
+  "id": "http://example.org/anno1",
+  "type": "Annotation",
+  "motivation": "commenting",
+  "created": "2015-01-28T12:00:00Z",
+  "creator": {
+    "id": "http://example.org/user1",
+    "type": "Person",
+    "name": "My Pseudonym"
+  },
+  "body": {
+    "type": "TextualBody",
+    "value": "Great point!",
+    "format": "text/plain",
+    "language": "en"
+  },
+  "target": {
+    "type": "SpecificResource",
+    "source": "http://example.org/book1",
+    "selector": {
+      "type": "FragmentSelector",
+      "value": "epubcfi(/6/2!/4/2,/1:0,/1:5)",
+      "conformsTo": "http://www.idpf.org/epub/linking/cfi/epub-cfi.html"
+    }
+  }
+}"#;
+  assert_eq!(parse(json).to_json().unwrap(), json);
 }
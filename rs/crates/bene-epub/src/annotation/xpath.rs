@@ -0,0 +1,273 @@
+//! Evaluation of a subset of `XPath`, for [`raw::XPathSelector`](super::raw::XPathSelector).
+//!
+//! Supports location paths made of child (`/`) and descendant (`//`) steps over elements, with
+//! name or `*` node tests, positional predicates (`[2]`, `[last()]`) and attribute equality
+//! predicates (`[@id='intro']`). Namespace prefixes on names are ignored.
+
+use anyhow::{Result, bail};
+
+use crate::dom::ElementInfo;
+
+/// Returns the index of the first element in `elements` (in document order) selected by `path`.
+///
+/// # Errors
+/// If `path` is not in the supported subset of `XPath`.
+pub(crate) fn select(elements: &[ElementInfo], path: &str) -> Result<Option<usize>> {
+  let steps = parse(path)?;
+
+  // `None` stands for the document node, whose only child is the root element.
+  let mut context: Vec<Option<usize>> = vec![None];
+  for step in &steps {
+    let mut selected = Vec::new();
+    for &node in &context {
+      let parents = match step.axis {
+        Axis::Child => vec![node],
+        Axis::Descendant => descendants_or_self(elements, node),
+      };
+      for parent in parents {
+        let children = match parent {
+          Some(parent) => elements[parent].children.clone(),
+          None => vec![0],
+        };
+        let matching = children
+          .into_iter()
+          .filter(|&child| step.test.matches(&elements[child].element.name))
+          .collect::<Vec<_>>();
+        selected.extend(apply_predicates(elements, matching, &step.predicates));
+      }
+    }
+    selected.sort_unstable();
+    selected.dedup();
+    context = selected.into_iter().map(Some).collect();
+  }
+
+  Ok(context.into_iter().flatten().next())
+}
+
+fn descendants_or_self(elements: &[ElementInfo], node: Option<usize>) -> Vec<Option<usize>> {
+  let mut nodes = vec![node];
+  let mut stack = match node {
+    Some(node) => elements[node].children.clone(),
+    None => vec![0],
+  };
+  stack.reverse();
+  while let Some(next) = stack.pop() {
+    nodes.push(Some(next));
+    stack.extend(elements[next].children.iter().rev());
+  }
+  nodes
+}
+
+fn apply_predicates(
+  elements: &[ElementInfo],
+  mut nodes: Vec<usize>,
+  predicates: &[Predicate],
+) -> Vec<usize> {
+  for predicate in predicates {
+    nodes = match predicate {
+      Predicate::Position(position) => nodes.get(position - 1).copied().into_iter().collect(),
+      Predicate::Last => nodes.last().copied().into_iter().collect(),
+      Predicate::Attribute { name, value } => nodes
+        .into_iter()
+        .filter(|&node| {
+          let element = elements[node].element;
+          element.attributes.iter().any(|(key, actual)| {
+            local_name(key) == local_name(name)
+              && value.as_ref().is_none_or(|value| actual == value)
+          })
+        })
+        .collect(),
+    };
+  }
+  nodes
+}
+
+fn local_name(name: &str) -> &str {
+  name.rsplit_once(':').map_or(name, |(_, local)| local)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Axis {
+  Child,
+  Descendant,
+}
+
+#[derive(Debug)]
+enum NodeTest {
+  Any,
+  Name(String),
+}
+
+impl NodeTest {
+  fn matches(&self, name: &str) -> bool {
+    match self {
+      NodeTest::Any => true,
+      NodeTest::Name(expected) => expected.eq_ignore_ascii_case(name),
+    }
+  }
+}
+
+#[derive(Debug)]
+enum Predicate {
+  /// A 1-based position, e.g. `[2]`.
+  Position(usize),
+  /// `[last()]`
+  Last,
+  /// `[@name]` or `[@name='value']`.
+  Attribute { name: String, value: Option<String> },
+}
+
+#[derive(Debug)]
+struct Step {
+  axis: Axis,
+  test: NodeTest,
+  predicates: Vec<Predicate>,
+}
+
+fn parse(path: &str) -> Result<Vec<Step>> {
+  let error = |message: &str| -> Result<Vec<Step>> { bail!("Invalid XPath {path:?}: {message}") };
+
+  let mut steps = Vec::new();
+  let mut rest = path.trim();
+  // A relative path is evaluated from the document node, the same as an absolute one.
+  let mut axis = Axis::Child;
+  loop {
+    if let Some(r) = rest.strip_prefix("//") {
+      axis = Axis::Descendant;
+      rest = r;
+    } else if let Some(r) = rest.strip_prefix('/') {
+      rest = r;
+    }
+
+    let end = rest.find(['/', '[']).unwrap_or(rest.len());
+    let test = match rest[..end].trim() {
+      "" => return error("expected a step"),
+      "*" | "*:*" => NodeTest::Any,
+      name if name.contains(['(', ')', '@', ':']) && !is_qualified_name(name) => {
+        return error(&format!("unsupported step {name:?}"));
+      }
+      name => NodeTest::Name(local_name(name).to_string()),
+    };
+    rest = &rest[end..];
+
+    let mut predicates = Vec::new();
+    while let Some(r) = rest.strip_prefix('[') {
+      let Some(close) = r.find(']') else {
+        return error("expected `]`");
+      };
+      let predicate = r[..close].trim();
+      predicates.push(match predicate {
+        "last()" => Predicate::Last,
+        _ if predicate.starts_with('@') => {
+          let (name, value) = match predicate[1..].split_once('=') {
+            Some((name, value)) => {
+              let value = value.trim();
+              let unquoted = value
+                .strip_prefix('\'')
+                .and_then(|v| v.strip_suffix('\''))
+                .or_else(|| value.strip_prefix('"').and_then(|v| v.strip_suffix('"')));
+              match unquoted {
+                Some(value) => (name.trim(), Some(value.to_string())),
+                None => return error("expected a quoted attribute value"),
+              }
+            }
+            None => (predicate[1..].trim(), None),
+          };
+          Predicate::Attribute {
+            name: name.to_string(),
+            value,
+          }
+        }
+        _ => match predicate.parse::<usize>() {
+          Ok(position) if position > 0 => Predicate::Position(position),
+          _ => return error(&format!("unsupported predicate [{predicate}]")),
+        },
+      });
+      rest = &r[close + 1..];
+    }
+
+    steps.push(Step {
+      axis,
+      test,
+      predicates,
+    });
+    axis = Axis::Child;
+
+    if rest.is_empty() {
+      break;
+    } else if !rest.starts_with('/') {
+      return error("expected `/`");
+    }
+  }
+
+  Ok(steps)
+}
+
+fn is_qualified_name(name: &str) -> bool {
+  let mut parts = name.split(':');
+  let valid = |part: &str| {
+    !part.is_empty()
+      && part
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
+  };
+  parts.next().is_some_and(valid) && parts.next().is_none_or(valid) && parts.next().is_none()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::dom::Document;
+
+  const DOC: &str = r#"<html><body>
+    <div><p>One</p><p id="two">Two</p></div>
+    <div><p>Three</p><span><p>Four</p></span></div>
+  </body></html>"#;
+
+  fn select_text(path: &str) -> Option<String> {
+    let doc = Document::parse(DOC).unwrap();
+    let elements = doc.elements();
+    let index = select(&elements, path).unwrap()?;
+    Some(match &elements[index].element.children[..] {
+      [crate::dom::Node::Text(text)] => text.clone(),
+      _ => elements[index].element.name.clone(),
+    })
+  }
+
+  #[test]
+  fn test_xpath_select() {
+    assert_eq!(
+      select_text("/html/body/div[2]/p[1]").as_deref(),
+      Some("Three")
+    );
+    assert_eq!(
+      select_text("/html[1]/body[1]/div[1]/p[2]").as_deref(),
+      Some("Two")
+    );
+    assert_eq!(select_text("//p[2]").as_deref(), Some("Two"));
+    assert_eq!(select_text("//span/p").as_deref(), Some("Four"));
+    assert_eq!(select_text("//div[last()]/*[2]").as_deref(), Some("span"));
+    assert_eq!(select_text("//p[@id='two']").as_deref(), Some("Two"));
+    assert_eq!(
+      select_text("/xhtml:html/xhtml:body/xhtml:div").as_deref(),
+      Some("div")
+    );
+    assert_eq!(select_text("/html/body/div[3]"), None);
+  }
+
+  #[test]
+  fn test_xpath_parse_errors() {
+    let doc = Document::parse(DOC).unwrap();
+    let elements = doc.elements();
+    for path in [
+      "",
+      "/html/",
+      "//p[0]",
+      "//p[position()>1]",
+      "/html/text()",
+      "//p[@id=two]",
+    ] {
+      assert!(select(&elements, path).is_err(), "{path}");
+    }
+  }
+}
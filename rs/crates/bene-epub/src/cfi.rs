@@ -1,19 +1,24 @@
-use anyhow::anyhow;
+//! Parser for EPUB Canonical Fragment Identifiers (CFIs).
+
+use std::fmt;
+
 use nom::{
-  IResult, Parser,
+  Parser,
   branch::alt,
   bytes::complete::tag,
   character::complete::{char, none_of, one_of},
-  combinator::{opt, recognize},
+  combinator::{cut, opt, recognize},
   error::ErrorKind,
-  multi::{count, many1},
-  sequence::{delimited, preceded},
+  multi::many1,
+  sequence::{preceded, terminated},
 };
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
+type IResult<'a, T> = nom::IResult<&'a str, T, NomError<'a>>;
+
 trait Parse: Sized {
-  fn nom(i: &str) -> IResult<&str, Self>;
+  fn nom(i: &str) -> IResult<'_, Self>;
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, TS)]
@@ -60,71 +65,273 @@ pub enum Offset {
   Character(u32),
 }
 
+impl fmt::Display for Fragment {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "epubcfi({}", self.path)?;
+    if let Some(range) = &self.range {
+      write!(f, ",{},{}", range.from, range.to)?;
+    }
+    write!(f, ")")
+  }
+}
+
+impl fmt::Display for Path {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for component in &self.components {
+      match component {
+        PathComponent::Step(n) => write!(f, "/{n}")?,
+        PathComponent::Assertion(Assertion::Id(id)) => write!(f, "[{id}]")?,
+        PathComponent::Indirection => write!(f, "!")?,
+      }
+    }
+    match self.offset {
+      Some(Offset::Character(n)) => write!(f, ":{n}"),
+      None => Ok(()),
+    }
+  }
+}
+
+/// The syntactic element that the CFI parser expected to find when it failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Expected {
+  /// The `epubcfi(` prefix.
+  Prefix,
+  /// A path step, e.g. `/4`.
+  Step,
+  /// The contents of an ID assertion, e.g. the `chap01` in `[chap01]`.
+  Assertion,
+  /// The closing `]` of an ID assertion.
+  AssertionEnd,
+  /// A character offset, e.g. `:10`.
+  Offset,
+  /// The second path of a range, e.g. `,/1:15`.
+  RangeEnd,
+  /// The closing `)` of the fragment.
+  ClosingParen,
+  /// The end of the input after the closing `)`.
+  End,
+}
+
+impl fmt::Display for Expected {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Expected::Prefix => "`epubcfi(`",
+      Expected::Step => "a step (e.g. `/4`)",
+      Expected::Assertion => "an assertion (e.g. `[chap01]`)",
+      Expected::AssertionEnd => "`]` to close the assertion",
+      Expected::Offset => "a character offset (e.g. `:10`)",
+      Expected::RangeEnd => "the end of the range (e.g. `,/1:15`)",
+      Expected::ClosingParen => "`)`",
+      Expected::End => "the end of the fragment",
+    })
+  }
+}
+
+/// An error from parsing an EPUB CFI with [`Fragment::parse`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+  input: String,
+  offset: usize,
+  expected: Expected,
+}
+
+impl ParseError {
+  fn new(input: &str, rest: &str, expected: Expected) -> Self {
+    ParseError {
+      input: input.to_string(),
+      offset: input.len() - rest.len(),
+      expected,
+    }
+  }
+
+  /// The byte offset into the input where parsing failed.
+  pub fn offset(&self) -> usize {
+    self.offset
+  }
+
+  /// What the parser expected to find at [`ParseError::offset`].
+  pub fn expected(&self) -> Expected {
+    self.expected
+  }
+}
+
+/// Maximum number of characters shown on either side of the error in an excerpt.
+const EXCERPT_RADIUS: usize = 40;
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let (before, after) = self.input.split_at(self.offset);
+    let before_len = before.chars().count();
+    let (ellipsis, before) = if before_len > EXCERPT_RADIUS {
+      let skip = before_len - EXCERPT_RADIUS;
+      ("...", before.chars().skip(skip).collect::<String>())
+    } else {
+      ("", before.to_string())
+    };
+    let after = after.chars().take(EXCERPT_RADIUS).collect::<String>();
+    let trailing = if after.len() < self.input.len() - self.offset {
+      "..."
+    } else {
+      ""
+    };
+    let caret_column = ellipsis.len() + before.chars().count();
+
+    writeln!(f, "expected {} at byte {}", self.expected, self.offset)?;
+    writeln!(f, "  {ellipsis}{before}{after}{trailing}")?;
+    write!(f, "  {:caret_column$}^", "")
+  }
+}
+
+impl std::error::Error for ParseError {}
+
+/// The internal error type threaded through the nom parsers.
+///
+/// `expected` is `None` only for errors produced directly by nom combinators,
+/// which [`expect`] replaces before they can escape a parser.
+struct NomError<'a> {
+  rest: &'a str,
+  expected: Option<Expected>,
+}
+
+impl<'a> nom::error::ParseError<&'a str> for NomError<'a> {
+  fn from_error_kind(input: &'a str, _kind: ErrorKind) -> Self {
+    NomError {
+      rest: input,
+      expected: None,
+    }
+  }
+
+  fn append(_input: &'a str, _kind: ErrorKind, other: Self) -> Self {
+    other
+  }
+}
+
+/// Labels recoverable errors from `parser` as expecting `expected` at the start of its input.
+fn expect<'a, O>(
+  expected: Expected,
+  mut parser: impl Parser<&'a str, Output = O, Error = NomError<'a>>,
+) -> impl Parser<&'a str, Output = O, Error = NomError<'a>> {
+  move |i: &'a str| {
+    parser.parse(i).map_err(|err| match err {
+      nom::Err::Error(_) => nom::Err::Error(NomError {
+        rest: i,
+        expected: Some(expected),
+      }),
+      err => err,
+    })
+  }
+}
+
 impl Fragment {
-  pub fn parse(i: &str) -> anyhow::Result<Self> {
-    let (_, fragment) = Fragment::nom(i).map_err(|e| anyhow!("{e}"))?;
-    Ok(fragment)
+  /// Parses an EPUB CFI of the form `epubcfi(...)`.
+  ///
+  /// # Errors
+  /// If `i` is not a syntactically valid CFI. The [`ParseError`] reports where and why.
+  pub fn parse(i: &str) -> Result<Self, ParseError> {
+    match Fragment::nom(i) {
+      Ok(("", fragment)) => Ok(fragment),
+      Ok((rest, _)) => Err(ParseError::new(i, rest, Expected::End)),
+      Err(nom::Err::Error(err) | nom::Err::Failure(err)) => Err(ParseError::new(
+        i,
+        err.rest,
+        err.expected.unwrap_or(Expected::Step),
+      )),
+      Err(nom::Err::Incomplete(_)) => unreachable!("complete parsers cannot be incomplete"),
+    }
   }
 }
 
 impl Parse for Fragment {
-  fn nom(i: &str) -> IResult<&str, Self> {
-    let (i, (path, range)) =
-      delimited(tag("epubcfi("), Path::nom.and(opt(Range::nom)), char(')')).parse(i)?;
+  fn nom(i: &str) -> IResult<'_, Self> {
+    let (i, _) = expect(Expected::Prefix, tag("epubcfi(")).parse(i)?;
+    let (i, (path, range)) = cut(Path::nom.and(opt(Range::nom))).parse(i)?;
+    let (i, _) = cut(expect(Expected::ClosingParen, char(')'))).parse(i)?;
     Ok((i, Fragment { path, range }))
   }
 }
 
 impl Parse for Range {
-  fn nom(i: &str) -> IResult<&str, Self> {
-    let (i, mut paths) = count(preceded(char(','), Path::nom), 2).parse(i)?;
-    let to = paths.remove(1);
-    let from = paths.remove(0);
-    let range = Range { from, to };
-    Ok((i, range))
+  fn nom(i: &str) -> IResult<'_, Self> {
+    let (i, from) = preceded(char(','), cut(Path::local)).parse(i)?;
+    let (i, to) = cut(expect(
+      Expected::RangeEnd,
+      preceded(char(','), cut(Path::local)),
+    ))
+    .parse(i)?;
+    Ok((i, Range { from, to }))
   }
 }
 
 impl Parse for Path {
-  fn nom(i: &str) -> IResult<&str, Self> {
+  fn nom(i: &str) -> IResult<'_, Self> {
     let (i, (components, offset)) = many1(PathComponent::nom).and(opt(Offset::nom)).parse(i)?;
     Ok((i, Path { components, offset }))
   }
 }
 
+impl Path {
+  /// Parses a path relative to the end of a range's parent path, which may be just an offset into
+  /// the node it ends at, like the `:0` in `epubcfi(/6/4!/4/2/1,:0,:11)`.
+  fn local(i: &str) -> IResult<'_, Self> {
+    expect(
+      Expected::Step,
+      alt((
+        Path::nom,
+        Offset::nom.map(|offset| Path {
+          components: Vec::new(),
+          offset: Some(offset),
+        }),
+      )),
+    )
+    .parse(i)
+  }
+}
+
 impl Parse for PathComponent {
-  fn nom(i: &str) -> IResult<&str, Self> {
-    alt((
-      preceded(char('/'), integer).map(PathComponent::Step),
-      char('!').map(|_| PathComponent::Indirection),
-      Assertion::nom.map(PathComponent::Assertion),
-    ))
+  fn nom(i: &str) -> IResult<'_, Self> {
+    expect(
+      Expected::Step,
+      alt((
+        preceded(char('/'), cut(expect(Expected::Step, integer))).map(PathComponent::Step),
+        char('!').map(|_| PathComponent::Indirection),
+        Assertion::nom.map(PathComponent::Assertion),
+      )),
+    )
     .parse(i)
   }
 }
 
-fn integer(i: &str) -> IResult<&str, u32> {
-  let (i, s) = recognize(many1(one_of("0123456789"))).parse(i)?;
+fn integer(i: &str) -> IResult<'_, u32> {
+  let (rest, s) = recognize(many1(one_of("0123456789"))).parse(i)?;
   let n = s
     .parse::<u32>()
-    .map_err(|_| nom::Err::Error(nom::error::Error::new(i, ErrorKind::AlphaNumeric)))?;
-  Ok((i, n))
+    .map_err(|_| nom::Err::Error(nom::error::ParseError::from_error_kind(i, ErrorKind::Digit)))?;
+  Ok((rest, n))
 }
 
-fn string(i: &str) -> IResult<&str, String> {
+fn string(i: &str) -> IResult<'_, String> {
   let (i, s) = recognize(many1(none_of("^[](),;="))).parse(i)?;
   Ok((i, s.to_string()))
 }
 
 impl Parse for Assertion {
-  fn nom(i: &str) -> IResult<&str, Self> {
-    delimited(char('['), alt((string.map(Assertion::Id),)), char(']')).parse(i)
+  fn nom(i: &str) -> IResult<'_, Self> {
+    preceded(
+      char('['),
+      cut(terminated(
+        expect(Expected::Assertion, string.map(Assertion::Id)),
+        expect(Expected::AssertionEnd, char(']')),
+      )),
+    )
+    .parse(i)
   }
 }
 
 impl Parse for Offset {
-  fn nom(i: &str) -> IResult<&str, Self> {
-    alt((preceded(char(':'), integer).map(Offset::Character),)).parse(i)
+  fn nom(i: &str) -> IResult<'_, Self> {
+    preceded(char(':'), cut(expect(Expected::Offset, integer)))
+      .map(Offset::Character)
+      .parse(i)
   }
 }
 
@@ -164,4 +371,45 @@ mod test {
       }
     );
   }
+
+  #[test]
+  fn test_cfi_display() {
+    for cfi in [
+      "epubcfi(/6/2[pageref]!/4/2/2/8,/1:0,/1:15)",
+      "epubcfi(/6/4!/4/10/1:3)",
+      "epubcfi(/6/4[chapter]!/4/2/1,:0,:11)",
+    ] {
+      assert_eq!(Fragment::parse(cfi).unwrap().to_string(), cfi);
+    }
+  }
+
+  #[test]
+  fn test_cfi_parse_errors() {
+    let cases = [
+      ("epubcfi/6/4)", 0, Expected::Prefix),
+      ("epubcfi(6/4)", 8, Expected::Step),
+      ("epubcfi(/6/x)", 11, Expected::Step),
+      ("epubcfi(/6/4[])", 13, Expected::Assertion),
+      ("epubcfi(/6/4[chap01)", 19, Expected::AssertionEnd),
+      ("epubcfi(/6/4:x)", 13, Expected::Offset),
+      ("epubcfi(/6/4,/1:0)", 17, Expected::RangeEnd),
+      ("epubcfi(/6/4,,:1)", 13, Expected::Step),
+      ("epubcfi(/6/4/1,:0,:x)", 19, Expected::Offset),
+      ("epubcfi(/6/4", 12, Expected::ClosingParen),
+      ("epubcfi(/6/4))", 13, Expected::End),
+    ];
+    for (cfi, offset, expected) in cases {
+      let err = Fragment::parse(cfi).unwrap_err();
+      assert_eq!((err.offset(), err.expected()), (offset, expected), "{cfi}");
+    }
+  }
+
+  #[test]
+  fn test_cfi_parse_error_message() {
+    let err = Fragment::parse("epubcfi(/6/4!/4/x)").unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "expected a step (e.g. `/4`) at byte 16\n  epubcfi(/6/4!/4/x)\n                  ^"
+    );
+  }
 }
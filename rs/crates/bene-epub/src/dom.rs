@@ -0,0 +1,397 @@
+//! A minimal DOM for XHTML content documents, used to resolve and construct EPUB CFIs.
+
+use std::ops::Range;
+
+use anyhow::{Context, Result, bail};
+use quick_xml::{
+  escape::resolve_predefined_entity,
+  events::{BytesStart, Event},
+};
+
+#[derive(Debug)]
+pub(crate) enum Node {
+  Element(Element),
+  Text(String),
+}
+
+#[derive(Debug)]
+pub(crate) struct Element {
+  /// The local name of the element, e.g. `p` for both `<p>` and `<xhtml:p>`.
+  pub name: String,
+  pub attributes: Vec<(String, String)>,
+  pub children: Vec<Node>,
+}
+
+impl Element {
+  fn from_start(start: &BytesStart) -> Result<Self> {
+    let name = String::from_utf8_lossy(start.local_name().as_ref()).into_owned();
+    let attributes = start
+      .attributes()
+      .map(|attr| {
+        let attr = attr?;
+        let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+        let value = attr.unescape_value()?.into_owned();
+        Ok((key, value))
+      })
+      .collect::<Result<Vec<_>>>()?;
+    Ok(Element {
+      name,
+      attributes,
+      children: Vec::new(),
+    })
+  }
+
+  /// Returns the value of the attribute with the qualified name `name`.
+  pub fn attribute(&self, name: &str) -> Option<&str> {
+    self
+      .attributes
+      .iter()
+      .find(|(key, _)| key == name)
+      .map(|(_, value)| value.as_str())
+  }
+
+  /// Returns the concatenated text of the element and its descendants.
+  pub fn text_content(&self) -> String {
+    fn visit(element: &Element, text: &mut String) {
+      for child in &element.children {
+        match child {
+          Node::Element(child) => visit(child, text),
+          Node::Text(s) => text.push_str(s),
+        }
+      }
+    }
+    let mut text = String::new();
+    visit(self, &mut text);
+    text
+  }
+
+  /// Returns the element's children which are elements, paired with their CFI step.
+  pub fn child_elements(&self) -> impl Iterator<Item = (u32, &Element)> {
+    self
+      .children
+      .iter()
+      .filter_map(|child| match child {
+        Node::Element(element) => Some(element),
+        Node::Text(_) => None,
+      })
+      .zip((2..).step_by(2))
+      .map(|(element, step)| (step, element))
+  }
+
+  fn push_text(&mut self, s: &str) {
+    if let Some(Node::Text(text)) = self.children.last_mut() {
+      text.push_str(s);
+    } else {
+      self.children.push(Node::Text(s.to_string()));
+    }
+  }
+}
+
+#[derive(Debug)]
+pub(crate) struct Document {
+  pub root: Element,
+}
+
+impl Document {
+  /// Parses an XHTML document.
+  ///
+  /// Adjacent text, CDATA sections and entity references are merged into a single
+  /// text node, matching how EPUB CFIs address character data.
+  pub fn parse(xml: &str) -> Result<Self> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut stack: Vec<Element> = Vec::new();
+    let mut root = None;
+
+    let mut close = |stack: &mut Vec<Element>, element: Element| match stack.last_mut() {
+      Some(parent) => parent.children.push(Node::Element(element)),
+      None => root = Some(element),
+    };
+
+    loop {
+      let event = reader
+        .read_event()
+        .with_context(|| format!("Invalid XML at byte {}", reader.error_position()))?;
+      match event {
+        Event::Start(start) => stack.push(Element::from_start(&start)?),
+        Event::Empty(start) => close(&mut stack, Element::from_start(&start)?),
+        Event::End(_) => {
+          let Some(element) = stack.pop() else {
+            bail!("Unbalanced end tag at byte {}", reader.buffer_position());
+          };
+          close(&mut stack, element);
+        }
+        Event::Text(text) => {
+          if let Some(parent) = stack.last_mut() {
+            parent.push_text(&text.xml_content()?);
+          }
+        }
+        Event::CData(cdata) => {
+          if let Some(parent) = stack.last_mut() {
+            parent.push_text(&cdata.xml_content()?);
+          }
+        }
+        Event::GeneralRef(reference) => {
+          if let Some(parent) = stack.last_mut() {
+            let name = reference.decode()?;
+            match reference.resolve_char_ref()? {
+              Some(c) => parent.push_text(c.encode_utf8(&mut [0; 4])),
+              None => match resolve_predefined_entity(&name) {
+                Some(s) => parent.push_text(s),
+                None => parent.push_text(&format!("&{name};")),
+              },
+            }
+          }
+        }
+        Event::Eof => break,
+        Event::Comment(_) | Event::Decl(_) | Event::PI(_) | Event::DocType(_) => {}
+      }
+    }
+
+    match root {
+      Some(root) if stack.is_empty() => Ok(Document { root }),
+      _ => bail!("Document has no root element or unclosed elements"),
+    }
+  }
+
+  /// Returns the `<body>` element and its CFI steps from the root, or the root itself if there is no body.
+  pub fn body(&self) -> (Vec<u32>, &Element) {
+    match self
+      .root
+      .child_elements()
+      .find(|(_, element)| element.name == "body")
+    {
+      Some((step, body)) => (vec![step], body),
+      None => (Vec::new(), &self.root),
+    }
+  }
+
+  /// Lists every element of the document in document order.
+  pub fn elements(&self) -> Vec<ElementInfo<'_>> {
+    fn visit<'a>(
+      element: &'a Element,
+      steps: &[u32],
+      parent: Option<usize>,
+      sibling_index: usize,
+      elements: &mut Vec<ElementInfo<'a>>,
+    ) -> usize {
+      let index = elements.len();
+      elements.push(ElementInfo {
+        element,
+        steps: steps.to_vec(),
+        parent,
+        sibling_index,
+        children: Vec::new(),
+      });
+      for (i, (step, child)) in element.child_elements().enumerate() {
+        let child_steps = [steps, &[step]].concat();
+        let child_index = visit(child, &child_steps, Some(index), i, elements);
+        elements[index].children.push(child_index);
+      }
+      index
+    }
+
+    let mut elements = Vec::new();
+    visit(&self.root, &[], None, 0, &mut elements);
+    elements
+  }
+
+  /// Indexes the text content of the document's body.
+  pub fn text_index(&self) -> TextIndex {
+    fn visit(element: &Element, steps: &mut Vec<u32>, index: &mut TextIndex) {
+      let mut elements_seen = 0;
+      for child in &element.children {
+        match child {
+          Node::Element(child) => {
+            elements_seen += 1;
+            steps.push(2 * elements_seen);
+            visit(child, steps, index);
+            steps.pop();
+          }
+          Node::Text(text) => {
+            let start = index.text.len();
+            index.text.push_str(text);
+            let mut segment_steps = steps.clone();
+            segment_steps.push(2 * elements_seen + 1);
+            index.segments.push(Segment {
+              steps: segment_steps,
+              start,
+              end: index.text.len(),
+            });
+          }
+        }
+      }
+    }
+
+    let (mut steps, body) = self.body();
+    let mut index = TextIndex {
+      text: String::new(),
+      segments: Vec::new(),
+    };
+    visit(body, &mut steps, &mut index);
+    index
+  }
+}
+
+/// An element in the flattened list returned by [`Document::elements`].
+#[derive(Debug)]
+pub(crate) struct ElementInfo<'a> {
+  pub element: &'a Element,
+  /// The CFI steps from the root element to this element.
+  pub steps: Vec<u32>,
+  /// The index of the parent element, or `None` for the root.
+  pub parent: Option<usize>,
+  /// The position of this element among its parent's child elements.
+  pub sibling_index: usize,
+  /// The indices of the element's child elements.
+  pub children: Vec<usize>,
+}
+
+/// A point in a document, as CFI steps from the root element and an optional character offset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Location {
+  pub steps: Vec<u32>,
+  pub offset: Option<u32>,
+}
+
+/// Which text node to prefer when a position falls on the boundary between two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Bias {
+  /// Prefer the following text node, as for the start of a range.
+  Start,
+  /// Prefer the preceding text node, as for the end of a range.
+  End,
+}
+
+#[derive(Debug)]
+struct Segment {
+  /// The CFI steps to the text node, ending with its odd-numbered step.
+  steps: Vec<u32>,
+  /// The byte range of the text node within [`TextIndex::text`].
+  start: usize,
+  end: usize,
+}
+
+/// The text content of a document, with a mapping from text positions to CFI locations.
+#[derive(Debug)]
+pub(crate) struct TextIndex {
+  pub text: String,
+  segments: Vec<Segment>,
+}
+
+impl TextIndex {
+  /// Converts a byte position in [`TextIndex::text`] into a [`Location`].
+  ///
+  /// Character offsets are measured in UTF-16 code units, matching the reader's DOM.
+  pub fn location(&self, pos: usize, bias: Bias) -> Option<Location> {
+    let segment = match bias {
+      Bias::Start => self
+        .segments
+        .iter()
+        .find(|segment| segment.start <= pos && pos < segment.end),
+      Bias::End => self
+        .segments
+        .iter()
+        .find(|segment| segment.start < pos && pos <= segment.end),
+    }?;
+    let offset = self.text[segment.start..pos].encode_utf16().count();
+    Some(Location {
+      steps: segment.steps.clone(),
+      offset: Some(u32::try_from(offset).ok()?),
+    })
+  }
+
+  /// Returns the byte range of all text within the element at `steps`.
+  ///
+  /// Returns `None` if the element contains no text.
+  pub fn element_range(&self, steps: &[u32]) -> Option<Range<usize>> {
+    let mut segments = self
+      .segments
+      .iter()
+      .filter(|segment| segment.steps.len() > steps.len() && segment.steps.starts_with(steps));
+    let first = segments.next()?;
+    let last = segments.next_back().unwrap_or(first);
+    Some(first.start..last.end)
+  }
+
+  /// Converts a [`Location`] back into a byte position in [`TextIndex::text`].
+  ///
+  /// A location without an offset that points to an element resolves to the start of the element's text.
+  pub fn position(&self, location: &Location) -> Option<usize> {
+    let Some(segment) = self
+      .segments
+      .iter()
+      .find(|segment| segment.steps == location.steps)
+    else {
+      return match location.offset {
+        None => Some(self.element_range(&location.steps)?.start),
+        Some(_) => None,
+      };
+    };
+
+    let offset = location.offset.unwrap_or(0) as usize;
+    let mut units = 0;
+    for (i, c) in self.text[segment.start..segment.end].char_indices() {
+      if units >= offset {
+        return Some(segment.start + i);
+      }
+      units += c.len_utf16();
+    }
+    (units >= offset).then_some(segment.end)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const DOC: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml"><head><title>T</title></head><body><p id="a">One &amp; <em>two</em>&#33;<![CDATA[ <three>]]></p></body></html>"#;
+
+  #[test]
+  fn test_parse() {
+    let doc = Document::parse(DOC).unwrap();
+    let (steps, body) = doc.body();
+    assert_eq!(steps, vec![4]);
+    let (step, p) = body.child_elements().next().unwrap();
+    assert_eq!(step, 2);
+    assert_eq!(p.attribute("id"), Some("a"));
+    assert_eq!(p.children.len(), 3);
+
+    let elements = doc.elements();
+    let names = elements
+      .iter()
+      .map(|e| e.element.name.as_str())
+      .collect::<Vec<_>>();
+    assert_eq!(names, ["html", "head", "title", "body", "p", "em"]);
+    assert_eq!(elements[5].steps, vec![4, 2, 2]);
+    assert_eq!(elements[5].parent, Some(4));
+  }
+
+  #[test]
+  fn test_text_index() {
+    let doc = Document::parse(DOC).unwrap();
+    let index = doc.text_index();
+    assert_eq!(index.text, "One & two! <three>");
+
+    let location = index.location(6, Bias::Start).unwrap();
+    assert_eq!(location.steps, vec![4, 2, 2, 1]);
+    assert_eq!(location.offset, Some(0));
+    assert_eq!(index.position(&location), Some(6));
+
+    // Position 6 is the boundary between the first text node and the <em>.
+    let location = index.location(6, Bias::End).unwrap();
+    assert_eq!(location.steps, vec![4, 2, 1]);
+    assert_eq!(location.offset, Some(6));
+
+    let location = index.location(10, Bias::Start).unwrap();
+    assert_eq!(location.steps, vec![4, 2, 3]);
+    assert_eq!(location.offset, Some(1));
+
+    assert_eq!(index.element_range(&[4, 2, 2]), Some(6..9));
+    let em = Location {
+      steps: vec![4, 2, 2],
+      offset: None,
+    };
+    assert_eq!(index.position(&em), Some(6));
+  }
+}
@@ -6,16 +6,24 @@
 use std::{path::Path, string::FromUtf8Error};
 
 use anyhow::{Context, Result, anyhow, ensure};
-use log::trace;
+use log::{trace, warn};
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
 use crate::annotation::{Annotation, RawAnnotation};
 
-pub use self::zip::{Archive, ArchiveFormat, FileZip, MemoryZip};
+pub use self::zip::{
+  Archive, ArchiveFormat, Checksums, FileZip, MemoryZip, ZipFormat, changed_files,
+};
 
-mod annotation;
-mod cfi;
+pub mod annotation;
+pub mod cfi;
+mod dom;
+pub mod nav;
+#[cfg(test)]
+mod test_utils;
+pub mod validate;
+pub mod write;
 mod zip;
 
 #[derive(Serialize, Deserialize, Debug, TS, Clone)]
@@ -107,6 +115,9 @@ pub struct Rendition {
   pub package: Package,
   pub package_string: String,
   pub root: String,
+  package_path: String,
+  annotations: Vec<Annotation>,
+  warnings: Vec<String>,
 }
 
 impl Rendition {
@@ -117,7 +128,10 @@ impl Rendition {
   /// - If [`Rootfile::full_path`] fails to be read from the archive.
   /// - If the [`Rendition`] contents cannot be interpreted as UTF-8.
   /// - If the [`Rendition`] UTF-8 contents cannot be interpreted as XML.
-  pub fn load(archive: &mut Archive, rootfile: &Rootfile) -> Result<Self> {
+  ///
+  /// Failing to load the rendition's annotations is not an error. Such failures are
+  /// instead recorded in [`Rendition::warnings`].
+  pub fn load<F: ZipFormat>(archive: &mut Archive<F>, rootfile: &Rootfile) -> Result<Self> {
     let root = Path::new(&rootfile.full_path)
       .parent()
       .ok_or_else(|| anyhow!("Rootfile path is not a file: {}", rootfile.full_path))?
@@ -128,13 +142,212 @@ impl Rendition {
       .context("Failed while reading EPUB package file")?;
     trace!("Package: {package:#?}");
 
-    Ok(Rendition {
+    let mut rendition = Rendition {
       package,
       package_string,
       root,
+      package_path: rootfile.full_path.clone(),
+      annotations: Vec::new(),
+      warnings: Vec::new(),
+    };
+
+    match load_annotations(archive, &rendition) {
+      Ok((annotations, diagnostics)) => {
+        rendition.annotations = annotations;
+        for diagnostic in diagnostics {
+          warn!("{diagnostic}");
+          rendition.warnings.push(diagnostic.to_string());
+        }
+      }
+      Err(err) => {
+        warn!("Failed to load annotations: {err:?}");
+        rendition.warnings.push(format!("{err:?}"));
+      }
+    }
+
+    Ok(rendition)
+  }
+
+  /// Returns the manifest item with the `ppub:annotations` property, if any.
+  pub fn annotations_item(&self) -> Option<&Item> {
+    self.item_with_property("ppub:annotations")
+  }
+
+  /// Returns the manifest item of the cover image, marked either by the `cover-image` property or
+  /// by an EPUB 2 `<meta name="cover">`.
+  pub fn cover_item(&self) -> Option<&Item> {
+    self.item_with_property("cover-image").or_else(|| {
+      let metadata = self.metadata_element()?;
+      let id = metadata
+        .child_elements()
+        .find(|(_, element)| element.name == "meta" && element.attribute("name") == Some("cover"))
+        .and_then(|(_, element)| element.attribute("content"))?;
+      self.item(id)
+    })
+  }
+
+  fn item_with_property(&self, property: &str) -> Option<&Item> {
+    let items = &self.package.manifest.items;
+    items.iter().find(|item| {
+      item
+        .properties
+        .as_deref()
+        .is_some_and(|properties| properties.split_whitespace().any(|p| p == property))
+    })
+  }
+
+  /// Returns the path in the archive of the rendition's package document.
+  pub fn package_path(&self) -> &str {
+    &self.package_path
+  }
+
+  /// Returns the annotations shipped with the rendition in its `ppub:annotations` manifest item.
+  pub fn annotations(&self) -> &[Annotation] {
+    &self.annotations
+  }
+
+  /// Returns non-fatal problems encountered while loading the rendition.
+  pub fn warnings(&self) -> &[String] {
+    &self.warnings
+  }
+
+  /// Returns the value of the `dc:identifier` referenced by the package's `unique-identifier`.
+  ///
+  /// Falls back to the first `dc:identifier` if the reference cannot be resolved.
+  pub fn unique_identifier(&self) -> Option<String> {
+    let text = |element: &dom::Element| {
+      element.children.iter().find_map(|child| match child {
+        dom::Node::Text(text) => Some(text.trim().to_string()),
+        dom::Node::Element(_) => None,
+      })
+    };
+    let referenced = dom::Document::parse(&self.package_string)
+      .ok()
+      .and_then(|document| {
+        let elements = document.elements();
+        elements
+          .iter()
+          .find(|info| {
+            info.element.name == "identifier"
+              && info.element.attribute("id") == Some(&self.package.unique_identifier)
+          })
+          .and_then(|info| text(info.element))
+      });
+    referenced.or_else(|| {
+      self
+        .package
+        .metadata
+        .fields
+        .iter()
+        .find_map(|field| match field {
+          MetaField::Identifier(identifier) => Some(identifier.trim().to_string()),
+          _ => None,
+        })
+    })
+  }
+
+  /// Returns the package's `dc:creator`s in order, with the names to sort them by given either by a
+  /// `file-as` meta which refines them or by an EPUB 2 `opf:file-as` attribute.
+  pub fn creators(&self) -> Vec<Creator> {
+    let Some(metadata) = self.metadata_element() else {
+      return Vec::new();
+    };
+    metadata
+      .child_elements()
+      .filter(|(_, element)| element.name == "creator")
+      .map(|(_, element)| {
+        let file_as = element
+          .attribute("id")
+          .and_then(|id| refinement(&metadata, id, "file-as"))
+          .or_else(|| {
+            let (_, file_as) = element
+              .attributes
+              .iter()
+              .find(|(key, _)| key.ends_with(":file-as"))?;
+            Some(file_as.trim().to_string())
+          });
+        Creator {
+          name: element.text_content().trim().to_string(),
+          file_as,
+        }
+      })
+      .collect()
+  }
+
+  /// Returns the series which the publication belongs to.
+  ///
+  /// The series is given by a `belongs-to-collection` meta whose `collection-type` is `series` or
+  /// unset, or else by Calibre's `calibre:series` meta.
+  pub fn series(&self) -> Option<Series> {
+    let metadata = self.metadata_element()?;
+    let metas = || {
+      metadata
+        .child_elements()
+        .map(|(_, element)| element)
+        .filter(|element| element.name == "meta")
+    };
+
+    let collection = metas().find_map(|element| {
+      if element.attribute("property") != Some("belongs-to-collection")
+        || element.attribute("refines").is_some()
+      {
+        return None;
+      }
+      let id = element.attribute("id");
+      let refined = |property| id.and_then(|id| refinement(&metadata, id, property));
+      match refined("collection-type").as_deref() {
+        None | Some("series") => Some(Series {
+          name: element.text_content().trim().to_string(),
+          position: refined("group-position"),
+        }),
+        Some(_) => None,
+      }
+    });
+    collection.or_else(|| {
+      let content = |name| {
+        metas()
+          .find(|element| element.attribute("name") == Some(name))
+          .and_then(|element| element.attribute("content"))
+          .map(|content| content.trim().to_string())
+      };
+      Some(Series {
+        name: content("calibre:series")?,
+        position: content("calibre:series_index"),
+      })
     })
   }
 
+  /// Returns the CFI step from the `<package>` element to its `<spine>`.
+  ///
+  /// This is usually 6, after `<metadata>` and `<manifest>`, but some packages put other elements
+  /// like `<guide>` first.
+  pub(crate) fn spine_step(&self) -> u32 {
+    dom::Document::parse(&self.package_string)
+      .ok()
+      .and_then(|document| {
+        document
+          .root
+          .child_elements()
+          .find(|(_, element)| element.name == "spine")
+          .map(|(step, _)| step)
+      })
+      .unwrap_or(6)
+  }
+
+  /// Parses the package's `<metadata>` element, which keeps the attributes like `refines` that are
+  /// dropped from [`Metadata`].
+  fn metadata_element(&self) -> Option<dom::Element> {
+    let document = dom::Document::parse(&self.package_string).ok()?;
+    document
+      .root
+      .children
+      .into_iter()
+      .find_map(|child| match child {
+        dom::Node::Element(element) if element.name == "metadata" => Some(element),
+        _ => None,
+      })
+  }
+
   /// Gets an [`Item`] by its [`Item::id`] from the rendition.
   ///
   /// Returns `None` if the [`Item::id`] is not contained in the rendition.
@@ -149,26 +362,48 @@ impl Rendition {
   }
 }
 
-#[allow(unused)]
-fn load_annotations(
-  archive: &mut Archive,
+/// Returns the value of the `property` meta which refines the element with `id` in `metadata`.
+fn refinement(metadata: &dom::Element, id: &str, property: &str) -> Option<String> {
+  let target = format!("#{id}");
+  metadata.child_elements().find_map(|(_, element)| {
+    (element.name == "meta"
+      && element.attribute("refines") == Some(&target)
+      && element.attribute("property") == Some(property))
+    .then(|| element.text_content().trim().to_string())
+  })
+}
+
+/// A `dc:creator` of a publication.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Creator {
+  pub name: String,
+  /// The name to sort the creator by, like "Woolf, Virginia", if the package gives one.
+  pub file_as: Option<String>,
+}
+
+/// A series of publications, and where one publication falls in it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Series {
+  pub name: String,
+  /// The publication's position in the series, like `2` or `1.5`.
+  pub position: Option<String>,
+}
+
+fn load_annotations<F: ZipFormat>(
+  archive: &mut Archive<F>,
   rendition: &Rendition,
-) -> Result<Vec<Annotation>, anyhow::Error> {
-  let annotation_item = rendition
-    .package
-    .manifest
-    .items
-    .iter()
-    .find(|item| matches!(item.properties.as_deref(), Some("ppub:annotations")));
+) -> Result<(Vec<Annotation>, Vec<annotation::Diagnostic>)> {
+  let annotation_item = rendition.annotations_item();
   let annotations = match annotation_item {
     Some(item) => {
       let annotations_path = rendition.file_path(&item.href);
       let raw_annotations = archive
         .read_json::<Vec<RawAnnotation>>(&annotations_path)
         .context("Error while parsing annotations file")?;
-      annotation::process(raw_annotations).context("Error while processing raw annotations")?
+      annotation::process(raw_annotations, archive, rendition)
     }
-    None => Vec::new(),
+    None => (Vec::new(), Vec::new()),
   };
   Ok(annotations)
 }
@@ -214,7 +449,7 @@ impl Epub {
   /// - If `META-INF/container.xml` cannot be read as a [`Container`].
   /// - If the [`Archive`] cannot be cloned with [`Archive::try_clone`].
   /// - If any [`Rendition`] fails to load with [`Rendition::load`].
-  pub fn load(archive: &mut Archive) -> Result<Self> {
+  pub fn load<F: ZipFormat>(archive: &mut Archive<F>) -> Result<Self> {
     let (container, _) = archive
       .read_xml::<Container>("META-INF/container.xml")
       .context("Failed to read EPUB metadata")?;
@@ -240,6 +475,20 @@ impl Epub {
 
     Ok(Epub { renditions })
   }
+
+  /// Returns the paths of the files which are parsed into the [`Epub`] by [`Epub::load`].
+  ///
+  /// Changes to other files, like chapters or stylesheets, do not change the [`Epub`].
+  pub fn package_files(&self) -> Vec<String> {
+    let mut files = vec!["META-INF/container.xml".to_string()];
+    for rendition in &self.renditions {
+      files.push(rendition.package_path().to_string());
+      if let Some(item) = rendition.annotations_item() {
+        files.push(rendition.file_path(&item.href));
+      }
+    }
+    files
+  }
 }
 
 /// Guesses the MIME type of a file based on its extension.
@@ -270,3 +519,134 @@ pub fn htmlify_xhtml(contents: Vec<u8>) -> Result<Vec<u8>, FromUtf8Error> {
   s = s.replace("<br></br>", "<br>");
   Ok(s.into_bytes())
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test_utils;
+
+  #[test]
+  fn test_rendition_annotations() {
+    let annotations = r#"[{
+      "@context": "http://www.w3.org/ns/anno.jsonld",
+      "id": "anno0",
+      "type": "Annotation",
+      "bodyValue": "A comment",
+      "target": {
+        "source": ".",
+        "selector": {
+          "type": "FragmentSelector",
+          "conformsTo": "http://www.idpf.org/epub/linking/cfi/epub-cfi.html",
+          "value": "epubcfi(/6/2!/4/2,/1:0,/1:5)"
+        }
+      }
+    }]"#;
+    let mut archive = test_utils::archive(&[("EPUB/annotations.json", annotations)]);
+    let epub = Epub::load(&mut archive).unwrap();
+    let rendition = &epub.renditions[0];
+    assert!(rendition.warnings().is_empty());
+    assert_eq!(rendition.annotations().len(), 1);
+    assert_eq!(
+      rendition.annotations()[0].bodies,
+      vec![annotation::Body::Text {
+        value: "A comment".into(),
+        format: None,
+        language: None,
+        purpose: Vec::new()
+      }]
+    );
+  }
+
+  #[test]
+  fn test_rendition_unique_identifier() {
+    let mut archive = test_utils::archive(&[]);
+    let epub = Epub::load(&mut archive).unwrap();
+    assert_eq!(
+      epub.renditions[0].unique_identifier().as_deref(),
+      Some("urn:test:book")
+    );
+  }
+
+  #[test]
+  fn test_rendition_catalog_metadata() {
+    let package = r##"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" xmlns:opf="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:test:book</dc:identifier>
+    <dc:title>The Waves</dc:title>
+    <dc:creator id="woolf">Virginia Woolf</dc:creator>
+    <meta refines="#woolf" property="file-as">Woolf, Virginia</meta>
+    <dc:creator opf:file-as="Bell, Vanessa">Vanessa Bell</dc:creator>
+    <dc:creator>Anonymous</dc:creator>
+    <meta property="belongs-to-collection" id="set">Modern Library</meta>
+    <meta refines="#set" property="collection-type">set</meta>
+    <meta property="belongs-to-collection" id="series">Novels</meta>
+    <meta refines="#series" property="group-position">7</meta>
+    <meta name="cover" content="cover"/>
+    <meta property="dcterms:modified">2024-01-01T00:00:00Z</meta>
+  </metadata>
+  <manifest>
+    <item id="chapter" href="chapter.xhtml" media-type="application/xhtml+xml" />
+    <item id="nav" href="nav.xhtml" properties="nav" media-type="application/xhtml+xml" />
+    <item id="cover" href="cover.jpg" media-type="image/jpeg" />
+  </manifest>
+  <spine>
+    <itemref idref="chapter" />
+  </spine>
+</package>"##;
+    let mut archive = test_utils::archive(&[("EPUB/package.opf", package)]);
+    let epub = Epub::load(&mut archive).unwrap();
+    let rendition = &epub.renditions[0];
+
+    let creator = |name: &str, file_as: Option<&str>| Creator {
+      name: name.into(),
+      file_as: file_as.map(Into::into),
+    };
+    assert_eq!(
+      rendition.creators(),
+      vec![
+        creator("Virginia Woolf", Some("Woolf, Virginia")),
+        creator("Vanessa Bell", Some("Bell, Vanessa")),
+        creator("Anonymous", None)
+      ]
+    );
+    assert_eq!(
+      rendition.series(),
+      Some(Series {
+        name: "Novels".into(),
+        position: Some("7".into())
+      })
+    );
+    assert_eq!(rendition.cover_item().unwrap().href, "cover.jpg");
+  }
+
+  #[test]
+  fn test_rendition_calibre_series() {
+    let package = test_utils::PACKAGE.replace(
+      "<dc:language>",
+      r#"<meta name="calibre:series" content="Tests"/>
+    <meta name="calibre:series_index" content="2.5"/>
+    <dc:language>"#,
+    );
+    let mut archive = test_utils::archive(&[("EPUB/package.opf", &package)]);
+    let epub = Epub::load(&mut archive).unwrap();
+    let rendition = &epub.renditions[0];
+    assert_eq!(
+      rendition.series(),
+      Some(Series {
+        name: "Tests".into(),
+        position: Some("2.5".into())
+      })
+    );
+    assert!(rendition.cover_item().is_none());
+  }
+
+  #[test]
+  fn test_rendition_annotation_warnings() {
+    let mut archive = test_utils::archive(&[("EPUB/annotations.json", "[{")]);
+    let epub = Epub::load(&mut archive).unwrap();
+    let rendition = &epub.renditions[0];
+    assert!(rendition.annotations().is_empty());
+    assert_eq!(rendition.warnings().len(), 1);
+  }
+}
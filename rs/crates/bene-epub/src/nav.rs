@@ -0,0 +1,233 @@
+//! The table of contents from an EPUB navigation document.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{Archive, Item, Rendition, ZipFormat, dom};
+
+/// An entry in the table of contents.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct TocEntry {
+  pub title: String,
+  /// The path in the archive of the linked document, including any fragment.
+  ///
+  /// Entries which only group their children may have no link.
+  pub href: Option<String>,
+  pub children: Vec<TocEntry>,
+}
+
+impl TocEntry {
+  /// Returns the path of the linked document without its fragment.
+  pub fn path(&self) -> Option<&str> {
+    let href = self.href.as_deref()?;
+    Some(href.split('#').next().unwrap_or(href))
+  }
+
+  /// Iterates over the entry and its descendants in document order.
+  pub fn flatten(&self) -> Box<dyn Iterator<Item = &TocEntry> + '_> {
+    Box::new(
+      [self]
+        .into_iter()
+        .chain(self.children.iter().flat_map(TocEntry::flatten)),
+    )
+  }
+}
+
+impl Rendition {
+  /// Returns the manifest item with the `nav` property, if any.
+  pub fn nav_item(&self) -> Option<&Item> {
+    let items = &self.package.manifest.items;
+    items.iter().find(|item| {
+      item
+        .properties
+        .as_deref()
+        .is_some_and(|properties| properties.split_whitespace().any(|p| p == "nav"))
+    })
+  }
+
+  /// Reads the table of contents from the rendition's navigation document.
+  ///
+  /// # Errors
+  /// - If the rendition has no navigation document.
+  /// - If the navigation document cannot be read or parsed.
+  /// - If the navigation document has no `<nav>` element.
+  pub fn toc<F: ZipFormat>(&self, archive: &mut Archive<F>) -> Result<Vec<TocEntry>> {
+    let item = self
+      .nav_item()
+      .context("Package has no navigation document")?;
+    let path = resolve_href(&self.root, &item.href);
+    let bytes = archive.read_file(&path)?;
+    let xml = String::from_utf8(bytes).with_context(|| format!("File is not UTF-8: {path}"))?;
+    let document = dom::Document::parse(&xml).with_context(|| format!("Failed to parse {path}"))?;
+
+    let elements = document.elements();
+    let navs = elements
+      .iter()
+      .filter(|info| info.element.name == "nav")
+      .collect::<Vec<_>>();
+    let is_toc = |element: &dom::Element| {
+      element
+        .attribute("epub:type")
+        .is_some_and(|kind| kind.split_whitespace().any(|kind| kind == "toc"))
+    };
+    let nav = navs
+      .iter()
+      .find(|info| is_toc(info.element))
+      .or(navs.first())
+      .context("Navigation document has no <nav> element")?;
+
+    let base = path.rsplit_once('/').map_or("", |(dir, _)| dir);
+    Ok(
+      find_child(nav.element, "ol")
+        .map(|list| list_entries(list, base))
+        .unwrap_or_default(),
+    )
+  }
+
+  /// Returns the path in the archive of the content document at `spine_index`.
+  pub(crate) fn spine_path(&self, spine_index: usize) -> Option<String> {
+    let itemref = self.package.spine.itemref.get(spine_index)?;
+    let item = self.item(&itemref.idref)?;
+    Some(resolve_href(&self.root, &item.href))
+  }
+}
+
+fn find_child<'a>(element: &'a dom::Element, name: &str) -> Option<&'a dom::Element> {
+  element
+    .child_elements()
+    .map(|(_, child)| child)
+    .find(|child| child.name == name)
+}
+
+/// Converts the `<li>` children of an `<ol>` into entries.
+fn list_entries(list: &dom::Element, base: &str) -> Vec<TocEntry> {
+  list
+    .child_elements()
+    .filter(|(_, item)| item.name == "li")
+    .filter_map(|(_, item)| {
+      // Each item is labeled by an <a> or a <span>, optionally followed by a nested list.
+      let label = item
+        .child_elements()
+        .map(|(_, child)| child)
+        .find(|child| child.name == "a" || child.name == "span")?;
+      let title = label
+        .text_content()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+      let href = label
+        .attribute("href")
+        .filter(|_| label.name == "a")
+        .map(|href| resolve_href(base, href));
+      let children = find_child(item, "ol")
+        .map(|list| list_entries(list, base))
+        .unwrap_or_default();
+      Some(TocEntry {
+        title,
+        href,
+        children,
+      })
+    })
+    .collect()
+}
+
+/// Resolves `href`, relative to the directory `base` in the archive, into a path in the archive.
+pub(crate) fn resolve_href(base: &str, href: &str) -> String {
+  let (path, fragment) = match href.split_once('#') {
+    Some((path, fragment)) => (path, Some(fragment)),
+    None => (href, None),
+  };
+  let mut segments = if path.starts_with('/') {
+    Vec::new()
+  } else {
+    base
+      .split('/')
+      .filter(|s| !s.is_empty())
+      .collect::<Vec<_>>()
+  };
+  for segment in path.split('/') {
+    match segment {
+      "" | "." => {}
+      ".." => {
+        segments.pop();
+      }
+      segment => segments.push(segment),
+    }
+  }
+
+  let mut resolved = segments.join("/");
+  if let Some(fragment) = fragment {
+    resolved.push('#');
+    resolved.push_str(fragment);
+  }
+  resolved
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{Epub, test_utils};
+
+  #[test]
+  fn test_resolve_href() {
+    assert_eq!(resolve_href("EPUB", "chapter.xhtml"), "EPUB/chapter.xhtml");
+    assert_eq!(
+      resolve_href("EPUB/nav", "../text/a.xhtml#s1"),
+      "EPUB/text/a.xhtml#s1"
+    );
+    assert_eq!(resolve_href("", "./a.xhtml"), "a.xhtml");
+    assert_eq!(resolve_href("EPUB", "/a.xhtml"), "a.xhtml");
+  }
+
+  #[test]
+  fn test_toc() {
+    let nav = r#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+  <body>
+    <nav epub:type="landmarks"><ol><li><a href="cover.xhtml">Cover</a></li></ol></nav>
+    <nav epub:type="toc">
+      <ol>
+        <li><a href="chapter.xhtml">The
+          Chapter</a>
+          <ol><li><a href="chapter.xhtml#first">A <em>Section</em></a></li></ol>
+        </li>
+        <li><span>Appendices</span><ol><li><a href="text/appendix.xhtml">Appendix</a></li></ol></li>
+      </ol>
+    </nav>
+  </body>
+</html>"#;
+    let mut archive = test_utils::archive(&[("EPUB/nav.xhtml", nav)]);
+    let epub = Epub::load(&mut archive).unwrap();
+    let toc = epub.renditions[0].toc(&mut archive).unwrap();
+
+    let entry = |title: &str, href: Option<&str>, children| TocEntry {
+      title: title.into(),
+      href: href.map(Into::into),
+      children,
+    };
+    assert_eq!(
+      toc,
+      vec![
+        entry(
+          "The Chapter",
+          Some("EPUB/chapter.xhtml"),
+          vec![entry("A Section", Some("EPUB/chapter.xhtml#first"), vec![])]
+        ),
+        entry(
+          "Appendices",
+          None,
+          vec![entry("Appendix", Some("EPUB/text/appendix.xhtml"), vec![])]
+        ),
+      ]
+    );
+    let titles = toc
+      .iter()
+      .flat_map(TocEntry::flatten)
+      .map(|entry| entry.title.as_str())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      titles,
+      ["The Chapter", "A Section", "Appendices", "Appendix"]
+    );
+    assert_eq!(toc[0].children[0].path(), Some("EPUB/chapter.xhtml"));
+  }
+}
@@ -0,0 +1,90 @@
+//! Helpers for building EPUB archives in memory for tests.
+
+use std::io::{Cursor, Write};
+
+use zip::{ZipWriter, write::SimpleFileOptions};
+
+use crate::{Archive, MemoryZip};
+
+pub const CONTAINER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container xmlns="urn:oasis:names:tc:opendocument:xmlns:container" version="1.0">
+  <rootfiles>
+    <rootfile full-path="EPUB/package.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#;
+
+pub const PACKAGE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid" prefix="ppub: http://example.com/ppub">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:test:book</dc:identifier>
+    <dc:title>Test Book</dc:title>
+    <dc:creator>Test Author</dc:creator>
+    <dc:language>en</dc:language>
+    <meta property="dcterms:modified">2024-01-01T00:00:00Z</meta>
+  </metadata>
+  <manifest>
+    <item id="chapter" href="chapter.xhtml" media-type="application/xhtml+xml" />
+    <item id="nav" href="nav.xhtml" properties="nav" media-type="application/xhtml+xml" />
+    <item id="annotations" href="annotations.json" properties="ppub:annotations" media-type="application/json" />
+  </manifest>
+  <spine>
+    <itemref idref="chapter" />
+  </spine>
+</package>"#;
+
+pub const CHAPTER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+  <head><title>Chapter</title></head>
+  <body>
+    <p id="first">Hello world, this is a test.</p>
+    <p>Another <em>emphatic</em> paragraph about the world.</p>
+  </body>
+</html>"#;
+
+pub const NAV: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+  <head><title>Navigation</title></head>
+  <body>
+    <nav epub:type="toc"><ol><li><a href="chapter.xhtml">The Chapter</a></li></ol></nav>
+  </body>
+</html>"#;
+
+/// Builds a ZIP file from `files`, with `mimetype` stored first.
+pub fn zip_bytes(files: &[(&str, &str)]) -> Vec<u8> {
+  let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+  let stored = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+  writer.start_file("mimetype", stored).unwrap();
+  writer.write_all(b"application/epub+zip").unwrap();
+  for (path, contents) in files {
+    writer
+      .start_file(*path, SimpleFileOptions::default())
+      .unwrap();
+    writer.write_all(contents.as_bytes()).unwrap();
+  }
+  writer.finish().unwrap().into_inner()
+}
+
+/// Builds an EPUB file with a default container, package, chapter and navigation document.
+///
+/// Entries in `overrides` replace the defaults at the same path, or are added otherwise.
+pub fn epub_bytes(overrides: &[(&str, &str)]) -> Vec<u8> {
+  let mut files = vec![
+    ("META-INF/container.xml", CONTAINER),
+    ("EPUB/package.opf", PACKAGE),
+    ("EPUB/chapter.xhtml", CHAPTER),
+    ("EPUB/nav.xhtml", NAV),
+    ("EPUB/annotations.json", "[]"),
+  ];
+  for (path, contents) in overrides {
+    match files.iter_mut().find(|(p, _)| p == path) {
+      Some(file) => file.1 = contents,
+      None => files.push((path, contents)),
+    }
+  }
+  zip_bytes(&files)
+}
+
+/// Loads the EPUB built by [`epub_bytes`] as an archive.
+pub fn archive(overrides: &[(&str, &str)]) -> Archive<MemoryZip> {
+  Archive::load(MemoryZip(epub_bytes(overrides).into())).unwrap()
+}
@@ -0,0 +1,257 @@
+//! Checks that an EPUB is well-formed beyond what is needed to load it.
+
+use std::{collections::HashSet, fmt};
+
+use serde::Serialize;
+
+use crate::{Archive, Epub, MetaField, Rendition, ZipFormat, nav::resolve_href};
+
+/// How serious a [`Problem`] is.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+  /// The EPUB violates the specification, and reading systems may fail to open it.
+  Error,
+  /// The EPUB is likely to be displayed incorrectly.
+  Warning,
+}
+
+/// A problem found in an EPUB by [`validate`].
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+  pub severity: Severity,
+  pub message: String,
+}
+
+impl fmt::Display for Problem {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let severity = match self.severity {
+      Severity::Error => "error",
+      Severity::Warning => "warning",
+    };
+    write!(f, "{severity}: {}", self.message)
+  }
+}
+
+/// Checks the container, packages, navigation documents and annotations of the EPUB in `archive`.
+///
+/// Returns the problems found, errors and warnings alike, in the order they were found.
+pub fn validate<F: ZipFormat>(archive: &mut Archive<F>) -> Vec<Problem> {
+  let mut problems = Problems::default();
+  check_mimetype(archive, &mut problems);
+
+  let epub = match Epub::load(archive) {
+    Ok(epub) => epub,
+    Err(err) => {
+      problems.error(format!("{err:#}"));
+      return problems.0;
+    }
+  };
+
+  let mut used = HashSet::from(["mimetype".to_string(), "META-INF/container.xml".to_string()]);
+  for rendition in &epub.renditions {
+    check_rendition(archive, rendition, &mut used, &mut problems);
+  }
+
+  for file in archive.file_names() {
+    if !used.contains(file) && !file.starts_with("META-INF/") && !file.ends_with('/') {
+      problems.warning(format!("File is not in any manifest: {file}"));
+    }
+  }
+
+  problems.0
+}
+
+#[derive(Default)]
+struct Problems(Vec<Problem>);
+
+impl Problems {
+  fn error(&mut self, message: String) {
+    self.0.push(Problem {
+      severity: Severity::Error,
+      message,
+    });
+  }
+
+  fn warning(&mut self, message: String) {
+    self.0.push(Problem {
+      severity: Severity::Warning,
+      message,
+    });
+  }
+}
+
+fn check_mimetype<F: ZipFormat>(archive: &mut Archive<F>, problems: &mut Problems) {
+  if archive.file_names().next() != Some("mimetype") {
+    problems.error("The first file in the archive is not `mimetype`".into());
+  }
+  let Ok(contents) = archive.read_file("mimetype") else {
+    problems.error("The archive has no `mimetype` file".into());
+    return;
+  };
+  if contents != b"application/epub+zip" {
+    problems.error("The `mimetype` file is not `application/epub+zip`".into());
+  }
+  if !archive.is_stored("mimetype").unwrap_or(true) {
+    problems.error("The `mimetype` file is compressed".into());
+  }
+}
+
+fn check_rendition<F: ZipFormat>(
+  archive: &mut Archive<F>,
+  rendition: &Rendition,
+  used: &mut HashSet<String>,
+  problems: &mut Problems,
+) {
+  let package_path = rendition.package_path();
+  used.insert(package_path.to_string());
+
+  let fields = &rendition.package.metadata.fields;
+  let has = |f: fn(&MetaField) -> bool| fields.iter().any(f);
+  if rendition.unique_identifier().is_none() {
+    problems.error(format!("{package_path}: Package has no `dc:identifier`"));
+  }
+  if !has(|field| matches!(field, MetaField::Title(_))) {
+    problems.error(format!("{package_path}: Package has no `dc:title`"));
+  }
+  if !has(|field| matches!(field, MetaField::Language(_))) {
+    problems.error(format!("{package_path}: Package has no `dc:language`"));
+  }
+  let modified = |field: &MetaField| match field {
+    MetaField::Meta { property, .. } => property.as_deref() == Some("dcterms:modified"),
+    _ => false,
+  };
+  if !fields.iter().any(modified) {
+    problems.error(format!(
+      "{package_path}: Package has no `dcterms:modified` meta"
+    ));
+  }
+
+  let mut ids = HashSet::new();
+  for item in &rendition.package.manifest.items {
+    if !ids.insert(item.id.as_str()) {
+      problems.error(format!(
+        "{package_path}: Duplicate manifest item id: {}",
+        item.id
+      ));
+    }
+    if item.href.contains("://") {
+      continue;
+    }
+    let path = resolve_href(&rendition.root, &item.href);
+    if archive.contains(&path) {
+      used.insert(path);
+    } else {
+      problems.error(format!(
+        "{package_path}: Manifest item `{}` is missing from the archive: {path}",
+        item.id
+      ));
+    }
+  }
+
+  if rendition.package.spine.itemref.is_empty() {
+    problems.error(format!("{package_path}: Spine is empty"));
+  }
+  for itemref in &rendition.package.spine.itemref {
+    if rendition.item(&itemref.idref).is_none() {
+      problems.error(format!(
+        "{package_path}: Spine refers to a missing manifest item: {}",
+        itemref.idref
+      ));
+    }
+  }
+
+  match rendition.toc(archive) {
+    Ok(toc) => {
+      for entry in toc.iter().flat_map(|entry| entry.flatten()) {
+        if let Some(path) = entry.path()
+          && !archive.contains(path)
+        {
+          problems.warning(format!(
+            "{package_path}: Table of contents entry `{}` links to a missing file: {path}",
+            entry.title
+          ));
+        }
+      }
+    }
+    Err(err) => problems.error(format!("{package_path}: {err:#}")),
+  }
+
+  for warning in rendition.warnings() {
+    problems.warning(format!("{package_path}: {warning}"));
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test_utils::{self, PACKAGE};
+
+  fn messages(problems: &[Problem]) -> Vec<String> {
+    problems.iter().map(ToString::to_string).collect()
+  }
+
+  #[test]
+  fn test_validate_valid() {
+    let mut archive = test_utils::archive(&[]);
+    assert_eq!(validate(&mut archive), []);
+  }
+
+  #[test]
+  fn test_validate_problems() {
+    let package = PACKAGE
+      .replace(
+        r#"<meta property="dcterms:modified">2024-01-01T00:00:00Z</meta>"#,
+        "",
+      )
+      .replace(
+        r#"<itemref idref="chapter" />"#,
+        r#"<itemref idref="chapter" /><itemref idref="appendix" />"#,
+      )
+      .replace(
+        "</manifest>",
+        r#"<item id="cover" href="images/cover.png" media-type="image/png" /></manifest>"#,
+      );
+    let mut archive =
+      test_utils::archive(&[("EPUB/package.opf", &package), ("EPUB/stray.css", "")]);
+    assert_eq!(
+      messages(&validate(&mut archive)),
+      [
+        "error: EPUB/package.opf: Package has no `dcterms:modified` meta",
+        "error: EPUB/package.opf: Manifest item `cover` is missing from the archive: EPUB/images/cover.png",
+        "error: EPUB/package.opf: Spine refers to a missing manifest item: appendix",
+        "warning: File is not in any manifest: EPUB/stray.css",
+      ]
+    );
+  }
+
+  #[test]
+  fn test_validate_mimetype() {
+    use std::io::{Cursor, Write};
+
+    use zip::{ZipWriter, write::SimpleFileOptions};
+
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    writer
+      .start_file("META-INF/container.xml", SimpleFileOptions::default())
+      .unwrap();
+    writer.write_all(test_utils::CONTAINER.as_bytes()).unwrap();
+    writer
+      .start_file("mimetype", SimpleFileOptions::default())
+      .unwrap();
+    writer.write_all(b"application/epub").unwrap();
+    let bytes = writer.finish().unwrap().into_inner();
+
+    let mut archive = Archive::load(crate::MemoryZip(bytes.into())).unwrap();
+    let problems = messages(&validate(&mut archive));
+    assert_eq!(
+      problems[..3],
+      [
+        "error: The first file in the archive is not `mimetype`",
+        "error: The `mimetype` file is not `application/epub+zip`",
+        "error: The `mimetype` file is compressed",
+      ]
+    );
+    assert!(problems[3].contains("Failed while reading EPUB package file"));
+  }
+}
@@ -0,0 +1,456 @@
+//! Writing changes back into EPUB files.
+
+use std::{
+  fmt::Write as _,
+  io::{Seek, Write},
+  ops::Range,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+  fs,
+  io::{self, BufWriter},
+  path::Path,
+  time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use quick_xml::{
+  Reader,
+  escape::escape,
+  events::{BytesStart, Event},
+};
+
+use crate::{Archive, Item, Rendition, ZipFormat, annotation::RawAnnotation};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{Epub, FileZip};
+
+/// The declaration of the `ppub:` prefix added to packages which lack one.
+const PPUB_PREFIX: &str = "ppub: http://example.com/ppub";
+
+/// Writes a copy of `archive` to `writer` with `annotations` as the `ppub:annotations` of `rendition`.
+///
+/// If the package has no `ppub:annotations` item, one is added to its manifest along with a
+/// declaration of the `ppub:` prefix. The package's `dcterms:modified` is set to `modified`,
+/// which should be a UTC timestamp like `2024-01-01T00:00:00Z`. All other files are preserved.
+///
+/// # Errors
+/// - If the package document is not well-formed XML.
+/// - If the archive cannot be read or `writer` cannot be written.
+pub fn write_annotations<F: ZipFormat, W: Write + Seek>(
+  archive: &mut Archive<F>,
+  rendition: &Rendition,
+  annotations: &[RawAnnotation],
+  modified: &str,
+  writer: W,
+) -> Result<W> {
+  let replacements = annotation_files(rendition, annotations, modified)?;
+  archive.rewrite(writer, &replacements)
+}
+
+/// Returns the paths and new contents of the files changed by [`write_annotations`].
+fn annotation_files(
+  rendition: &Rendition,
+  annotations: &[RawAnnotation],
+  modified: &str,
+) -> Result<[(String, Vec<u8>); 2]> {
+  let (href, new_item) = match rendition.annotations_item() {
+    Some(item) => (item.href.clone(), None),
+    None => {
+      let (id, href) = unused_item(&rendition.package.manifest.items);
+      let item = format!(
+        r#"<item id="{id}" href="{href}" media-type="application/json" properties="ppub:annotations" />"#
+      );
+      (href, Some(item))
+    }
+  };
+
+  let package = edit_package(&rendition.package_string, new_item.as_deref(), modified)
+    .context("Failed to update package document")?;
+  let json = serde_json::to_vec_pretty(annotations)?;
+  Ok([
+    (rendition.package_path().to_string(), package.into_bytes()),
+    (rendition.file_path(&href), json),
+  ])
+}
+
+/// Replaces the `ppub:annotations` of the EPUB file at `path`, as in [`write_annotations`].
+///
+/// The new EPUB is written to a temporary file next to `path` which is then renamed over it,
+/// so a crash never leaves a partially written book. If `path` is an unzipped EPUB directory,
+/// only its package document and annotations file are replaced, each in the same way.
+///
+/// # Errors
+/// - If the EPUB at `path` cannot be loaded.
+/// - If the new EPUB cannot be written.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn save_annotations(path: &Path, annotations: &[RawAnnotation]) -> Result<()> {
+  let mut archive = Archive::load(FileZip(path.to_path_buf()))?;
+  let epub = Epub::load(&mut archive)?;
+  let rendition = epub.renditions.first().context("EPUB has no renditions")?;
+  let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+  let modified = timestamp(now.as_secs());
+
+  if let Some(dir) = archive.directory() {
+    for (file, contents) in annotation_files(rendition, annotations, &modified)? {
+      replace_file(&dir.join(file), |mut writer| {
+        writer.write_all(&contents)?;
+        Ok(writer)
+      })?;
+    }
+    return Ok(());
+  }
+
+  replace_file(path, |writer| {
+    write_annotations(&mut archive, rendition, annotations, &modified, writer)
+  })
+}
+
+/// Replaces the file at `path` with the output of `write`, by way of a temporary file.
+#[cfg(not(target_arch = "wasm32"))]
+fn replace_file(
+  path: &Path,
+  write: impl FnOnce(BufWriter<fs::File>) -> Result<BufWriter<fs::File>>,
+) -> Result<()> {
+  let file_name = path.file_name().context("Path is not a file")?;
+  let temp_path = path.with_file_name(format!(
+    ".{}.{}.tmp",
+    file_name.to_string_lossy(),
+    std::process::id()
+  ));
+  let result = (|| -> Result<()> {
+    let file = fs::File::create_new(&temp_path)?;
+    let writer = write(BufWriter::new(file))?;
+    writer
+      .into_inner()
+      .map_err(io::IntoInnerError::into_error)?
+      .sync_all()?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+  })();
+  if result.is_err() {
+    let _ = fs::remove_file(&temp_path);
+  }
+  result.with_context(|| format!("Failed to write: {}", path.display()))
+}
+
+/// Picks an id and href for a new annotations item which do not clash with `items`.
+fn unused_item(items: &[Item]) -> (String, String) {
+  let mut n = 1;
+  loop {
+    let suffix = if n == 1 {
+      String::new()
+    } else {
+      format!("-{n}")
+    };
+    let id = format!("annotations{suffix}");
+    let href = format!("annotations{suffix}.json");
+    if !items.iter().any(|item| item.id == id || item.href == href) {
+      return (id, href);
+    }
+    n += 1;
+  }
+}
+
+/// Updates the package document, changing as little of its text as possible.
+///
+/// Adds `new_item` to the manifest if provided, ensures the `ppub:` prefix is declared,
+/// and sets `dcterms:modified` to `modified`.
+fn edit_package(package: &str, new_item: Option<&str>, modified: &str) -> Result<String> {
+  let modified = escape(modified);
+  let meta = format!(r#"<meta property="dcterms:modified">{modified}</meta>"#);
+
+  let mut edits: Vec<(Range<usize>, String)> = Vec::new();
+  let mut reader = Reader::from_str(package);
+  let mut modified_text_start = None;
+  let mut has_modified = false;
+  loop {
+    let start = usize::try_from(reader.buffer_position())?;
+    let event = reader
+      .read_event()
+      .with_context(|| format!("Invalid XML at byte {}", reader.error_position()))?;
+    let end = usize::try_from(reader.buffer_position())?;
+    match event {
+      Event::Start(tag) if tag.local_name().as_ref() == b"package" => {
+        if let Some(tag) = declare_ppub_prefix(&tag)? {
+          edits.push((start..end, tag));
+        }
+      }
+      Event::Start(tag) if is_modified_meta(&tag)? => modified_text_start = Some(end),
+      Event::Empty(tag) if is_modified_meta(&tag)? => {
+        edits.push((start..end, meta.clone()));
+        has_modified = true;
+      }
+      Event::End(tag) => {
+        if let Some(text_start) = modified_text_start.take() {
+          edits.push((text_start..start, modified.to_string()));
+          has_modified = true;
+        } else if tag.local_name().as_ref() == b"metadata" && !has_modified {
+          edits.push((start..start, child(package, start, &meta)));
+        } else if tag.local_name().as_ref() == b"manifest"
+          && let Some(item) = new_item
+        {
+          edits.push((start..start, child(package, start, item)));
+        }
+      }
+      Event::Eof => break,
+      _ => {}
+    }
+  }
+
+  let mut package = package.to_string();
+  edits.sort_by_key(|(range, _)| range.start);
+  for (range, replacement) in edits.into_iter().rev() {
+    package.replace_range(range, &replacement);
+  }
+  Ok(package)
+}
+
+fn is_modified_meta(tag: &BytesStart) -> Result<bool> {
+  Ok(
+    tag.local_name().as_ref() == b"meta"
+      && tag
+        .try_get_attribute("property")?
+        .is_some_and(|attr| attr.value.as_ref() == b"dcterms:modified"),
+  )
+}
+
+/// Returns the `<package>` start tag with a `ppub:` prefix declaration, or `None` if it has one.
+fn declare_ppub_prefix(tag: &BytesStart) -> Result<Option<String>> {
+  let mut attributes = tag
+    .attributes()
+    .map(|attr| {
+      let attr = attr?;
+      let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+      Ok((key, attr.unescape_value()?.into_owned()))
+    })
+    .collect::<Result<Vec<_>>>()?;
+
+  match attributes.iter_mut().find(|(key, _)| key == "prefix") {
+    Some((_, prefix)) if prefix.split_whitespace().any(|token| token == "ppub:") => {
+      return Ok(None);
+    }
+    Some((_, prefix)) if !prefix.trim().is_empty() => {
+      *prefix = format!("{} {PPUB_PREFIX}", prefix.trim());
+    }
+    Some((_, prefix)) => *prefix = PPUB_PREFIX.to_string(),
+    None => attributes.push(("prefix".into(), PPUB_PREFIX.into())),
+  }
+
+  let mut start = format!("<{}", String::from_utf8_lossy(tag.name().as_ref()));
+  for (key, value) in attributes {
+    write!(start, r#" {key}="{}""#, escape(&value))?;
+  }
+  start.push('>');
+  Ok(Some(start))
+}
+
+/// Formats `element` for insertion before the closing tag at `close_start`, matching its indentation.
+fn child(source: &str, close_start: usize, element: &str) -> String {
+  let line_start = source[..close_start].rfind('\n').map_or(0, |i| i + 1);
+  let indent = &source[line_start..close_start];
+  if line_start > 0 && indent.chars().all(char::is_whitespace) {
+    format!("  {element}\n{indent}")
+  } else {
+    element.to_string()
+  }
+}
+
+/// Formats seconds since the Unix epoch as a UTC timestamp, e.g. `2024-01-01T00:00:00Z`.
+pub(crate) fn timestamp(secs: u64) -> String {
+  // Converts days since the epoch to a date, following Howard Hinnant's `civil_from_days`.
+  let z = secs / 86_400 + 719_468;
+  let era = z / 146_097;
+  let day_of_era = z - era * 146_097;
+  let year_of_era =
+    (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let shifted_month = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+  let month = if shifted_month < 10 {
+    shifted_month + 3
+  } else {
+    shifted_month - 9
+  };
+  let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+  let time = secs % 86_400;
+  format!(
+    "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+    time / 3600,
+    time % 3600 / 60,
+    time % 60
+  )
+}
+
+#[cfg(test)]
+mod test {
+  use std::io::{Cursor, Read};
+
+  use zip::{CompressionMethod, ZipArchive};
+
+  use super::*;
+  use crate::{MemoryZip, test_utils};
+
+  const ANNOTATIONS: &str = r#"[{
+    "@context": "http://www.w3.org/ns/anno.jsonld",
+    "id": "anno1",
+    "type": "Annotation",
+    "bodyValue": "A comment",
+    "target": "chapter.xhtml#epubcfi(/6/2!/4/2,/1:0,/1:5)"
+  }]"#;
+
+  fn annotations() -> Vec<RawAnnotation> {
+    serde_json::from_str(ANNOTATIONS).unwrap()
+  }
+
+  fn write(overrides: &[(&str, &str)]) -> (Vec<u8>, Vec<u8>) {
+    let original = test_utils::epub_bytes(overrides);
+    let mut archive = Archive::load(MemoryZip(original.clone().into())).unwrap();
+    let epub = Epub::load(&mut archive).unwrap();
+    let written = write_annotations(
+      &mut archive,
+      &epub.renditions[0],
+      &annotations(),
+      "2025-06-01T12:00:00Z",
+      Cursor::new(Vec::new()),
+    )
+    .unwrap();
+    (original, written.into_inner())
+  }
+
+  fn raw_entry(zip: &[u8], name: &str) -> Vec<u8> {
+    let mut zip = ZipArchive::new(Cursor::new(zip)).unwrap();
+    let index = zip.index_for_name(name).unwrap();
+    let mut bytes = Vec::new();
+    zip
+      .by_index_raw(index)
+      .unwrap()
+      .read_to_end(&mut bytes)
+      .unwrap();
+    bytes
+  }
+
+  fn load(bytes: Vec<u8>) -> Epub {
+    let mut archive = Archive::load(MemoryZip(bytes.into())).unwrap();
+    Epub::load(&mut archive).unwrap()
+  }
+
+  #[test]
+  fn test_write_annotations() {
+    let (original, written) = write(&[]);
+
+    let mut zip = ZipArchive::new(Cursor::new(&written)).unwrap();
+    let mimetype = zip.by_index(0).unwrap();
+    assert_eq!(mimetype.name(), "mimetype");
+    assert_eq!(mimetype.compression(), CompressionMethod::Stored);
+    drop(mimetype);
+
+    for name in [
+      "EPUB/chapter.xhtml",
+      "EPUB/nav.xhtml",
+      "META-INF/container.xml",
+    ] {
+      assert_eq!(
+        raw_entry(&original, name),
+        raw_entry(&written, name),
+        "{name}"
+      );
+    }
+
+    let epub = load(written);
+    let rendition = &epub.renditions[0];
+    assert!(
+      rendition.warnings().is_empty(),
+      "{:?}",
+      rendition.warnings()
+    );
+    assert_eq!(rendition.annotations().len(), 1);
+    assert_eq!(rendition.package.manifest.items.len(), 3);
+    assert!(
+      rendition
+        .package_string
+        .contains(r#"<meta property="dcterms:modified">2025-06-01T12:00:00Z</meta>"#)
+    );
+  }
+
+  #[test]
+  fn test_write_annotations_adds_item() {
+    let package = test_utils::PACKAGE
+      .replace(r#" prefix="ppub: http://example.com/ppub""#, "")
+      .replace(
+        r#"<meta property="dcterms:modified">2024-01-01T00:00:00Z</meta>"#,
+        "",
+      )
+      .replace(
+        r#"<item id="annotations" href="annotations.json" properties="ppub:annotations" media-type="application/json" />"#,
+        r#"<item id="annotations" href="notes.json" media-type="application/json" />"#,
+      );
+    let (_, written) = write(&[("EPUB/package.opf", &package)]);
+
+    let epub = load(written);
+    let rendition = &epub.renditions[0];
+    assert!(
+      rendition.warnings().is_empty(),
+      "{:?}",
+      rendition.warnings()
+    );
+    assert_eq!(rendition.annotations().len(), 1);
+    let item = rendition.annotations_item().unwrap();
+    assert_eq!(item.id, "annotations-2");
+    assert_eq!(item.href, "annotations-2.json");
+
+    let package = &rendition.package_string;
+    assert!(package.contains(r#"prefix="ppub: http://example.com/ppub""#));
+    assert!(package.contains(r#"<meta property="dcterms:modified">2025-06-01T12:00:00Z</meta>"#));
+  }
+
+  #[test]
+  fn test_edit_package_prefix() {
+    let package = r#"<package prefix="rendition: http://www.idpf.org/vocab/rendition/#"><metadata/><manifest></manifest></package>"#;
+    let edited = edit_package(package, None, "2025-06-01T12:00:00Z").unwrap();
+    assert!(edited.starts_with(
+      r#"<package prefix="rendition: http://www.idpf.org/vocab/rendition/# ppub: http://example.com/ppub">"#
+    ));
+
+    let package = "<package>\n  <metadata>\n    <title>T</title>\n  </metadata>\n</package>";
+    let edited = edit_package(package, None, "2025-06-01T12:00:00Z").unwrap();
+    assert_eq!(
+      edited,
+      "<package prefix=\"ppub: http://example.com/ppub\">\n  <metadata>\n    <title>T</title>\n    <meta property=\"dcterms:modified\">2025-06-01T12:00:00Z</meta>\n  </metadata>\n</package>"
+    );
+  }
+
+  #[test]
+  fn test_save_annotations() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("book.epub");
+    fs::write(&path, test_utils::epub_bytes(&[])).unwrap();
+
+    save_annotations(&path, &annotations()).unwrap();
+
+    let epub = load(fs::read(&path).unwrap());
+    assert_eq!(epub.renditions[0].annotations().len(), 1);
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+  }
+
+  #[test]
+  fn test_save_annotations_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut zip = ZipArchive::new(Cursor::new(test_utils::epub_bytes(&[]))).unwrap();
+    zip.extract(dir.path()).unwrap();
+
+    save_annotations(dir.path(), &annotations()).unwrap();
+
+    let mut archive = Archive::load(FileZip(dir.path().to_path_buf())).unwrap();
+    let epub = Epub::load(&mut archive).unwrap();
+    assert_eq!(epub.renditions[0].annotations().len(), 1);
+    assert_eq!(fs::read_dir(dir.path().join("EPUB")).unwrap().count(), 4);
+  }
+
+  #[test]
+  fn test_timestamp() {
+    assert_eq!(timestamp(0), "1970-01-01T00:00:00Z");
+    assert_eq!(timestamp(951_782_400), "2000-02-29T00:00:00Z");
+    assert_eq!(timestamp(1_704_067_199), "2023-12-31T23:59:59Z");
+  }
+}
@@ -1,31 +1,49 @@
 //! Abstraction over ZIP files which are either resident in memory (for web usage) or on disk (for native usage).
+//!
+//! On disk, an EPUB can also be an unzipped directory, which is read as if it were zipped.
 
-#[cfg(not(target_arch = "wasm32"))]
-use std::{fs::File, io::BufReader};
 use std::{
-  io::{BufRead, Cursor, Read, Seek},
-  path::PathBuf,
+  collections::HashMap,
+  fs,
+  io::{self, BufRead, Cursor, Read, Seek, Write},
+  path::{Path, PathBuf},
   sync::Arc,
 };
+#[cfg(not(target_arch = "wasm32"))]
+use std::{fs::File, io::BufReader};
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
 use format_serde_error::SerdeError;
+use itertools::Either;
 use log::{trace, warn};
 use serde::de::DeserializeOwned;
-use zip::ZipArchive;
+use zip::{CompressionMethod, ZipArchive, ZipWriter, write::SimpleFileOptions};
 
 /// A pointer to a ZIP file in memory.
 #[derive(Clone)]
 pub struct MemoryZip(pub Arc<[u8]>);
 
-/// A path to a ZIP file on disk.
+/// A path to a ZIP file on disk, or to a directory holding the unzipped contents of one.
 #[derive(Clone)]
 pub struct FileZip(pub PathBuf);
 
+/// The contents of the `mimetype` file of an EPUB.
+const MIMETYPE: &[u8] = b"application/epub+zip";
+
 /// A common interface for interpreting an object as a cursor into a ZIP file.
 pub trait ZipFormat: Clone {
   type Format: BufRead + Seek;
+
+  /// Opens a fresh reader over the ZIP file.
+  ///
+  /// # Errors
+  /// If the underlying ZIP file cannot be opened.
   fn as_reader(&self) -> Result<Self::Format>;
+
+  /// Returns the directory holding the files of the archive, if it has been unzipped.
+  fn directory(&self) -> Option<&Path> {
+    None
+  }
 }
 
 impl ZipFormat for MemoryZip {
@@ -41,6 +59,10 @@ impl ZipFormat for FileZip {
   fn as_reader(&self) -> Result<Self::Format> {
     Ok(BufReader::new(File::open(&self.0)?))
   }
+
+  fn directory(&self) -> Option<&Path> {
+    self.0.is_dir().then_some(self.0.as_path())
+  }
 }
 
 /// A ZIP archive generic over the details of the format.
@@ -48,8 +70,19 @@ pub struct Archive<F: ZipFormat = ArchiveFormat> {
   /// A description of the ZIP file's format.
   format: F,
 
+  /// The files in the archive.
+  entries: Entries<F::Format>,
+}
+
+enum Entries<R> {
   /// A cursor into the ZIP file.
-  zip: ZipArchive<F::Format>,
+  Zip(ZipArchive<R>),
+
+  /// An unzipped directory, read as if it were zipped.
+  ///
+  /// Its files are listed when the archive is loaded, so that paths which leave the directory or
+  /// name hidden files cannot be read. If the directory has no `mimetype` file, one is made up.
+  Directory { root: PathBuf, files: Vec<String> },
 }
 
 impl<F: ZipFormat> Archive<F> {
@@ -57,10 +90,20 @@ impl<F: ZipFormat> Archive<F> {
   ///
   /// # Errors
   /// - The file's bytes cannot be interpreted as a ZIP file.
+  /// - The format's directory cannot be listed.
   pub fn load(format: F) -> Result<Self> {
-    let reader = format.as_reader()?;
-    let zip = ZipArchive::new(reader).context("Failed to parse EPUB as ZIP")?;
-    Ok(Archive { format, zip })
+    let entries = match format.directory() {
+      Some(root) => Entries::Directory {
+        root: root.to_path_buf(),
+        files: list_directory(root)
+          .with_context(|| format!("Failed to list EPUB directory: {}", root.display()))?,
+      },
+      None => {
+        let reader = format.as_reader()?;
+        Entries::Zip(ZipArchive::new(reader).context("Failed to parse EPUB as ZIP")?)
+      }
+    };
+    Ok(Archive { format, entries })
   }
 
   /// Reads the contents of a file in the archive.
@@ -71,14 +114,108 @@ impl<F: ZipFormat> Archive<F> {
   pub fn read_file(&mut self, file: &str) -> Result<Vec<u8>> {
     trace!("Reading from archive file {file}");
 
-    let mut reader = self
-      .zip
-      .by_name(file)
-      .with_context(|| format!("No entry in epub for file: {file}"))?;
+    match &mut self.entries {
+      Entries::Zip(zip) => {
+        let mut reader = zip
+          .by_name(file)
+          .with_context(|| format!("No entry in epub for file: {file}"))?;
 
-    let mut buffer = Vec::new();
-    reader.read_to_end(&mut buffer)?;
-    Ok(buffer)
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer)?;
+        Ok(buffer)
+      }
+      Entries::Directory { root, files } => {
+        if !files.iter().any(|f| f == file) {
+          bail!("No entry in epub for file: {file}");
+        }
+        let path = root.join(file);
+        if file == "mimetype" && !path.is_file() {
+          return Ok(MIMETYPE.to_vec());
+        }
+        fs::read(&path).with_context(|| format!("Failed to read: {}", path.display()))
+      }
+    }
+  }
+
+  /// Returns the paths of the files in the archive, in the order they are stored.
+  pub fn file_names(&self) -> impl Iterator<Item = &str> {
+    match &self.entries {
+      Entries::Zip(zip) => {
+        Either::Left((0..zip.len()).filter_map(|index| zip.name_for_index(index)))
+      }
+      Entries::Directory { files, .. } => Either::Right(files.iter().map(String::as_str)),
+    }
+  }
+
+  /// Returns true if the archive contains a file at the path `file`.
+  pub fn contains(&self, file: &str) -> bool {
+    match &self.entries {
+      Entries::Zip(zip) => zip.index_for_name(file).is_some(),
+      Entries::Directory { files, .. } => files.iter().any(|f| f == file),
+    }
+  }
+
+  /// Returns the directory holding the files of the archive, if it has been unzipped.
+  pub fn directory(&self) -> Option<&Path> {
+    match &self.entries {
+      Entries::Zip(_) => None,
+      Entries::Directory { root, .. } => Some(root),
+    }
+  }
+
+  /// Returns true if the file at the path `file` is stored without compression.
+  ///
+  /// Files in unzipped directories are never compressed.
+  ///
+  /// # Errors
+  /// If the file path is not contained in the archive.
+  pub fn is_stored(&mut self, file: &str) -> Result<bool> {
+    match &mut self.entries {
+      Entries::Zip(zip) => {
+        let file = zip
+          .by_name(file)
+          .with_context(|| format!("No entry in epub for file: {file}"))?;
+        Ok(file.compression() == CompressionMethod::Stored)
+      }
+      Entries::Directory { files, .. } if files.iter().any(|f| f == file) => Ok(true),
+      Entries::Directory { .. } => bail!("No entry in epub for file: {file}"),
+    }
+  }
+
+  /// Returns the CRC-32 checksum of each file in the archive.
+  ///
+  /// ZIP files store checksums in their central directory, so they are not read. Files in unzipped
+  /// directories are read and checksummed, skipping any which were removed since the archive was
+  /// loaded, like the temporary files of an editor.
+  ///
+  /// # Errors
+  /// If a file in the archive cannot be read.
+  pub fn checksums(&mut self) -> Result<Checksums> {
+    match &mut self.entries {
+      Entries::Zip(zip) => (0..zip.len())
+        .map(|index| {
+          let file = zip.by_index_raw(index)?;
+          Ok((file.name().to_string(), file.crc32()))
+        })
+        .collect(),
+      Entries::Directory { .. } => {
+        let mut checksums = Checksums::new();
+        let files = self.file_names().map(String::from).collect::<Vec<_>>();
+        for file in files {
+          match self.read_file(&file) {
+            Ok(contents) => {
+              checksums.insert(file, crc32fast::hash(&contents));
+            }
+            Err(err)
+              if err
+                .downcast_ref::<io::Error>()
+                .is_some_and(|err| err.kind() == io::ErrorKind::NotFound) => {}
+            Err(err) => return Err(err),
+          }
+        }
+        Ok(checksums)
+      }
+    }
   }
 
   /// Reads a file as XML from the archive.
@@ -117,17 +254,242 @@ impl<F: ZipFormat> Archive<F> {
     })
   }
 
+  /// Writes a copy of the archive to `writer`, replacing the contents of the files in `replacements`.
+  ///
+  /// Other files are copied without being recompressed, so their bytes are preserved exactly.
+  /// Replacements for paths not in the archive are added at the end. The `mimetype` file is
+  /// always written first and uncompressed, as required by the EPUB Open Container Format.
+  ///
+  /// # Errors
+  /// - If a file in the archive cannot be read.
+  /// - If writing to `writer` fails.
+  pub fn rewrite<W: Write + Seek>(
+    &mut self,
+    writer: W,
+    replacements: &[(String, Vec<u8>)],
+  ) -> Result<W> {
+    let mut zip = ZipWriter::new(writer);
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    let Entries::Zip(archive) = &mut self.entries else {
+      let files = self.file_names().map(String::from).collect::<Vec<_>>();
+      zip.start_file("mimetype", stored)?;
+      zip.write_all(&self.read_file("mimetype")?)?;
+      for file in files.into_iter().filter(|file| file != "mimetype") {
+        let contents = match replacements.iter().find(|(path, _)| *path == file) {
+          Some((_, contents)) => contents.clone(),
+          None => self.read_file(&file)?,
+        };
+        zip.start_file(file, deflated)?;
+        zip.write_all(&contents)?;
+      }
+      for (path, contents) in replacements {
+        if !self.contains(path) {
+          zip.start_file(path, deflated)?;
+          zip.write_all(contents)?;
+        }
+      }
+      return Ok(zip.finish()?);
+    };
+
+    match archive.index_for_name("mimetype") {
+      Some(index) if archive.by_index_raw(index)?.compression() == CompressionMethod::Stored => {
+        zip.raw_copy_file(archive.by_index_raw(index)?)?;
+      }
+      Some(index) => {
+        let mut contents = Vec::new();
+        archive.by_index(index)?.read_to_end(&mut contents)?;
+        zip.start_file("mimetype", stored)?;
+        zip.write_all(&contents)?;
+      }
+      None => {
+        zip.start_file("mimetype", stored)?;
+        zip.write_all(MIMETYPE)?;
+      }
+    }
+
+    for index in 0..archive.len() {
+      let file = archive.by_index_raw(index)?;
+      let name = file.name().to_string();
+      if name == "mimetype" {
+        continue;
+      }
+      match replacements.iter().find(|(path, _)| *path == name) {
+        Some((_, contents)) => {
+          drop(file);
+          zip.start_file(name, deflated)?;
+          zip.write_all(contents)?;
+        }
+        None => zip.raw_copy_file(file)?,
+      }
+    }
+
+    for (path, contents) in replacements {
+      if archive.index_for_name(path).is_none() {
+        zip.start_file(path, deflated)?;
+        zip.write_all(contents)?;
+      }
+    }
+
+    Ok(zip.finish()?)
+  }
+
   /// Loads a clone of the current archive.
   ///
   /// # Errors
   /// This may fail if e.g. the file on disk was deleted after `self` was loaded.
   pub fn try_clone(&self) -> Result<Self> {
-    let reader = self.format.clone();
-    Self::load(reader)
+    match &self.entries {
+      Entries::Zip(_) => Self::load(self.format.clone()),
+      Entries::Directory { root, files } => Ok(Archive {
+        format: self.format.clone(),
+        entries: Entries::Directory {
+          root: root.clone(),
+          files: files.clone(),
+        },
+      }),
+    }
   }
 }
 
+/// The CRC-32 checksums of the files in an archive, by path.
+pub type Checksums = HashMap<String, u32>;
+
+/// Returns the paths of the files whose checksums differ between `old` and `new`, sorted.
+///
+/// A file differs if it is only in one of the archives, or if its checksum differs.
+pub fn changed_files(old: &Checksums, new: &Checksums) -> Vec<String> {
+  let mut changed = old
+    .iter()
+    .filter(|(file, checksum)| new.get(*file) != Some(checksum))
+    .chain(new.iter().filter(|(file, _)| !old.contains_key(*file)))
+    .map(|(file, _)| file.clone())
+    .collect::<Vec<_>>();
+  changed.sort();
+  changed
+}
+
+/// Lists the paths of the files under `root` relative to it, with `mimetype` first.
+///
+/// Hidden files and directories, like `.git` or `.DS_Store`, are skipped.
+fn list_directory(root: &Path) -> Result<Vec<String>> {
+  fn walk(dir: &Path, prefix: &str, files: &mut Vec<String>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+      let entry = entry?;
+      let Some(name) = entry.file_name().to_str().map(String::from) else {
+        warn!(
+          "Skipping file with non-UTF-8 name: {}",
+          entry.path().display()
+        );
+        continue;
+      };
+      if name.starts_with('.') {
+        continue;
+      }
+      let path = format!("{prefix}{name}");
+      if entry.file_type()?.is_dir() {
+        walk(&entry.path(), &format!("{path}/"), files)?;
+      } else if entry.path().is_file() {
+        files.push(path);
+      }
+    }
+    Ok(())
+  }
+
+  let mut files = Vec::new();
+  walk(root, "", &mut files)?;
+  files.retain(|file| file != "mimetype");
+  files.sort();
+  files.insert(0, "mimetype".to_string());
+  Ok(files)
+}
+
 #[cfg(target_arch = "wasm32")]
 pub type ArchiveFormat = MemoryZip;
 #[cfg(not(target_arch = "wasm32"))]
 pub type ArchiveFormat = FileZip;
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{Epub, test_utils};
+
+  fn unzip(bytes: Vec<u8>) -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    ZipArchive::new(Cursor::new(bytes))
+      .unwrap()
+      .extract(dir.path())
+      .unwrap();
+    dir
+  }
+
+  #[test]
+  fn test_directory() {
+    let dir = unzip(test_utils::epub_bytes(&[]));
+    fs::remove_file(dir.path().join("mimetype")).unwrap();
+    fs::write(dir.path().join(".DS_Store"), "").unwrap();
+
+    let mut archive = Archive::load(FileZip(dir.path().to_path_buf())).unwrap();
+    assert_eq!(archive.directory(), Some(dir.path()));
+    assert_eq!(
+      archive.file_names().collect::<Vec<_>>(),
+      [
+        "mimetype",
+        "EPUB/annotations.json",
+        "EPUB/chapter.xhtml",
+        "EPUB/nav.xhtml",
+        "EPUB/package.opf",
+        "META-INF/container.xml",
+      ]
+    );
+    assert_eq!(archive.read_file("mimetype").unwrap(), MIMETYPE);
+    assert!(archive.is_stored("EPUB/chapter.xhtml").unwrap());
+    assert!(archive.read_file(".DS_Store").is_err());
+    assert!(archive.read_file("EPUB/../META-INF/container.xml").is_err());
+
+    let epub = Epub::load(&mut archive.try_clone().unwrap()).unwrap();
+    assert_eq!(epub.renditions.len(), 1);
+  }
+
+  #[test]
+  fn test_changed_files() {
+    let mut old = Archive::load(MemoryZip(test_utils::epub_bytes(&[]).into())).unwrap();
+    let old = old.checksums().unwrap();
+    let mut new = Archive::load(MemoryZip(
+      test_utils::epub_bytes(&[("EPUB/chapter.xhtml", "<html/>"), ("EPUB/style.css", "")]).into(),
+    ))
+    .unwrap();
+    assert_eq!(
+      changed_files(&old, &new.checksums().unwrap()),
+      ["EPUB/chapter.xhtml", "EPUB/style.css"]
+    );
+
+    let dir = unzip(test_utils::epub_bytes(&[]));
+    let mut unzipped = Archive::load(FileZip(dir.path().to_path_buf())).unwrap();
+    assert!(changed_files(&old, &unzipped.checksums().unwrap()).is_empty());
+    fs::remove_file(dir.path().join("EPUB/nav.xhtml")).unwrap();
+    assert_eq!(
+      changed_files(&old, &unzipped.checksums().unwrap()),
+      ["EPUB/nav.xhtml"]
+    );
+  }
+
+  #[test]
+  fn test_directory_rewrite() {
+    let dir = unzip(test_utils::epub_bytes(&[]));
+    let mut archive = Archive::load(FileZip(dir.path().to_path_buf())).unwrap();
+    let replacements = [("EPUB/annotations.json".to_string(), b"[1]".to_vec())];
+    let written = archive
+      .rewrite(Cursor::new(Vec::new()), &replacements)
+      .unwrap();
+
+    let mut zip = Archive::load(MemoryZip(written.into_inner().into())).unwrap();
+    assert_eq!(
+      zip.file_names().collect::<Vec<_>>(),
+      archive.file_names().collect::<Vec<_>>()
+    );
+    assert!(zip.is_stored("mimetype").unwrap());
+    assert_eq!(zip.read_file("EPUB/annotations.json").unwrap(), b"[1]");
+  }
+}